@@ -0,0 +1,33 @@
+use std::fmt;
+
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // The config file could not be read (path)
+    FileReadError(String),
+    // The config file is not valid toml (line, what went wrong)
+    ParseError(usize, String),
+    // A setting we don't know about (key)
+    UnknownKey(String),
+    // A setting with a value we can't use (key or env var, what we expected)
+    InvalidValue(String, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::FileReadError(ref path) => {
+                write!(f, "{}: can't read the file", path)
+            }
+            ConfigError::ParseError(line, ref desc) => {
+                write!(f, "line {}: {}", line, desc)
+            }
+            ConfigError::UnknownKey(ref key) => {
+                write!(f, "{}: unknown setting", key)
+            }
+            ConfigError::InvalidValue(ref key, expected) => {
+                write!(f, "{}: {}", key, expected)
+            }
+        }
+    }
+}
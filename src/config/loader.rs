@@ -0,0 +1,532 @@
+use std::fs::File;
+use std::io::Read;
+
+use toml;
+
+use metrics::sinks::parse_sink_specs;
+use options::MemcacheOptions;
+use options::parse_fsync_policy;
+use options::parse_namespaces;
+use options::parse_size;
+
+use super::errors::ConfigError;
+use super::typedefs::ConfigResult;
+
+
+// The environment variable that overrides each setting of the config file
+const ENV_VARS: &'static [(&'static str, &'static str)] = &[
+    ("EMCACHE_HOST", "server.host"),
+    ("EMCACHE_PORT", "server.port"),
+    ("EMCACHE_MEM", "memory.mem"),
+    ("EMCACHE_MAX_ITEM_SIZE", "memory.max_item_size"),
+    ("EMCACHE_MAX_KEY_LEN", "memory.max_key_len"),
+    ("EMCACHE_COMPRESS_THRESHOLD", "memory.compress_threshold"),
+    ("EMCACHE_NAMESPACES", "namespaces.quotas"),
+    ("EMCACHE_NAMESPACE_DELIMITER", "namespaces.delimiter"),
+    ("EMCACHE_CRAWLER_BATCH", "expiry.crawler_batch"),
+    ("EMCACHE_EXTSTORE_PATH", "extstore.path"),
+    ("EMCACHE_EXTSTORE_SIZE", "extstore.size"),
+    ("EMCACHE_EXTSTORE_ITEM_MIN", "extstore.item_min"),
+    ("EMCACHE_SNAPSHOT_PATH", "persistence.snapshot_path"),
+    ("EMCACHE_SNAPSHOT_INTERVAL", "persistence.snapshot_interval"),
+    ("EMCACHE_LOG_PATH", "persistence.log_path"),
+    ("EMCACHE_LOG_FSYNC", "persistence.log_fsync"),
+    ("EMCACHE_LOG_COMPACT_MB", "persistence.log_compact_mb"),
+    ("EMCACHE_METRICS", "metrics.enabled"),
+    ("EMCACHE_METRICS_SINKS", "metrics.sinks"),
+    ("EMCACHE_HOTKEYS", "metrics.hotkeys"),
+    ("EMCACHE_SLOWLOG_THRESHOLD_US", "slowlog.threshold_us"),
+    ("EMCACHE_SLOWLOG_MAX_LEN", "slowlog.max_len"),
+    ("EMCACHE_DAEMON", "process.daemon"),
+    ("EMCACHE_DAEMON_LOG", "process.daemon_log"),
+    ("EMCACHE_PIDFILE", "process.pidfile"),
+    ("EMCACHE_USER", "process.user"),
+    ("EMCACHE_ADMIN_PORT", "admin.port"),
+    ("EMCACHE_ADMIN_HOST", "admin.host"),
+    ("EMCACHE_ADMIN_WRITES", "admin.writes"),
+    ("EMCACHE_ADMIN_READ_TIMEOUT", "timeouts.admin_read"),
+    ("EMCACHE_ADMIN_DRIVER_TIMEOUT", "timeouts.admin_driver"),
+    ("EMCACHE_ADMIN_READY_TIMEOUT", "timeouts.admin_ready"),
+    ("EMCACHE_DRAIN_TIMEOUT", "timeouts.drain"),
+    ("EMCACHE_IDLE_INTERVAL", "timeouts.idle_interval"),
+    ("EMCACHE_ENABLE_DEBUGTIME", "debug.debugtime"),
+];
+
+// Points at the config file when --config isn't given
+const ENV_CONFIG_PATH: &'static str = "EMCACHE_CONFIG";
+
+
+// A setting as it was given, in the config file or in the environment
+#[derive(Clone, Copy)]
+enum RawValue<'a> {
+    Toml(&'a toml::Value),
+    Env(&'a str),
+}
+
+
+// Builds the options from (lowest precedence first): the defaults, the
+// config file, the environment and the command line
+pub fn load_options<I>(cli: &MemcacheOptions,
+                       vars: I)
+                       -> ConfigResult<MemcacheOptions>
+    where I: Iterator<Item = (String, String)>
+{
+    let vars: Vec<(String, String)> = vars.collect();
+    let mut opts = MemcacheOptions::new();
+
+    let path = match cli.flag_config {
+        Some(ref path) => Some(path.clone()),
+        None => {
+            vars.iter()
+                .find(|&&(ref var, _)| var == ENV_CONFIG_PATH)
+                .map(|&(_, ref path)| path.clone())
+        }
+    };
+
+    // The quotas are checked against the memory limit once we have both,
+    // and reported under the name they were given with
+    let quotas_name = if cli.flag_namespaces.is_some() {
+        "--namespaces"
+    } else if vars.iter().any(|&(ref var, _)| var == "EMCACHE_NAMESPACES") {
+        "EMCACHE_NAMESPACES"
+    } else {
+        "namespaces.quotas"
+    };
+
+    match path {
+        Some(path) => {
+            let text = try!(read_config_file(&path));
+            try!(apply_config(&mut opts, &text));
+        }
+        None => (),
+    }
+
+    try!(apply_env(&mut opts, vars.into_iter()));
+    try!(check_cli(cli));
+    opts.override_with(cli);
+    try!(check_quotas(&opts, quotas_name));
+
+    Ok(opts)
+}
+
+fn read_config_file(path: &str) -> ConfigResult<String> {
+    let mut text = String::new();
+
+    let result = File::open(path)
+                     .and_then(|mut file| file.read_to_string(&mut text));
+    match result {
+        Ok(_) => Ok(text),
+        Err(_) => Err(ConfigError::FileReadError(path.to_string())),
+    }
+}
+
+
+// Sets the options given in a toml config file, eg.
+//
+// [server]
+// port = 11311
+pub fn apply_config(opts: &mut MemcacheOptions,
+                    text: &str)
+                    -> ConfigResult<()> {
+    let mut parser = toml::Parser::new(text);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let error = &parser.errors[0];
+            let (line, _) = parser.to_linecol(error.lo);
+            return Err(ConfigError::ParseError(line + 1, error.desc.clone()));
+        }
+    };
+
+    for (section, entries) in table.iter() {
+        let entries = match entries.as_table() {
+            Some(entries) => entries,
+            None => {
+                return Err(ConfigError::InvalidValue(section.clone(),
+                                                     "expected a section"))
+            }
+        };
+
+        for (name, value) in entries.iter() {
+            let key = format!("{}.{}", section, name);
+            try!(apply_setting(opts, &key, &key, RawValue::Toml(value)));
+        }
+    }
+
+    Ok(())
+}
+
+// Sets the options given as EMCACHE_* environment variables. Variables we
+// don't know about are left alone, the environment isn't ours alone.
+pub fn apply_env<I>(opts: &mut MemcacheOptions, vars: I) -> ConfigResult<()>
+    where I: Iterator<Item = (String, String)>
+{
+    for (var, value) in vars {
+        let key = ENV_VARS.iter()
+                          .find(|&&(env_var, _)| env_var == var)
+                          .map(|&(_, key)| key);
+
+        match key {
+            Some(key) => {
+                try!(apply_setting(opts, key, &var, RawValue::Env(&value)))
+            }
+            None => (),
+        }
+    }
+
+    Ok(())
+}
+
+
+// The flags are mostly checked as they're parsed, but docopt happily takes
+// a zero where there has to be something
+fn check_cli(cli: &MemcacheOptions) -> ConfigResult<()> {
+    let flags = [("--mem", cli.flag_mem),
+                 ("--max-key-len", cli.flag_max_key_len),
+                 ("--extstore-size", cli.flag_extstore_size),
+                 ("--snapshot-interval", cli.flag_snapshot_interval),
+                 ("--log-compact-mb", cli.flag_log_compact_mb)];
+
+    for &(flag, value) in flags.iter() {
+        if value == Some(0) {
+            return Err(invalid(flag, "expected a number above 0"));
+        }
+    }
+
+    let switches = [("--metrics", cli.flag_metrics, cli.flag_no_metrics),
+                    ("--hotkeys", cli.flag_hotkeys, cli.flag_no_hotkeys),
+                    ("--daemon", cli.flag_daemon, cli.flag_no_daemon),
+                    ("--admin-writes",
+                     cli.flag_admin_writes,
+                     cli.flag_no_admin_writes),
+                    ("--enable-debugtime",
+                     cli.flag_enable_debugtime,
+                     cli.flag_disable_debugtime)];
+
+    for &(flag, on, off) in switches.iter() {
+        if on && off {
+            return Err(invalid(flag, "given together with its opposite"));
+        }
+    }
+
+    let secs_flags = [("--admin-read-timeout", cli.flag_admin_read_timeout),
+                      ("--admin-driver-timeout",
+                       cli.flag_admin_driver_timeout),
+                      ("--admin-ready-timeout", cli.flag_admin_ready_timeout),
+                      ("--drain-timeout", cli.flag_drain_timeout),
+                      ("--idle-interval", cli.flag_idle_interval)];
+
+    for &(flag, value) in secs_flags.iter() {
+        match value {
+            Some(secs) if !is_secs(secs) => {
+                return Err(invalid(flag, "expected seconds above 0"));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+
+// The namespaces' quotas are taken out of the memory limit, so they can't
+// add up to more than it
+fn check_quotas(opts: &MemcacheOptions, name: &str) -> ConfigResult<()> {
+    let quotas = match opts.flag_namespaces {
+        Some(ref specs) => parse_namespaces(specs).unwrap_or(vec![]),
+        None => return Ok(()),
+    };
+
+    let total = quotas.iter()
+                      .fold(0u64, |total, &(_, quota)| {
+                          total.saturating_add(quota)
+                      });
+    if total > opts.get_mem_limit_bytes() {
+        return Err(invalid(name, "expected quotas that add up to mem or less"));
+    }
+
+    Ok(())
+}
+
+
+// Sets the option for the setting key. Errors are reported under name,
+// which is what the user wrote: the key itself or an env var.
+fn apply_setting(opts: &mut MemcacheOptions,
+                 key: &str,
+                 name: &str,
+                 raw: RawValue)
+                 -> ConfigResult<()> {
+    match key {
+        "server.host" => {
+            opts.flag_host = Some(try!(get_string(name, raw)));
+        }
+        "server.port" => {
+            opts.flag_port = Some(try!(get_port(name, raw)));
+        }
+
+        "memory.mem" => {
+            opts.flag_mem = Some(try!(get_above_zero(name, raw)));
+        }
+        "memory.max_item_size" => {
+            opts.flag_max_item_size = Some(try!(get_size(name, raw)));
+        }
+        "memory.max_key_len" => {
+            opts.flag_max_key_len = Some(try!(get_above_zero(name, raw)));
+        }
+        "memory.compress_threshold" => {
+            opts.flag_compress_threshold = Some(try!(get_u64(name, raw)));
+        }
+
+        "namespaces.quotas" => {
+            let specs = try!(get_namespaces(name, raw));
+            if parse_namespaces(&specs).is_none() {
+                return Err(invalid(name, "expected name:MB pairs"));
+            }
+            opts.flag_namespaces = Some(specs);
+        }
+        "namespaces.delimiter" => {
+            let delimiter = try!(get_string(name, raw));
+            if delimiter.len() != 1 {
+                return Err(invalid(name, "expected a single character"));
+            }
+            opts.flag_namespace_delimiter = Some(delimiter);
+        }
+
+        "expiry.crawler_batch" => {
+            opts.flag_crawler_batch = Some(try!(get_u64(name, raw)));
+        }
+
+        "extstore.path" => {
+            opts.flag_extstore_path = Some(try!(get_string(name, raw)));
+        }
+        "extstore.size" => {
+            opts.flag_extstore_size = Some(try!(get_above_zero(name, raw)));
+        }
+        "extstore.item_min" => {
+            opts.flag_extstore_item_min = Some(try!(get_u64(name, raw)));
+        }
+
+        "persistence.snapshot_path" => {
+            opts.flag_snapshot_path = Some(try!(get_string(name, raw)));
+        }
+        "persistence.snapshot_interval" => {
+            let interval = try!(get_above_zero(name, raw));
+            opts.flag_snapshot_interval = Some(interval);
+        }
+        "persistence.log_path" => {
+            opts.flag_log_path = Some(try!(get_string(name, raw)));
+        }
+        "persistence.log_fsync" => {
+            let policy = try!(get_string(name, raw));
+            if parse_fsync_policy(&policy).is_none() {
+                return Err(invalid(name,
+                                   "expected always, everysec or never"));
+            }
+            opts.flag_log_fsync = Some(policy);
+        }
+        "persistence.log_compact_mb" => {
+            let compact_mb = try!(get_above_zero(name, raw));
+            opts.flag_log_compact_mb = Some(compact_mb);
+        }
+
+        "metrics.enabled" => {
+            opts.flag_metrics = try!(get_bool(name, raw));
+        }
+        "metrics.sinks" => {
+            let specs = try!(get_list(name, raw));
+            if parse_sink_specs(&specs).is_none() {
+                return Err(invalid(name,
+                                   "expected stdout, json:PATH or \
+                                    statsd:HOST:PORT"));
+            }
+            opts.flag_metrics_sinks = Some(specs);
+        }
+        "metrics.hotkeys" => {
+            opts.flag_no_hotkeys = !try!(get_bool(name, raw));
+        }
+
+        "slowlog.threshold_us" => {
+            opts.flag_slowlog_threshold_us = Some(try!(get_u64(name, raw)));
+        }
+        "slowlog.max_len" => {
+            let max_len = try!(get_u64(name, raw));
+            opts.flag_slowlog_max_len = Some(max_len as usize);
+        }
+
+        "process.daemon" => {
+            opts.flag_daemon = try!(get_bool(name, raw));
+        }
+        "process.daemon_log" => {
+            opts.flag_daemon_log = Some(try!(get_string(name, raw)));
+        }
+        "process.pidfile" => {
+            opts.flag_pidfile = Some(try!(get_string(name, raw)));
+        }
+        "process.user" => {
+            opts.flag_user = Some(try!(get_string(name, raw)));
+        }
+
+        "admin.port" => {
+            opts.flag_admin_port = Some(try!(get_port(name, raw)));
+        }
+        "admin.host" => {
+            opts.flag_admin_host = Some(try!(get_string(name, raw)));
+        }
+        "admin.writes" => {
+            opts.flag_admin_writes = try!(get_bool(name, raw));
+        }
+
+        "timeouts.admin_read" => {
+            let timeout = try!(get_secs(name, raw));
+            opts.flag_admin_read_timeout = Some(timeout);
+        }
+        "timeouts.admin_driver" => {
+            let timeout = try!(get_secs(name, raw));
+            opts.flag_admin_driver_timeout = Some(timeout);
+        }
+        "timeouts.admin_ready" => {
+            let timeout = try!(get_secs(name, raw));
+            opts.flag_admin_ready_timeout = Some(timeout);
+        }
+        "timeouts.drain" => {
+            opts.flag_drain_timeout = Some(try!(get_secs(name, raw)));
+        }
+        "timeouts.idle_interval" => {
+            opts.flag_idle_interval = Some(try!(get_secs(name, raw)));
+        }
+
+        "debug.debugtime" => {
+            opts.flag_enable_debugtime = try!(get_bool(name, raw));
+        }
+
+        _ => return Err(ConfigError::UnknownKey(name.to_string())),
+    }
+
+    Ok(())
+}
+
+fn invalid(name: &str, expected: &'static str) -> ConfigError {
+    ConfigError::InvalidValue(name.to_string(), expected)
+}
+
+fn get_string(name: &str, raw: RawValue) -> ConfigResult<String> {
+    match raw {
+        RawValue::Toml(&toml::Value::String(ref value)) => Ok(value.clone()),
+        RawValue::Env(value) => Ok(value.to_string()),
+        _ => Err(invalid(name, "expected a string")),
+    }
+}
+
+fn get_u64(name: &str, raw: RawValue) -> ConfigResult<u64> {
+    match raw {
+        RawValue::Toml(&toml::Value::Integer(value)) if value >= 0 => {
+            Ok(value as u64)
+        }
+        RawValue::Env(value) => {
+            value.parse::<u64>()
+                 .map_err(|_| invalid(name, "expected a positive number"))
+        }
+        _ => Err(invalid(name, "expected a positive number")),
+    }
+}
+
+// For the settings where zero makes no sense, eg. no memory at all
+fn get_above_zero(name: &str, raw: RawValue) -> ConfigResult<u64> {
+    match try!(get_u64(name, raw)) {
+        0 => Err(invalid(name, "expected a number above 0")),
+        value => Ok(value),
+    }
+}
+
+// A number of megabytes, or a string with a unit (see parse_size)
+fn get_size(name: &str, raw: RawValue) -> ConfigResult<String> {
+    let size = match raw {
+        RawValue::Toml(&toml::Value::Integer(value)) => value.to_string(),
+        RawValue::Toml(&toml::Value::String(ref value)) => value.clone(),
+        RawValue::Env(value) => value.to_string(),
+        _ => return Err(invalid(name, "expected a size, eg. 512k or 2m")),
+    };
+
+    match parse_size(&size) {
+        Some(_) => Ok(size),
+        None => Err(invalid(name, "expected a size, eg. 512k or 2m")),
+    }
+}
+
+// A duration in seconds, which may be fractional but can't be zero
+fn get_secs(name: &str, raw: RawValue) -> ConfigResult<f64> {
+    let secs = match raw {
+        RawValue::Toml(&toml::Value::Integer(value)) => value as f64,
+        RawValue::Toml(&toml::Value::Float(value)) => value,
+        RawValue::Env(value) => value.parse::<f64>().unwrap_or(0.0),
+        _ => 0.0,
+    };
+
+    match is_secs(secs) {
+        true => Ok(secs),
+        false => Err(invalid(name, "expected seconds above 0")),
+    }
+}
+
+fn is_secs(secs: f64) -> bool {
+    secs.is_finite() && secs > 0.0
+}
+
+fn get_port(name: &str, raw: RawValue) -> ConfigResult<u16> {
+    let port = try!(get_u64(name, raw));
+    if port > 65535 {
+        return Err(invalid(name, "expected a port number"));
+    }
+    Ok(port as u16)
+}
+
+fn get_bool(name: &str, raw: RawValue) -> ConfigResult<bool> {
+    match raw {
+        RawValue::Toml(&toml::Value::Boolean(value)) => Ok(value),
+        RawValue::Env("true") | RawValue::Env("1") => Ok(true),
+        RawValue::Env("false") | RawValue::Env("0") => Ok(false),
+        _ => Err(invalid(name, "expected true or false")),
+    }
+}
+
+// A list is an array of strings in the config file and comma separated in
+// the environment. Either way we return it comma separated.
+fn get_list(name: &str, raw: RawValue) -> ConfigResult<String> {
+    let values = match raw {
+        RawValue::Toml(&toml::Value::Array(ref values)) => values,
+        RawValue::Toml(&toml::Value::String(ref value)) => {
+            return Ok(value.clone())
+        }
+        RawValue::Env(value) => return Ok(value.to_string()),
+        _ => return Err(invalid(name, "expected a list of strings")),
+    };
+
+    let mut items = vec![];
+    for value in values {
+        match value.as_str() {
+            Some(item) => items.push(item.to_string()),
+            None => return Err(invalid(name, "expected a list of strings")),
+        }
+    }
+
+    Ok(items.join(","))
+}
+
+// Quotas are a table of name = MB in the config file and name:MB pairs in
+// the environment (same as --namespaces). Either way we return the pairs.
+fn get_namespaces(name: &str, raw: RawValue) -> ConfigResult<String> {
+    let quotas = match raw {
+        RawValue::Toml(&toml::Value::Table(ref quotas)) => quotas,
+        RawValue::Env(value) => return Ok(value.to_string()),
+        _ => return Err(invalid(name, "expected a table of quotas")),
+    };
+
+    let mut specs = vec![];
+    for (namespace, quota) in quotas.iter() {
+        let quota_name = format!("{}.{}", name, namespace);
+        let quota = try!(get_above_zero(&quota_name, RawValue::Toml(quota)));
+        specs.push(format!("{}:{}", namespace, quota));
+    }
+
+    Ok(specs.join(","))
+}
@@ -0,0 +1,15 @@
+// Declare sub modules
+pub mod errors;
+pub mod loader;
+pub mod typedefs;
+
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
+
+
+// Export our public api
+pub use self::errors::ConfigError;
+pub use self::loader::apply_config;
+pub use self::loader::apply_env;
+pub use self::loader::load_options;
+pub use self::typedefs::ConfigResult;
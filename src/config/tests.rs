@@ -0,0 +1,476 @@
+use std::fs::File;
+use std::fs;
+use std::io::Write;
+
+use metrics::sinks::SinkSpec;
+use options::MemcacheOptions;
+use options::parse_size;
+use persistence::FsyncPolicy;
+use testlib::tempfile::get_temp_path;
+
+use super::ConfigError;
+use super::apply_config;
+use super::apply_env;
+use super::load_options;
+
+
+fn env_vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|&(var, value)| (var.to_string(), value.to_string()))
+        .collect()
+}
+
+
+// Config file
+
+#[test]
+fn test_config_every_setting() {
+    let text = r#"
+[server]
+host = "0.0.0.0"
+port = 11411
+
+[memory]
+mem = 128
+max_item_size = 2
+max_key_len = 100
+compress_threshold = 4096
+
+[namespaces]
+delimiter = "/"
+
+[namespaces.quotas]
+sessions = 8
+users = 16
+
+[expiry]
+crawler_batch = 50
+
+[extstore]
+path = "/tmp/emcache.ext"
+size = 2048
+item_min = 1024
+
+[persistence]
+snapshot_path = "/tmp/emcache.snapshot"
+snapshot_interval = 60
+log_path = "/tmp/emcache.log"
+log_fsync = "always"
+log_compact_mb = 32
+
+[metrics]
+enabled = true
+sinks = ["stdout", "statsd:localhost:8125"]
+hotkeys = false
+
+[slowlog]
+threshold_us = 500
+max_len = 16
+
+[process]
+daemon = true
+daemon_log = "/tmp/emcache.out"
+pidfile = "/tmp/emcache.pid"
+user = "nobody"
+
+[admin]
+port = 11412
+host = "0.0.0.0"
+writes = true
+
+[timeouts]
+admin_read = 2
+admin_driver = 3
+admin_ready = 0.5
+drain = 10
+idle_interval = 0.25
+
+[debug]
+debugtime = true
+"#;
+    let mut opts = MemcacheOptions::new();
+    apply_config(&mut opts, text).unwrap();
+
+    assert_eq!("0.0.0.0:11411", opts.get_bind_string());
+    assert_eq!(128, opts.get_mem_limit());
+    assert_eq!(2 << 20, opts.get_max_item_size_bytes());
+    assert_eq!(100, opts.get_max_key_len());
+    assert_eq!(4096, opts.get_compress_threshold());
+    assert_eq!(vec![("sessions".to_string(), 8 << 20),
+                    ("users".to_string(), 16 << 20)],
+               opts.get_namespaces());
+    assert_eq!(b'/', opts.get_namespace_delimiter());
+    assert_eq!(50, opts.get_crawler_batch());
+    assert_eq!(Some("/tmp/emcache.ext".to_string()),
+               opts.get_extstore_path());
+    assert_eq!(2048 << 20, opts.get_extstore_size_bytes());
+    assert_eq!(1024, opts.get_extstore_item_min());
+    assert_eq!(Some("/tmp/emcache.snapshot".to_string()),
+               opts.get_snapshot_path());
+    assert_eq!(Some(60.0), opts.get_snapshot_interval());
+    assert_eq!(Some("/tmp/emcache.log".to_string()), opts.get_log_path());
+    assert_eq!(FsyncPolicy::Always, opts.get_log_fsync_policy());
+    assert_eq!(32 << 20, opts.get_log_compact_bytes());
+    assert_eq!(true, opts.get_metrics_enabled());
+    assert_eq!(vec![SinkSpec::Stdout,
+                    SinkSpec::Statsd("localhost:8125".to_string())],
+               opts.get_metrics_sinks());
+    assert_eq!(false, opts.get_hot_keys_enabled());
+    assert_eq!(0.0005, opts.get_slow_log_threshold());
+    assert_eq!(16, opts.get_slow_log_max_len());
+    assert_eq!(true, opts.get_daemon_enabled());
+    assert_eq!("/tmp/emcache.out", opts.get_daemon_log_path());
+    assert_eq!(Some("/tmp/emcache.pid".to_string()), opts.get_pidfile_path());
+    assert_eq!(Some("nobody".to_string()), opts.get_user());
+    assert_eq!(Some(("0.0.0.0".to_string(), 11412)),
+               opts.get_admin_bind_params());
+    assert_eq!(true, opts.get_admin_writes_enabled());
+    assert_eq!(2.0, opts.get_admin_read_timeout());
+    assert_eq!(3.0, opts.get_admin_driver_timeout());
+    assert_eq!(0.5, opts.get_admin_ready_timeout());
+    assert_eq!(10.0, opts.get_drain_timeout());
+    assert_eq!(0.25, opts.get_idle_interval());
+    assert_eq!(true, opts.get_debug_time_enabled());
+}
+
+#[test]
+fn test_config_keeps_defaults() {
+    let mut opts = MemcacheOptions::new();
+    apply_config(&mut opts, "[server]\nport = 11411\n").unwrap();
+
+    assert_eq!("127.0.0.1:11411", opts.get_bind_string());
+    assert_eq!(64, opts.get_mem_limit());
+    assert_eq!(FsyncPolicy::EverySec, opts.get_log_fsync_policy());
+    assert_eq!(vec![SinkSpec::Stdout], opts.get_metrics_sinks());
+    assert_eq!(0.01, opts.get_slow_log_threshold());
+    assert_eq!(128, opts.get_slow_log_max_len());
+    assert_eq!(5.0, opts.get_admin_read_timeout());
+    assert_eq!(1.0, opts.get_admin_ready_timeout());
+    assert_eq!(5.0, opts.get_drain_timeout());
+    assert_eq!(1.0, opts.get_idle_interval());
+}
+
+#[test]
+fn test_config_errors_name_the_key() {
+    let mut opts = MemcacheOptions::new();
+
+    assert_eq!(ConfigError::UnknownKey("memory.memory".to_string()),
+               apply_config(&mut opts, "[memory]\nmemory = 1\n")
+                   .unwrap_err());
+    assert_eq!(ConfigError::UnknownKey("sever.port".to_string()),
+               apply_config(&mut opts, "[sever]\nport = 1\n").unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("port".to_string(),
+                                         "expected a section"),
+               apply_config(&mut opts, "port = 1\n").unwrap_err());
+
+    assert_eq!(ConfigError::InvalidValue("memory.mem".to_string(),
+                                         "expected a positive number"),
+               apply_config(&mut opts, "[memory]\nmem = \"64\"\n")
+                   .unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("memory.mem".to_string(),
+                                         "expected a positive number"),
+               apply_config(&mut opts, "[memory]\nmem = -1\n").unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("server.port".to_string(),
+                                         "expected a port number"),
+               apply_config(&mut opts, "[server]\nport = 70000\n")
+                   .unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("persistence.log_fsync"
+                                             .to_string(),
+                                         "expected always, everysec or \
+                                          never"),
+               apply_config(&mut opts, "[persistence]\nlog_fsync = \"no\"\n")
+                   .unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("namespaces.quotas.users"
+                                             .to_string(),
+                                         "expected a positive number"),
+               apply_config(&mut opts, "[namespaces.quotas]\nusers = 1.5\n")
+                   .unwrap_err());
+
+    assert_eq!(ConfigError::InvalidValue("metrics.sinks".to_string(),
+                                         "expected stdout, json:PATH or \
+                                          statsd:HOST:PORT"),
+               apply_config(&mut opts, "[metrics]\nsinks = [\"udp\"]\n")
+                   .unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("metrics.sinks".to_string(),
+                                         "expected a list of strings"),
+               apply_config(&mut opts, "[metrics]\nsinks = [1]\n")
+                   .unwrap_err());
+
+    // Nothing was set
+    assert_eq!(64, opts.get_mem_limit());
+}
+
+#[test]
+fn test_config_rejects_zero() {
+    let mut opts = MemcacheOptions::new();
+
+    assert_eq!(ConfigError::InvalidValue("memory.mem".to_string(),
+                                         "expected a number above 0"),
+               apply_config(&mut opts, "[memory]\nmem = 0\n").unwrap_err());
+    assert_eq!(ConfigError::InvalidValue("namespaces.quotas.users"
+                                             .to_string(),
+                                         "expected a number above 0"),
+               apply_config(&mut opts, "[namespaces.quotas]\nusers = 0\n")
+                   .unwrap_err());
+
+    let vars = env_vars(&[("EMCACHE_LOG_COMPACT_MB", "0")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_LOG_COMPACT_MB"
+                                             .to_string(),
+                                         "expected a number above 0"),
+               apply_env(&mut opts, vars.into_iter()).unwrap_err());
+
+    assert_eq!(ConfigError::InvalidValue("timeouts.drain".to_string(),
+                                         "expected seconds above 0"),
+               apply_config(&mut opts, "[timeouts]\ndrain = 0\n")
+                   .unwrap_err());
+
+    let vars = env_vars(&[("EMCACHE_IDLE_INTERVAL", "-1")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_IDLE_INTERVAL".to_string(),
+                                         "expected seconds above 0"),
+               apply_env(&mut opts, vars.into_iter()).unwrap_err());
+
+    let vars = env_vars(&[("EMCACHE_NAMESPACES", "users:0")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_NAMESPACES".to_string(),
+                                         "expected name:MB pairs"),
+               apply_env(&mut opts, vars.into_iter()).unwrap_err());
+
+    // Flags too, they aren't checked otherwise
+    let mut cli = MemcacheOptions::new();
+    cli.flag_mem = Some(0);
+    assert_eq!(ConfigError::InvalidValue("--mem".to_string(),
+                                         "expected a number above 0"),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+
+    let mut cli = MemcacheOptions::new();
+    cli.flag_admin_ready_timeout = Some(0.0);
+    assert_eq!(ConfigError::InvalidValue("--admin-ready-timeout"
+                                             .to_string(),
+                                         "expected seconds above 0"),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+
+    // Nothing was set
+    assert_eq!(64, opts.get_mem_limit());
+}
+
+#[test]
+fn test_config_error_display() {
+    let err = ConfigError::InvalidValue("memory.mem".to_string(),
+                                        "expected a positive number");
+    assert_eq!("memory.mem: expected a positive number", err.to_string());
+
+    let err = ConfigError::UnknownKey("sever.port".to_string());
+    assert_eq!("sever.port: unknown setting", err.to_string());
+
+    let err = ConfigError::ParseError(2, "expected a value".to_string());
+    assert_eq!("line 2: expected a value", err.to_string());
+
+    let err = ConfigError::FileReadError("/etc/emcache.toml".to_string());
+    assert_eq!("/etc/emcache.toml: can't read the file", err.to_string());
+}
+
+#[test]
+fn test_config_max_item_size_units() {
+    let mut opts = MemcacheOptions::new();
+
+    apply_config(&mut opts, "[memory]\nmax_item_size = \"512k\"\n").unwrap();
+    assert_eq!(512 << 10, opts.get_max_item_size_bytes());
+
+    let vars = env_vars(&[("EMCACHE_MAX_ITEM_SIZE", "3M")]);
+    apply_env(&mut opts, vars.into_iter()).unwrap();
+    assert_eq!(3 << 20, opts.get_max_item_size_bytes());
+
+    assert_eq!(ConfigError::InvalidValue("memory.max_item_size".to_string(),
+                                         "expected a size, eg. 512k or 2m"),
+               apply_config(&mut opts, "[memory]\nmax_item_size = \"big\"\n")
+                   .unwrap_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(Some(4096), parse_size("4096b"));
+    assert_eq!(Some(512 << 10), parse_size("512k"));
+    assert_eq!(Some(2 << 20), parse_size("2m"));
+    assert_eq!(Some(1 << 30), parse_size("1G"));
+
+    // Plain numbers are megabytes
+    assert_eq!(Some(2 << 20), parse_size("2"));
+
+    assert_eq!(None, parse_size("0k"));
+    assert_eq!(None, parse_size("k"));
+    assert_eq!(None, parse_size("1.5m"));
+    assert_eq!(None, parse_size("1t"));
+    assert_eq!(None, parse_size("99999999999999g"));
+}
+
+#[test]
+fn test_config_parse_error() {
+    let mut opts = MemcacheOptions::new();
+
+    match apply_config(&mut opts, "[server]\nport = \n").unwrap_err() {
+        ConfigError::ParseError(line, _) => assert_eq!(2, line),
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+
+// Environment
+
+#[test]
+fn test_env_settings() {
+    let mut opts = MemcacheOptions::new();
+    let vars = env_vars(&[("EMCACHE_PORT", "11411"),
+                          ("EMCACHE_NAMESPACES", "users:16"),
+                          ("EMCACHE_HOTKEYS", "0"),
+                          ("EMCACHE_METRICS_SINKS", "json:/tmp/m.jsonl"),
+                          ("EMCACHE_DRAIN_TIMEOUT", "2.5"),
+                          ("EMCACHE_UNRELATED", "whatever"),
+                          ("HOME", "/root")]);
+    apply_env(&mut opts, vars.into_iter()).unwrap();
+
+    assert_eq!("127.0.0.1:11411", opts.get_bind_string());
+    assert_eq!(vec![("users".to_string(), 16 << 20)], opts.get_namespaces());
+    assert_eq!(2.5, opts.get_drain_timeout());
+    assert_eq!(false, opts.get_hot_keys_enabled());
+    assert_eq!(vec![SinkSpec::JsonLines("/tmp/m.jsonl".to_string())],
+               opts.get_metrics_sinks());
+}
+
+#[test]
+fn test_env_errors_name_the_var() {
+    let mut opts = MemcacheOptions::new();
+
+    let vars = env_vars(&[("EMCACHE_MEM", "lots")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_MEM".to_string(),
+                                         "expected a positive number"),
+               apply_env(&mut opts, vars.into_iter()).unwrap_err());
+
+    let vars = env_vars(&[("EMCACHE_METRICS", "yes")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_METRICS".to_string(),
+                                         "expected true or false"),
+               apply_env(&mut opts, vars.into_iter()).unwrap_err());
+}
+
+
+// Precedence
+
+#[test]
+fn test_load_options_precedence() {
+    let path = get_temp_path("config-precedence.toml");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"[server]\nhost = \"0.0.0.0\"\nport = 11411\n\
+                         [memory]\nmem = 128\n")
+            .unwrap();
+    }
+
+    // Env overrides the file, flags override both
+    let mut cli = MemcacheOptions::new();
+    cli.flag_config = Some(path.clone());
+    cli.flag_host = None;
+    cli.flag_port = None;
+    cli.flag_mem = Some(256);
+    let vars = env_vars(&[("EMCACHE_PORT", "11511"), ("EMCACHE_MEM", "512")]);
+
+    let opts = load_options(&cli, vars.into_iter()).unwrap();
+    assert_eq!("0.0.0.0:11511", opts.get_bind_string());
+    assert_eq!(256, opts.get_mem_limit());
+
+    // The file can be given in the env too
+    let mut cli = MemcacheOptions::new();
+    cli.flag_host = None;
+    let vars = env_vars(&[("EMCACHE_CONFIG", &path)]);
+
+    let opts = load_options(&cli, vars.into_iter()).unwrap();
+    assert_eq!("0.0.0.0", opts.get_bind_params().0);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_options_switches() {
+    let vars = env_vars(&[("EMCACHE_METRICS", "1"),
+                          ("EMCACHE_HOTKEYS", "0"),
+                          ("EMCACHE_DAEMON", "1"),
+                          ("EMCACHE_ADMIN_WRITES", "1"),
+                          ("EMCACHE_ENABLE_DEBUGTIME", "1")]);
+
+    // Without the flags the env decides
+    let cli = MemcacheOptions::new();
+    let opts = load_options(&cli, vars.clone().into_iter()).unwrap();
+    assert_eq!(true, opts.get_metrics_enabled());
+    assert_eq!(false, opts.get_hot_keys_enabled());
+    assert_eq!(true, opts.get_daemon_enabled());
+    assert_eq!(true, opts.get_admin_writes_enabled());
+    assert_eq!(true, opts.get_debug_time_enabled());
+
+    // The flags can switch things back
+    let mut cli = MemcacheOptions::new();
+    cli.flag_no_metrics = true;
+    cli.flag_hotkeys = true;
+    cli.flag_no_daemon = true;
+    cli.flag_no_admin_writes = true;
+    cli.flag_disable_debugtime = true;
+    let opts = load_options(&cli, vars.into_iter()).unwrap();
+    assert_eq!(false, opts.get_metrics_enabled());
+    assert_eq!(true, opts.get_hot_keys_enabled());
+    assert_eq!(false, opts.get_daemon_enabled());
+    assert_eq!(false, opts.get_admin_writes_enabled());
+    assert_eq!(false, opts.get_debug_time_enabled());
+
+    // But not both ways at once
+    let mut cli = MemcacheOptions::new();
+    cli.flag_metrics = true;
+    cli.flag_no_metrics = true;
+    assert_eq!(ConfigError::InvalidValue("--metrics".to_string(),
+                                         "given together with its opposite"),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+}
+
+#[test]
+fn test_load_options_quotas_over_mem() {
+    let path = get_temp_path("config-quotas.toml");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"[memory]\nmem = 64\n\
+                         [namespaces.quotas]\na = 60\nb = 60\n")
+            .unwrap();
+    }
+
+    // Reported under the name the quotas were given with
+    let mut cli = MemcacheOptions::new();
+    cli.flag_config = Some(path.clone());
+    cli.flag_mem = None;
+    assert_eq!(ConfigError::InvalidValue("namespaces.quotas".to_string(),
+                                         "expected quotas that add up to \
+                                          mem or less"),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+
+    let vars = env_vars(&[("EMCACHE_NAMESPACES", "a:32,b:40")]);
+    assert_eq!(ConfigError::InvalidValue("EMCACHE_NAMESPACES".to_string(),
+                                         "expected quotas that add up to \
+                                          mem or less"),
+               load_options(&cli, vars.into_iter()).unwrap_err());
+
+    cli.flag_namespaces = Some("a:60,b:60".to_string());
+    assert_eq!(ConfigError::InvalidValue("--namespaces".to_string(),
+                                         "expected quotas that add up to \
+                                          mem or less"),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+
+    // They fit once there's enough memory, wherever it's given
+    cli.flag_mem = Some(120);
+    let opts = load_options(&cli, vec![].into_iter()).unwrap();
+    assert_eq!(2, opts.get_namespaces().len());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_options_missing_file() {
+    let path = get_temp_path("config-missing.toml");
+    let mut cli = MemcacheOptions::new();
+    cli.flag_config = Some(path.clone());
+
+    assert_eq!(ConfigError::FileReadError(path),
+               load_options(&cli, vec![].into_iter()).unwrap_err());
+}
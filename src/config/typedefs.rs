@@ -0,0 +1,4 @@
+use super::errors::ConfigError;
+
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
@@ -0,0 +1,7 @@
+#[derive(Debug, PartialEq)]
+pub enum HttpError {
+    BadRequestLine,
+    HeadersTooLarge,
+    StreamReadError,
+    Utf8Error,
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+
+
+// A json document, built up in memory and then written out in one go.
+// Objects keep their keys in the order they were added.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn str(value: &str) -> Json {
+        Json::Str(value.to_string())
+    }
+
+    pub fn opt_str(value: Option<String>) -> Json {
+        match value {
+            Some(value) => Json::Str(value),
+            None => Json::Null,
+        }
+    }
+
+    // Numbers stay numbers, anything else becomes a string
+    pub fn from_text(value: &str) -> Json {
+        let numeric = !value.is_empty() &&
+                      value.chars()
+                           .all(|c| c.is_digit(10) || c == '.' || c == '-');
+        if !numeric {
+            return Json::str(value);
+        }
+
+        match (value.parse::<i64>(), value.parse::<f64>()) {
+            (Ok(int), _) => Json::Int(int),
+            (_, Ok(float)) => Json::Float(float),
+            _ => Json::str(value),
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Int(value) => write!(f, "{}", value),
+            // Json has no way to spell these
+            Json::Float(value) if !value.is_finite() => write!(f, "null"),
+            Json::Float(value) => write!(f, "{}", value),
+            Json::Str(ref value) => write_str(f, value),
+            Json::Array(ref items) => {
+                try!(write!(f, "["));
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write!(f, "{}", item));
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref fields) => {
+                try!(write!(f, "{{"));
+                for (i, &(ref key, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write_str(f, key));
+                    try!(write!(f, ":{}", value));
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+
+fn write_str(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+
+    for c in value.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\r' => try!(write!(f, "\\r")),
+            '\t' => try!(write!(f, "\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+
+    write!(f, "\"")
+}
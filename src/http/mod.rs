@@ -0,0 +1,21 @@
+// Just enough http/1.x to serve the admin endpoints: one request per
+// connection, no request bodies.
+
+// Declare sub modules
+pub mod errors;
+pub mod json;
+pub mod request;
+pub mod response;
+pub mod typedefs;
+
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
+
+
+// Export our public api
+pub use self::errors::HttpError;
+pub use self::json::Json;
+pub use self::request::Method;
+pub use self::request::Request;
+pub use self::request::read_request;
+pub use self::response::Response;
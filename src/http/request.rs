@@ -0,0 +1,91 @@
+use std::io::BufRead;
+use std::io::Read;
+
+use super::errors::HttpError;
+use super::typedefs::HttpResult;
+
+
+// Request line and headers together
+const MAX_HEAD_LEN: usize = 8192;
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    pub path: String, // without the query string
+}
+
+
+// Reads the request line and skips over the headers, which we have no use
+// for. Any body is left unread.
+pub fn read_request<R: BufRead>(reader: &mut R) -> HttpResult<Request> {
+    let mut head_len = 0;
+
+    let line = try!(read_line(reader, &mut head_len));
+    let mut parts = line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version))
+            if version.starts_with("HTTP/") => (method, target),
+        _ => return Err(HttpError::BadRequestLine),
+    };
+
+    let method = match method {
+        "GET" => Method::Get,
+        "POST" => Method::Post,
+        _ => Method::Other,
+    };
+    let path = target.splitn(2, '?').next().unwrap().to_string();
+
+    // The headers end with an empty line
+    loop {
+        let header = try!(read_line(reader, &mut head_len));
+        if header.is_empty() {
+            break;
+        }
+    }
+
+    Ok(Request {
+        method: method,
+        path: path,
+    })
+}
+
+// Reads a line and returns it without the line ending
+fn read_line<R: BufRead>(reader: &mut R,
+                         head_len: &mut usize)
+                         -> HttpResult<String> {
+    if *head_len >= MAX_HEAD_LEN {
+        return Err(HttpError::HeadersTooLarge);
+    }
+
+    let mut bytes = vec![];
+    let limit = (MAX_HEAD_LEN - *head_len) as u64;
+    let rv = reader.by_ref().take(limit).read_until(b'\n', &mut bytes);
+    match rv {
+        Ok(0) => return Err(HttpError::StreamReadError),
+        Ok(_) => (),
+        Err(_) => return Err(HttpError::StreamReadError),
+    }
+
+    *head_len += bytes.len();
+    if bytes.last() != Some(&b'\n') {
+        return match *head_len >= MAX_HEAD_LEN {
+            true => Err(HttpError::HeadersTooLarge),
+            false => Err(HttpError::StreamReadError),
+        };
+    }
+
+    bytes.pop();
+    if bytes.last() == Some(&b'\r') {
+        bytes.pop();
+    }
+
+    String::from_utf8(bytes).map_err(|_| HttpError::Utf8Error)
+}
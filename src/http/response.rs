@@ -0,0 +1,60 @@
+use std::io;
+use std::io::Write;
+
+use super::Json;
+
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16,
+               content_type: &'static str,
+               body: String)
+               -> Response {
+        Response {
+            status: status,
+            content_type: content_type,
+            body: body,
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body.to_string())
+    }
+
+    pub fn json(status: u16, body: &Json) -> Response {
+        Response::new(status, "application/json", format!("{}\n", body))
+    }
+
+    // We don't keep connections open, so the client can read to the end
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(write!(writer,
+                    "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    self.status,
+                    get_reason(self.status),
+                    self.content_type,
+                    self.body.len()));
+        try!(writer.write_all(self.body.as_bytes()));
+        writer.flush()
+    }
+}
+
+
+fn get_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
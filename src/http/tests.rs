@@ -0,0 +1,100 @@
+use std::io::Cursor;
+
+use super::HttpError;
+use super::Json;
+use super::Method;
+use super::Request;
+use super::Response;
+use super::read_request;
+
+
+fn parse(text: &str) -> Result<Request, HttpError> {
+    read_request(&mut Cursor::new(text.as_bytes().to_vec()))
+}
+
+
+// Requests
+
+#[test]
+fn test_read_request() {
+    let req = parse("GET /stats?pretty=1 HTTP/1.1\r\nHost: localhost\r\n\
+                     Accept: */*\r\n\r\n")
+                  .unwrap();
+    assert_eq!(Request {
+                   method: Method::Get,
+                   path: "/stats".to_string(),
+               },
+               req);
+
+    // Bare newlines are fine too
+    let req = parse("POST /flush HTTP/1.0\n\n").unwrap();
+    assert_eq!(Method::Post, req.method);
+    assert_eq!("/flush", req.path);
+
+    let req = parse("DELETE /flush HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(Method::Other, req.method);
+}
+
+#[test]
+fn test_read_request_invalid() {
+    assert_eq!(HttpError::BadRequestLine, parse("GET /\r\n\r\n").unwrap_err());
+    assert_eq!(HttpError::BadRequestLine,
+               parse("GET / FTP/1.0\r\n\r\n").unwrap_err());
+
+    // The client went away before the headers ended
+    assert_eq!(HttpError::StreamReadError,
+               parse("GET / HTTP/1.1\r\nHost: x").unwrap_err());
+    assert_eq!(HttpError::StreamReadError, parse("").unwrap_err());
+
+    let huge = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+                       "x".repeat(10000));
+    assert_eq!(HttpError::HeadersTooLarge, parse(&huge).unwrap_err());
+}
+
+
+// Responses
+
+#[test]
+fn test_write_response() {
+    let mut bytes = vec![];
+    Response::text(404, "not found\n").write_to(&mut bytes).unwrap();
+
+    assert_eq!("HTTP/1.1 404 Not Found\r\n\
+                Content-Type: text/plain; charset=utf-8\r\n\
+                Content-Length: 10\r\nConnection: close\r\n\r\n\
+                not found\n",
+               String::from_utf8(bytes).unwrap());
+}
+
+
+// Json
+
+#[test]
+fn test_json_display() {
+    let doc = Json::Object(vec![("a".to_string(), Json::Int(-1)),
+                                ("b".to_string(), Json::Float(0.5)),
+                                ("c".to_string(),
+                                 Json::Array(vec![Json::Null,
+                                                  Json::Bool(true)])),
+                                ("d".to_string(), Json::Object(vec![])),
+                                ("e".to_string(), Json::Float(1.0 / 0.0))]);
+    assert_eq!("{\"a\":-1,\"b\":0.5,\"c\":[null,true],\"d\":{},\"e\":null}",
+               doc.to_string());
+}
+
+#[test]
+fn test_json_escaping() {
+    assert_eq!("\"say \\\"hi\\\"\\n\\\\ \\u0001 \u{e9}\"",
+               Json::str("say \"hi\"\n\\ \u{1} \u{e9}").to_string());
+}
+
+#[test]
+fn test_json_from_text() {
+    assert_eq!(Json::Int(42), Json::from_text("42"));
+    assert_eq!(Json::Int(-3), Json::from_text("-3"));
+    assert_eq!(Json::Float(1.5), Json::from_text("1.50"));
+    assert_eq!(Json::str("emcache 0.1.0"), Json::from_text("emcache 0.1.0"));
+    assert_eq!(Json::str("inf"), Json::from_text("inf"));
+    assert_eq!(Json::str("1.2.3"), Json::from_text("1.2.3"));
+    assert_eq!(Json::str(""), Json::from_text(""));
+}
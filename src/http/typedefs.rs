@@ -0,0 +1,4 @@
+use super::errors::HttpError;
+
+
+pub type HttpResult<T> = Result<T, HttpError>;
@@ -0,0 +1,72 @@
+//! An in-memory cache that speaks the memcached protocol. The emcache binary
+//! is a thin wrapper around this crate, which can also run the server inside
+//! another process:
+//!
+//! ```
+//! use emcache::Cmd;
+//! use emcache::Resp;
+//! use emcache::ServerBuilder;
+//!
+//! let server = ServerBuilder::new().with_port(0).start().unwrap();
+//! let handle = server.get_handle();
+//!
+//! match handle.run(Cmd::Version) {
+//!     Resp::Version(_) => (),
+//!     resp => panic!("unexpected response: {:?}", resp),
+//! }
+//!
+//! server.shutdown();
+//! ```
+//!
+//! `ServerBuilder` starts a `Server` and a `CacheHandle` executes commands
+//! (`Cmd`) on its cache, returning responses (`Resp`). To use the cache
+//! directly, without any threads, see `Cache` and `Driver`.
+
+// Benchmark testing primitives
+#![feature(test)]
+extern crate test;
+
+#[macro_use]
+extern crate maplit;
+extern crate bufstream;
+extern crate docopt;
+extern crate linked_hash_map;
+extern crate libc;
+extern crate lz4_flex;
+extern crate net2;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate time;
+extern crate toml;
+
+// Declare sub modules
+pub mod options;
+pub mod storage;
+
+// Only for the emcache binary
+#[doc(hidden)]
+pub mod common;
+#[doc(hidden)]
+pub mod platform;
+
+// internal stuff
+mod config;
+mod http;
+mod metrics;
+mod orchestrator;
+mod persistence;
+mod protocol;
+mod tcp_transport;
+mod testlib;
+
+
+// Export our public api
+pub use options::MemcacheOptions;
+pub use orchestrator::CacheHandle;
+pub use orchestrator::Server;
+pub use orchestrator::ServerBuilder;
+pub use protocol::Driver;
+pub use protocol::cmd;
+pub use protocol::cmd::Cmd;
+pub use protocol::cmd::Resp;
+pub use storage::Cache;
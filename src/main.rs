@@ -1,37 +1,26 @@
-// Benchmark testing primitives
-#![feature(test)]
-extern crate test;
-
-#[macro_use]
-extern crate maplit;
-extern crate bufstream;
-extern crate docopt;
-extern crate linked_hash_map;
-extern crate libc;
-extern crate net2;
-extern crate rand;
-extern crate rustc_serialize;
-extern crate time;
-
-mod common;
-mod metrics;
-mod options;
-mod orchestrator;
-mod platform;
-mod protocol;
-mod storage;
-mod tcp_transport;
-mod testlib;
-
-use common::consts;
-use options::parse_args;
-use orchestrator::ListenerTask;
+extern crate emcache;
+
+use std::process;
+
+use emcache::ServerBuilder;
+use emcache::common::consts;
+use emcache::options::parse_args;
+use emcache::options::reload_args;
+use emcache::platform::daemon::Pidfile;
+use emcache::platform::daemon::daemonize;
+use emcache::platform::signals::Signal;
+use emcache::platform::signals::SignalListener;
 
 
 fn print_version() {
     println!("{} {}", consts::APP_NAME, consts::APP_VERSION);
 }
 
+fn exit_with_error(what: &str, err: &std::error::Error) -> ! {
+    println!("{}: {}", what, err);
+    process::exit(1);
+}
+
 fn main() {
     print_version();
 
@@ -41,9 +30,70 @@ fn main() {
         return;
     }
 
+    // Lock the pidfile before detaching, so a second instance still fails
+    // in the foreground
+    let mut pidfile = match opts.get_pidfile_path() {
+        Some(path) => {
+            match Pidfile::lock(&path) {
+                Ok(pidfile) => Some(pidfile),
+                Err(err) => exit_with_error("Can't lock the pidfile", &err),
+            }
+        }
+        None => None,
+    };
+
     println!("Running tcp server on {} with {}mb capacity...",
              opts.get_bind_string(),
              opts.get_mem_limit());
-    let mut listener_task = ListenerTask::new(opts.clone());
-    listener_task.run();
+
+    // Detach before starting any threads, fork only takes the current one
+    if opts.get_daemon_enabled() {
+        match daemonize(&opts.get_daemon_log_path()) {
+            Ok(_) => (),
+            Err(err) => exit_with_error("Can't daemonize", &err),
+        }
+    }
+
+    match pidfile {
+        Some(ref mut pidfile) => {
+            match pidfile.write_pid() {
+                Ok(_) => (),
+                Err(err) => exit_with_error("Can't write the pidfile", &err),
+            }
+        }
+        None => (),
+    }
+
+    // The server's threads inherit the blocked signals, so they're all left
+    // for us to handle here
+    let signals = match SignalListener::new() {
+        Ok(signals) => signals,
+        Err(err) => exit_with_error("Can't set up signal handling", &err),
+    };
+
+    let server = match ServerBuilder::from_options(opts).start() {
+        Ok(server) => server,
+        Err(err) => exit_with_error("Can't start the server", &err),
+    };
+
+    loop {
+        match signals.wait() {
+            Ok(Signal::Terminate) => {
+                println!("Shutting down...");
+                server.shutdown();
+                break;
+            }
+            Ok(Signal::Reload) => {
+                match reload_args() {
+                    Ok(opts) => server.reload(opts),
+                    Err(err) => {
+                        println!("Invalid configuration: {}, not reloaded",
+                                 err);
+                    }
+                }
+            }
+            Ok(Signal::DumpStats) => server.dump_stats(),
+            Err(err) => exit_with_error("Can't wait for signals", &err),
+        }
+    }
 }
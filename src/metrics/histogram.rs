@@ -0,0 +1,75 @@
+use std::f64;
+
+use super::Duration;
+
+
+// Upper bounds for timings, in seconds: 10us to 1s
+pub const LATENCY_BUCKETS: &'static [Duration] = &[0.00001, 0.000025,
+                                                   0.00005, 0.0001, 0.00025,
+                                                   0.0005, 0.001, 0.0025,
+                                                   0.005, 0.01, 0.025, 0.05,
+                                                   0.1, 0.25, 0.5, 1.0];
+
+
+// Counts values into buckets by upper bound. Nothing is ever removed, so
+// the counts only go up (which is what a Prometheus histogram is).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>, // ascending, the last bucket (+Inf) is implied
+    counts: Vec<u64>, // per bucket, one more than there are bounds
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn for_latency() -> Histogram {
+        Histogram::new(LATENCY_BUCKETS)
+    }
+
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_sum(&self) -> f64 {
+        self.sum
+    }
+
+    // Each upper bound with the number of values at or below it, ending
+    // with +Inf and the total
+    pub fn get_buckets(&self) -> Vec<(f64, u64)> {
+        let mut buckets = vec![];
+        let mut total = 0;
+
+        for (i, count) in self.counts.iter().enumerate() {
+            total += *count;
+            let bound = match self.bounds.get(i) {
+                Some(bound) => *bound,
+                None => f64::INFINITY,
+            };
+            buckets.push((bound, total));
+        }
+
+        buckets
+    }
+
+
+    pub fn observe(&mut self, value: f64) {
+        let pos = self.bounds
+                      .iter()
+                      .position(|bound| value <= *bound)
+                      .unwrap_or(self.bounds.len());
+
+        self.counts[pos] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
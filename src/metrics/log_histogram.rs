@@ -0,0 +1,140 @@
+use std::f64;
+
+use super::Duration;
+
+
+// Each power of two (in nanoseconds) is split into this many equal
+// buckets, so a value is off by at most 1/32 (about 3%) of itself
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+
+// Counts durations into log-linear buckets, like an HDR histogram. It takes
+// the same space no matter how many values go in, and two can be merged to
+// get the histogram of all their values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogHistogram {
+    counts: Vec<u64>, // by bucket index, grows to the biggest value seen
+    count: u64,
+    sum: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl LogHistogram {
+    pub fn new() -> LogHistogram {
+        LogHistogram {
+            counts: vec![],
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: 0.0,
+        }
+    }
+
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_min(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            _ => Some(self.min),
+        }
+    }
+
+    pub fn get_max(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            _ => Some(self.max),
+        }
+    }
+
+    pub fn get_mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            n => Some(self.sum / n as f64),
+        }
+    }
+
+    // The value at pct (0.0 to 1.0) of the sorted values, same as
+    // compute_px would pick, to within the width of its bucket
+    pub fn get_quantile(&self, pct: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let pos = ((self.count as f64) * pct) as u64;
+        let mut seen = 0;
+
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen > pos {
+                let value = get_bucket_middle(index) as f64 / 1e9;
+                // The ends are known exactly
+                return Some(value.max(self.min).min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+
+    pub fn record(&mut self, value: Duration) {
+        let value = value.max(0.0);
+        let index = get_bucket_index((value * 1e9).round() as u64);
+
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &LogHistogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (index, count) in other.counts.iter().enumerate() {
+            self.counts[index] += *count;
+        }
+
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+
+// Values below SUB_BUCKETS get a bucket each, above that every power of two
+// gets SUB_BUCKETS buckets
+fn get_bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+
+    let exp = 63 - nanos.leading_zeros(); // >= SUB_BUCKET_BITS
+    let shift = exp - SUB_BUCKET_BITS;
+    let sub = (nanos >> shift) - SUB_BUCKETS;
+
+    (SUB_BUCKETS + (shift as u64) * SUB_BUCKETS + sub) as usize
+}
+
+fn get_bucket_middle(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub) << shift;
+    let width = 1 << shift;
+
+    lower + width / 2
+}
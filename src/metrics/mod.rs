@@ -1,8 +1,12 @@
 // Declare sub modules
+pub mod histogram;
 pub mod live_timers;
+pub mod log_histogram;
 pub mod metric;
 pub mod metrics;
+pub mod prometheus;
 pub mod recorder;
+pub mod sinks;
 pub mod statistics;
 pub mod time_series;
 pub mod timer;
@@ -14,11 +18,17 @@ mod tests;  // needed to be part of the compilation unit in test mode
 
 
 // Export our public api
+pub use self::histogram::Histogram;
 pub use self::live_timers::LiveTimers;
+pub use self::log_histogram::LogHistogram;
 pub use self::metric::Metric;
 pub use self::metrics::Metrics;
 pub use self::recorder::MetricsRecorder;
+pub use self::recorder::Recorder;
+pub use self::recorder::new_recorder;
+pub use self::time_series::MAX_WINDOW;
 pub use self::time_series::TimeSeries;
+pub use self::time_series::WINDOWS;
 pub use self::timer::Timer;
 pub use self::timing::Timing;
 pub use self::typedefs::Duration;
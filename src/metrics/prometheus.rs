@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::f64;
+
+use protocol::cmd::Stat;
+
+use super::Histogram;
+
+
+// Version 0.0.4 of the text format, which is what Prometheus scrapes
+pub const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4; \
+                                        charset=utf-8";
+
+const PREFIX: &'static str = "emcache_";
+
+// Stats that go up and down, the rest count up from when we started
+const GAUGE_STATS: &'static [&'static str] = &["uptime",
+                                               "time",
+                                               "limit_maxbytes",
+                                               "bytes",
+                                               "curr_items",
+                                               "crawler_rate",
+                                               "compress_ratio",
+                                               "extstore_limit_maxbytes",
+                                               "extstore_bytes",
+                                               "extstore_items"];
+
+// Stats that are no use as a metric
+const SKIPPED_STATS: &'static [&'static str] = &["pid"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+
+// Builds the text of a scrape, one family of samples at a time:
+//
+// # HELP emcache_curr_items Same as curr_items in stats
+// # TYPE emcache_curr_items gauge
+// emcache_curr_items 3
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition { text: String::new() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+
+    // Starts a family, its samples have to follow right after
+    pub fn add_family(&mut self, name: &str, help: &str, kind: MetricKind) {
+        self.text.push_str(&format!("# HELP {}{} {}\n", PREFIX, name, help));
+        self.text.push_str(&format!("# TYPE {}{} {}\n",
+                                    PREFIX,
+                                    name,
+                                    kind.as_str()));
+    }
+
+    pub fn add_sample(&mut self,
+                      name: &str,
+                      labels: &[(&str, &str)],
+                      value: f64) {
+        self.text.push_str(PREFIX);
+        self.text.push_str(name);
+
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                                            .map(|&(label, value)| {
+                                                format!("{}=\"{}\"",
+                                                        label,
+                                                        escape_label(value))
+                                            })
+                                            .collect();
+            self.text.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+
+        self.text.push_str(&format!(" {}\n", format_value(value)));
+    }
+
+    // The _bucket, _sum and _count samples of a histogram
+    pub fn add_histogram(&mut self,
+                         name: &str,
+                         labels: &[(&str, &str)],
+                         histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+        for (bound, count) in histogram.get_buckets() {
+            let le = format_value(bound);
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.add_sample(&bucket_name, &bucket_labels, count as f64);
+        }
+
+        self.add_sample(&format!("{}_sum", name), labels, histogram.get_sum());
+        self.add_sample(&format!("{}_count", name),
+                        labels,
+                        histogram.get_count() as f64);
+    }
+
+
+    // Everything the stats command reports that is a number
+    pub fn add_stats(&mut self, stats: &[Stat]) {
+        for stat in stats {
+            if stat.key == "version" {
+                self.add_family("version_info",
+                                "The version we run, always 1",
+                                MetricKind::Gauge);
+                self.add_sample("version_info",
+                                &[("version", &stat.value)],
+                                1.0);
+                continue;
+            }
+
+            let kind = match get_stat_kind(&stat.key) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let value = match stat.value.parse::<f64>() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            // Counters are named so that it's clear they only go up
+            let name = match kind {
+                MetricKind::Counter => format!("{}_total", stat.key),
+                _ => stat.key.clone(),
+            };
+            let help = format!("Same as {} in stats", stat.key);
+
+            self.add_family(&name, &help, kind);
+            self.add_sample(&name, &[], value);
+        }
+    }
+
+    // The timers from the metrics, as one histogram family with the timer
+    // name as a label
+    pub fn add_timers(&mut self, histograms: &HashMap<String, Histogram>) {
+        let mut names: Vec<&String> = histograms.keys().collect();
+        names.sort();
+
+        self.add_family("timer_seconds",
+                        "How long the timed sections of the server took",
+                        MetricKind::Histogram);
+
+        for name in names {
+            let histogram = histograms.get(name).unwrap();
+            self.add_histogram("timer_seconds", &[("timer", name)], histogram);
+        }
+    }
+}
+
+
+pub fn get_stat_kind(key: &str) -> Option<MetricKind> {
+    if SKIPPED_STATS.contains(&key) {
+        None
+    } else if GAUGE_STATS.contains(&key) {
+        Some(MetricKind::Gauge)
+    } else {
+        Some(MetricKind::Counter)
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        format!("{}", value)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\")
+         .replace("\"", "\\\"")
+         .replace("\n", "\\n")
+}
@@ -3,15 +3,28 @@ use orchestrator::MetricsSender;
 use super::LiveTimers;
 use super::Metric;
 use super::Metrics;
+use super::Timing;
 
 
-pub struct MetricsRecorder {
-    // If not enabled the recorder is just a shim - records nothing, transmits
-    // nothing (no performance overhead)
-    // TODO: Consider replacing this flag with a Collector trait - can implement
-    // a real collector and a NullCollector
-    enabled: bool,
+// Where the tasks record their timings. Pick one with new_recorder.
+pub trait Recorder: Send {
+    fn start_timer(&mut self, name: &str);
+    fn stop_timer(&mut self, name: &str);
+    // Records the timing under other as well, eg. to break it down further
+    fn stop_timer_also(&mut self, name: &str, other: &str);
+    fn flush_metrics(&mut self);
+}
+
+pub fn new_recorder(met_tx: MetricsSender, enabled: bool) -> Box<Recorder> {
+    match enabled {
+        true => Box::new(MetricsRecorder::new(met_tx)),
+        false => Box::new(NullRecorder),
+    }
+}
+
 
+// Records timings and transmits them to the metrics task
+pub struct MetricsRecorder {
     live_timers: LiveTimers,
     metrics: Metrics,
 
@@ -19,37 +32,33 @@ pub struct MetricsRecorder {
 }
 
 impl MetricsRecorder {
-    pub fn new(met_tx: MetricsSender, enabled: bool) -> MetricsRecorder {
+    pub fn new(met_tx: MetricsSender) -> MetricsRecorder {
         MetricsRecorder {
-            enabled: enabled,
             live_timers: LiveTimers::new(),
             metrics: Metrics::new(),
             met_tx: met_tx,
         }
     }
+}
 
-    pub fn start_timer(&mut self, name: &str) {
-        if !self.enabled {
-            return;
-        }
-
+impl Recorder for MetricsRecorder {
+    fn start_timer(&mut self, name: &str) {
         self.live_timers.start(name);
     }
 
-    pub fn stop_timer(&mut self, name: &str) {
-        if !self.enabled {
-            return;
-        }
-
+    fn stop_timer(&mut self, name: &str) {
         let timing = self.live_timers.stop(name);
         self.metrics.push(Metric::Timing(timing));
     }
 
-    pub fn flush_metrics(&mut self) {
-        if !self.enabled {
-            return;
-        }
+    fn stop_timer_also(&mut self, name: &str, other: &str) {
+        let timing = self.live_timers.stop(name);
+        let other = Timing::new(other, timing.start_time, timing.duration);
+        self.metrics.push(Metric::Timing(timing));
+        self.metrics.push(Metric::Timing(other));
+    }
 
+    fn flush_metrics(&mut self) {
         // package up all our data into a metrics object
         let metrics = self.metrics.clone();
 
@@ -60,3 +69,17 @@ impl MetricsRecorder {
         self.metrics.clear();
     }
 }
+
+
+// Used when metrics are disabled: records nothing, transmits nothing
+pub struct NullRecorder;
+
+impl Recorder for NullRecorder {
+    fn start_timer(&mut self, _name: &str) {}
+
+    fn stop_timer(&mut self, _name: &str) {}
+
+    fn stop_timer_also(&mut self, _name: &str, _other: &str) {}
+
+    fn flush_metrics(&mut self) {}
+}
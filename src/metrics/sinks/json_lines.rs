@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::io;
+
+use http::Json;
+use metrics::Timing;
+use metrics::statistics::AggregatedMetric;
+
+use super::MetricsSink;
+
+
+// Appends each summary to a file as one line of json, eg.
+//
+// {"at":1500000000,"interval":1,"timers":{"DriverTask:exec_cmd":{"n":3,...
+pub struct JsonLinesSink {
+    path: String,
+    file: File,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: &str) -> io::Result<JsonLinesSink> {
+        let file = try!(OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path));
+
+        Ok(JsonLinesSink {
+            path: path.to_string(),
+            file: file,
+        })
+    }
+}
+
+impl MetricsSink for JsonLinesSink {
+    fn get_name(&self) -> String {
+        format!("json:{}", self.path)
+    }
+
+    fn add_timing(&mut self, _timing: &Timing) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_summary(&mut self,
+                     at: f64,
+                     interval: f64,
+                     agg_mets: &[AggregatedMetric])
+                     -> io::Result<()> {
+        let line = Json::Object(vec![
+            ("at".to_string(), Json::Int(at as i64)),
+            ("interval".to_string(), Json::Float(interval)),
+            ("timers".to_string(), get_summary_json(agg_mets)),
+        ]);
+
+        try!(writeln!(self.file, "{}", line));
+        self.file.flush()
+    }
+}
+
+
+// The timers of a summary by name, in milliseconds like the summary that's
+// printed
+pub fn get_summary_json(agg_mets: &[AggregatedMetric]) -> Json {
+    let mut timers = vec![];
+
+    for agg in agg_mets {
+        let fields = vec![
+            ("n".to_string(), Json::Int(agg.n as i64)),
+            ("p0_ms".to_string(), get_millis(agg.p0)),
+            ("avg_ms".to_string(), get_millis(agg.avg)),
+            ("p90_ms".to_string(), get_millis(agg.p90)),
+            ("p99_ms".to_string(), get_millis(agg.p99)),
+            ("p999_ms".to_string(), get_millis(agg.p999)),
+        ];
+        timers.push((agg.name.clone(), Json::Object(fields)));
+    }
+
+    Json::Object(timers)
+}
+
+fn get_millis(secs: Option<f64>) -> Json {
+    match secs {
+        Some(secs) => Json::Float(secs * 1000.0),
+        None => Json::Null,
+    }
+}
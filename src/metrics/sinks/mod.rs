@@ -0,0 +1,19 @@
+// Declare sub modules
+pub mod json_lines;
+pub mod sink;
+pub mod statsd;
+pub mod stdout;
+
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
+
+
+// Export our public api
+pub use self::json_lines::JsonLinesSink;
+pub use self::json_lines::get_summary_json;
+pub use self::sink::MetricsSink;
+pub use self::sink::SinkSpec;
+pub use self::sink::open_sink;
+pub use self::sink::parse_sink_specs;
+pub use self::statsd::StatsdSink;
+pub use self::stdout::StdoutSink;
@@ -0,0 +1,72 @@
+use std::io;
+
+use metrics::Timing;
+use metrics::statistics::AggregatedMetric;
+
+use super::JsonLinesSink;
+use super::StatsdSink;
+use super::StdoutSink;
+
+
+// Where the metrics task sends the metrics it collects
+pub trait MetricsSink: Send {
+    // Names the sink in error messages
+    fn get_name(&self) -> String;
+
+    // Every timing, as it comes in
+    fn add_timing(&mut self, timing: &Timing) -> io::Result<()>;
+
+    // The summary of the timings over the last interval, sorted by name
+    fn write_summary(&mut self,
+                     at: f64,
+                     interval: f64,
+                     agg_mets: &[AggregatedMetric])
+                     -> io::Result<()>;
+}
+
+
+// A sink as given in the options
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSpec {
+    Stdout,
+    JsonLines(String), // path
+    Statsd(String), // host:port
+}
+
+// Parses a comma separated list of sinks, eg.
+// stdout,json:/var/log/emcache.jsonl,statsd:127.0.0.1:8125
+pub fn parse_sink_specs(specs: &str) -> Option<Vec<SinkSpec>> {
+    let mut sinks = vec![];
+
+    for spec in specs.split(',') {
+        let mut parts = spec.splitn(2, ':');
+        let kind = parts.next().unwrap();
+        let arg = parts.next().unwrap_or("");
+
+        let sink = match (kind, arg) {
+            ("stdout", "") => SinkSpec::Stdout,
+            ("json", path) if !path.is_empty() => {
+                SinkSpec::JsonLines(path.to_string())
+            }
+            ("statsd", addr) if addr.contains(':') => {
+                SinkSpec::Statsd(addr.to_string())
+            }
+            _ => return None,
+        };
+        sinks.push(sink);
+    }
+
+    Some(sinks)
+}
+
+pub fn open_sink(spec: &SinkSpec) -> io::Result<Box<MetricsSink>> {
+    match *spec {
+        SinkSpec::Stdout => Ok(Box::new(StdoutSink::new())),
+        SinkSpec::JsonLines(ref path) => {
+            Ok(Box::new(try!(JsonLinesSink::open(path))))
+        }
+        SinkSpec::Statsd(ref addr) => {
+            Ok(Box::new(try!(StatsdSink::connect(addr))))
+        }
+    }
+}
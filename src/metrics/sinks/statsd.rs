@@ -0,0 +1,97 @@
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+
+use metrics::Timing;
+use metrics::statistics::AggregatedMetric;
+
+use super::MetricsSink;
+
+
+// Keeps packets small enough to not be fragmented on any network
+const MAX_PACKET_LEN: usize = 512;
+
+const PREFIX: &'static str = "emcache.";
+
+
+// Sends every timing to a StatsD server, eg.
+//
+// emcache.DriverTask.exec_cmd:0.031|ms
+//
+// StatsD does the aggregating, so the summaries are of no use here.
+pub struct StatsdSink {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    packet: String, // the lines we haven't sent yet
+}
+
+impl StatsdSink {
+    pub fn connect(addr: &str) -> io::Result<StatsdSink> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "no address to send to"))
+            }
+        };
+
+        let local_addr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = try!(UdpSocket::bind(local_addr));
+        try!(socket.connect(addr));
+
+        Ok(StatsdSink {
+            addr: addr,
+            socket: socket,
+            packet: String::new(),
+        })
+    }
+
+    fn send_packet(&mut self) -> io::Result<()> {
+        if self.packet.is_empty() {
+            return Ok(());
+        }
+
+        let rv = self.socket.send(self.packet.as_bytes());
+        // Whatever happened, these are gone, we don't want them to pile up
+        self.packet.clear();
+        rv.map(|_| ())
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn get_name(&self) -> String {
+        format!("statsd:{}", self.addr)
+    }
+
+    fn add_timing(&mut self, timing: &Timing) -> io::Result<()> {
+        // : separates the value in StatsD
+        let line = format!("{}{}:{:.3}|ms",
+                           PREFIX,
+                           timing.name.replace(":", "."),
+                           timing.duration * 1000.0);
+
+        if self.packet.len() + line.len() + 1 > MAX_PACKET_LEN {
+            try!(self.send_packet());
+        }
+
+        if !self.packet.is_empty() {
+            self.packet.push('\n');
+        }
+        self.packet.push_str(&line);
+
+        Ok(())
+    }
+
+    // Sends the timings that didn't fill a packet
+    fn write_summary(&mut self,
+                     _at: f64,
+                     _interval: f64,
+                     _agg_mets: &[AggregatedMetric])
+                     -> io::Result<()> {
+        self.send_packet()
+    }
+}
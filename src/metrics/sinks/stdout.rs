@@ -0,0 +1,57 @@
+use std::io;
+
+use metrics::Timing;
+use metrics::statistics::AggregatedMetric;
+
+use super::MetricsSink;
+
+
+// Prints a summary for people to read
+pub struct StdoutSink;
+
+impl StdoutSink {
+    pub fn new() -> StdoutSink {
+        StdoutSink
+    }
+}
+
+impl MetricsSink for StdoutSink {
+    fn get_name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn add_timing(&mut self, _timing: &Timing) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_summary(&mut self,
+                     at: f64,
+                     interval: f64,
+                     agg_mets: &[AggregatedMetric])
+                     -> io::Result<()> {
+        println!("== Metrics {}s snapshot at {} ==", interval, at as u64);
+
+        for agg in agg_mets {
+            println!("{:30}  n: {:5}  p0: {}  avg: {}  p90: {}  p99: {}  \
+                      p999: {}",
+                     agg.name,
+                     agg.n,
+                     format_millis(agg.p0),
+                     format_millis(agg.avg),
+                     format_millis(agg.p90),
+                     format_millis(agg.p99),
+                     format_millis(agg.p999));
+        }
+
+        Ok(())
+    }
+}
+
+
+// Percentiles that need more samples than there are are left out
+fn format_millis(secs: Option<f64>) -> String {
+    match secs {
+        Some(secs) => format!("{:.3}ms", secs * 1000.0),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::fs;
+use std::io::Read;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use metrics::Timing;
+use metrics::statistics::AggregatedMetric;
+use testlib::tempfile::get_temp_path;
+
+use super::JsonLinesSink;
+use super::MetricsSink;
+use super::SinkSpec;
+use super::StatsdSink;
+use super::open_sink;
+use super::parse_sink_specs;
+
+
+fn get_agg_met(name: &str) -> AggregatedMetric {
+    AggregatedMetric {
+        name: name.to_string(),
+        n: 2,
+        avg: Some(0.0015),
+        p0: Some(0.001),
+        p90: None,
+        p99: None,
+        p999: None,
+    }
+}
+
+
+#[test]
+fn test_parse_sink_specs() {
+    assert_eq!(Some(vec![SinkSpec::Stdout]), parse_sink_specs("stdout"));
+    assert_eq!(Some(vec![SinkSpec::Stdout,
+                         SinkSpec::JsonLines("/tmp/m.jsonl".to_string()),
+                         SinkSpec::Statsd("localhost:8125".to_string())]),
+               parse_sink_specs("stdout,json:/tmp/m.jsonl,\
+                                 statsd:localhost:8125"));
+
+    assert_eq!(None, parse_sink_specs(""));
+    assert_eq!(None, parse_sink_specs("stdout:x"));
+    assert_eq!(None, parse_sink_specs("json"));
+    assert_eq!(None, parse_sink_specs("statsd:localhost"));
+    assert_eq!(None, parse_sink_specs("stdout,graphite:localhost:2003"));
+}
+
+
+#[test]
+fn test_json_lines_sink() {
+    let path = get_temp_path("metrics-sink.jsonl");
+    {
+        let mut sink = JsonLinesSink::open(&path).unwrap();
+        sink.add_timing(&Timing::new("cmd", 1.0, 0.001)).unwrap();
+        sink.write_summary(17.5, 1.0, &[get_agg_met("cmd")]).unwrap();
+        sink.write_summary(18.5, 1.0, &[]).unwrap();
+    }
+
+    let mut text = String::new();
+    File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    let expected = "{\"at\":17,\"interval\":1,\"timers\":{\"cmd\":{\"n\":2,\
+                    \"p0_ms\":1,\"avg_ms\":1.5,\"p90_ms\":null,\
+                    \"p99_ms\":null,\"p999_ms\":null}}}\n\
+                    {\"at\":18,\"interval\":1,\"timers\":{}}\n";
+    assert_eq!(expected, text);
+
+    fs::remove_file(&path).unwrap();
+}
+
+
+#[test]
+fn test_statsd_sink() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+
+    let mut sink = open_sink(&SinkSpec::Statsd(addr.clone())).unwrap();
+    assert_eq!(format!("statsd:{}", addr), sink.get_name());
+
+    // Timings are sent in one packet when the summary comes around
+    let timings = vec![Timing::new("DriverTask:exec_cmd", 1.0, 0.0012),
+                       Timing::new("DriverTask:crawl", 1.0, 0.5)];
+    for timing in timings.iter() {
+        sink.add_timing(timing).unwrap();
+    }
+    sink.write_summary(1.0, 1.0, &[]).unwrap();
+
+    let mut buf = [0; 1024];
+    let len = server.recv(&mut buf).unwrap();
+    assert_eq!("emcache.DriverTask.exec_cmd:1.200|ms\n\
+                emcache.DriverTask.crawl:500.000|ms",
+               String::from_utf8_lossy(&buf[..len]));
+
+    // Nothing more to send
+    sink.write_summary(2.0, 1.0, &[]).unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(server.recv(&mut buf).is_err());
+}
+
+#[test]
+fn test_statsd_sink_splits_packets() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+
+    let mut sink = StatsdSink::connect(&addr).unwrap();
+    for _ in 0..40 {
+        sink.add_timing(&Timing::new("TransportTask:read_cmd", 1.0, 0.001))
+            .unwrap();
+    }
+    sink.write_summary(1.0, 1.0, &[]).unwrap();
+
+    let mut lines = 0;
+    let mut buf = [0; 1024];
+    while lines < 40 {
+        let len = server.recv(&mut buf).unwrap();
+        assert!(len <= 512);
+        lines += String::from_utf8_lossy(&buf[..len]).lines().count();
+    }
+    assert_eq!(40, lines);
+}
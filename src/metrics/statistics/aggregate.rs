@@ -1,5 +1,7 @@
 use std::cmp::Ordering;
 
+use metrics::LogHistogram;
+
 use super::AggregatedMetric;
 
 
@@ -24,14 +26,17 @@ pub fn compute_average(samples: &Vec<f64>) -> Option<f64> {
 
 
 pub fn compute_px(samples: &Vec<f64>, len: usize, pct: f64) -> Option<f64> {
-    if samples.len() < len {
+    let mut clone = samples.clone();
+    get_px(sort_f64(&mut clone), len, pct)
+}
+
+// Same as compute_px, for samples that are sorted already
+fn get_px(sorted: &Vec<f64>, len: usize, pct: f64) -> Option<f64> {
+    if sorted.len() < len {
         return None;
     }
 
-    let mut clone = samples.clone();
-    let sorted = sort_f64(&mut clone);
-    let pos = ((samples.len() as f64) * pct) as usize;
-
+    let pos = ((sorted.len() as f64) * pct) as usize;
     Some(sorted[pos])
 }
 
@@ -53,13 +58,41 @@ pub fn compute_p999(samples: &Vec<f64>) -> Option<f64> {
 
 
 pub fn aggregate_metric(name: &str, samples: &Vec<f64>) -> AggregatedMetric {
+    // Sorted once for all the percentiles
+    let mut sorted = samples.clone();
+    sort_f64(&mut sorted);
+
     AggregatedMetric {
         name: name.to_string(),
         n: samples.len() as u64,
         avg: compute_average(&samples),
-        p0: compute_p0(&samples),
-        p90: compute_p90(&samples),
-        p99: compute_p99(&samples),
-        p999: compute_p999(&samples),
+        p0: get_px(&sorted, 1, 0.0),
+        p90: get_px(&sorted, 10, 0.9),
+        p99: get_px(&sorted, 100, 0.99),
+        p999: get_px(&sorted, 1000, 0.999),
+    }
+}
+
+// Same as aggregate_metric, for samples counted into a histogram. The
+// percentiles are left out for too few samples in the same way.
+pub fn aggregate_histogram(name: &str,
+                           hist: &LogHistogram)
+                           -> AggregatedMetric {
+    let n = hist.get_count();
+    let get_px = |len: u64, pct: f64| {
+        match n >= len {
+            true => hist.get_quantile(pct),
+            false => None,
+        }
+    };
+
+    AggregatedMetric {
+        name: name.to_string(),
+        n: n,
+        avg: hist.get_mean(),
+        p0: hist.get_min(),
+        p90: get_px(10, 0.9),
+        p99: get_px(100, 0.99),
+        p999: get_px(1000, 0.999),
     }
 }
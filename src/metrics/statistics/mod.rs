@@ -7,6 +7,7 @@ mod tests;  // needed to be part of the compilation unit in test mode
 
 
 // Export our public api
+pub use self::aggregate::aggregate_histogram;
 pub use self::aggregate::aggregate_metric;
 pub use self::aggregate::compute_average;
 pub use self::aggregate::compute_p0;
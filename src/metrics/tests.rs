@@ -1,14 +1,30 @@
+use std::collections::HashMap;
+use std::f64;
 use std::sync::mpsc;
 
 use platform::time::sleep_secs;
 use platform::time::time_now;
+use protocol::cmd::Stat;
 use testlib::cmp::eq_f64;
+use testlib::datagen::get_rand_f64_vec;
 
+use super::Histogram;
 use super::LiveTimers;
+use super::LogHistogram;
+use super::MAX_WINDOW;
 use super::MetricsRecorder;
+use super::Recorder;
+use super::Second;
 use super::TimeSeries;
 use super::Timer;
 use super::Timing;
+use super::new_recorder;
+use super::prometheus::Exposition;
+use super::prometheus::MetricKind;
+use super::prometheus::get_stat_kind;
+use super::statistics::compute_average;
+use super::statistics::compute_p0;
+use super::statistics::aggregate::compute_px;
 
 
 #[test]
@@ -48,61 +64,295 @@ fn test_live_timers_ok() {
 fn test_time_series_updates() {
     let mut ts = TimeSeries::new();
 
-    // add a timer
+    // add timings, one second at a time
     ts.add_timing(&Timing::new("cmd", 1.1, 0.25));
-    // construct the expected value for comparison
-    let expected = hashmap!{
-        "cmd".to_string() => hashmap!{
-            1 => vec![0.25],
-        },
-    };
-    // compare
-    assert_eq!(&expected, ts.get_timers());
-
-    // add another timer
     ts.add_timing(&Timing::new("cmd", 1.9, 0.51));
-    // construct the expected value for comparison
-    let expected = hashmap!{
-        "cmd".to_string() => hashmap!{
-            1 => vec![0.25, 0.51],
-        },
-    };
-    // compare
-    assert_eq!(&expected, ts.get_timers());
+    assert_eq!(vec![1], ts.get_seconds("cmd"));
 
-    // add another timer
     ts.add_timing(&Timing::new("cmd", 2.3, 8.8));
-    // construct the expected value for comparison
-    let expected = hashmap!{
-        "cmd".to_string() => hashmap!{
-            1 => vec![0.25, 0.51],
-            2 => vec![8.8],
-        },
-    };
-    // compare
-    assert_eq!(&expected, ts.get_timers());
+    assert_eq!(vec![1, 2], ts.get_seconds("cmd"));
+
+    // late timings go where they belong
+    ts.add_timing(&Timing::new("cmd", 0.5, 0.1));
+    assert_eq!(vec![0, 1, 2], ts.get_seconds("cmd"));
 
-    // add another timer
     ts.add_timing(&Timing::new("resp", 4.1, 1.0));
-    // construct the expected value for comparison
-    let expected = hashmap!{
-        "cmd".to_string() => hashmap!{
-            1 => vec![0.25, 0.51],
-            2 => vec![8.8],
-        },
-        "resp".to_string() => hashmap!{
-            4 => vec![1.0],
-        },
-    };
-    // compare
-    assert_eq!(&expected, ts.get_timers());
+    assert_eq!(vec![4], ts.get_seconds("resp"));
 
     // empty the series
     ts.clear();
-    // construct the expected value for comparison
-    let expected = hashmap!{};
-    // compare
-    assert_eq!(&expected, ts.get_timers());
+    assert_eq!(Vec::<Second>::new(), ts.get_seconds("cmd"));
+}
+
+#[test]
+fn test_time_series_forgets_old_seconds() {
+    let mut ts = TimeSeries::new();
+
+    for sec in 0..100 {
+        ts.add_timing(&Timing::new("cmd", sec as f64, 0.1));
+    }
+
+    // the newest second and the MAX_WINDOW before it
+    let seconds = ts.get_seconds("cmd");
+    assert_eq!(MAX_WINDOW as usize + 1, seconds.len());
+    assert_eq!(99 - MAX_WINDOW, seconds[0]);
+
+    // too late for any window
+    ts.add_timing(&Timing::new("cmd", 5.0, 0.1));
+    assert_eq!(99 - MAX_WINDOW, ts.get_seconds("cmd")[0]);
+}
+
+#[test]
+fn test_time_series_windows() {
+    let mut ts = TimeSeries::new();
+
+    // 10 timings in each of the seconds 90 to 99, the one in second 99 is
+    // slower, then the second we're in (100)
+    for sec in 90..100 {
+        for i in 0..10 {
+            let duration = match sec {
+                99 => 0.5,
+                _ => 0.001 * (i + 1) as f64,
+            };
+            ts.add_timing(&Timing::new("cmd", sec as f64 + 0.05, duration));
+        }
+    }
+    ts.add_timing(&Timing::new("cmd", 100.5, 2.0));
+    ts.add_timing(&Timing::new("resp", 95.0, 0.001));
+
+    // the last second only
+    let agg_mets = ts.aggregate_metrics(100, 1);
+    assert_eq!(1, agg_mets.len());
+    assert_eq!("cmd", agg_mets[0].name);
+    assert_eq!(10, agg_mets[0].n);
+    assert_eq!(Some(0.5), agg_mets[0].p0);
+
+    // every complete second, sorted by name
+    let agg_mets = ts.aggregate_metrics(100, 10);
+    assert_eq!(2, agg_mets.len());
+    assert_eq!("cmd", agg_mets[0].name);
+    assert_eq!(100, agg_mets[0].n);
+    assert_eq!(Some(0.001), agg_mets[0].p0);
+    assert!(eq_f64(0.5, agg_mets[0].p90.unwrap(), 0.5 / 32.0));
+    assert!(eq_f64(0.5, agg_mets[0].p99.unwrap(), 0.5 / 32.0));
+    assert_eq!(None, agg_mets[0].p999);
+    assert_eq!("resp", agg_mets[1].name);
+    assert_eq!(1, agg_mets[1].n);
+
+    // nothing
+    assert_eq!(0, ts.aggregate_metrics(200, 60).len());
+}
+
+
+#[test]
+fn test_log_histogram_empty() {
+    let hist = LogHistogram::new();
+
+    assert_eq!(0, hist.get_count());
+    assert_eq!(None, hist.get_min());
+    assert_eq!(None, hist.get_max());
+    assert_eq!(None, hist.get_mean());
+    assert_eq!(None, hist.get_quantile(0.5));
+}
+
+#[test]
+fn test_log_histogram_quantiles() {
+    // 0.1us to 10s, spread over a lot of powers of two
+    let samples: Vec<f64> = get_rand_f64_vec(1, 10000)
+                                .iter()
+                                .map(|x| x * x / 1e7)
+                                .collect();
+    let mut hist = LogHistogram::new();
+    for sample in samples.iter() {
+        hist.record(*sample);
+    }
+
+    assert_eq!(10000, hist.get_count());
+    assert_eq!(compute_p0(&samples), hist.get_min());
+    assert_eq!(Some(10.0), hist.get_max());
+    assert!(eq_f64(compute_average(&samples).unwrap(),
+                   hist.get_mean().unwrap(),
+                   0.000001));
+
+    // within the width of a bucket
+    for pct in vec![0.1, 0.5, 0.9, 0.99, 0.999] {
+        let exact = compute_px(&samples, 1, pct).unwrap();
+        let approx = hist.get_quantile(pct).unwrap();
+        assert!(eq_f64(exact, approx, exact / 32.0),
+                "p{}: {} vs {}",
+                pct,
+                exact,
+                approx);
+    }
+}
+
+#[test]
+fn test_log_histogram_merge() {
+    let mut all = LogHistogram::new();
+    let mut first = LogHistogram::new();
+    let mut second = LogHistogram::new();
+
+    for i in 0..1000 {
+        let value = (i as f64) / 1000.0;
+        all.record(value);
+        match i % 3 {
+            0 => first.record(value),
+            _ => second.record(value),
+        }
+    }
+
+    first.merge(&second);
+    assert_eq!(all.get_count(), first.get_count());
+    assert_eq!(all.get_min(), first.get_min());
+    assert_eq!(all.get_max(), first.get_max());
+    assert_eq!(all.get_quantile(0.5), first.get_quantile(0.5));
+    assert_eq!(all.get_quantile(0.99), first.get_quantile(0.99));
+
+    // merging nothing changes nothing
+    let before = first.clone();
+    first.merge(&LogHistogram::new());
+    assert_eq!(before, first);
+}
+
+
+#[test]
+fn test_null_recorder() {
+    let (met_tx, met_rx) = mpsc::channel();
+    let mut rec = new_recorder(met_tx, false);
+
+    rec.start_timer("cmd");
+    rec.stop_timer("cmd");
+    rec.stop_timer("never started"); // not even checked
+    rec.flush_metrics();
+
+    assert!(met_rx.try_recv().is_err());
+}
+
+#[test]
+fn test_metrics_recorder() {
+    let (met_tx, met_rx) = mpsc::channel();
+    let mut rec = new_recorder(met_tx, true);
+
+    {
+        let _t = Timer::new(&mut *rec, "cmd");
+    }
+    rec.flush_metrics();
+
+    let metrics = met_rx.try_recv().unwrap();
+    assert_eq!("cmd", metrics.first().get_timing().name);
+}
+
+#[test]
+fn test_metrics_recorder_stop_timer_also() {
+    let (met_tx, met_rx) = mpsc::channel();
+    let mut rec = new_recorder(met_tx, true);
+
+    rec.start_timer("cmd");
+    rec.stop_timer_also("cmd", "cmd:hit");
+    rec.flush_metrics();
+
+    // the same timing under both names
+    let metrics = met_rx.try_recv().unwrap();
+    assert_eq!(2, metrics.metrics.len());
+    let first = metrics.metrics[0].get_timing();
+    let second = metrics.metrics[1].get_timing();
+    assert_eq!("cmd", first.name);
+    assert_eq!("cmd:hit", second.name);
+    assert_eq!(first.start_time, second.start_time);
+    assert_eq!(first.duration, second.duration);
+}
+
+
+#[test]
+fn test_histogram_buckets() {
+    let mut hist = Histogram::new(&[0.1, 1.0]);
+    assert_eq!(vec![(0.1, 0), (1.0, 0), (f64::INFINITY, 0)],
+               hist.get_buckets());
+
+    hist.observe(0.05);
+    hist.observe(0.1); // the bound is inclusive
+    hist.observe(0.5);
+    hist.observe(3.0);
+
+    // counts are cumulative
+    assert_eq!(vec![(0.1, 2), (1.0, 3), (f64::INFINITY, 4)],
+               hist.get_buckets());
+    assert_eq!(4, hist.get_count());
+    assert!(eq_f64(3.65, hist.get_sum(), 0.0001));
+}
+
+
+#[test]
+fn test_prometheus_stat_kinds() {
+    assert_eq!(Some(MetricKind::Counter), get_stat_kind("cmd_get"));
+    assert_eq!(Some(MetricKind::Counter), get_stat_kind("extstore_hits"));
+    assert_eq!(Some(MetricKind::Gauge), get_stat_kind("curr_items"));
+    assert_eq!(None, get_stat_kind("pid"));
+}
+
+#[test]
+fn test_prometheus_stats() {
+    let stats = vec![Stat::new("pid", "123".to_string()),
+                     Stat::new("version", "emcache 0.1.0".to_string()),
+                     Stat::new("cmd_get", "7".to_string()),
+                     Stat::new("compress_ratio", "1.50".to_string())];
+
+    let mut expo = Exposition::new();
+    expo.add_stats(&stats);
+
+    let expected = "\
+# HELP emcache_version_info The version we run, always 1
+# TYPE emcache_version_info gauge
+emcache_version_info{version=\"emcache 0.1.0\"} 1
+# HELP emcache_cmd_get_total Same as cmd_get in stats
+# TYPE emcache_cmd_get_total counter
+emcache_cmd_get_total 7
+# HELP emcache_compress_ratio Same as compress_ratio in stats
+# TYPE emcache_compress_ratio gauge
+emcache_compress_ratio 1.5
+";
+    assert_eq!(expected, expo.as_str());
+}
+
+#[test]
+fn test_prometheus_timers() {
+    let mut hist = Histogram::new(&[0.001, 0.01]);
+    hist.observe(0.0005);
+    hist.observe(0.005);
+    let histograms = hashmap!{
+        "DriverTask:exec_cmd".to_string() => hist,
+    };
+
+    let mut expo = Exposition::new();
+    expo.add_timers(&histograms);
+
+    let labels = "timer=\"DriverTask:exec_cmd\"";
+    let expected = format!("\
+# HELP emcache_timer_seconds How long the timed sections of the server took
+# TYPE emcache_timer_seconds histogram
+emcache_timer_seconds_bucket{{{0},le=\"0.001\"}} 1
+emcache_timer_seconds_bucket{{{0},le=\"0.01\"}} 2
+emcache_timer_seconds_bucket{{{0},le=\"+Inf\"}} 2
+emcache_timer_seconds_sum{{{0}}} 0.0055
+emcache_timer_seconds_count{{{0}}} 2
+",
+                           labels);
+    assert_eq!(expected, expo.as_str());
+
+    // The family is there even without timers
+    let mut expo = Exposition::new();
+    expo.add_timers(&HashMap::new());
+    assert!(expo.as_str()
+                .ends_with("# TYPE emcache_timer_seconds histogram\n"));
+}
+
+#[test]
+fn test_prometheus_label_escaping() {
+    let mut expo = Exposition::new();
+    expo.add_sample("x", &[("a", "say \"hi\"\\\n")], 0.5);
+
+    assert_eq!("emcache_x{a=\"say \\\"hi\\\"\\\\\\n\"} 0.5\n",
+               expo.as_str());
 }
 
 
@@ -111,7 +361,7 @@ fn test_time_series_updates() {
 #[test]
 fn test_timer_correct() {
     let (met_tx, met_rx) = mpsc::channel();
-    let mut rec = MetricsRecorder::new(met_tx, true);
+    let mut rec = MetricsRecorder::new(met_tx);
 
     // use Timer to make one timing
     let _rv = {
@@ -135,7 +385,7 @@ fn test_timer_correct() {
 #[test]
 fn test_timer_wrong_binding() {
     let (met_tx, met_rx) = mpsc::channel();
-    let mut rec = MetricsRecorder::new(met_tx, true);
+    let mut rec = MetricsRecorder::new(met_tx);
 
     // use Timer to make one timing
     let _rv = {
@@ -160,7 +410,7 @@ fn test_timer_wrong_binding() {
 #[test]
 fn test_timer_no_binding() {
     let (met_tx, met_rx) = mpsc::channel();
-    let mut rec = MetricsRecorder::new(met_tx, true);
+    let mut rec = MetricsRecorder::new(met_tx);
 
     // use Timer to make one timing
     let _rv = {
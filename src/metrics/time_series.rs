@@ -1,16 +1,27 @@
 use std::collections::HashMap;
+use std::collections::VecDeque;
 
-use super::Duration;
+use super::LogHistogram;
 use super::Second;
 use super::Timing;
 use super::statistics::AggregatedMetric;
-use super::statistics::aggregate_metric;
+use super::statistics::aggregate_histogram;
 
 
+// The windows the metrics are summarized over, in seconds
+pub const WINDOWS: &'static [Second] = &[1, 10, 60];
+
+// The longest window that can be aggregated over
+pub const MAX_WINDOW: Second = 60;
+
+
+// Keeps a histogram of the timings of every second, for as many seconds as
+// the longest window needs, so that memory stays the same however many
+// timings come in.
 #[derive(Debug, Clone, PartialEq)]
 pub struct TimeSeries {
-    // name -> { 1 -> [0.13, 0.41], 2 -> [0.42, 0.6] }
-    timers: HashMap<String, HashMap<Second, Vec<Duration>>>,
+    // name -> [(1, hist), (2, hist)], oldest second first
+    timers: HashMap<String, VecDeque<(Second, LogHistogram)>>,
 }
 
 impl TimeSeries {
@@ -18,42 +29,66 @@ impl TimeSeries {
         TimeSeries { timers: HashMap::new() }
     }
 
-    pub fn get_timers(&self) -> &HashMap<String, HashMap<Second, Vec<Duration>>> {
-        &self.timers
+    // The seconds we have timings of for name, oldest first
+    pub fn get_seconds(&self, name: &str) -> Vec<Second> {
+        match self.timers.get(name) {
+            Some(seconds) => seconds.iter().map(|&(sec, _)| sec).collect(),
+            None => vec![],
+        }
     }
 
 
     pub fn add_timing(&mut self, timing: &Timing) {
-        // does the name series exist?
-        if !self.timers.contains_key(&timing.name) {
-            self.timers.insert(timing.name.to_string(), HashMap::new());
-        }
-
-        // does the second series exist?
+        let seconds = self.timers
+                          .entry(timing.name.to_string())
+                          .or_insert_with(VecDeque::new);
         let sec = timing.start_time as Second;
-        if !self.timers.get(&timing.name).unwrap().contains_key(&sec) {
-            self.timers.get_mut(&timing.name).unwrap().insert(sec, vec![]);
+
+        // Timings mostly come in order, so look from the newest second
+        let pos = seconds.iter()
+                         .rposition(|&(other, _)| other <= sec)
+                         .map(|pos| pos + 1)
+                         .unwrap_or(0);
+
+        if pos > 0 && seconds[pos - 1].0 == sec {
+            seconds[pos - 1].1.record(timing.duration);
+        } else {
+            let mut hist = LogHistogram::new();
+            hist.record(timing.duration);
+            seconds.insert(pos, (sec, hist));
         }
 
-        // insert the value
-        self.timers
-            .get_mut(&timing.name)
-            .unwrap()
-            .get_mut(&sec)
-            .unwrap()
-            .push(timing.duration);
+        // Forget what's too old for any window (the newest second is still
+        // filling up, so it doesn't count)
+        let newest = seconds.back().unwrap().0;
+        while newest - seconds.front().unwrap().0 > MAX_WINDOW {
+            seconds.pop_front();
+        }
     }
 
-    pub fn aggregate_metrics(&self) -> HashMap<String, AggregatedMetric> {
-        let mut agg_mets = HashMap::new();
+    // Aggregates the timings of the window seconds before now (so only
+    // complete seconds), sorted by name. Names without timings in the
+    // window are left out.
+    pub fn aggregate_metrics(&self,
+                             now: Second,
+                             window: Second)
+                             -> Vec<AggregatedMetric> {
+        let mut agg_mets = vec![];
 
         for (name, seconds) in self.timers.iter() {
-            for (_, samples) in seconds.iter() {
-                let agg = aggregate_metric(name, samples);
-                agg_mets.insert(name.to_string(), agg);
+            let mut hist = LogHistogram::new();
+            for &(sec, ref sec_hist) in seconds.iter() {
+                if sec < now && sec + window >= now {
+                    hist.merge(sec_hist);
+                }
+            }
+
+            if hist.get_count() > 0 {
+                agg_mets.push(aggregate_histogram(name, &hist));
             }
         }
 
+        agg_mets.sort_by(|a, b| a.name.cmp(&b.name));
         agg_mets
     }
 
@@ -1,13 +1,13 @@
-use super::MetricsRecorder;
+use super::Recorder;
 
 
 pub struct Timer<'a> {
-    recorder: &'a mut MetricsRecorder,
+    recorder: &'a mut Recorder,
     name: &'a str,
 }
 
 impl<'a> Timer<'a> {
-    pub fn new(recorder: &'a mut MetricsRecorder, name: &'a str) -> Timer<'a> {
+    pub fn new(recorder: &'a mut Recorder, name: &'a str) -> Timer<'a> {
         recorder.start_timer(name);
 
         Timer {
@@ -1,5 +1,14 @@
+use std::env;
+use std::process;
+
 use docopt::Docopt;
 
+use config::ConfigResult;
+use config::load_options;
+use metrics::sinks::SinkSpec;
+use metrics::sinks::parse_sink_specs;
+use persistence::FsyncPolicy;
+
 
 // Write the Docopt usage string.
 const USAGE: &'static str = "
@@ -7,25 +16,383 @@ Usage:
     emcache [options]
 
 Options:
-    --host HOST         Interface to listen on (ie. ip hostname/ip).
-    -p --port PORT      Port to bind to.
-    -m --mem MEMSIZE    Max memory to use (in megabytes).
-    --metrics           Collect server performance metrics.
-    -V --version        Print version info and exit
-    -h --help           Show this screen.
+    -c --config PATH            Read settings from this toml file. Env vars
+                                (EMCACHE_*) override it, flags override both.
+    --host HOST                 Interface to listen on (ie. ip hostname/ip).
+    -p --port PORT              Port to bind to.
+    -m --mem MEMSIZE            Max memory to use (in megabytes).
+    --max-item-size SIZE        Max size of a value, eg. 512k or 2m (a plain
+                                number is in megabytes).
+    --max-key-len BYTES         Max length of a key (in bytes).
+    --metrics                   Collect server performance metrics.
+    --no-metrics                Don't, even if the config file says so.
+    --metrics-sinks SINKS       Where the metrics go: stdout, json:PATH
+                                and/or statsd:HOST:PORT, comma separated
+                                (default: stdout).
+    --no-hotkeys                Don't keep track of the most accessed keys.
+    --hotkeys                   Do, even if the config file says not to.
+    --slowlog-threshold-us US   Log commands that take at least this long
+                                (in microseconds, default: 10000).
+    --slowlog-max-len NUM       Keep this many slow commands (0 disables,
+                                default: 128).
+    --crawler-batch NUM         Items checked per expiry crawler run
+                                (0 disables).
+    --compress-threshold BYTES  Compress values of at least this size
+                                (0 disables).
+    --extstore-path PATH        Write big values out to this file instead of
+                                evicting them.
+    --extstore-size MB          Max size of the extstore file (in megabytes).
+    --extstore-item-min BYTES   Only write out values of at least this size.
+    --namespaces SPECS          Give key namespaces their own memory quota,
+                                eg. users:16,sessions:8 (in megabytes).
+    --namespace-delimiter CHAR  Separates the namespace from the rest of a
+                                key.
+    --snapshot-path PATH        Persist the cache to this file on shutdown
+                                and restore it on startup.
+    --snapshot-interval SECS    Also write a snapshot every SECS seconds.
+    --log-path PATH             Record every mutation in this append-only log
+                                and replay it on startup.
+    --log-fsync POLICY          When to fsync the log: always, everysec or
+                                never.
+    --log-compact-mb MB         Compact the log into a snapshot once it grows
+                                beyond this size (in megabytes).
+    --daemon                    Detach from the terminal and run in the
+                                background.
+    --no-daemon                 Stay in the foreground, even if the config
+                                file says otherwise.
+    --daemon-log PATH           Where stdout and stderr go in the background
+                                (default: /dev/null).
+    --pidfile PATH              Write the pid to this file and lock it, so a
+                                second instance refuses to start.
+    --user USER                 Switch to this user after binding the socket,
+                                when started as root.
+    --admin-port PORT           Serve health checks, stats and metrics over
+                                http on this port.
+    --admin-host HOST           Interface for the admin port to listen on.
+    --admin-writes              Allow flushing the cache and writing a
+                                snapshot through the admin port.
+    --no-admin-writes           Don't, even if the config file allows it.
+    --admin-read-timeout SECS   How long an admin client has to send its
+                                request (default: 5).
+    --admin-driver-timeout SECS
+                                How long an admin request waits for the cache
+                                (default: 5).
+    --admin-ready-timeout SECS  How long /ready waits for the cache before
+                                reporting that it's not ready (default: 1).
+    --drain-timeout SECS        How long a shutdown waits for clients to
+                                finish the command they're on (default: 5).
+    --idle-interval SECS        How long the cache waits for a command before
+                                crawling for expired items (default: 1).
+    --enable-debugtime          Let clients shift the time of the server with
+                                the debugtime command (for testing only).
+    --disable-debugtime         Don't, even if the config file enables it.
+    -V --version                Print version info and exit
+    -h --help                   Show this screen.
 ";
 
 
 #[derive(Debug, Clone, RustcDecodable)]
 pub struct MemcacheOptions {
+    pub flag_config: Option<String>,
     pub flag_host: Option<String>,
     pub flag_port: Option<u16>,
     pub flag_mem: Option<u64>,
+    pub flag_max_item_size: Option<String>,
+    pub flag_max_key_len: Option<u64>,
     pub flag_metrics: bool,
+    pub flag_no_metrics: bool,
+    pub flag_metrics_sinks: Option<String>,
+    pub flag_no_hotkeys: bool,
+    pub flag_hotkeys: bool,
+    pub flag_slowlog_threshold_us: Option<u64>,
+    pub flag_slowlog_max_len: Option<usize>,
+    pub flag_crawler_batch: Option<u64>,
+    pub flag_compress_threshold: Option<u64>,
+    pub flag_extstore_path: Option<String>,
+    pub flag_extstore_size: Option<u64>,
+    pub flag_extstore_item_min: Option<u64>,
+    pub flag_namespaces: Option<String>,
+    pub flag_namespace_delimiter: Option<String>,
+    pub flag_snapshot_path: Option<String>,
+    pub flag_snapshot_interval: Option<u64>,
+    pub flag_log_path: Option<String>,
+    pub flag_log_fsync: Option<String>,
+    pub flag_log_compact_mb: Option<u64>,
+    pub flag_daemon: bool,
+    pub flag_no_daemon: bool,
+    pub flag_daemon_log: Option<String>,
+    pub flag_pidfile: Option<String>,
+    pub flag_user: Option<String>,
+    pub flag_admin_port: Option<u16>,
+    pub flag_admin_host: Option<String>,
+    pub flag_admin_writes: bool,
+    pub flag_no_admin_writes: bool,
+    pub flag_admin_read_timeout: Option<f64>,
+    pub flag_admin_driver_timeout: Option<f64>,
+    pub flag_admin_ready_timeout: Option<f64>,
+    pub flag_drain_timeout: Option<f64>,
+    pub flag_idle_interval: Option<f64>,
+    pub flag_enable_debugtime: bool,
+    pub flag_disable_debugtime: bool,
     pub flag_version: bool,
 }
 
 impl MemcacheOptions {
+    // The options the server runs with when none are given
+    pub fn new() -> MemcacheOptions {
+        let mut opts = MemcacheOptions {
+            flag_config: None,
+            flag_host: None,
+            flag_port: None,
+            flag_mem: None,
+            flag_max_item_size: None,
+            flag_max_key_len: None,
+            flag_metrics: false,
+            flag_no_metrics: false,
+            flag_metrics_sinks: None,
+            flag_no_hotkeys: false,
+            flag_hotkeys: false,
+            flag_slowlog_threshold_us: None,
+            flag_slowlog_max_len: None,
+            flag_crawler_batch: None,
+            flag_compress_threshold: None,
+            flag_extstore_path: None,
+            flag_extstore_size: None,
+            flag_extstore_item_min: None,
+            flag_namespaces: None,
+            flag_namespace_delimiter: None,
+            flag_snapshot_path: None,
+            flag_snapshot_interval: None,
+            flag_log_path: None,
+            flag_log_fsync: None,
+            flag_log_compact_mb: None,
+            flag_daemon: false,
+            flag_no_daemon: false,
+            flag_daemon_log: None,
+            flag_pidfile: None,
+            flag_user: None,
+            flag_admin_port: None,
+            flag_admin_host: None,
+            flag_admin_writes: false,
+            flag_no_admin_writes: false,
+            flag_admin_read_timeout: None,
+            flag_admin_driver_timeout: None,
+            flag_admin_ready_timeout: None,
+            flag_drain_timeout: None,
+            flag_idle_interval: None,
+            flag_enable_debugtime: false,
+            flag_disable_debugtime: false,
+            flag_version: false,
+        };
+        opts.set_defaults();
+        opts
+    }
+
+    // Fills in every option that wasn't given
+    fn set_defaults(&mut self) {
+        if self.flag_host.is_none() {
+            self.flag_host = Some("127.0.0.1".to_string());
+        }
+        if self.flag_port.is_none() {
+            self.flag_port = Some(11311);
+        }
+
+        if self.flag_mem.is_none() {
+            self.flag_mem = Some(64);
+        }
+
+        if self.flag_max_item_size.is_none() {
+            self.flag_max_item_size = Some("1m".to_string());
+        }
+        if self.flag_max_key_len.is_none() {
+            self.flag_max_key_len = Some(250);
+        }
+
+        if self.flag_metrics_sinks.is_none() {
+            self.flag_metrics_sinks = Some("stdout".to_string());
+        }
+
+        if self.flag_slowlog_threshold_us.is_none() {
+            self.flag_slowlog_threshold_us = Some(10000);
+        }
+        if self.flag_slowlog_max_len.is_none() {
+            self.flag_slowlog_max_len = Some(128);
+        }
+
+        if self.flag_crawler_batch.is_none() {
+            self.flag_crawler_batch = Some(100);
+        }
+
+        if self.flag_compress_threshold.is_none() {
+            self.flag_compress_threshold = Some(0);
+        }
+
+        if self.flag_extstore_size.is_none() {
+            self.flag_extstore_size = Some(1024);
+        }
+        if self.flag_extstore_item_min.is_none() {
+            self.flag_extstore_item_min = Some(512);
+        }
+
+        if self.flag_namespace_delimiter.is_none() {
+            self.flag_namespace_delimiter = Some(":".to_string());
+        }
+
+        if self.flag_log_fsync.is_none() {
+            self.flag_log_fsync = Some("everysec".to_string());
+        }
+        if self.flag_log_compact_mb.is_none() {
+            self.flag_log_compact_mb = Some(64);
+        }
+
+        if self.flag_daemon_log.is_none() {
+            self.flag_daemon_log = Some("/dev/null".to_string());
+        }
+
+        if self.flag_admin_host.is_none() {
+            self.flag_admin_host = Some("127.0.0.1".to_string());
+        }
+        if self.flag_admin_read_timeout.is_none() {
+            self.flag_admin_read_timeout = Some(5.0);
+        }
+        if self.flag_admin_driver_timeout.is_none() {
+            self.flag_admin_driver_timeout = Some(5.0);
+        }
+        if self.flag_admin_ready_timeout.is_none() {
+            self.flag_admin_ready_timeout = Some(1.0);
+        }
+
+        if self.flag_drain_timeout.is_none() {
+            self.flag_drain_timeout = Some(5.0);
+        }
+        if self.flag_idle_interval.is_none() {
+            self.flag_idle_interval = Some(1.0);
+        }
+    }
+
+    // Takes every option that was given in other
+    pub fn override_with(&mut self, other: &MemcacheOptions) {
+        macro_rules! take {
+            ( $( $field:ident ),* ) => {
+                $(
+                    if other.$field.is_some() {
+                        self.$field = other.$field.clone();
+                    }
+                )*
+            }
+        }
+
+        take!(flag_config,
+              flag_host,
+              flag_port,
+              flag_mem,
+              flag_max_item_size,
+              flag_max_key_len,
+              flag_metrics_sinks,
+              flag_slowlog_threshold_us,
+              flag_slowlog_max_len,
+              flag_crawler_batch,
+              flag_compress_threshold,
+              flag_extstore_path,
+              flag_extstore_size,
+              flag_extstore_item_min,
+              flag_namespaces,
+              flag_namespace_delimiter,
+              flag_snapshot_path,
+              flag_snapshot_interval,
+              flag_log_path,
+              flag_log_fsync,
+              flag_log_compact_mb,
+              flag_daemon_log,
+              flag_pidfile,
+              flag_user,
+              flag_admin_port,
+              flag_admin_host,
+              flag_admin_read_timeout,
+              flag_admin_driver_timeout,
+              flag_admin_ready_timeout,
+              flag_drain_timeout,
+              flag_idle_interval);
+
+        // A flag and its opposite both override, when neither is given the
+        // setting is kept
+        macro_rules! switch {
+            ( $( $field:ident / $opposite:ident ),* ) => {
+                $(
+                    if other.$field {
+                        self.$field = true;
+                    } else if other.$opposite {
+                        self.$field = false;
+                    }
+                )*
+            }
+        }
+
+        switch!(flag_metrics / flag_no_metrics,
+                flag_no_hotkeys / flag_hotkeys,
+                flag_daemon / flag_no_daemon,
+                flag_admin_writes / flag_no_admin_writes,
+                flag_enable_debugtime / flag_disable_debugtime);
+
+        self.flag_version |= other.flag_version;
+    }
+
+    // Takes the options that a running server can change from other, and
+    // returns the flags of those that differ but need a restart
+    pub fn reload_from(&mut self,
+                       other: &MemcacheOptions)
+                       -> Vec<&'static str> {
+        self.flag_max_item_size = other.flag_max_item_size.clone();
+        self.flag_max_key_len = other.flag_max_key_len;
+        self.flag_no_hotkeys = other.flag_no_hotkeys;
+        self.flag_slowlog_threshold_us = other.flag_slowlog_threshold_us;
+        self.flag_slowlog_max_len = other.flag_slowlog_max_len;
+        self.flag_crawler_batch = other.flag_crawler_batch;
+        self.flag_compress_threshold = other.flag_compress_threshold;
+        self.flag_snapshot_interval = other.flag_snapshot_interval;
+        self.flag_log_compact_mb = other.flag_log_compact_mb;
+        self.flag_enable_debugtime = other.flag_enable_debugtime;
+        self.flag_admin_writes = other.flag_admin_writes;
+        self.flag_admin_read_timeout = other.flag_admin_read_timeout;
+        self.flag_admin_driver_timeout = other.flag_admin_driver_timeout;
+        self.flag_admin_ready_timeout = other.flag_admin_ready_timeout;
+        self.flag_drain_timeout = other.flag_drain_timeout;
+        self.flag_idle_interval = other.flag_idle_interval;
+
+        let mut restart_flags = vec![];
+        macro_rules! check {
+            ( $( $field:ident => $flag:expr ),* ) => {
+                $(
+                    if self.$field != other.$field {
+                        restart_flags.push($flag);
+                    }
+                )*
+            }
+        }
+
+        check!(flag_host => "--host",
+               flag_port => "--port",
+               flag_mem => "--mem",
+               flag_metrics => "--metrics",
+               flag_metrics_sinks => "--metrics-sinks",
+               flag_extstore_path => "--extstore-path",
+               flag_extstore_size => "--extstore-size",
+               flag_extstore_item_min => "--extstore-item-min",
+               flag_namespaces => "--namespaces",
+               flag_namespace_delimiter => "--namespace-delimiter",
+               flag_snapshot_path => "--snapshot-path",
+               flag_log_path => "--log-path",
+               flag_log_fsync => "--log-fsync",
+               flag_daemon => "--daemon",
+               flag_daemon_log => "--daemon-log",
+               flag_pidfile => "--pidfile",
+               flag_user => "--user",
+               flag_admin_port => "--admin-port",
+               flag_admin_host => "--admin-host");
+
+        restart_flags
+    }
+
     pub fn get_bind_params(&self) -> (String, u16) {
         let opts = self.clone();
         (opts.flag_host.unwrap().clone(),
@@ -45,27 +412,251 @@ impl MemcacheOptions {
         self.flag_mem.unwrap() << 20
     }
 
+    pub fn get_max_item_size_bytes(&self) -> u64 {
+        parse_size(self.flag_max_item_size.as_ref().unwrap()).unwrap()
+    }
+
+    pub fn get_max_key_len(&self) -> u64 {
+        self.flag_max_key_len.unwrap()
+    }
+
     pub fn get_metrics_enabled(&self) -> bool {
         self.flag_metrics
     }
+
+    pub fn get_metrics_sinks(&self) -> Vec<SinkSpec> {
+        parse_sink_specs(self.flag_metrics_sinks.as_ref().unwrap()).unwrap()
+    }
+
+    pub fn get_hot_keys_enabled(&self) -> bool {
+        !self.flag_no_hotkeys
+    }
+
+    // In seconds
+    pub fn get_slow_log_threshold(&self) -> f64 {
+        self.flag_slowlog_threshold_us.unwrap() as f64 / 1e6
+    }
+
+    pub fn get_slow_log_max_len(&self) -> usize {
+        self.flag_slowlog_max_len.unwrap()
+    }
+
+    pub fn get_crawler_batch(&self) -> u64 {
+        self.flag_crawler_batch.unwrap()
+    }
+
+    pub fn get_compress_threshold(&self) -> u64 {
+        self.flag_compress_threshold.unwrap()
+    }
+
+    pub fn get_extstore_path(&self) -> Option<String> {
+        self.flag_extstore_path.clone()
+    }
+
+    pub fn get_extstore_size_bytes(&self) -> u64 {
+        self.flag_extstore_size.unwrap() << 20
+    }
+
+    pub fn get_extstore_item_min(&self) -> u64 {
+        self.flag_extstore_item_min.unwrap()
+    }
+
+    pub fn get_namespaces(&self) -> Vec<(String, u64)> {
+        match self.flag_namespaces {
+            Some(ref specs) => parse_namespaces(specs).unwrap(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_namespace_delimiter(&self) -> u8 {
+        self.flag_namespace_delimiter.as_ref().unwrap().as_bytes()[0]
+    }
+
+    pub fn get_snapshot_path(&self) -> Option<String> {
+        // The log is compacted into a snapshot, so it needs one too
+        match (&self.flag_snapshot_path, &self.flag_log_path) {
+            (&Some(ref path), _) => Some(path.clone()),
+            (&None, &Some(ref log_path)) => {
+                Some(format!("{}.snapshot", log_path))
+            }
+            (&None, &None) => None,
+        }
+    }
+
+    pub fn get_snapshot_interval(&self) -> Option<f64> {
+        self.flag_snapshot_interval.map(|secs| secs as f64)
+    }
+
+    pub fn get_log_path(&self) -> Option<String> {
+        self.flag_log_path.clone()
+    }
+
+    pub fn get_log_fsync_policy(&self) -> FsyncPolicy {
+        parse_fsync_policy(self.flag_log_fsync.as_ref().unwrap()).unwrap()
+    }
+
+    pub fn get_log_compact_bytes(&self) -> u64 {
+        self.flag_log_compact_mb.unwrap() << 20
+    }
+
+    pub fn get_daemon_enabled(&self) -> bool {
+        self.flag_daemon
+    }
+
+    pub fn get_daemon_log_path(&self) -> String {
+        self.flag_daemon_log.clone().unwrap()
+    }
+
+    pub fn get_pidfile_path(&self) -> Option<String> {
+        self.flag_pidfile.clone()
+    }
+
+    pub fn get_user(&self) -> Option<String> {
+        self.flag_user.clone()
+    }
+
+    pub fn get_admin_bind_params(&self) -> Option<(String, u16)> {
+        match self.flag_admin_port {
+            Some(port) => Some((self.flag_admin_host.clone().unwrap(), port)),
+            None => None,
+        }
+    }
+
+    pub fn get_admin_writes_enabled(&self) -> bool {
+        self.flag_admin_writes
+    }
+
+    // The timeouts and the idle interval are in seconds
+    pub fn get_admin_read_timeout(&self) -> f64 {
+        self.flag_admin_read_timeout.unwrap()
+    }
+
+    pub fn get_admin_driver_timeout(&self) -> f64 {
+        self.flag_admin_driver_timeout.unwrap()
+    }
+
+    pub fn get_admin_ready_timeout(&self) -> f64 {
+        self.flag_admin_ready_timeout.unwrap()
+    }
+
+    pub fn get_drain_timeout(&self) -> f64 {
+        self.flag_drain_timeout.unwrap()
+    }
+
+    pub fn get_idle_interval(&self) -> f64 {
+        self.flag_idle_interval.unwrap()
+    }
+
+    pub fn get_debug_time_enabled(&self) -> bool {
+        self.flag_enable_debugtime
+    }
+}
+
+
+// Parses name:MB pairs, with the quota converted to bytes
+pub fn parse_namespaces(specs: &str) -> Option<Vec<(String, u64)>> {
+    let mut namespaces = vec![];
+
+    for spec in specs.split(',') {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let quota = match parts.next().map(|mb| mb.parse::<u64>()) {
+            Some(Ok(mb)) if mb > 0 => mb << 20,
+            _ => return None,
+        };
+
+        if name.is_empty() {
+            return None;
+        }
+
+        namespaces.push((name.to_string(), quota));
+    }
+
+    Some(namespaces)
+}
+
+// A size in bytes with a unit: 4096b, 512k, 2m or 1g. A plain number is in
+// megabytes, like the other sizes we take. Zero is no size at all.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.to_lowercase();
+    let (num, shift) = match size.chars().last() {
+        Some('b') => (&size[..size.len() - 1], 0),
+        Some('k') => (&size[..size.len() - 1], 10),
+        Some('m') => (&size[..size.len() - 1], 20),
+        Some('g') => (&size[..size.len() - 1], 30),
+        _ => (&size[..], 20),
+    };
+
+    match num.parse::<u64>() {
+        Ok(num) if num > 0 && num <= (u64::max_value() >> shift) => {
+            Some(num << shift)
+        }
+        _ => None,
+    }
 }
 
+pub fn parse_fsync_policy(policy: &str) -> Option<FsyncPolicy> {
+    match policy {
+        "always" => Some(FsyncPolicy::Always),
+        "everysec" => Some(FsyncPolicy::EverySec),
+        "never" => Some(FsyncPolicy::Never),
+        _ => None,
+    }
+}
+
+
+fn parse_cli() -> MemcacheOptions {
+    Docopt::new(USAGE)
+        .and_then(|d| d.decode())
+        .unwrap_or_else(|e| e.exit())
+}
 
 pub fn parse_args() -> MemcacheOptions {
-    let mut opts: MemcacheOptions = Docopt::new(USAGE)
-                                        .and_then(|d| d.decode())
-                                        .unwrap_or_else(|e| e.exit());
+    let cli = parse_cli();
 
-    if opts.flag_host.is_none() {
-        opts.flag_host = Some("127.0.0.1".to_string());
+    // The flags are checked below, the config file and env vars are
+    // checked as they're loaded
+    let opts = match load_options(&cli, env::vars()) {
+        Ok(opts) => opts,
+        Err(err) => {
+            println!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if opts.flag_namespaces.is_some() &&
+       parse_namespaces(opts.flag_namespaces.as_ref().unwrap()).is_none() {
+        println!("Invalid namespaces: {}", opts.flag_namespaces.unwrap());
+        process::exit(1);
+    }
+    if opts.flag_namespace_delimiter.as_ref().unwrap().len() != 1 {
+        println!("Invalid namespace delimiter: {}",
+                 opts.flag_namespace_delimiter.unwrap());
+        process::exit(1);
     }
-    if opts.flag_port.is_none() {
-        opts.flag_port = Some(11311);
+
+    if parse_size(opts.flag_max_item_size.as_ref().unwrap()).is_none() {
+        println!("Invalid max item size: {}",
+                 opts.flag_max_item_size.unwrap());
+        process::exit(1);
     }
 
-    if opts.flag_mem.is_none() {
-        opts.flag_mem = Some(64);
+    if parse_sink_specs(opts.flag_metrics_sinks.as_ref().unwrap()).is_none() {
+        println!("Invalid metrics sinks: {}",
+                 opts.flag_metrics_sinks.unwrap());
+        process::exit(1);
+    }
+
+    if parse_fsync_policy(opts.flag_log_fsync.as_ref().unwrap()).is_none() {
+        println!("Invalid fsync policy: {}", opts.flag_log_fsync.unwrap());
+        process::exit(1);
     }
 
     opts
 }
+
+// Reads the config file again, so that a running server can pick up the
+// changes. The flags and the environment still take precedence.
+pub fn reload_args() -> ConfigResult<MemcacheOptions> {
+    load_options(&parse_cli(), env::vars())
+}
@@ -0,0 +1,358 @@
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+
+use http::Json;
+use http::Method;
+use http::Request;
+use http::Response;
+use http::read_request;
+use metrics::WINDOWS;
+use metrics::prometheus::CONTENT_TYPE;
+use metrics::prometheus::Exposition;
+use metrics::sinks::get_summary_json;
+use options::MemcacheOptions;
+use platform::time::convert_secs_to_duration;
+use platform::time::time_now;
+use protocol::cmd::Cmd;
+use protocol::cmd::FlushAll;
+use protocol::cmd::Resp;
+use protocol::cmd::Stat;
+use protocol::cmd::StatsInstr;
+
+use super::CacheHandle;
+use super::Connections;
+use super::DriverCtl;
+use super::DriverCtlSender;
+use super::MetricsHistograms;
+use super::MetricsSummary;
+
+
+// Reads from the stream until the deadline (unixtime) has passed, however
+// slowly the bytes come in
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: f64,
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline - time_now();
+        if left <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      "request took too long"));
+        }
+
+        // A timeout of zero is not allowed
+        let timeout = convert_secs_to_duration(left.max(0.001));
+        try!(self.stream.set_read_timeout(Some(timeout)));
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+
+// Serves the admin endpoints over http, each connection on a thread of its
+// own so that a slow client doesn't hold up the others:
+//
+// GET /health     we're up
+// GET /ready      the driver is executing commands
+// GET /stats      stats, settings, connected clients and the latest summary
+//                 of the metrics, as json
+// GET /metrics    stats and timings for Prometheus to scrape
+// POST /flush     flush_all (only with --admin-writes)
+// POST /snapshot  write a snapshot (only with --admin-writes)
+pub struct AdminTask {
+    tcp_listener: TcpListener,
+    handle: CacheHandle,
+    ctl_tx: DriverCtlSender,
+    connections: Connections,
+    settings: Arc<Mutex<MemcacheOptions>>,
+    summary: MetricsSummary,
+    histograms: MetricsHistograms,
+}
+
+impl AdminTask {
+    pub fn new(tcp_listener: TcpListener,
+               handle: CacheHandle,
+               ctl_tx: DriverCtlSender,
+               connections: Connections,
+               settings: Arc<Mutex<MemcacheOptions>>,
+               summary: MetricsSummary,
+               histograms: MetricsHistograms)
+               -> AdminTask {
+        AdminTask {
+            tcp_listener: tcp_listener,
+            handle: handle,
+            ctl_tx: ctl_tx,
+            connections: connections,
+            settings: settings,
+            summary: summary,
+            histograms: histograms,
+        }
+    }
+
+    // Serves requests until the server's connections are stopped
+    pub fn run(self) {
+        let task = Arc::new(self);
+
+        loop {
+            let stream = task.tcp_listener.accept().map(|(stream, _)| stream);
+
+            // Checked after accepting, see Server::shutdown
+            if task.connections.is_stopping() {
+                break;
+            }
+
+            match stream {
+                Ok(stream) => {
+                    let task = task.clone();
+                    thread::spawn(move || task.serve(stream));
+                }
+                Err(_) => {
+                    println!("Admin connection failed :(");
+                }
+            }
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) {
+        let resp = {
+            let deadline_reader = DeadlineReader {
+                stream: &stream,
+                deadline: time_now() + self.get_read_timeout(),
+            };
+            let mut reader = BufReader::new(deadline_reader);
+            match read_request(&mut reader) {
+                Ok(req) => self.route(&req),
+                Err(_) => Response::text(400, "bad request\n"),
+            }
+        };
+
+        let _ = resp.write_to(&mut &stream);
+    }
+
+    fn route(&self, req: &Request) -> Response {
+        match (req.method, req.path.as_str()) {
+            (Method::Get, "/health") => Response::text(200, "OK\n"),
+            (Method::Get, "/ready") => self.do_ready(),
+            (Method::Get, "/stats") => self.do_stats(),
+            (Method::Get, "/metrics") => self.do_metrics(),
+            (Method::Post, "/flush") => self.do_flush(),
+            (Method::Post, "/snapshot") => self.do_snapshot(),
+
+            (_, "/health") | (_, "/ready") | (_, "/stats") |
+            (_, "/metrics") | (_, "/flush") | (_, "/snapshot") => {
+                Response::text(405, "method not allowed\n")
+            }
+            _ => Response::text(404, "not found\n"),
+        }
+    }
+
+    fn do_ready(&self) -> Response {
+        let timeout = self.settings.lock().unwrap().get_admin_ready_timeout();
+        match self.handle.run_timeout(Cmd::Version, timeout) {
+            Resp::ServerError(msg) => {
+                Response::text(503, &format!("not ready: {}\n", msg))
+            }
+            _ => Response::text(200, "OK\n"),
+        }
+    }
+
+    fn do_stats(&self) -> Response {
+        let stats = match self.get_stats() {
+            Ok(stats) => stats,
+            Err(resp) => return resp,
+        };
+
+        let mut stat_fields = vec![];
+        for stat in stats {
+            let value = Json::from_text(&stat.value);
+            stat_fields.push((stat.key, value));
+        }
+
+        let now = time_now();
+        let mut connections = vec![];
+        for info in self.connections.list() {
+            let peer = info.peer_addr.map(|addr| addr.to_string());
+            connections.push(Json::Object(vec![
+                field("id", Json::Int(info.id.0 as i64)),
+                field("peer", Json::opt_str(peer)),
+                field("connected_secs", Json::Float(now - info.connected_at)),
+            ]));
+        }
+
+        let settings = get_settings(&self.settings.lock().unwrap());
+
+        Response::json(200,
+                       &Json::Object(vec![
+                           field("stats", Json::Object(stat_fields)),
+                           field("settings", settings),
+                           field("connections", Json::Array(connections)),
+                           field("timers", self.get_timers()),
+                       ]))
+    }
+
+    fn do_metrics(&self) -> Response {
+        let stats = match self.get_stats() {
+            Ok(stats) => stats,
+            Err(resp) => return resp,
+        };
+
+        let mut expo = Exposition::new();
+        expo.add_stats(&stats);
+        expo.add_timers(&self.histograms.lock().unwrap());
+
+        Response::new(200, CONTENT_TYPE, expo.as_str().to_string())
+    }
+
+    fn get_stats(&self) -> Result<Vec<Stat>, Response> {
+        let cmd = Cmd::Stats(StatsInstr::General);
+        match self.handle.run_timeout(cmd, self.get_driver_timeout()) {
+            Resp::Stats(stats) => Ok(stats),
+            resp => Err(server_error(&resp)),
+        }
+    }
+
+    // The latest summary of the metrics over each window, like the one
+    // that's printed
+    fn get_timers(&self) -> Json {
+        let summary = self.summary.lock().unwrap();
+        let mut windows = vec![];
+
+        for window in WINDOWS {
+            let agg_mets = match summary.get(window) {
+                Some(agg_mets) => get_summary_json(agg_mets),
+                None => Json::Object(vec![]),
+            };
+            windows.push((format!("{}s", window), agg_mets));
+        }
+
+        Json::Object(windows)
+    }
+
+    fn do_flush(&self) -> Response {
+        if !self.writes_enabled() {
+            return writes_disabled();
+        }
+
+        let cmd = Cmd::FlushAll(FlushAll::new(None, false));
+        match self.handle.run_timeout(cmd, self.get_driver_timeout()) {
+            Resp::Ok => Response::text(200, "OK\n"),
+            resp => server_error(&resp),
+        }
+    }
+
+    fn do_snapshot(&self) -> Response {
+        if !self.writes_enabled() {
+            return writes_disabled();
+        }
+
+        let path = self.settings.lock().unwrap().get_snapshot_path();
+        if path.is_none() {
+            return Response::text(400, "no snapshot path configured\n");
+        }
+
+        let (ack_tx, ack_rx) = mpsc::channel();
+        match self.ctl_tx.send(DriverCtl::Snapshot(ack_tx)) {
+            Ok(_) => (),
+            Err(_) => return Response::text(503, "server is not running\n"),
+        }
+
+        match ack_rx.recv() {
+            Ok(true) => Response::text(200, "OK\n"),
+            Ok(false) => Response::text(500, "failed to write snapshot\n"),
+            Err(_) => Response::text(503, "server is not running\n"),
+        }
+    }
+
+    fn writes_enabled(&self) -> bool {
+        self.settings.lock().unwrap().get_admin_writes_enabled()
+    }
+
+    // How long a client has to send its request, all of it
+    fn get_read_timeout(&self) -> f64 {
+        self.settings.lock().unwrap().get_admin_read_timeout()
+    }
+
+    // How long the driver has to answer a request (other than /ready)
+    fn get_driver_timeout(&self) -> f64 {
+        self.settings.lock().unwrap().get_admin_driver_timeout()
+    }
+}
+
+
+fn field(key: &str, value: Json) -> (String, Json) {
+    (key.to_string(), value)
+}
+
+fn server_error(resp: &Resp) -> Response {
+    match *resp {
+        // The driver is gone or didn't answer in time
+        Resp::ServerError(ref msg) => {
+            Response::text(503, &format!("not available: {}\n", msg))
+        }
+        _ => {
+            Response::text(500, &format!("unexpected response: {:?}\n", resp))
+        }
+    }
+}
+
+fn writes_disabled() -> Response {
+    Response::text(403, "writes are disabled, see --admin-writes\n")
+}
+
+// The settings the server runs with, named as in the config file
+fn get_settings(opts: &MemcacheOptions) -> Json {
+    let (host, port) = opts.get_bind_params();
+    let delimiter = opts.get_namespace_delimiter() as char;
+
+    let mut namespaces = vec![];
+    for (name, quota) in opts.get_namespaces() {
+        namespaces.push((name, Json::Int((quota >> 20) as i64)));
+    }
+
+    let snapshot_interval = match opts.get_snapshot_interval() {
+        Some(secs) => Json::Float(secs),
+        None => Json::Null,
+    };
+
+    Json::Object(vec![
+        field("host", Json::Str(host)),
+        field("port", Json::Int(port as i64)),
+        field("mem", Json::Int(opts.get_mem_limit() as i64)),
+        field("max_item_size",
+              Json::Int(opts.get_max_item_size_bytes() as i64)),
+        field("max_key_len", Json::Int(opts.get_max_key_len() as i64)),
+        field("compress_threshold",
+              Json::Int(opts.get_compress_threshold() as i64)),
+        field("namespaces", Json::Object(namespaces)),
+        field("namespace_delimiter", Json::Str(delimiter.to_string())),
+        field("crawler_batch", Json::Int(opts.get_crawler_batch() as i64)),
+        field("extstore_path", Json::opt_str(opts.get_extstore_path())),
+        field("snapshot_path", Json::opt_str(opts.get_snapshot_path())),
+        field("snapshot_interval", snapshot_interval),
+        field("log_path", Json::opt_str(opts.get_log_path())),
+        field("metrics", Json::Bool(opts.get_metrics_enabled())),
+        field("hotkeys", Json::Bool(opts.get_hot_keys_enabled())),
+        field("slowlog_threshold",
+              Json::Float(opts.get_slow_log_threshold())),
+        field("slowlog_max_len",
+              Json::Int(opts.get_slow_log_max_len() as i64)),
+        field("admin_writes", Json::Bool(opts.get_admin_writes_enabled())),
+        field("admin_read_timeout",
+              Json::Float(opts.get_admin_read_timeout())),
+        field("admin_driver_timeout",
+              Json::Float(opts.get_admin_driver_timeout())),
+        field("admin_ready_timeout",
+              Json::Float(opts.get_admin_ready_timeout())),
+        field("drain_timeout", Json::Float(opts.get_drain_timeout())),
+        field("idle_interval", Json::Float(opts.get_idle_interval())),
+        field("debugtime", Json::Bool(opts.get_debug_time_enabled())),
+    ])
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use platform::time::sleep_secs;
+use platform::time::time_now;
+
+use super::TransportId;
+
+
+// What we know about a client connection
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: TransportId,
+    pub peer_addr: Option<SocketAddr>,
+    pub connected_at: f64, // unixtime
+}
+
+
+struct ConnectionsInner {
+    streams: HashMap<TransportId, (TcpStream, ConnectionInfo)>,
+    stopping: bool,
+}
+
+
+// The client connections currently being served, shared between the
+// listener, the transports and the server. Lets a shutdown refuse new
+// connections and wind down the open ones.
+#[derive(Clone)]
+pub struct Connections {
+    inner: Arc<Mutex<ConnectionsInner>>,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        let inner = ConnectionsInner {
+            streams: HashMap::new(),
+            stopping: false,
+        };
+
+        Connections { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    // Returns false if we're stopping, the connection should be dropped then
+    pub fn add(&self, id: TransportId, stream: &TcpStream) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.stopping {
+            return false;
+        }
+
+        let info = ConnectionInfo {
+            id: id,
+            peer_addr: stream.peer_addr().ok(),
+            connected_at: time_now(),
+        };
+        match stream.try_clone() {
+            Ok(stream) => {
+                inner.streams.insert(id, (stream, info));
+            }
+            Err(_) => (),
+        }
+        true
+    }
+
+    pub fn remove(&self, id: TransportId) {
+        self.inner.lock().unwrap().streams.remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().streams.len()
+    }
+
+    // Oldest connection first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut infos = vec![];
+        for &(_, ref info) in inner.streams.values() {
+            infos.push(info.clone());
+        }
+        infos.sort_by_key(|info| info.id.0);
+        infos
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.inner.lock().unwrap().stopping
+    }
+
+    // Refuses new connections and stops reading from the open ones. A
+    // transport waiting for a command sees the client leave, one that's
+    // busy with a command still gets to send the response.
+    pub fn stop(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stopping = true;
+
+        for &(ref stream, _) in inner.streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    // Waits up to timeout secs for the open connections to close. Returns
+    // whether they did.
+    pub fn wait_closed(&self, timeout: f64) -> bool {
+        let deadline = time_now() + timeout;
+
+        while self.len() > 0 {
+            if time_now() > deadline {
+                return false;
+            }
+            sleep_secs(0.01);
+        }
+
+        true
+    }
+}
@@ -1,19 +1,45 @@
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
 
-use metrics::MetricsRecorder;
 use metrics::Timer;
+use metrics::new_recorder;
 use options::MemcacheOptions;
+use persistence::MutationLog;
+use persistence::SnapshotFile;
+use persistence::load_snapshot;
+use persistence::replay_log;
+use persistence::save_snapshot;
+use platform::time::convert_secs_to_duration;
+use platform::time::time_now;
 use protocol::Driver;
+use protocol::SlowLog;
 use storage::Cache;
+use storage::ExtStore;
 use tcp_transport::stats::TransportStats;
 
 use super::CmdReceiver;
+use super::DriverCtl;
+use super::DriverCtlReceiver;
 use super::MetricsSender;
 use super::TransportId;
 
 
 type StatsMap = HashMap<TransportId, TransportStats>;
 
+// Bytes of items written per step of a snapshot. Steps are taken in between
+// commands, so this bounds how long a client may have to wait.
+const SNAPSHOT_STEP: usize = 1024 * 1024;
+
+// While commands keep coming the crawler only runs once this many have been
+// executed, or this many seconds have passed since it last ran, so that it
+// takes a bounded share of the time. It always runs when the server is idle.
+const CRAWL_EVERY_CMDS: u64 = 64;
+const CRAWL_INTERVAL: f64 = 0.01;
+
 fn compute_stats_sums(map: &StatsMap) -> TransportStats {
     let mut total_stats = TransportStats::new();
 
@@ -28,60 +54,462 @@ fn compute_stats_sums(map: &StatsMap) -> TransportStats {
 
 pub struct DriverTask {
     cmd_rx: CmdReceiver,
+    ctl_rx: DriverCtlReceiver,
     met_tx: MetricsSender,
     options: MemcacheOptions,
+    slow_log: SlowLog, // shared with the transports
 }
 
 impl DriverTask {
     pub fn new(cmd_rx: CmdReceiver,
+               ctl_rx: DriverCtlReceiver,
                met_tx: MetricsSender,
-               options: MemcacheOptions)
+               options: MemcacheOptions,
+               slow_log: SlowLog)
                -> DriverTask {
         DriverTask {
             cmd_rx: cmd_rx,
+            ctl_rx: ctl_rx,
             met_tx: met_tx,
             options: options,
+            slow_log: slow_log,
+        }
+    }
+
+    fn open_extstore(&self, cache: &mut Cache) {
+        let path = match self.options.get_extstore_path() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let size = self.options.get_extstore_size_bytes();
+        match ExtStore::open(&path, size) {
+            Ok(extstore) => {
+                cache.with_extstore(extstore)
+                     .with_ext_item_min(self.options.get_extstore_item_min());
+            }
+            Err(err) => {
+                // We can do without it, values are just evicted instead
+                println!("Failed to open extstore {}: {:?}", path, err);
+            }
+        }
+    }
+
+    fn restore_snapshot(&self, cache: &mut Cache) {
+        let path = match self.options.get_snapshot_path() {
+            Some(path) => path,
+            None => return,
+        };
+
+        // No snapshot yet is not an error, eg. the first time we start up
+        if !Path::new(&path).exists() {
+            return;
+        }
+
+        match load_snapshot(cache, &path) {
+            Ok(cnt) => {
+                println!("Restored {} items from snapshot {}", cnt, path);
+            }
+            Err(err) => {
+                println!("Failed to restore snapshot {}: {:?}, \
+                    starting with an empty cache",
+                         path,
+                         err);
+            }
+        }
+    }
+
+    // Replays the mutation log on top of whatever the snapshot restored and
+    // opens it for appending.
+    fn restore_mutation_log(&self, cache: &mut Cache) -> Option<MutationLog> {
+        let path = match self.options.get_log_path() {
+            Some(path) => path,
+            None => return None,
+        };
+        let rotated_path = get_rotated_log_path(&path);
+
+        // A rotated log is left behind if we stopped before its compaction
+        // finished, and it predates the current log
+        let mut replayed = false;
+        for log_path in vec![&rotated_path, &path] {
+            if !Path::new(log_path).exists() {
+                continue;
+            }
+
+            match replay_log(cache, log_path) {
+                Ok(cnt) => {
+                    println!("Replayed {} mutations from log {}",
+                             cnt,
+                             log_path);
+                    replayed = true;
+                }
+                Err(err) => {
+                    // Refuse to start rather than overwrite a log we
+                    // don't understand
+                    println!("Failed to replay log {}: {:?}", log_path, err);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // Fold the replayed records into a snapshot so we start with a clean
+        // log. If that fails we keep appending to the old one.
+        let mut truncate = false;
+        if replayed {
+            let snapshot_path = self.options.get_snapshot_path().unwrap();
+            match save_snapshot(cache, &snapshot_path) {
+                Ok(_) => {
+                    let _ = fs::remove_file(&rotated_path);
+                    truncate = true;
+                }
+                Err(err) => {
+                    println!("Failed to write snapshot {}: {:?}",
+                             snapshot_path,
+                             err);
+                }
+            }
+        }
+
+        match MutationLog::open(&path,
+                                self.options.get_log_fsync_policy(),
+                                truncate) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                println!("Failed to open log {}: {:?}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Starts a snapshot that is written a step at a time in between
+    // commands, which also compacts the log: a fresh log is started and the
+    // old one is only needed until the snapshot is written. The snapshot may
+    // capture changes that are in the fresh log as well, replaying the fresh
+    // log on top of it leaves them as they are now.
+    fn start_snapshot(&self, driver: &mut Driver) -> Option<SnapshotFile> {
+        let snapshot_path = match self.options.get_snapshot_path() {
+            Some(path) => path,
+            None => return None,
+        };
+
+        let snapshot = match SnapshotFile::create(driver.get_cache(),
+                                                  &snapshot_path) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                println!("Failed to write snapshot {}: {:?}",
+                         snapshot_path,
+                         err);
+                return None;
+            }
+        };
+
+        let log = match driver.get_mutation_log_mut() {
+            Some(log) => log,
+            None => return Some(snapshot),
+        };
+
+        // A previous snapshot failed or was cut short, so the rotated log is
+        // still needed. We keep appending to the current log, it's replayed
+        // after the rotated one and on top of this snapshot.
+        let rotated_path = get_rotated_log_path(log.get_path());
+        if Path::new(&rotated_path).exists() {
+            return Some(snapshot);
+        }
+
+        match log.rotate(&rotated_path) {
+            Ok(_) => Some(snapshot),
+            Err(err) => {
+                println!("Failed to compact log {}: {:?}", log.get_path(), err);
+                None
+            }
+        }
+    }
+
+    // Takes the next step of a snapshot. Returns the snapshot if there are
+    // more steps to take, otherwise tells everyone waiting for it whether it
+    // was written.
+    fn step_snapshot(&self,
+                     driver: &Driver,
+                     mut snapshot: SnapshotFile,
+                     acks: &mut Vec<Sender<bool>>)
+                     -> Option<SnapshotFile> {
+        let rv = match snapshot.step(driver.get_cache(), SNAPSHOT_STEP) {
+            Ok(false) => return Some(snapshot),
+            Ok(true) => snapshot.finish(driver.get_cache()),
+            Err(err) => Err(err),
+        };
+
+        let written = match rv {
+            Ok(_) => {
+                // The rotated log is now captured in the snapshot
+                match self.options.get_log_path() {
+                    Some(log_path) => {
+                        let rotated_path = get_rotated_log_path(&log_path);
+                        let _ = fs::remove_file(&rotated_path);
+                    }
+                    None => (),
+                }
+                true
+            }
+            Err(err) => {
+                println!("Failed to write snapshot {}: {:?}",
+                         self.options.get_snapshot_path().unwrap(),
+                         err);
+                false
+            }
+        };
+
+        for ack_tx in acks.drain(..) {
+            let _ = ack_tx.send(written);
         }
+        None
     }
 
-    pub fn run(&self) {
-        let cache = Cache::new(self.options.get_mem_limit_bytes());
+    // Writes a snapshot in one go, which holds up every command until it's
+    // written. Only meant for when we're stopping. Returns whether a
+    // snapshot was written.
+    fn write_snapshot(&self, driver: &mut Driver) -> bool {
+        let path = match self.options.get_snapshot_path() {
+            Some(path) => path,
+            None => return false,
+        };
+
+        match save_snapshot(driver.get_cache(), &path) {
+            Ok(_) => {
+                // Everything in the log (and any rotated log left behind by a
+                // compaction that failed or was cut short) is in the
+                // snapshot now
+                match driver.get_mutation_log_mut() {
+                    Some(ref mut log) => {
+                        let rotated_path =
+                            get_rotated_log_path(log.get_path());
+                        let _ = fs::remove_file(&rotated_path);
+                    }
+                    None => (),
+                }
+                match driver.get_mutation_log_mut() {
+                    Some(ref mut log) if log.has_records() => {
+                        match log.reset() {
+                            Ok(_) => (),
+                            Err(err) => {
+                                println!("Failed to reset log {}: {:?}",
+                                         log.get_path(),
+                                         err);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                true
+            }
+            Err(err) => {
+                println!("Failed to write snapshot {}: {:?}", path, err);
+                false
+            }
+        }
+    }
+
+    // Applies the options that can change while we're running, the rest
+    // keep their current values until a restart
+    fn reload_options(&mut self,
+                      driver: &mut Driver,
+                      options: MemcacheOptions) {
+        let hot_keys_enabled = self.options.get_hot_keys_enabled();
+        let restart_flags = self.options.reload_from(&options);
+
+        driver.get_cache_mut()
+              .with_key_maxlen(self.options.get_max_key_len())
+              .with_value_maxlen(self.options.get_max_item_size_bytes())
+              .with_crawler_batch(self.options.get_crawler_batch())
+              .with_compress_threshold(self.options.get_compress_threshold());
+        driver.with_debug_time(self.options.get_debug_time_enabled());
+        driver.get_slow_log()
+              .configure(self.options.get_slow_log_threshold(),
+                         self.options.get_slow_log_max_len());
+
+        // Switching them on again would forget the keys tracked so far
+        if self.options.get_hot_keys_enabled() != hot_keys_enabled {
+            driver.with_hot_keys(self.options.get_hot_keys_enabled());
+        }
+
+        println!("Reloaded configuration");
+        for flag in restart_flags {
+            println!("Changing {} needs a restart, ignored", flag);
+        }
+    }
+
+    pub fn run(&mut self) {
+        let mut cache = Cache::new(self.options.get_mem_limit_bytes());
+        cache.with_key_maxlen(self.options.get_max_key_len())
+             .with_value_maxlen(self.options.get_max_item_size_bytes())
+             .with_crawler_batch(self.options.get_crawler_batch())
+             .with_compress_threshold(self.options.get_compress_threshold())
+             .with_namespace_delimiter(self.options.get_namespace_delimiter());
+        for (name, quota) in self.options.get_namespaces() {
+            cache.with_namespace(&name, quota);
+        }
+        self.open_extstore(&mut cache);
+        self.restore_snapshot(&mut cache);
+        let log = self.restore_mutation_log(&mut cache);
+
         let mut driver = Driver::new(cache);
+        driver.with_hot_keys(self.options.get_hot_keys_enabled())
+              .with_debug_time(self.options.get_debug_time_enabled())
+              .with_slow_log(self.slow_log.clone());
+        match log {
+            Some(log) => {
+                driver.with_mutation_log(log);
+            }
+            None => (),
+        }
 
         // Here we store stats per transport
         let mut transport_stats: StatsMap = HashMap::new();
 
         // For collecting server metrics
-        let mut rec = MetricsRecorder::new(self.met_tx.clone(),
-                                           self.options.get_metrics_enabled());
+        let mut rec = new_recorder(self.met_tx.clone(),
+                                   self.options.get_metrics_enabled());
+
+        let mut last_snapshot_at = time_now();
+        let mut last_crawl_at = time_now();
+        let mut cmds_since_crawl = 0;
+        let mut snapshot: Option<SnapshotFile> = None;
+        let mut snapshot_acks: Vec<Sender<bool>> = vec![];
 
         loop {
             // Time the whole loop
             rec.start_timer("DriverTask:loop");
 
-            // Receive command
-            let (id, resp_tx, cmd, stats) = {
-                let _t = Timer::new(&mut rec, "DriverTask:recv_cmd");
-                self.cmd_rx.recv().unwrap()
+            // Receive command - or time out so that the crawler still gets
+            // to run when the server is idle. A snapshot in progress makes
+            // use of the idle time instead.
+            let timeout = match snapshot {
+                Some(_) => 0.0,
+                None => self.options.get_idle_interval(),
+            };
+            let rv = {
+                let _t = Timer::new(&mut *rec, "DriverTask:recv_cmd");
+                self.cmd_rx.recv_timeout(convert_secs_to_duration(timeout))
             };
 
-            // Update our stats store
-            transport_stats.insert(id, stats);
+            let mut is_idle = false;
+            match rv {
+                Ok((id, resp_tx, cmd, stats)) => {
+                    // Update our stats store
+                    transport_stats.insert(id, stats);
+
+                    // Update the driver's view of all transport metrics
+                    let total_stats = compute_stats_sums(&transport_stats);
+                    driver.update_transport_stats(total_stats);
 
-            // Update the driver's view of all transport metrics
-            let total_stats = compute_stats_sums(&transport_stats);
-            driver.update_transport_stats(total_stats);
+                    // Execute the command
+                    let (resp, duration) = {
+                        let _t = Timer::new(&mut *rec, "DriverTask:exec_cmd");
+                        let exec_st = time_now();
+                        let resp = driver.run(cmd);
+                        (resp, time_now() - exec_st)
+                    };
+
+                    // Send response. Nobody may be waiting for it anymore (eg.
+                    // a handle that gave up), that's no reason to stop.
+                    {
+                        let _t = Timer::new(&mut *rec, "DriverTask:send_resp");
+                        let _ = resp_tx.send((resp, duration));
+                    }
+
+                    cmds_since_crawl += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => is_idle = true,
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Nobody can send us commands anymore, so there's no
+                    // point waiting for a Stop either
+                    rec.stop_timer("DriverTask:loop");
+                    break;
+                }
+            }
 
-            // Execute the command
-            let resp = {
-                let _t = Timer::new(&mut rec, "DriverTask:exec_cmd");
-                driver.run(cmd)
+            // Reclaim expired items in between commands
+            if is_idle || cmds_since_crawl >= CRAWL_EVERY_CMDS ||
+               time_now() - last_crawl_at >= CRAWL_INTERVAL {
+                let _t = Timer::new(&mut *rec, "DriverTask:crawl");
+                driver.crawl();
+                last_crawl_at = time_now();
+                cmds_since_crawl = 0;
+            }
+
+            // Carry on with a snapshot
+            match snapshot.take() {
+                Some(in_progress) => {
+                    let _t = Timer::new(&mut *rec, "DriverTask:snapshot");
+                    snapshot = self.step_snapshot(&driver,
+                                                  in_progress,
+                                                  &mut snapshot_acks);
+                }
+                None => (),
+            }
+
+            // Perform any fsync of the log that is due, and compact it if it
+            // has grown too big
+            let compact_bytes = self.options.get_log_compact_bytes();
+            let compact_log = match driver.get_mutation_log_mut() {
+                Some(log) => {
+                    match log.tick() {
+                        Ok(_) => (),
+                        Err(err) => {
+                            println!("Failed to sync log {}: {:?}",
+                                     log.get_path(),
+                                     err);
+                        }
+                    }
+                    log.get_size() > compact_bytes
+                }
+                None => false,
             };
+            if compact_log && snapshot.is_none() {
+                let _t = Timer::new(&mut *rec, "DriverTask:compact_log");
+                snapshot = self.start_snapshot(&mut driver);
+            }
 
-            // Send response
-            {
-                let _t = Timer::new(&mut rec, "DriverTask:send_resp");
-                resp_tx.send(resp).unwrap();
+            // Start a periodic snapshot if it's time (unless one is being
+            // written already)
+            match self.options.get_snapshot_interval() {
+                Some(interval) if last_snapshot_at + interval < time_now() &&
+                                  snapshot.is_none() => {
+                    let _t = Timer::new(&mut *rec, "DriverTask:snapshot");
+                    snapshot = self.start_snapshot(&mut driver);
+                    last_snapshot_at = time_now();
+                }
+                _ => (),
+            }
+
+            // Pick up new options, or stop if we've been asked to
+            match self.ctl_rx.try_recv() {
+                Ok(DriverCtl::Reload(options)) => {
+                    self.reload_options(&mut driver, options);
+                }
+                Ok(DriverCtl::Snapshot(ack_tx)) => {
+                    // A snapshot in progress may have gone past keys that
+                    // changed before we were asked, so start over. The
+                    // rotated log stays until the new one is written.
+                    snapshot_acks.push(ack_tx);
+                    drop(snapshot.take());
+
+                    let _t = Timer::new(&mut *rec, "DriverTask:snapshot");
+                    snapshot = self.start_snapshot(&mut driver);
+                    last_snapshot_at = time_now();
+
+                    // It didn't even get started
+                    if snapshot.is_none() {
+                        for ack_tx in snapshot_acks.drain(..) {
+                            let _ = ack_tx.send(false);
+                        }
+                    }
+                }
+                Ok(DriverCtl::Stop) => {
+                    rec.stop_timer("DriverTask:loop");
+                    break;
+                }
+                Err(_) => (),
             }
 
             // Stop timing the loop
@@ -90,5 +518,20 @@ impl DriverTask {
             // Now flush metrics outside the request path
             rec.flush_metrics();
         }
+
+        // A snapshot in progress is of no use anymore, the final snapshot
+        // captures everything its log holds
+        drop(snapshot);
+
+        // Persist the cache so the next run can start warm
+        let written = self.write_snapshot(&mut driver);
+        for ack_tx in snapshot_acks {
+            let _ = ack_tx.send(written);
+        }
     }
 }
+
+
+fn get_rotated_log_path(log_path: &str) -> String {
+    format!("{}.old", log_path)
+}
@@ -0,0 +1,82 @@
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+
+use platform::time::convert_secs_to_duration;
+use protocol::cmd::Cmd;
+use protocol::cmd::Resp;
+use tcp_transport::stats::TransportStats;
+
+use super::CmdSender;
+use super::RespReceiver;
+use super::RespSender;
+use super::TransportId;
+
+
+// Handles don't read or write any bytes, so they all report the same empty
+// transport stats under this id. Ids handed out to connections start at 1.
+const HANDLE_TRANSPORT_ID: TransportId = TransportId(0);
+
+
+/// Lets the host process execute commands on a running server's cache
+/// without going through a socket. Commands are queued up with those of the
+/// clients and executed by the driver one at a time, so every command sees
+/// the effect of the ones before it.
+///
+/// Clone the handle to use it from another thread.
+#[derive(Clone)]
+pub struct CacheHandle {
+    cmd_tx: CmdSender,
+}
+
+impl CacheHandle {
+    // Handles are only given out by Server::get_handle
+    pub(crate) fn new(cmd_tx: CmdSender) -> CacheHandle {
+        CacheHandle { cmd_tx: cmd_tx }
+    }
+
+    fn send(&self, cmd: Cmd) -> Option<RespReceiver> {
+        let (resp_tx, resp_rx): (RespSender, RespReceiver) = mpsc::channel();
+        let stats = TransportStats::new();
+
+        match self.cmd_tx.send((HANDLE_TRANSPORT_ID, resp_tx, cmd, stats)) {
+            Ok(_) => Some(resp_rx),
+            Err(_) => None,
+        }
+    }
+
+    /// Executes the command and waits for the response. Returns
+    /// `Resp::ServerError` if the driver has stopped.
+    pub fn run(&self, cmd: Cmd) -> Resp {
+        let resp_rx = match self.send(cmd) {
+            Some(resp_rx) => resp_rx,
+            None => return server_gone(),
+        };
+
+        match resp_rx.recv() {
+            Ok((resp, _)) => resp,
+            Err(_) => server_gone(),
+        }
+    }
+
+    /// Same as `run`, but gives up waiting for the response after timeout
+    /// secs. The command is still executed when the driver gets to it.
+    pub fn run_timeout(&self, cmd: Cmd, timeout: f64) -> Resp {
+        let resp_rx = match self.send(cmd) {
+            Some(resp_rx) => resp_rx,
+            None => return server_gone(),
+        };
+
+        match resp_rx.recv_timeout(convert_secs_to_duration(timeout)) {
+            Ok((resp, _)) => resp,
+            Err(RecvTimeoutError::Timeout) => {
+                Resp::ServerError("timed out".to_string())
+            }
+            Err(RecvTimeoutError::Disconnected) => server_gone(),
+        }
+    }
+}
+
+
+fn server_gone() -> Resp {
+    Resp::ServerError("server is not running".to_string())
+}
@@ -1,28 +1,44 @@
 use std::net::TcpListener;
-use std::sync::mpsc;
 use std::thread;
 
 use net2::TcpStreamExt;
 
-use common::conversions::string_to_str;
 use options::MemcacheOptions;
+use protocol::SlowLog;
 
-use super::DriverTask;
-use super::MetricsTask;
+use super::CmdSender;
+use super::Connections;
+use super::MetricsSender;
 use super::TransportId;
 use super::TransportTask;
 
 
 pub struct ListenerTask {
     cur_transport_id: TransportId,
+    tcp_listener: TcpListener,
+    cmd_tx: CmdSender,
+    met_tx: MetricsSender,
     options: MemcacheOptions,
+    connections: Connections,
+    slow_log: SlowLog,
 }
 
 impl ListenerTask {
-    pub fn new(options: MemcacheOptions) -> ListenerTask {
+    pub fn new(tcp_listener: TcpListener,
+               cmd_tx: CmdSender,
+               met_tx: MetricsSender,
+               options: MemcacheOptions,
+               connections: Connections,
+               slow_log: SlowLog)
+               -> ListenerTask {
         ListenerTask {
             cur_transport_id: TransportId(0),
+            tcp_listener: tcp_listener,
+            cmd_tx: cmd_tx,
+            met_tx: met_tx,
             options: options,
+            connections: connections,
+            slow_log: slow_log,
         }
     }
 
@@ -33,41 +49,35 @@ impl ListenerTask {
         TransportId(next_id)
     }
 
+    // Accepts clients until the connections are stopped. The listening
+    // socket is closed when we return.
     pub fn run(&mut self) {
-        // Initialize the metrics sink
-        let (met_tx, met_rx) = mpsc::channel();
-        let metrics = MetricsTask::new(met_rx);
+        loop {
+            let stream = self.tcp_listener.accept().map(|(stream, _)| stream);
 
-        thread::spawn(move || {
-            metrics.run();
-        });
-
-        // Initialize the driver
-        let (cmd_tx, cmd_rx) = mpsc::channel();
-        let driver = DriverTask::new(cmd_rx,
-                                     met_tx.clone(),
-                                     self.options.clone());
-
-        thread::spawn(move || {
-            driver.run();
-        });
-
-        // Start up a tcp server
-        let (host, port) = self.options.get_bind_params();
-        let tcp_listener = TcpListener::bind((string_to_str(&host), port))
-                               .unwrap();
-
-        for stream in tcp_listener.incoming() {
             match stream {
                 Ok(stream) => {
+                    // Refused if we're stopping (eg. the connection made to
+                    // wake us up so we notice)
+                    let id = self.next_transport_id();
+                    if !self.connections.add(id, &stream) {
+                        break;
+                    }
+
                     // Make sure we don't delay on sending
                     TcpStreamExt::set_nodelay(&stream, true).unwrap();
 
-                    let id = self.next_transport_id();
-                    let cmd_tx = cmd_tx.clone();
-                    let met_tx = met_tx.clone();
+                    let cmd_tx = self.cmd_tx.clone();
+                    let met_tx = self.met_tx.clone();
                     let opts = self.options.clone();
-                    let task = TransportTask::new(id, cmd_tx, met_tx, opts);
+                    let conns = self.connections.clone();
+                    let slow_log = self.slow_log.clone();
+                    let task = TransportTask::new(id,
+                                                  cmd_tx,
+                                                  met_tx,
+                                                  opts,
+                                                  conns,
+                                                  slow_log);
 
                     thread::spawn(move || {
                         task.run(stream);
@@ -78,7 +88,5 @@ impl ListenerTask {
                 }
             }
         }
-
-        drop(tcp_listener);
     }
 }
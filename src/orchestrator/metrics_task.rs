@@ -1,72 +1,147 @@
+use std::io;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+
+use metrics::Histogram;
 use metrics::Metric;
+use metrics::Second;
 use metrics::TimeSeries;
+use metrics::WINDOWS;
+use metrics::sinks::MetricsSink;
+use metrics::sinks::StdoutSink;
+use platform::time::convert_secs_to_duration;
 use platform::time::time_now;
 
+use super::MetricsHistograms;
 use super::MetricsReceiver;
+use super::MetricsSummary;
 
 
 pub struct MetricsTask {
     met_rx: MetricsReceiver,
+    dump_rx: Receiver<()>, // asks for a summary of the metrics so far
+    summary: MetricsSummary, // where we publish each summary we write
+    histograms: MetricsHistograms, // every timing since we started
+    sinks: Vec<Box<MetricsSink>>, // where the metrics go
 
     summary_interval: f64,
 }
 
 impl MetricsTask {
-    pub fn new(met_rx: MetricsReceiver) -> MetricsTask {
+    pub fn new(met_rx: MetricsReceiver,
+               dump_rx: Receiver<()>,
+               summary: MetricsSummary,
+               histograms: MetricsHistograms,
+               sinks: Vec<Box<MetricsSink>>)
+               -> MetricsTask {
         MetricsTask {
             met_rx: met_rx,
+            dump_rx: dump_rx,
+            summary: summary,
+            histograms: histograms,
+            sinks: sinks,
 
             summary_interval: 1.0,
         }
     }
 
-    pub fn run(&self) {
+    pub fn run(&mut self) {
         let mut ts = TimeSeries::new();
-        let mut last_summary_at = time_now();
+        let mut last_summary_at = time_now() as Second;
+        let timeout = convert_secs_to_duration(self.summary_interval);
 
         loop {
-            // Receive metrics
-            let metrics = self.met_rx.recv().unwrap();
-            for metric in metrics.metrics {
-                match metric {
-                    Metric::Timing(timing) => {
-                        ts.add_timing(&timing);
+            // Receive metrics - or time out so that the summaries don't have
+            // to wait for the next ones
+            match self.met_rx.recv_timeout(timeout) {
+                Ok(metrics) => {
+                    let mut histograms = self.histograms.lock().unwrap();
+
+                    for metric in metrics.metrics {
+                        match metric {
+                            Metric::Timing(timing) => {
+                                histograms.entry(timing.name.clone())
+                                          .or_insert_with(|| {
+                                              Histogram::for_latency()
+                                          })
+                                          .observe(timing.duration);
+                                ts.add_timing(&timing);
+
+                                for sink in self.sinks.iter_mut() {
+                                    let rv = sink.add_timing(&timing);
+                                    report_error(&**sink, rv);
+                                }
+                            }
+                        }
                     }
                 }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    // The rest of the server has stopped
+                    break;
+                }
+            };
+
+            // Has a summary been asked for? That one is always printed.
+            if self.dump_rx.try_recv().is_ok() {
+                self.print_summaries(&ts);
             }
 
-            // Is is time to print a summary?
-            if last_summary_at + self.summary_interval < time_now() {
-                self.print_summary(&ts);
-                ts.clear();
+            // Has another second gone by? Then write the windows that end
+            // with it (each is written whenever it has gone by).
+            let now = time_now() as Second;
+            if now > last_summary_at {
+                for window in WINDOWS {
+                    if now % window == 0 {
+                        self.write_summary(&ts, now, *window);
+                    }
+                }
 
-                last_summary_at = time_now();
+                last_summary_at = now;
             }
         }
     }
 
-    pub fn print_summary(&self, ts: &TimeSeries) {
-        let agg_mets = ts.aggregate_metrics();
-        let mut names: Vec<&String> = agg_mets.keys().collect();
-        names.sort();
+    pub fn write_summary(&mut self,
+                         ts: &TimeSeries,
+                         now: Second,
+                         window: Second) {
+        let agg_mets = ts.aggregate_metrics(now, window);
+        self.summary.lock().unwrap().insert(window, agg_mets.clone());
 
-        println!("== Metrics {}s snapshot at {} ==",
-                 self.summary_interval,
-                 time_now() as u64);
+        // Nothing to say (eg. when metrics are disabled)
+        if agg_mets.is_empty() {
+            return;
+        }
 
-        for name in names {
-            let agg = agg_mets.get(name).unwrap();
+        for sink in self.sinks.iter_mut() {
+            let rv = sink.write_summary(now as f64, window as f64, &agg_mets);
+            report_error(&**sink, rv);
+        }
+    }
+
+    pub fn print_summaries(&self, ts: &TimeSeries) {
+        let now = time_now() as Second;
+        let mut stdout = StdoutSink::new();
+
+        for window in WINDOWS {
+            let agg_mets = ts.aggregate_metrics(now, *window);
+            let rv = stdout.write_summary(now as f64,
+                                          *window as f64,
+                                          &agg_mets);
+            report_error(&stdout, rv);
+        }
+    }
+}
 
-            let avg = agg.avg.unwrap_or(-1.0) * 1000.0;
-            let p0 = agg.p0.unwrap_or(-1.0) * 1000.0;
-            let p99 = agg.p99.unwrap_or(-1.0) * 1000.0;
 
-            println!("{:30}  n: {:5}  p0: {:.3}ms  avg: {:.3}ms  p99: {:.3}ms",
-                     name,
-                     agg.n,
-                     p0,
-                     avg,
-                     p99);
+fn report_error(sink: &MetricsSink, rv: io::Result<()>) {
+    match rv {
+        Ok(_) => (),
+        Err(err) => {
+            println!("Failed to write metrics to {}: {:?}",
+                     sink.get_name(),
+                     err);
         }
     }
 }
@@ -1,20 +1,37 @@
 // Declare sub modules
+pub mod admin_task;
+pub mod connections;
 pub mod driver_task;
+pub mod handle;
 pub mod metrics_task;
 pub mod listener_task;
+pub mod server;
 pub mod transport_task;
 pub mod typedefs;
 
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
+
 
 // Export our public api
+pub use self::admin_task::AdminTask;
+pub use self::connections::Connections;
 pub use self::driver_task::DriverTask;
+pub use self::handle::CacheHandle;
 pub use self::listener_task::ListenerTask;
 pub use self::metrics_task::MetricsTask;
+pub use self::server::Server;
+pub use self::server::ServerBuilder;
 pub use self::transport_task::TransportTask;
 pub use self::typedefs::CmdReceiver;
 pub use self::typedefs::CmdSender;
+pub use self::typedefs::DriverCtl;
+pub use self::typedefs::DriverCtlReceiver;
+pub use self::typedefs::DriverCtlSender;
 pub use self::typedefs::MetricsReceiver;
 pub use self::typedefs::MetricsSender;
+pub use self::typedefs::MetricsHistograms;
+pub use self::typedefs::MetricsSummary;
 pub use self::typedefs::RespReceiver;
 pub use self::typedefs::RespSender;
 pub use self::typedefs::TransportId;
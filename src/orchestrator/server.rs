@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::thread::JoinHandle;
+
+use common::conversions::string_to_str;
+use metrics::sinks::open_sink;
+use options::MemcacheOptions;
+use platform::process::drop_privileges;
+use platform::time::time_now;
+use protocol::SlowLog;
+use protocol::cmd::Cmd;
+use protocol::cmd::Resp;
+use protocol::cmd::StatsInstr;
+
+use super::AdminTask;
+use super::CacheHandle;
+use super::Connections;
+use super::DriverCtl;
+use super::DriverCtlSender;
+use super::DriverTask;
+use super::ListenerTask;
+use super::MetricsTask;
+
+
+/// Configures a server and starts it inside the current process. Starts out
+/// with the same defaults as the emcache binary.
+///
+/// ```
+/// use emcache::ServerBuilder;
+///
+/// let server = ServerBuilder::new()
+///                  .with_host("127.0.0.1")
+///                  .with_port(0)
+///                  .with_mem_limit(16)
+///                  .start()
+///                  .unwrap();
+/// println!("listening on {}", server.get_local_addr());
+/// ```
+pub struct ServerBuilder {
+    options: MemcacheOptions,
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::from_options(MemcacheOptions::new())
+    }
+
+    pub fn from_options(options: MemcacheOptions) -> ServerBuilder {
+        ServerBuilder { options: options }
+    }
+
+    pub fn with_host(&mut self, host: &str) -> &mut ServerBuilder {
+        self.options.flag_host = Some(host.to_string());
+        self
+    }
+
+    /// Port 0 picks a free port, see `Server::get_local_addr`
+    pub fn with_port(&mut self, port: u16) -> &mut ServerBuilder {
+        self.options.flag_port = Some(port);
+        self
+    }
+
+    /// In megabytes
+    pub fn with_mem_limit(&mut self, mem: u64) -> &mut ServerBuilder {
+        self.options.flag_mem = Some(mem);
+        self
+    }
+
+    pub fn with_metrics(&mut self, enabled: bool) -> &mut ServerBuilder {
+        self.options.flag_metrics = enabled;
+        self
+    }
+
+    /// Serves the admin endpoints (stats, flush, settings) over HTTP on this
+    /// port, 0 picks a free one. See `Server::get_admin_addr`
+    pub fn with_admin_port(&mut self, port: u16) -> &mut ServerBuilder {
+        self.options.flag_admin_port = Some(port);
+        self
+    }
+
+    /// Lets clients shift the time of the server, see
+    /// `Driver::with_debug_time`
+    pub fn with_debug_time(&mut self, enabled: bool) -> &mut ServerBuilder {
+        self.options.flag_enable_debugtime = enabled;
+        self
+    }
+
+    /// For everything else the command line offers
+    pub fn get_options_mut(&mut self) -> &mut MemcacheOptions {
+        &mut self.options
+    }
+
+    /// Binds the socket and starts all the tasks of the server on their own
+    /// threads. The server keeps running until it's shut down or dropped.
+    ///
+    /// With a user set in the options we switch to that user right after
+    /// binding, so a privileged port can be used without running as root.
+    pub fn start(&self) -> io::Result<Server> {
+        let (host, port) = self.options.get_bind_params();
+        let tcp_listener = try!(TcpListener::bind((string_to_str(&host),
+                                                   port)));
+        let local_addr = try!(tcp_listener.local_addr());
+
+        let admin_listener = match self.options.get_admin_bind_params() {
+            Some((host, port)) => {
+                Some(try!(TcpListener::bind((string_to_str(&host), port))))
+            }
+            None => None,
+        };
+        let admin_addr = match admin_listener {
+            Some(ref listener) => Some(try!(listener.local_addr())),
+            None => None,
+        };
+
+        // Opened before dropping privileges too, like the sockets
+        let mut sinks = vec![];
+        if self.options.get_metrics_enabled() {
+            for spec in self.options.get_metrics_sinks() {
+                sinks.push(try!(open_sink(&spec)));
+            }
+        }
+
+        match self.options.get_user() {
+            Some(user) => try!(drop_privileges(&user)),
+            None => (),
+        }
+
+        // Initialize the metrics sink
+        let (met_tx, met_rx) = mpsc::channel();
+        let (dump_tx, dump_rx) = mpsc::channel();
+        let summary = Arc::new(Mutex::new(HashMap::new()));
+        let histograms = Arc::new(Mutex::new(HashMap::new()));
+        let mut metrics = MetricsTask::new(met_rx,
+                                           dump_rx,
+                                           summary.clone(),
+                                           histograms.clone(),
+                                           sinks);
+
+        thread::spawn(move || {
+            metrics.run();
+        });
+
+        // Initialize the driver
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (ctl_tx, ctl_rx) = mpsc::channel();
+        let slow_log = SlowLog::new(self.options.get_slow_log_threshold(),
+                                    self.options.get_slow_log_max_len());
+        let mut driver = DriverTask::new(cmd_rx,
+                                         ctl_rx,
+                                         met_tx.clone(),
+                                         self.options.clone(),
+                                         slow_log.clone());
+
+        let driver_thread = thread::spawn(move || {
+            driver.run();
+        });
+
+        // Start accepting clients
+        let handle = CacheHandle::new(cmd_tx.clone());
+        let connections = Connections::new();
+        let mut listener = ListenerTask::new(tcp_listener,
+                                             cmd_tx,
+                                             met_tx,
+                                             self.options.clone(),
+                                             connections.clone(),
+                                             slow_log);
+
+        let listener_thread = thread::spawn(move || {
+            listener.run();
+        });
+
+        // Start serving the admin endpoints
+        let settings = Arc::new(Mutex::new(self.options.clone()));
+        let admin_thread = match admin_listener {
+            Some(admin_listener) => {
+                let admin = AdminTask::new(admin_listener,
+                                           handle.clone(),
+                                           ctl_tx.clone(),
+                                           connections.clone(),
+                                           settings.clone(),
+                                           summary,
+                                           histograms);
+
+                Some(thread::spawn(move || {
+                    admin.run();
+                }))
+            }
+            None => None,
+        };
+
+        Ok(Server {
+            handle: handle,
+            local_addr: local_addr,
+            admin_addr: admin_addr,
+            connections: connections,
+            settings: settings,
+            ctl_tx: ctl_tx,
+            dump_tx: dump_tx,
+            listener_thread: Some(listener_thread),
+            driver_thread: Some(driver_thread),
+            admin_thread: admin_thread,
+        })
+    }
+}
+
+
+/// A server running inside the current process. Dropping it shuts it down,
+/// see `Server::shutdown`.
+pub struct Server {
+    handle: CacheHandle,
+    local_addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+    connections: Connections,
+    settings: Arc<Mutex<MemcacheOptions>>, // as shown by the admin task
+    ctl_tx: DriverCtlSender,
+    dump_tx: Sender<()>,
+    // Taken once the server is stopped
+    listener_thread: Option<JoinHandle<()>>,
+    driver_thread: Option<JoinHandle<()>>,
+    admin_thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// A handle for running commands on the cache of this server
+    pub fn get_handle(&self) -> CacheHandle {
+        self.handle.clone()
+    }
+
+    /// The address clients can connect to
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address of the admin endpoints, if enabled
+    pub fn get_admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Applies the options that can change while the server is running, see
+    /// `MemcacheOptions::reload_from`
+    pub fn reload(&self, options: MemcacheOptions) {
+        self.settings.lock().unwrap().reload_from(&options);
+        let _ = self.ctl_tx.send(DriverCtl::Reload(options));
+    }
+
+    /// Prints the stats and a summary of the metrics collected so far
+    pub fn dump_stats(&self) {
+        match self.handle.run(Cmd::Stats(StatsInstr::General)) {
+            Resp::Stats(stats) => {
+                println!("== Stats at {} ==", time_now() as u64);
+                for stat in stats {
+                    println!("{:30}  {}", stat.key, stat.value);
+                }
+            }
+            resp => println!("Failed to get stats: {:?}", resp),
+        }
+
+        let _ = self.dump_tx.send(());
+    }
+
+    /// Stops accepting clients, gives the connected ones a few seconds to
+    /// finish the command they're on, then stops the driver, which writes
+    /// the final snapshot if there is one to write. Blocks until it's done.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    /// Blocks for as long as the server is accepting clients
+    pub fn wait(mut self) {
+        match self.listener_thread.take() {
+            Some(listener_thread) => {
+                let _ = listener_thread.join();
+            }
+            None => (),
+        }
+    }
+
+    fn stop(&mut self) {
+        let driver_thread = match self.driver_thread.take() {
+            Some(driver_thread) => driver_thread,
+            None => return, // already stopped
+        };
+
+        self.connections.stop();
+
+        // The listeners only notice once they accept a connection
+        match self.listener_thread.take() {
+            Some(listener_thread) => {
+                let addr = get_connectable_addr(self.local_addr);
+                let _ = TcpStream::connect(addr);
+                let _ = listener_thread.join();
+            }
+            None => (),
+        }
+        match (self.admin_addr, self.admin_thread.take()) {
+            (Some(admin_addr), Some(admin_thread)) => {
+                let _ = TcpStream::connect(get_connectable_addr(admin_addr));
+                let _ = admin_thread.join();
+            }
+            _ => (),
+        }
+
+        // How long clients get to finish the command they're on
+        let timeout = self.settings.lock().unwrap().get_drain_timeout();
+        if !self.connections.wait_closed(timeout) {
+            println!("Gave up waiting for {} connections to close",
+                     self.connections.len());
+        }
+
+        let _ = self.ctl_tx.send(DriverCtl::Stop);
+        let _ = driver_thread.join();
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+// Bound to all interfaces we can still only connect to one of them
+fn get_connectable_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                            addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                            addr.port())
+        }
+        _ => addr,
+    }
+}
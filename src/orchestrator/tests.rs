@@ -0,0 +1,495 @@
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+
+use options::MemcacheOptions;
+use platform::time::sleep_secs;
+use platform::time::time_now;
+use protocol::SlowLog;
+use protocol::cmd::Cmd;
+use protocol::cmd::Get;
+use protocol::cmd::GetInstr;
+use protocol::cmd::Resp;
+use protocol::cmd::Set;
+use protocol::cmd::SetInstr;
+use protocol::cmd::SlowLogInstr;
+use testlib::tempfile::get_temp_path;
+
+use super::CacheHandle;
+use super::DriverCtl;
+use super::DriverCtlSender;
+use super::DriverTask;
+use super::ServerBuilder;
+
+
+// Makes a request and returns the whole response
+fn http_request(addr: SocketAddr, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path)
+        .unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+fn get_status_line(resp: &str) -> &str {
+    resp.lines().next().unwrap()
+}
+
+fn get_body(resp: &str) -> &str {
+    resp.splitn(2, "\r\n\r\n").nth(1).unwrap()
+}
+
+fn get_x(stream: &mut TcpStream) -> Vec<u8> {
+    stream.write_all(b"get x\r\n").unwrap();
+
+    let expected_len = b"VALUE x 0 1\r\na\r\nEND\r\n".len();
+    let mut buf = vec![0; expected_len];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+// Runs a driver on its own, without a listener or any clients, that writes
+// its snapshot to path
+fn start_driver(path: &str)
+                -> (CacheHandle, DriverCtlSender, JoinHandle<()>) {
+    let mut options = MemcacheOptions::new();
+    options.flag_snapshot_path = Some(path.to_string());
+    start_driver_with(options)
+}
+
+fn start_driver_with(options: MemcacheOptions)
+                     -> (CacheHandle, DriverCtlSender, JoinHandle<()>) {
+    let (cmd_tx, cmd_rx) = mpsc::channel();
+    let (ctl_tx, ctl_rx) = mpsc::channel();
+    let (met_tx, _) = mpsc::channel();
+    let slow_log = SlowLog::new(options.get_slow_log_threshold(),
+                                options.get_slow_log_max_len());
+    let mut driver = DriverTask::new(cmd_rx,
+                                     ctl_rx,
+                                     met_tx,
+                                     options,
+                                     slow_log);
+
+    let driver_thread = thread::spawn(move || {
+        driver.run();
+    });
+
+    (CacheHandle::new(cmd_tx), ctl_tx, driver_thread)
+}
+
+
+#[test]
+fn test_driver_stop_writes_snapshot() {
+    let path = get_temp_path("driver-stop.snapshot");
+    let (handle, ctl_tx, driver_thread) = start_driver(&path);
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // Stopping doesn't wait for everyone who can send commands to go away
+    ctl_tx.send(DriverCtl::Stop).unwrap();
+    driver_thread.join().unwrap();
+    assert!(Path::new(&path).exists());
+
+    // The next run starts out where this one stopped
+    let (handle, ctl_tx, driver_thread) = start_driver(&path);
+    let resp = handle.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+    assert_eq!(vec![b'a'], resp.get_first_value().unwrap().data.to_vec());
+
+    ctl_tx.send(DriverCtl::Stop).unwrap();
+    driver_thread.join().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_driver_periodic_snapshot() {
+    let log_path = get_temp_path("driver-periodic.log");
+    let rotated_path = format!("{}.old", log_path);
+    let mut options = MemcacheOptions::new();
+    options.flag_log_path = Some(log_path.clone());
+    options.flag_snapshot_interval = Some(1);
+    let path = options.get_snapshot_path().unwrap();
+    let (handle, ctl_tx, driver_thread) = start_driver_with(options);
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // The snapshot is written while commands keep being answered
+    let started_at = time_now();
+    let get = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    while !Path::new(&path).exists() {
+        assert!(time_now() - started_at < 10.0);
+        assert_eq!(1, handle.run(get.clone()).get_values().unwrap().len());
+        sleep_secs(0.1);
+    }
+
+    // The log it was started with is captured in it
+    handle.run(get.clone());
+    assert!(!Path::new(&rotated_path).exists());
+
+    ctl_tx.send(DriverCtl::Stop).unwrap();
+    driver_thread.join().unwrap();
+    fs::remove_file(&log_path).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_server_handle() {
+    let server = ServerBuilder::new().with_port(0).start().unwrap();
+    let handle = server.get_handle();
+
+    let set = Set::new(SetInstr::Set, "x", 15, 0, vec![1, 2, 3], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // Handles can be used from other threads
+    let handle_clone = handle.clone();
+    let reader = thread::spawn(move || {
+        handle_clone.run(Cmd::Get(Get::one(GetInstr::Get, "x")))
+    });
+    let resp = reader.join().unwrap();
+    assert_eq!(vec![1, 2, 3], resp.get_first_value().unwrap().data.to_vec());
+}
+
+#[test]
+fn test_server_handle_timeout() {
+    let server = ServerBuilder::new().with_port(0).start().unwrap();
+    let handle = server.get_handle();
+
+    // Give up before the driver can reply (at least some of the time), so
+    // it finds nobody to send the response to
+    for _ in 0..10 {
+        handle.run_timeout(Cmd::Version, 0.0);
+    }
+
+    // The driver carries on regardless
+    match handle.run(Cmd::Version) {
+        Resp::Version(_) => (),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+}
+
+#[test]
+fn test_server_stops_on_drop() {
+    let server = ServerBuilder::new().with_port(0).start().unwrap();
+    let handle = server.get_handle();
+    let addr = server.get_local_addr();
+    drop(server);
+
+    // Nothing is left listening or executing commands
+    assert!(TcpStream::connect(addr).is_err());
+    match handle.run(Cmd::Version) {
+        Resp::ServerError(_) => (),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+}
+
+#[test]
+fn test_server_accepts_clients() {
+    let server = ServerBuilder::new().with_port(0).start().unwrap();
+    let handle = server.get_handle();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // Clients see what was stored through the handle
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    stream.write_all(b"get x\r\n").unwrap();
+
+    let expected = b"VALUE x 0 1\r\na\r\nEND\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(expected.to_vec(), buf);
+}
+
+#[test]
+fn test_server_use_namespace() {
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).get_options_mut().flag_namespaces =
+        Some("a:1".to_string());
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    stream.write_all(b"use_namespace a\r\nset x 0 0 1 \r\na\r\n").unwrap();
+
+    let expected = b"OK\r\nSTORED\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(expected.to_vec(), buf);
+
+    // The client sees the key without the namespace, everyone else sees it
+    // in the namespace
+    assert_eq!(b"VALUE x 0 1\r\na\r\nEND\r\n".to_vec(), get_x(&mut stream));
+    let resp = handle.run(Cmd::Get(Get::one(GetInstr::Get, "a:x")));
+    assert_eq!(vec![b'a'], resp.get_first_value().unwrap().data.to_vec());
+    let resp = handle.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+    assert_eq!(Resp::Values(vec![]), resp);
+}
+
+#[test]
+fn test_server_shutdown() {
+    let path = get_temp_path("server-shutdown.snapshot");
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).get_options_mut().flag_snapshot_path =
+        Some(path.clone());
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+    let addr = server.get_local_addr();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // A client that's connected but idle
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(b"VALUE x 0 1\r\na\r\nEND\r\n".to_vec(), get_x(&mut stream));
+
+    server.shutdown();
+
+    // The client was disconnected without a word
+    let mut buf = vec![];
+    assert_eq!(0, stream.read_to_end(&mut buf).unwrap());
+
+    // No more clients or commands are taken on
+    assert!(TcpStream::connect(addr).is_err());
+    match handle.run(Cmd::Version) {
+        Resp::ServerError(_) => (),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+
+    // The cache was persisted
+    assert!(Path::new(&path).exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_server_slow_log() {
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).get_options_mut().flag_slowlog_threshold_us =
+        Some(0); // every command is slow
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // Only commands from clients are timed from start to finish
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    assert_eq!(b"VALUE x 0 1\r\na\r\nEND\r\n".to_vec(), get_x(&mut stream));
+
+    // The command is logged once the response is written
+    let cmd = Cmd::SlowLog(SlowLogInstr::Get(None));
+    let mut stats = vec![];
+    for _ in 0..100 {
+        stats = handle.run(cmd.clone()).get_stats().unwrap().clone();
+        if !stats.is_empty() {
+            break;
+        }
+        sleep_secs(0.01);
+    }
+
+    let get = |key: &str| {
+        stats.iter().find(|stat| stat.key == key).unwrap().value.clone()
+    };
+    assert_eq!("get", get("slowlog:1:cmd"));
+    assert_eq!("x", get("slowlog:1:keys"));
+    assert_eq!("1", get("slowlog:1:conn"));
+    assert_eq!("1", get("slowlog:1:payload"));
+
+    assert_eq!(Resp::Ok, handle.run(Cmd::SlowLog(SlowLogInstr::Reset)));
+    assert_eq!(Resp::Stats(vec![]), handle.run(cmd));
+}
+
+#[test]
+fn test_server_reload() {
+    let server = ServerBuilder::new().with_port(0).start().unwrap();
+    let handle = server.get_handle();
+
+    let mut options = MemcacheOptions::new();
+    options.flag_max_key_len = Some(1);
+    options.flag_port = Some(1); // needs a restart, ignored
+    server.reload(options);
+
+    // The driver picks up new options after the command it's on
+    handle.run(Cmd::Version);
+
+    let set = Set::new(SetInstr::Set, "xx", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::ClientError("bad command line format".to_string()),
+               handle.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+}
+
+#[test]
+fn test_server_admin() {
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).with_admin_port(0);
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+    let admin_addr = server.get_admin_addr().unwrap();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    get_x(&mut stream);
+
+    let resp = http_request(admin_addr, "GET", "/health");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    assert_eq!("OK\n", get_body(&resp));
+
+    let resp = http_request(admin_addr, "GET", "/ready");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+
+    // Stats, settings and our client
+    let resp = http_request(admin_addr, "GET", "/stats");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    assert!(resp.contains("Content-Type: application/json"));
+    let body = get_body(&resp);
+    assert!(body.starts_with("{\"stats\":{\"pid\":"));
+    assert!(body.contains("\"curr_items\":1,"));
+    assert!(body.contains("\"version\":\"emcache "));
+    assert!(body.contains("\"settings\":{\"host\":\"127.0.0.1\","));
+    assert!(body.contains("\"admin_writes\":false,"));
+    let client = format!("\"connections\":[{{\"id\":1,\"peer\":\"{}\",",
+                         stream.local_addr().unwrap());
+    assert!(body.contains(&client));
+    assert!(body.ends_with(",\"timers\":{\"1s\":{},\"10s\":{},\
+                            \"60s\":{}}}\n"));
+
+    // The same stats for Prometheus
+    let resp = http_request(admin_addr, "GET", "/metrics");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    assert!(resp.contains("Content-Type: text/plain; version=0.0.4"));
+    let body = get_body(&resp);
+    assert!(body.contains("# TYPE emcache_curr_items gauge\n\
+                           emcache_curr_items 1\n"));
+    assert!(body.contains("# TYPE emcache_cmd_set_total counter\n\
+                           emcache_cmd_set_total 1\n"));
+    assert!(body.contains("# TYPE emcache_timer_seconds histogram\n"));
+
+    // Writes are disabled
+    let resp = http_request(admin_addr, "POST", "/flush");
+    assert_eq!("HTTP/1.1 403 Forbidden", get_status_line(&resp));
+    let resp = http_request(admin_addr, "POST", "/snapshot");
+    assert_eq!("HTTP/1.1 403 Forbidden", get_status_line(&resp));
+
+    let resp = http_request(admin_addr, "GET", "/flush");
+    assert_eq!("HTTP/1.1 405 Method Not Allowed", get_status_line(&resp));
+    let resp = http_request(admin_addr, "GET", "/nope");
+    assert_eq!("HTTP/1.1 404 Not Found", get_status_line(&resp));
+
+    server.shutdown();
+    assert!(TcpStream::connect(admin_addr).is_err());
+}
+
+#[test]
+fn test_server_admin_slow_client() {
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).with_admin_port(0);
+    let server = builder.start().unwrap();
+    let admin_addr = server.get_admin_addr().unwrap();
+
+    // A client that never finishes its request...
+    let mut slow = TcpStream::connect(admin_addr).unwrap();
+    slow.write_all(b"GET /health HTTP/1.1\r\n").unwrap();
+
+    // ...doesn't hold up anyone else
+    let started_at = time_now();
+    let resp = http_request(admin_addr, "GET", "/health");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    assert!(time_now() - started_at < 1.0);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_server_admin_writes() {
+    let path = get_temp_path("server-admin.snapshot");
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).with_admin_port(0);
+    builder.get_options_mut().flag_admin_writes = true;
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+    let admin_addr = server.get_admin_addr().unwrap();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+
+    // Nowhere to write a snapshot to
+    let resp = http_request(admin_addr, "POST", "/snapshot");
+    assert_eq!("HTTP/1.1 400 Bad Request", get_status_line(&resp));
+
+    let resp = http_request(admin_addr, "POST", "/flush");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    let resp = handle.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+    assert_eq!(Resp::Values(vec![]), resp);
+
+    // Writes can be switched off again
+    let mut options = MemcacheOptions::new();
+    options.flag_snapshot_path = Some(path.clone()); // needs a restart
+    server.reload(options);
+    let resp = http_request(admin_addr, "POST", "/flush");
+    assert_eq!("HTTP/1.1 403 Forbidden", get_status_line(&resp));
+
+    server.shutdown();
+}
+
+#[test]
+fn test_server_admin_snapshot() {
+    let path = get_temp_path("server-admin-snapshot.snapshot");
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).with_admin_port(0);
+    builder.get_options_mut().flag_admin_writes = true;
+    builder.get_options_mut().flag_snapshot_path = Some(path.clone());
+    let server = builder.start().unwrap();
+    let admin_addr = server.get_admin_addr().unwrap();
+
+    let resp = http_request(admin_addr, "POST", "/snapshot");
+    assert_eq!("HTTP/1.1 200 OK", get_status_line(&resp));
+    assert!(Path::new(&path).exists());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_server_admin_metrics_timers() {
+    let mut builder = ServerBuilder::new();
+    builder.with_port(0).with_admin_port(0);
+    builder.get_options_mut().flag_metrics = true;
+    let server = builder.start().unwrap();
+    let handle = server.get_handle();
+    let admin_addr = server.get_admin_addr().unwrap();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'], false);
+    assert_eq!(Resp::Stored, handle.run(Cmd::Set(set)));
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    get_x(&mut stream);
+
+    // The timings reach the metrics task in the background
+    let bucket = "emcache_timer_seconds_bucket{timer=\"DriverTask:exec_cmd\",\
+                  le=\"+Inf\"}";
+    let mut body = String::new();
+    for _ in 0..50 {
+        let resp = http_request(admin_addr, "GET", "/metrics");
+        body = get_body(&resp).to_string();
+        if body.contains(bucket) {
+            break;
+        }
+        sleep_secs(0.1);
+    }
+
+    assert!(body.contains(bucket));
+    assert!(body.contains("emcache_timer_seconds_count{timer=\
+                           \"TransportTask:read_cmd\"}"));
+
+    server.shutdown();
+}
@@ -1,14 +1,20 @@
 use std::net::TcpStream;
 use std::sync::mpsc;
 
-use metrics::MetricsRecorder;
 use metrics::Timer;
+use metrics::new_recorder;
 use options::MemcacheOptions;
+use platform::time::time_now;
+use protocol::SlowLog;
+use protocol::SlowLogDraft;
 use protocol::cmd::Cmd;
 use protocol::cmd::Resp;
+use protocol::cmd_stats::Outcome;
 use tcp_transport::TcpTransport;
+use tcp_transport::TcpTransportError;
 
 use super::CmdSender;
+use super::Connections;
 use super::MetricsSender;
 use super::RespReceiver;
 use super::RespSender;
@@ -20,38 +26,74 @@ pub struct TransportTask {
     cmd_tx: CmdSender,
     met_tx: MetricsSender,
     options: MemcacheOptions,
+    connections: Connections,
+    slow_log: SlowLog,
 }
 
 impl TransportTask {
     pub fn new(id: TransportId,
                cmd_tx: CmdSender,
                met_tx: MetricsSender,
-               options: MemcacheOptions)
+               options: MemcacheOptions,
+               connections: Connections,
+               slow_log: SlowLog)
                -> TransportTask {
         TransportTask {
             id: id,
             cmd_tx: cmd_tx,
             met_tx: met_tx,
             options: options,
+            connections: connections,
+            slow_log: slow_log,
         }
     }
 
     pub fn run(&self, stream: TcpStream) {
-        let mut rec = MetricsRecorder::new(self.met_tx.clone(),
-                                           self.options.get_metrics_enabled());
+        let mut rec = new_recorder(self.met_tx.clone(),
+                                   self.options.get_metrics_enabled());
 
         let mut transport = TcpTransport::new(stream);
+        transport.with_max_item_size(self.options.get_max_item_size_bytes());
         let (resp_tx, resp_rx): (RespSender, RespReceiver) = mpsc::channel();
 
+        // The namespace the client bound the connection to, if any
+        let delimiter = self.options.get_namespace_delimiter();
+        let mut namespace: Option<String> = None;
+
+        // Timer names are only made up when they're recorded
+        let metrics_enabled = self.options.get_metrics_enabled();
+
+        // Reused for every command, see SlowLogDraft
+        let mut slow_draft = SlowLogDraft::new();
+
         loop {
             // Time the whole loop
             rec.start_timer("TransportTask:loop");
 
             // println!("Ready to read command...");
-            let rv = {
-                let _t = Timer::new(&mut rec, "TransportTask:read_cmd");
-                transport.read_cmd()
+            let (read_st, rv) = {
+                let _t = Timer::new(&mut *rec, "TransportTask:read_cmd");
+                // The time spent waiting for the client doesn't count
+                // towards the command (read_cmd reports any error)
+                let _ = transport.wait_for_input();
+                let read_st = time_now();
+                (read_st, transport.read_cmd())
             };
+            let read_end = time_now();
+
+            // We're shutting down and have stopped reading from the client
+            if !rv.is_ok() && self.connections.is_stopping() {
+                break;
+            }
+
+            // The client sent more data than any item can hold, which has
+            // been skipped. That's no reason to drop the connection.
+            if rv == Err(TcpTransportError::ValueTooLong) {
+                let msg = "object too large for cache".to_string();
+                let _ = transport.write_resp(&Resp::ServerError(msg));
+                rec.stop_timer("TransportTask:loop");
+                continue;
+            }
 
             // If we couldn't parse the command return an error
             if !rv.is_ok() {
@@ -62,7 +104,7 @@ impl TransportTask {
                 break; // Here we just drop the connection
             }
 
-            let cmd = rv.unwrap();
+            let mut cmd = rv.unwrap();
 
             // Special case commands handled directly by transport
             match cmd {
@@ -73,37 +115,108 @@ impl TransportTask {
                 _ => (),
             }
 
+            // The driver checks the namespace exists before we bind to it
+            let bind_to = match cmd {
+                Cmd::UseNamespace(ref name) => Some(name.clone()),
+                _ => None,
+            };
+            match namespace {
+                Some(ref name) => cmd.bind_namespace(name, delimiter),
+                None => (),
+            }
+
+            // Only what we need of the command in case it turns out slow
+            let slow_kept = self.slow_log.is_enabled();
+            if slow_kept {
+                slow_draft.keep(&cmd);
+            }
+
+            // Time each kind of command from sending it off to having
+            // written the response, eg. TransportTask:cmd:get
+            let cmd_timer = match metrics_enabled {
+                true => Some(format!("TransportTask:cmd:{}", cmd.get_name())),
+                false => None,
+            };
+            match cmd_timer {
+                Some(ref cmd_timer) => rec.start_timer(cmd_timer),
+                None => (),
+            }
+
             // Send the command to the driver
             let resp_tx_clone = resp_tx.clone();
             let stats = transport.get_stats_clone();
             {
-                let _t = Timer::new(&mut rec, "TransportTask:send_cmd");
+                let _t = Timer::new(&mut *rec, "TransportTask:send_cmd");
                 self.cmd_tx
                     .send((self.id, resp_tx_clone, cmd, stats))
                     .unwrap();
             }
 
             // Obtain a response
-            let resp = {
-                let _t = Timer::new(&mut rec, "TransportTask:recv_resp");
+            let (mut resp, exec) = {
+                let _t = Timer::new(&mut *rec, "TransportTask:recv_resp");
                 resp_rx.recv().unwrap()
             };
+            let recv_end = time_now();
+
+            match (bind_to, &resp) {
+                (Some(name), &Resp::Ok) => namespace = Some(name),
+                _ => (),
+            }
+            match namespace {
+                Some(ref name) => resp.unbind_namespace(name, delimiter),
+                None => (),
+            }
 
             // Return a response
             // println!("Returning response: {:?}", &resp);
             let rv = {
-                let _t = Timer::new(&mut rec, "TransportTask:write_resp");
+                let _t = Timer::new(&mut *rec, "TransportTask:write_resp");
                 transport.write_resp(&resp)
             };
             if !rv.is_ok() {
                 println!("Failed to write response :(");
             }
+            let write_end = time_now();
+
+            // Log the command if it was slow, with where the time went
+            if slow_kept && self.slow_log.is_slow(write_end - read_st) {
+                let mut entry = slow_draft.to_entry(self.id.0);
+                let payload = entry.payload + resp.get_payload_len();
+                entry.with_timings(read_st,
+                                   read_end - read_st,
+                                   recv_end - read_end - exec,
+                                   exec,
+                                   write_end - recv_end)
+                     .with_payload(payload);
+                self.slow_log.add(entry);
+            }
+
+            // The same time again under the outcome, so the summary counts
+            // eg. hits and misses separately: TransportTask:cmd:get:hit
+            match (cmd_timer, Outcome::of(&resp)) {
+                (Some(cmd_timer), Some(outcome)) => {
+                    let outcome_timer = format!("{}:{}",
+                                                cmd_timer,
+                                                outcome.as_str());
+                    rec.stop_timer_also(&cmd_timer, &outcome_timer);
+                }
+                (Some(cmd_timer), None) => rec.stop_timer(&cmd_timer),
+                (None, _) => (),
+            }
 
             // Stop timing the loop
             rec.stop_timer("TransportTask:loop");
 
             // Now flush metrics outside the request path
             rec.flush_metrics();
+
+            // Don't take on another command if we're shutting down
+            if self.connections.is_stopping() {
+                break;
+            }
         }
+
+        self.connections.remove(self.id);
     }
 }
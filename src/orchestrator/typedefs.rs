@@ -1,7 +1,15 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::Receiver;
 
+use metrics::Duration;
+use metrics::Histogram;
 use metrics::Metrics;
+use metrics::Second;
+use metrics::statistics::AggregatedMetric;
+use options::MemcacheOptions;
 use protocol::cmd::Cmd;
 use protocol::cmd::Resp;
 use tcp_transport::stats::TransportStats;
@@ -12,8 +20,9 @@ use tcp_transport::stats::TransportStats;
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct TransportId(pub u64);
 
-pub type RespSender = Sender<Resp>;
-pub type RespReceiver = Receiver<Resp>;
+// The response, with how long the driver took to execute the command
+pub type RespSender = Sender<(Resp, Duration)>;
+pub type RespReceiver = Receiver<(Resp, Duration)>;
 
 pub type CmdSender = Sender<(TransportId, RespSender, Cmd, TransportStats)>;
 pub type CmdReceiver = Receiver<(TransportId,
@@ -21,7 +30,24 @@ pub type CmdReceiver = Receiver<(TransportId,
                                  Cmd,
                                  TransportStats)>;
 
+// Driver control
+
+pub enum DriverCtl {
+    Reload(MemcacheOptions),
+    Snapshot(Sender<bool>), // replies whether the snapshot was written
+    Stop,
+}
+
+pub type DriverCtlSender = Sender<DriverCtl>;
+pub type DriverCtlReceiver = Receiver<DriverCtl>;
+
 // Metrics
 
 pub type MetricsSender = Sender<Metrics>;
 pub type MetricsReceiver = Receiver<Metrics>;
+
+// The latest summary of the metrics over each window, by its length
+pub type MetricsSummary = Arc<Mutex<HashMap<Second, Vec<AggregatedMetric>>>>;
+
+// The timings since we started, by name
+pub type MetricsHistograms = Arc<Mutex<HashMap<String, Histogram>>>;
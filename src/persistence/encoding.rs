@@ -0,0 +1,328 @@
+use std::io::Read;
+use std::io::Write;
+
+use storage::Chunks;
+use storage::Collection;
+use storage::ListEnd;
+use storage::Members;
+use storage::Tag;
+use storage::Value;
+
+use super::errors::PersistenceError;
+use super::typedefs::PersistenceResult;
+
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// What the data of a value is
+const ITEM_BYTES: u8 = 0;
+const ITEM_LIST: u8 = 1;
+const ITEM_SET: u8 = 2;
+const ITEM_HASH: u8 = 3;
+
+
+// A running FNV-1a hash, used to detect corrupted files. It's not meant to
+// withstand tampering, just bit rot and partial writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    state: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum { state: FNV_OFFSET_BASIS }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.state
+    }
+}
+
+
+// Writes primitives in little endian byte order and keeps a checksum of
+// everything written so far.
+pub struct Encoder<W: Write> {
+    writer: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W) -> Encoder<W> {
+        Encoder {
+            checksum: Checksum::new(),
+            writer: writer,
+        }
+    }
+
+    pub fn get_checksum(&self) -> u64 {
+        self.checksum.value()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> PersistenceResult<()> {
+        self.checksum.update(bytes);
+
+        match self.writer.write_all(bytes) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(PersistenceError::FileWriteError),
+        }
+    }
+
+    pub fn write_u16(&mut self, num: u16) -> PersistenceResult<()> {
+        self.write_uint(num as u64, 2)
+    }
+
+    pub fn write_u32(&mut self, num: u32) -> PersistenceResult<()> {
+        self.write_uint(num as u64, 4)
+    }
+
+    pub fn write_u64(&mut self, num: u64) -> PersistenceResult<()> {
+        self.write_uint(num, 8)
+    }
+
+    pub fn write_f64(&mut self, num: f64) -> PersistenceResult<()> {
+        self.write_u64(num.to_bits())
+    }
+
+    // A length prefixed blob
+    pub fn write_blob(&mut self, bytes: &[u8]) -> PersistenceResult<()> {
+        try!(self.write_u64(bytes.len() as u64));
+        self.write_bytes(bytes)
+    }
+
+    // A length prefixed blob, written a chunk at a time. Reads back just like
+    // a blob written in one go.
+    pub fn write_chunked_blob(&mut self,
+                              chunks: &Chunks)
+                              -> PersistenceResult<()> {
+        try!(self.write_u64(chunks.len() as u64));
+        for chunk in chunks.iter() {
+            try!(self.write_bytes(chunk));
+        }
+        Ok(())
+    }
+
+    // The data of a value: a kind byte followed by a blob, or by a count and
+    // the members of a collection (for a hash, the field and the value)
+    pub fn write_item(&mut self, value: &Value) -> PersistenceResult<()> {
+        let collection = match value.get_collection() {
+            Some(collection) => collection,
+            None => {
+                try!(self.write_bytes(&[ITEM_BYTES]));
+                return self.write_chunked_blob(&value.get_item());
+            }
+        };
+
+        match *collection.get_members() {
+            Members::List(ref list) => {
+                try!(self.write_bytes(&[ITEM_LIST]));
+                try!(self.write_u64(list.len() as u64));
+                for item in list {
+                    try!(self.write_blob(item));
+                }
+            }
+            Members::Set(ref set) => {
+                try!(self.write_bytes(&[ITEM_SET]));
+                try!(self.write_u64(set.len() as u64));
+                for item in set {
+                    try!(self.write_blob(item));
+                }
+            }
+            Members::Hash(ref hash) => {
+                try!(self.write_bytes(&[ITEM_HASH]));
+                try!(self.write_u64(hash.len() as u64));
+                for (field, item) in hash {
+                    try!(self.write_blob(field));
+                    try!(self.write_blob(item));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A count followed by the name and version of each tag
+    pub fn write_tags(&mut self, tags: &[Tag]) -> PersistenceResult<()> {
+        try!(self.write_u32(tags.len() as u32));
+        for tag in tags {
+            try!(self.write_blob(tag.name.as_bytes()));
+            try!(self.write_u64(tag.version));
+        }
+        Ok(())
+    }
+
+    fn write_uint(&mut self, num: u64, width: usize) -> PersistenceResult<()> {
+        let mut bytes = [0u8; 8];
+        for i in 0..width {
+            bytes[i] = (num >> (8 * i)) as u8;
+        }
+        self.write_bytes(&bytes[..width])
+    }
+}
+
+
+// Reads primitives written by an Encoder from a reader (eg. a buffer or a
+// file) and keeps a checksum of everything read so far. Running out of data
+// means it was truncated.
+pub struct Decoder<R: Read> {
+    reader: R,
+    position: u64, // bytes read so far
+    checksum: Checksum,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Decoder<R> {
+        Decoder {
+            reader: reader,
+            position: 0,
+            checksum: Checksum::new(),
+        }
+    }
+
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn get_checksum(&self) -> u64 {
+        self.checksum.value()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> PersistenceResult<Vec<u8>> {
+        // Don't trust the length to allocate a buffer, it might be garbage.
+        // The buffer only grows as far as there is data.
+        let mut bytes = vec![];
+        let rv = Read::by_ref(&mut self.reader)
+                     .take(len as u64)
+                     .read_to_end(&mut bytes);
+        match rv {
+            Ok(_) if bytes.len() == len => (),
+            Ok(_) => return Err(PersistenceError::Truncated),
+            Err(_) => return Err(PersistenceError::FileReadError),
+        }
+
+        self.checksum.update(&bytes);
+        self.position += len as u64;
+        Ok(bytes)
+    }
+
+    // Reads past len bytes, only taking them into the checksum
+    pub fn skip(&mut self, len: u64) -> PersistenceResult<()> {
+        let mut buf = [0u8; 65536];
+        let mut left = len;
+
+        while left > 0 {
+            let chunk_len = left.min(buf.len() as u64) as usize;
+            match self.reader.read_exact(&mut buf[..chunk_len]) {
+                Ok(_) => (),
+                Err(_) => return Err(PersistenceError::Truncated),
+            }
+
+            self.checksum.update(&buf[..chunk_len]);
+            self.position += chunk_len as u64;
+            left -= chunk_len as u64;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_u16(&mut self) -> PersistenceResult<u16> {
+        Ok(try!(self.read_uint(2)) as u16)
+    }
+
+    pub fn read_u32(&mut self) -> PersistenceResult<u32> {
+        Ok(try!(self.read_uint(4)) as u32)
+    }
+
+    pub fn read_u64(&mut self) -> PersistenceResult<u64> {
+        self.read_uint(8)
+    }
+
+    pub fn read_f64(&mut self) -> PersistenceResult<f64> {
+        Ok(f64::from_bits(try!(self.read_u64())))
+    }
+
+    pub fn read_blob(&mut self) -> PersistenceResult<Vec<u8>> {
+        let len = try!(self.read_u64());
+        self.read_bytes(len as usize)
+    }
+
+    pub fn read_string(&mut self) -> PersistenceResult<String> {
+        match String::from_utf8(try!(self.read_blob())) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(PersistenceError::InvalidFormat),
+        }
+    }
+
+    // Returns the blob, or the collection if the data is one
+    pub fn read_item(&mut self)
+                     -> PersistenceResult<(Vec<u8>, Option<Collection>)> {
+        let kind = try!(self.read_bytes(1))[0];
+        let mut collection = match kind {
+            ITEM_BYTES => return Ok((try!(self.read_blob()), None)),
+            ITEM_LIST => Collection::list(),
+            ITEM_SET => Collection::set(),
+            ITEM_HASH => Collection::hash(),
+            _ => return Err(PersistenceError::InvalidFormat),
+        };
+
+        let count = try!(self.read_u64());
+        for _ in 0..count {
+            let item = try!(self.read_blob());
+            let rv = match kind {
+                ITEM_LIST => {
+                    collection.push(ListEnd::Tail, item, None).map(|_| ())
+                }
+                ITEM_SET => collection.add(item).map(|_| ()),
+                _ => {
+                    let data = try!(self.read_blob());
+                    collection.set_field(item, data)
+                }
+            };
+            try!(rv.map_err(|_| PersistenceError::InvalidFormat));
+        }
+
+        Ok((vec![], Some(collection)))
+    }
+
+    pub fn read_tags(&mut self) -> PersistenceResult<Vec<Tag>> {
+        let count = try!(self.read_u32());
+
+        let mut tags = vec![];
+        for _ in 0..count {
+            let name = try!(self.read_string());
+            let version = try!(self.read_u64());
+            tags.push(Tag {
+                name: name,
+                version: version,
+            });
+        }
+        Ok(tags)
+    }
+
+    fn read_uint(&mut self, width: usize) -> PersistenceResult<u64> {
+        let bytes = try!(self.read_bytes(width));
+
+        let mut num = 0u64;
+        for i in 0..width {
+            num |= (bytes[i] as u64) << (8 * i);
+        }
+        Ok(num)
+    }
+}
+
+impl<'a> Decoder<&'a [u8]> {
+    // Reading from a buffer consumes it, so what is left of it is the rest
+    pub fn remaining(&self) -> usize {
+        self.reader.len()
+    }
+}
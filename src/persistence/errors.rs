@@ -0,0 +1,10 @@
+#[derive(Debug, PartialEq)]
+pub enum PersistenceError {
+    ChecksumMismatch,
+    FileOpenError,
+    FileReadError,
+    FileWriteError,
+    InvalidFormat,
+    Truncated,
+    UnsupportedVersion,
+}
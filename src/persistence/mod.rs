@@ -0,0 +1,25 @@
+// Declare sub modules
+pub mod encoding;
+pub mod errors;
+pub mod mutation_log;
+pub mod snapshot;
+pub mod typedefs;
+
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
+
+
+// Export our public api
+pub use self::errors::PersistenceError;
+pub use self::mutation_log::Change;
+pub use self::mutation_log::Edit;
+pub use self::mutation_log::FsyncPolicy;
+pub use self::mutation_log::Mutation;
+pub use self::mutation_log::MutationLog;
+pub use self::mutation_log::replay_log;
+pub use self::snapshot::SnapshotFile;
+pub use self::snapshot::load_snapshot;
+pub use self::snapshot::read_snapshot;
+pub use self::snapshot::save_snapshot;
+pub use self::snapshot::write_snapshot;
+pub use self::typedefs::PersistenceResult;
@@ -0,0 +1,744 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+
+use platform::time::time_now;
+use storage::Cache;
+use storage::Chunks;
+use storage::Key;
+use storage::ListEnd;
+use storage::Value;
+
+use super::encoding::Checksum;
+use super::encoding::Decoder;
+use super::encoding::Encoder;
+use super::errors::PersistenceError;
+use super::typedefs::PersistenceResult;
+
+
+// Log file layout (all numbers are little endian):
+//
+// magic      8 bytes   "EMCLOG\0\0"
+// version    u32
+// records    * { len: u32, payload: len bytes, checksum: u64 }
+//
+// Each payload starts with a tag byte followed by the fields of the
+// mutation. Records describe the state a command left an item in rather than
+// the command itself, so replaying them gives the same result no matter how
+// much time has passed. Small edits of a big value (append, touch, ...) are
+// recorded as the edit, along with the cas id of the value it was made to.
+pub const LOG_MAGIC: &'static [u8] = b"EMCLOG\0\0";
+pub const LOG_VERSION: u32 = 6;
+
+const HEADER_LEN: u64 = 8 + 4;
+
+const TAG_PUT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_FLUSH_ALL: u8 = 3;
+const TAG_FLUSH_NAMESPACE: u8 = 4;
+const TAG_INVALIDATE_TAG: u8 = 5;
+const TAG_DELETE_PREFIX: u8 = 6;
+const TAG_EDIT: u8 = 7;
+
+const CHANGE_APPEND: u8 = 1;
+const CHANGE_PREPEND: u8 = 2;
+const CHANGE_TOUCH: u8 = 3;
+const CHANGE_COUNTER: u8 = 4;
+const CHANGE_LIST_PUSH: u8 = 5;
+const CHANGE_LIST_POP: u8 = 6;
+const CHANGE_SET_ADD: u8 = 7;
+const CHANGE_SET_REMOVE: u8 = 8;
+const CHANGE_HASH_SET: u8 = 9;
+const CHANGE_HASH_DELETE: u8 = 10;
+
+const LIST_HEAD: u8 = 1;
+const LIST_TAIL: u8 = 2;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always, // fsync after every record
+    EverySec, // fsync at most once a second
+    Never, // leave it to the OS
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    Put(Key, Value), // the key now holds this value
+    Remove(Key), // the key is gone
+    FlushAll(f64), // items touched before this time are dead
+    FlushNamespace(String, f64), // ...but only those in this namespace
+    // The tag is now at this version, and these floors were raised by
+    // forgetting other tags to make room for it
+    InvalidateTag(String, u64, Vec<(usize, u64)>),
+    DeletePrefix(Vec<u8>), // all keys starting with this are gone
+    Edit(Key, Edit), // the value under the key was edited
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Append(Vec<u8>), // the data was added at the end
+    Prepend(Vec<u8>), // ...or at the front
+    Touch, // only the metadata changed
+    Counter(u64), // the value is now this number
+    ListPush(ListEnd, Vec<u8>, Option<usize>), // with the max_len given
+    ListPop(ListEnd),
+    SetAdd(Vec<u8>),
+    SetRemove(Vec<u8>),
+    HashSet(Vec<u8>, Vec<u8>), // field, item
+    HashDelete(Vec<u8>),
+}
+
+
+// An edit only applies to the value it was made to. Replayed on top of a
+// snapshot that has the edited value already (or a later one) it's skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub change: Change,
+    pub prev_cas_id: u64, // of the value the edit was made to
+    pub cas_id: u64, // of the edited value
+    pub flags: u16,
+    pub exptime: f64,
+    pub atime: f64,
+}
+
+impl Mutation {
+    pub fn apply(self, cache: &mut Cache) {
+        match self {
+            Mutation::Put(key, value) => {
+                // If the value can't be restored the old one is stale too
+                match cache.restore(key.clone(), value) {
+                    Ok(true) => (),
+                    _ => {
+                        let _ = cache.remove(&key);
+                    }
+                }
+            }
+            Mutation::Remove(key) => {
+                let _ = cache.remove(&key);
+            }
+            Mutation::FlushAll(exptime) => {
+                let _ = cache.flush_all(exptime);
+            }
+            Mutation::FlushNamespace(name, exptime) => {
+                // The namespace may no longer be configured
+                let _ = cache.flush_namespace(&name, exptime);
+            }
+            Mutation::InvalidateTag(tag, version, floors) => {
+                for (bucket, floor) in floors {
+                    cache.restore_tag_floor(bucket, floor);
+                }
+                cache.restore_tag_version(&tag, version);
+            }
+            Mutation::DeletePrefix(prefix) => {
+                cache.delete_prefix(&prefix);
+            }
+            Mutation::Edit(key, edit) => {
+                apply_edit(cache, key, edit);
+            }
+        }
+    }
+}
+
+fn apply_edit(cache: &mut Cache, key: Key, edit: Edit) {
+    let mut value = match cache.peek(&key) {
+        Some(value) => value.into_owned(),
+        None => return,
+    };
+
+    if *value.get_cas_id() != edit.prev_cas_id {
+        return;
+    }
+
+    let rv = match edit.change {
+        Change::Append(data) => {
+            value.append_item(Chunks::new(data));
+            Ok(())
+        }
+        Change::Prepend(data) => {
+            value.prepend_item(Chunks::new(data));
+            Ok(())
+        }
+        Change::Touch => Ok(()),
+        Change::Counter(num) => {
+            value.set_counter(num);
+            Ok(())
+        }
+        Change::ListPush(end, item, max_len) => {
+            value.update_collection(|list| list.push(end, item, max_len))
+                 .map(|_| ())
+        }
+        Change::ListPop(end) => {
+            value.update_collection(|list| list.pop(end)).map(|_| ())
+        }
+        Change::SetAdd(member) => {
+            value.update_collection(|set| set.add(member)).map(|_| ())
+        }
+        Change::SetRemove(member) => {
+            value.update_collection(|set| set.remove(&member)).map(|_| ())
+        }
+        Change::HashSet(field, item) => {
+            value.update_collection(|hash| hash.set_field(field, item))
+        }
+        Change::HashDelete(field) => {
+            value.update_collection(|hash| hash.delete_field(&field))
+                 .map(|_| ())
+        }
+    };
+
+    // The edit was made to this very value, so it can't fail unless the
+    // log is damaged. Either way the old value is stale.
+    if rv.is_err() {
+        let _ = cache.remove(&key);
+        return;
+    }
+
+    // A collection that was left empty is gone
+    let is_empty = match value.get_collection() {
+        Some(collection) => collection.is_empty(),
+        None => false,
+    };
+    if is_empty {
+        let _ = cache.remove(&key);
+        return;
+    }
+    value.set_flags(edit.flags);
+    value.set_exptime(edit.exptime);
+    value.touch(edit.atime);
+    value.restore_cas_id(edit.cas_id);
+
+    // If the value can't be restored the old one is stale too
+    match cache.restore(key.clone(), value) {
+        Ok(true) => (),
+        _ => {
+            let _ = cache.remove(&key);
+        }
+    }
+}
+
+
+fn encode_put(key: &Key, value: &Value) -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_PUT]));
+    try!(enc.write_blob(&key.item));
+    try!(enc.write_item(value));
+    try!(enc.write_u16(*value.get_flags()));
+    try!(enc.write_f64(*value.get_exptime()));
+    try!(enc.write_f64(*value.get_atime()));
+    try!(enc.write_u64(*value.get_cas_id()));
+    try!(enc.write_tags(value.get_tags()));
+    Ok(enc.into_inner())
+}
+
+fn encode_remove(key: &Key) -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_REMOVE]));
+    try!(enc.write_blob(&key.item));
+    Ok(enc.into_inner())
+}
+
+fn encode_flush_all(exptime: f64) -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_FLUSH_ALL]));
+    try!(enc.write_f64(exptime));
+    Ok(enc.into_inner())
+}
+
+fn encode_flush_namespace(name: &str,
+                          exptime: f64)
+                          -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_FLUSH_NAMESPACE]));
+    try!(enc.write_blob(name.as_bytes()));
+    try!(enc.write_f64(exptime));
+    Ok(enc.into_inner())
+}
+
+fn encode_invalidate_tag(tag: &str,
+                         version: u64,
+                         floors: &[(usize, u64)])
+                         -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_INVALIDATE_TAG]));
+    try!(enc.write_blob(tag.as_bytes()));
+    try!(enc.write_u64(version));
+    try!(enc.write_u32(floors.len() as u32));
+    for &(bucket, floor) in floors {
+        try!(enc.write_u32(bucket as u32));
+        try!(enc.write_u64(floor));
+    }
+    Ok(enc.into_inner())
+}
+
+fn encode_delete_prefix(prefix: &[u8]) -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_DELETE_PREFIX]));
+    try!(enc.write_blob(prefix));
+    Ok(enc.into_inner())
+}
+
+fn encode_edit(key: &Key, edit: &Edit) -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(&[TAG_EDIT]));
+    try!(enc.write_blob(&key.item));
+
+    match edit.change {
+        Change::Append(ref data) => {
+            try!(enc.write_bytes(&[CHANGE_APPEND]));
+            try!(enc.write_blob(data));
+        }
+        Change::Prepend(ref data) => {
+            try!(enc.write_bytes(&[CHANGE_PREPEND]));
+            try!(enc.write_blob(data));
+        }
+        Change::Touch => {
+            try!(enc.write_bytes(&[CHANGE_TOUCH]));
+        }
+        Change::Counter(num) => {
+            try!(enc.write_bytes(&[CHANGE_COUNTER]));
+            try!(enc.write_u64(num));
+        }
+        Change::ListPush(end, ref item, max_len) => {
+            try!(enc.write_bytes(&[CHANGE_LIST_PUSH, encode_list_end(end)]));
+            try!(enc.write_blob(item));
+            let max_len = match max_len {
+                Some(max_len) => max_len as u64,
+                None => u64::MAX,
+            };
+            try!(enc.write_u64(max_len));
+        }
+        Change::ListPop(end) => {
+            try!(enc.write_bytes(&[CHANGE_LIST_POP, encode_list_end(end)]));
+        }
+        Change::SetAdd(ref member) => {
+            try!(enc.write_bytes(&[CHANGE_SET_ADD]));
+            try!(enc.write_blob(member));
+        }
+        Change::SetRemove(ref member) => {
+            try!(enc.write_bytes(&[CHANGE_SET_REMOVE]));
+            try!(enc.write_blob(member));
+        }
+        Change::HashSet(ref field, ref item) => {
+            try!(enc.write_bytes(&[CHANGE_HASH_SET]));
+            try!(enc.write_blob(field));
+            try!(enc.write_blob(item));
+        }
+        Change::HashDelete(ref field) => {
+            try!(enc.write_bytes(&[CHANGE_HASH_DELETE]));
+            try!(enc.write_blob(field));
+        }
+    }
+
+    try!(enc.write_u64(edit.prev_cas_id));
+    try!(enc.write_u64(edit.cas_id));
+    try!(enc.write_u16(edit.flags));
+    try!(enc.write_f64(edit.exptime));
+    try!(enc.write_f64(edit.atime));
+    Ok(enc.into_inner())
+}
+
+fn encode_list_end(end: ListEnd) -> u8 {
+    match end {
+        ListEnd::Head => LIST_HEAD,
+        ListEnd::Tail => LIST_TAIL,
+    }
+}
+
+fn decode_list_end(dec: &mut Decoder<&[u8]>) -> PersistenceResult<ListEnd> {
+    match try!(dec.read_bytes(1))[0] {
+        LIST_HEAD => Ok(ListEnd::Head),
+        LIST_TAIL => Ok(ListEnd::Tail),
+        _ => Err(PersistenceError::InvalidFormat),
+    }
+}
+
+fn decode_payload(payload: &[u8]) -> PersistenceResult<Mutation> {
+    let mut dec = Decoder::new(payload);
+
+    let mutation = match try!(dec.read_bytes(1))[0] {
+        TAG_PUT => {
+            let key = Key::new(try!(dec.read_blob()));
+            let (item, collection) = try!(dec.read_item());
+            let flags = try!(dec.read_u16());
+            let exptime = try!(dec.read_f64());
+            let atime = try!(dec.read_f64());
+            let cas_id = try!(dec.read_u64());
+            let tags = try!(dec.read_tags());
+            let mut value = Value::from_parts(item,
+                                              flags,
+                                              exptime,
+                                              atime,
+                                              cas_id,
+                                              tags);
+            match collection {
+                Some(collection) => {
+                    value.with_collection(collection);
+                }
+                None => (),
+            }
+            Mutation::Put(key, value)
+        }
+        TAG_REMOVE => Mutation::Remove(Key::new(try!(dec.read_blob()))),
+        TAG_FLUSH_ALL => Mutation::FlushAll(try!(dec.read_f64())),
+        TAG_FLUSH_NAMESPACE => {
+            let name = try!(dec.read_string());
+            let exptime = try!(dec.read_f64());
+            Mutation::FlushNamespace(name, exptime)
+        }
+        TAG_INVALIDATE_TAG => {
+            let tag = try!(dec.read_string());
+            let version = try!(dec.read_u64());
+            let mut floors = vec![];
+            for _ in 0..try!(dec.read_u32()) {
+                let bucket = try!(dec.read_u32()) as usize;
+                floors.push((bucket, try!(dec.read_u64())));
+            }
+            Mutation::InvalidateTag(tag, version, floors)
+        }
+        TAG_DELETE_PREFIX => Mutation::DeletePrefix(try!(dec.read_blob())),
+        TAG_EDIT => {
+            let key = Key::new(try!(dec.read_blob()));
+            let change = match try!(dec.read_bytes(1))[0] {
+                CHANGE_APPEND => Change::Append(try!(dec.read_blob())),
+                CHANGE_PREPEND => Change::Prepend(try!(dec.read_blob())),
+                CHANGE_TOUCH => Change::Touch,
+                CHANGE_COUNTER => Change::Counter(try!(dec.read_u64())),
+                CHANGE_LIST_PUSH => {
+                    let end = try!(decode_list_end(&mut dec));
+                    let item = try!(dec.read_blob());
+                    let max_len = match try!(dec.read_u64()) {
+                        u64::MAX => None,
+                        max_len => Some(max_len as usize),
+                    };
+                    Change::ListPush(end, item, max_len)
+                }
+                CHANGE_LIST_POP => {
+                    Change::ListPop(try!(decode_list_end(&mut dec)))
+                }
+                CHANGE_SET_ADD => Change::SetAdd(try!(dec.read_blob())),
+                CHANGE_SET_REMOVE => Change::SetRemove(try!(dec.read_blob())),
+                CHANGE_HASH_SET => {
+                    let field = try!(dec.read_blob());
+                    Change::HashSet(field, try!(dec.read_blob()))
+                }
+                CHANGE_HASH_DELETE => {
+                    Change::HashDelete(try!(dec.read_blob()))
+                }
+                _ => return Err(PersistenceError::InvalidFormat),
+            };
+            let edit = Edit {
+                change: change,
+                prev_cas_id: try!(dec.read_u64()),
+                cas_id: try!(dec.read_u64()),
+                flags: try!(dec.read_u16()),
+                exptime: try!(dec.read_f64()),
+                atime: try!(dec.read_f64()),
+            };
+            Mutation::Edit(key, edit)
+        }
+        _ => return Err(PersistenceError::InvalidFormat),
+    };
+
+    if dec.remaining() > 0 {
+        return Err(PersistenceError::InvalidFormat);
+    }
+
+    Ok(mutation)
+}
+
+fn frame_record(payload: &[u8]) -> PersistenceResult<Vec<u8>> {
+    let mut checksum = Checksum::new();
+    checksum.update(payload);
+
+    let mut enc = Encoder::new(Vec::with_capacity(payload.len() + 12));
+    try!(enc.write_u32(payload.len() as u32));
+    try!(enc.write_bytes(payload));
+    try!(enc.write_u64(checksum.value()));
+    Ok(enc.into_inner())
+}
+
+fn log_header() -> PersistenceResult<Vec<u8>> {
+    let mut enc = Encoder::new(vec![]);
+    try!(enc.write_bytes(LOG_MAGIC));
+    try!(enc.write_u32(LOG_VERSION));
+    Ok(enc.into_inner())
+}
+
+
+// Decodes all the intact records in a log. A crash can leave a partially
+// written record at the end, so reading stops at the first record that is
+// truncated or fails its checksum. Returns the records and the length of the
+// log up to the last good record.
+pub fn read_log(bytes: &[u8]) -> PersistenceResult<(Vec<Mutation>, u64)> {
+    let mut dec = Decoder::new(bytes);
+
+    match dec.read_bytes(LOG_MAGIC.len()) {
+        Ok(ref magic) if magic == LOG_MAGIC => (),
+        Ok(_) => return Err(PersistenceError::InvalidFormat),
+        Err(err) => return Err(err),
+    }
+
+    if try!(dec.read_u32()) != LOG_VERSION {
+        return Err(PersistenceError::UnsupportedVersion);
+    }
+
+    let mut mutations = vec![];
+    let mut good_len = dec.get_position();
+
+    while dec.remaining() > 0 {
+        let payload = match dec.read_u32() {
+            Ok(len) => {
+                match dec.read_bytes(len as usize) {
+                    Ok(payload) => payload,
+                    Err(_) => break,
+                }
+            }
+            Err(_) => break,
+        };
+
+        let mut checksum = Checksum::new();
+        checksum.update(&payload);
+        match dec.read_u64() {
+            Ok(expected) if expected == checksum.value() => (),
+            _ => break,
+        }
+
+        match decode_payload(&payload) {
+            Ok(mutation) => mutations.push(mutation),
+            Err(_) => break,
+        }
+
+        good_len = dec.get_position();
+    }
+
+    Ok((mutations, good_len))
+}
+
+// Applies every intact record in the log at path to the cache. Anything after
+// the last good record is cut off, so that new records are appended to a
+// clean log. Returns the number of records applied.
+pub fn replay_log(cache: &mut Cache, path: &str) -> PersistenceResult<u64> {
+    let mut bytes = vec![];
+    {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Err(PersistenceError::FileOpenError),
+        };
+        match file.read_to_end(&mut bytes) {
+            Ok(_) => (),
+            Err(_) => return Err(PersistenceError::FileReadError),
+        }
+    }
+
+    let (mutations, good_len) = try!(read_log(&bytes));
+
+    if good_len < bytes.len() as u64 {
+        let file = match OpenOptions::new().write(true).open(path) {
+            Ok(file) => file,
+            Err(_) => return Err(PersistenceError::FileOpenError),
+        };
+        match file.set_len(good_len) {
+            Ok(_) => (),
+            Err(_) => return Err(PersistenceError::FileWriteError),
+        }
+    }
+
+    let cnt = mutations.len() as u64;
+    for mutation in mutations {
+        mutation.apply(cache);
+    }
+
+    Ok(cnt)
+}
+
+
+pub struct MutationLog {
+    file: File,
+    path: String,
+    policy: FsyncPolicy,
+    size: u64, // in bytes
+    last_sync_at: f64, // unixtime
+    dirty: bool, // written to since the last fsync
+}
+
+impl MutationLog {
+    // Opens the log for appending, creating it if necessary. Pass truncate
+    // to discard any existing records.
+    pub fn open(path: &str,
+                policy: FsyncPolicy,
+                truncate: bool)
+                -> PersistenceResult<MutationLog> {
+        let rv = OpenOptions::new()
+                     .create(true)
+                     .append(true)
+                     .open(path);
+        let file = match rv {
+            Ok(file) => file,
+            Err(_) => return Err(PersistenceError::FileOpenError),
+        };
+
+        if truncate {
+            match file.set_len(0) {
+                Ok(_) => (),
+                Err(_) => return Err(PersistenceError::FileWriteError),
+            }
+        }
+
+        let size = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => return Err(PersistenceError::FileReadError),
+        };
+
+        let mut log = MutationLog {
+            file: file,
+            path: path.to_string(),
+            policy: policy,
+            size: size,
+            last_sync_at: time_now(),
+            dirty: false,
+        };
+
+        // A fresh log needs a header
+        if log.size == 0 {
+            let header = try!(log_header());
+            try!(log.write(&header));
+            try!(log.sync());
+        }
+
+        Ok(log)
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    // Does the log contain any records?
+    pub fn has_records(&self) -> bool {
+        self.size > HEADER_LEN
+    }
+
+    pub fn append_put(&mut self,
+                      key: &Key,
+                      value: &Value)
+                      -> PersistenceResult<()> {
+        let payload = try!(encode_put(key, value));
+        self.append(&payload)
+    }
+
+    pub fn append_remove(&mut self, key: &Key) -> PersistenceResult<()> {
+        let payload = try!(encode_remove(key));
+        self.append(&payload)
+    }
+
+    pub fn append_flush_all(&mut self, exptime: f64) -> PersistenceResult<()> {
+        let payload = try!(encode_flush_all(exptime));
+        self.append(&payload)
+    }
+
+    pub fn append_flush_namespace(&mut self,
+                                  name: &str,
+                                  exptime: f64)
+                                  -> PersistenceResult<()> {
+        let payload = try!(encode_flush_namespace(name, exptime));
+        self.append(&payload)
+    }
+
+    pub fn append_invalidate_tag(&mut self,
+                                 tag: &str,
+                                 version: u64,
+                                 floors: &[(usize, u64)])
+                                 -> PersistenceResult<()> {
+        let payload = try!(encode_invalidate_tag(tag, version, floors));
+        self.append(&payload)
+    }
+
+    pub fn append_delete_prefix(&mut self,
+                                prefix: &[u8])
+                                -> PersistenceResult<()> {
+        let payload = try!(encode_delete_prefix(prefix));
+        self.append(&payload)
+    }
+
+    pub fn append_edit(&mut self,
+                       key: &Key,
+                       edit: &Edit)
+                       -> PersistenceResult<()> {
+        let payload = try!(encode_edit(key, edit));
+        self.append(&payload)
+    }
+
+    // Performs any fsync that is due. Meant to be called regularly so that
+    // with EverySec we don't sit on unsynced records while idle.
+    pub fn tick(&mut self) -> PersistenceResult<()> {
+        match self.policy {
+            FsyncPolicy::EverySec if self.dirty &&
+                                     self.last_sync_at + 1.0 < time_now() => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Starts a fresh log, moving the current one aside to rotated_path.
+    pub fn rotate(&mut self, rotated_path: &str) -> PersistenceResult<()> {
+        try!(self.sync());
+
+        match fs::rename(&self.path, rotated_path) {
+            Ok(_) => (),
+            Err(_) => return Err(PersistenceError::FileWriteError),
+        }
+
+        let log = try!(MutationLog::open(&self.path, self.policy, true));
+        *self = log;
+        Ok(())
+    }
+
+    // Discards all records, eg. once they have been captured in a snapshot
+    pub fn reset(&mut self) -> PersistenceResult<()> {
+        let log = try!(MutationLog::open(&self.path, self.policy, true));
+        *self = log;
+        Ok(())
+    }
+
+
+    fn append(&mut self, payload: &[u8]) -> PersistenceResult<()> {
+        let record = try!(frame_record(payload));
+        try!(self.write(&record));
+
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::EverySec => self.tick(),
+            FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> PersistenceResult<()> {
+        match self.file.write_all(bytes) {
+            Ok(_) => {
+                self.size += bytes.len() as u64;
+                self.dirty = true;
+                Ok(())
+            }
+            Err(_) => Err(PersistenceError::FileWriteError),
+        }
+    }
+
+    fn sync(&mut self) -> PersistenceResult<()> {
+        match self.file.sync_data() {
+            Ok(_) => {
+                self.dirty = false;
+                self.last_sync_at = time_now();
+                Ok(())
+            }
+            Err(_) => Err(PersistenceError::FileWriteError),
+        }
+    }
+}
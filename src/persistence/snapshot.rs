@@ -0,0 +1,400 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::usize;
+
+use storage::Cache;
+use storage::Key;
+use storage::Tag;
+use storage::Value;
+use storage::Walk;
+
+use super::encoding::Decoder;
+use super::encoding::Encoder;
+use super::errors::PersistenceError;
+use super::typedefs::PersistenceResult;
+
+
+// Snapshot file layout (all numbers are little endian):
+//
+// magic      8 bytes   "EMCSNAP\0"
+// version    u32
+// items      { 1u8, key: blob, item: item, flags: u16, exptime: f64,
+//              atime: f64, cas_id: u64,
+//              tags: u32 x { name: blob, version: u64 } } for every item
+// end        0u8
+// tags       u32 x { name: blob, version: u64 }   invalidated tags
+// floors     u32 x { bucket: u32, floor: u64 }   floors of forgotten tags
+// checksum   u64       FNV-1a of everything above
+//
+// An item is a kind byte followed by a blob, or by the members of a list, set
+// or hash (see Encoder::write_item).
+//
+// Items are stored in the order of their keys, since that's the order they
+// can be walked in while the cache keeps changing. They're restored in order
+// of their access times, which reproduces the LRU order of the cache.
+// Loading keeps only where each item starts and its access time, and seeks
+// back to the items to restore them.
+pub const SNAPSHOT_MAGIC: &'static [u8] = b"EMCSNAP\0";
+pub const SNAPSHOT_VERSION: u32 = 5;
+
+const HEADER_LEN: usize = 8 + 4;
+const CHECKSUM_LEN: usize = 8;
+
+const ITEM_FOLLOWS: u8 = 1;
+const ITEMS_END: u8 = 0;
+
+
+// A snapshot that is written a few items at a time, so that taking one in
+// between commands never holds them up for long. Items are written as they
+// are when the snapshot gets to them, so one that is changed in the meantime
+// may be captured before or after the change. A log of the mutations made
+// since the snapshot was started is needed to bring it up to date.
+pub struct SnapshotWriter<W: Write> {
+    enc: Encoder<W>,
+    walk: Walk,
+    count: u64, // items written so far
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(cache: &Cache,
+               writer: W)
+               -> PersistenceResult<SnapshotWriter<W>> {
+        let mut enc = Encoder::new(writer);
+
+        try!(enc.write_bytes(SNAPSHOT_MAGIC));
+        try!(enc.write_u32(SNAPSHOT_VERSION));
+
+        Ok(SnapshotWriter {
+            enc: enc,
+            walk: cache.start_walk(),
+            count: 0,
+        })
+    }
+
+    // Writes items until at least max_bytes of keys and data have been
+    // written. Returns true once all of them are.
+    pub fn step(&mut self,
+                cache: &Cache,
+                max_bytes: usize)
+                -> PersistenceResult<bool> {
+        let mut written = 0;
+
+        while written < max_bytes {
+            let key = match cache.walk(&mut self.walk) {
+                Some(key) => key,
+                None => break,
+            };
+
+            // Items that are dead already are not worth persisting.
+            // Snapshots always hold the plain data, even if it is on disk.
+            let value = match cache.peek(&key) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            try!(self.enc.write_bytes(&[ITEM_FOLLOWS]));
+            try!(self.enc.write_blob(&key.item));
+            try!(self.enc.write_item(&value));
+            try!(self.enc.write_u16(*value.get_flags()));
+            try!(self.enc.write_f64(*value.get_exptime()));
+            try!(self.enc.write_f64(*value.get_atime()));
+            try!(self.enc.write_u64(*value.get_cas_id()));
+            try!(self.enc.write_tags(value.get_tags()));
+
+            self.count += 1;
+            written += key.len() + value.len();
+        }
+
+        Ok(self.walk.is_done())
+    }
+
+    // Writes the rest of the items, then the tag versions as they are now.
+    // Returns the number of items written along with the writer.
+    pub fn finish(mut self, cache: &Cache) -> PersistenceResult<(u64, W)> {
+        while !try!(self.step(cache, usize::MAX)) {}
+        try!(self.enc.write_bytes(&[ITEMS_END]));
+
+        // Without the versions the items' tags would be meaningless
+        let tags: Vec<Tag> = cache.iter_tag_versions()
+                                  .map(|(name, version)| {
+                                      Tag::new(name, *version)
+                                  })
+                                  .collect();
+        try!(self.enc.write_tags(&tags));
+
+        // ...and so would those of tags that were forgotten since
+        let floors = cache.get_tag_floors();
+        try!(self.enc.write_u32(floors.len() as u32));
+        for (bucket, floor) in floors {
+            try!(self.enc.write_u32(bucket as u32));
+            try!(self.enc.write_u64(floor));
+        }
+
+        let checksum = self.enc.get_checksum();
+        try!(self.enc.write_u64(checksum));
+
+        // Make sure everything has reached the writer
+        let mut writer = self.enc.into_inner();
+        match writer.flush() {
+            Ok(_) => Ok((self.count, writer)),
+            Err(_) => Err(PersistenceError::FileWriteError),
+        }
+    }
+}
+
+
+pub fn write_snapshot<W: Write>(cache: &Cache,
+                                writer: W)
+                                -> PersistenceResult<u64> {
+    let snapshot = try!(SnapshotWriter::new(cache, writer));
+    let (count, _) = try!(snapshot.finish(cache));
+    Ok(count)
+}
+
+// Restores the items in a snapshot held in a buffer, see read_snapshot_from
+pub fn read_snapshot(cache: &mut Cache,
+                     bytes: &[u8])
+                     -> PersistenceResult<u64> {
+    read_snapshot_from(cache, Cursor::new(bytes))
+}
+
+// Restores the items in a snapshot to the cache and returns the number of
+// items restored. The snapshot is read three times over rather than held in
+// memory: to verify the checksum, to decode the items and note where each
+// one starts, and to restore them in order of their access times. A bad
+// snapshot leaves the cache unchanged.
+pub fn read_snapshot_from<R: Read + Seek>(cache: &mut Cache,
+                                          mut reader: R)
+                                          -> PersistenceResult<u64> {
+    let len = try!(seek(&mut reader, SeekFrom::End(0)));
+    let body_len = len.saturating_sub(CHECKSUM_LEN as u64);
+    try!(seek(&mut reader, SeekFrom::Start(0)));
+
+    {
+        let mut dec = Decoder::new(&mut reader);
+
+        // Is this even a snapshot file?
+        if try!(dec.read_bytes(SNAPSHOT_MAGIC.len())) != SNAPSHOT_MAGIC {
+            return Err(PersistenceError::InvalidFormat);
+        }
+
+        if len < (HEADER_LEN + CHECKSUM_LEN) as u64 {
+            return Err(PersistenceError::Truncated);
+        }
+
+        if try!(dec.read_u32()) != SNAPSHOT_VERSION {
+            return Err(PersistenceError::UnsupportedVersion);
+        }
+
+        // Verify the whole body before we trust anything in it
+        try!(dec.skip(body_len - HEADER_LEN as u64));
+        let checksum = dec.get_checksum();
+        if try!(dec.read_u64()) != checksum {
+            return Err(PersistenceError::ChecksumMismatch);
+        }
+    }
+
+    // Decode all the items before touching the cache, so that a bad file
+    // leaves the cache unchanged. Only their access times and offsets are
+    // kept.
+    try!(seek(&mut reader, SeekFrom::Start(HEADER_LEN as u64)));
+    let (mut items, tags, floors) = {
+        let mut dec = Decoder::new(&mut reader);
+
+        let mut items = vec![];
+        loop {
+            match try!(dec.read_bytes(1))[0] {
+                ITEM_FOLLOWS => (),
+                ITEMS_END => break,
+                _ => return Err(PersistenceError::InvalidFormat),
+            }
+
+            let offset = HEADER_LEN as u64 + dec.get_position();
+            let (_, value) = try!(read_item(&mut dec));
+            items.push((*value.get_atime(), offset));
+        }
+
+        let tags = try!(dec.read_tags());
+        let mut floors = vec![];
+        for _ in 0..try!(dec.read_u32()) {
+            let bucket = try!(dec.read_u32()) as usize;
+            floors.push((bucket, try!(dec.read_u64())));
+        }
+
+        // There should be nothing left but the checksum
+        if HEADER_LEN as u64 + dec.get_position() != body_len {
+            return Err(PersistenceError::InvalidFormat);
+        }
+
+        (items, tags, floors)
+    };
+
+    // Restore the tag versions first, so that items whose tags have been
+    // invalidated are recognized as dead
+    for (bucket, floor) in floors {
+        cache.restore_tag_floor(bucket, floor);
+    }
+    for tag in tags {
+        cache.restore_tag_version(&tag.name, tag.version);
+    }
+
+    // Least recently used first, like the cache keeps them
+    items.sort_by(|&(a, _), &(b, _)| a.partial_cmp(&b).unwrap());
+
+    let mut restored = 0;
+    for (_, offset) in items {
+        try!(seek(&mut reader, SeekFrom::Start(offset)));
+        let (key, value) = try!(read_item(&mut Decoder::new(&mut reader)));
+
+        // Items that are expired or no longer fit in the cache are skipped
+        match cache.restore(key, value) {
+            Ok(true) => restored += 1,
+            _ => (),
+        }
+    }
+
+    Ok(restored)
+}
+
+fn read_item<R: Read>(dec: &mut Decoder<R>)
+                      -> PersistenceResult<(Key, Value)> {
+    let key = Key::new(try!(dec.read_blob()));
+    let (item, collection) = try!(dec.read_item());
+    let flags = try!(dec.read_u16());
+    let exptime = try!(dec.read_f64());
+    let atime = try!(dec.read_f64());
+    let cas_id = try!(dec.read_u64());
+    let tags = try!(dec.read_tags());
+
+    // The items are sorted by their access times
+    if !atime.is_finite() {
+        return Err(PersistenceError::InvalidFormat);
+    }
+
+    let mut value = Value::from_parts(item,
+                                      flags,
+                                      exptime,
+                                      atime,
+                                      cas_id,
+                                      tags);
+    match collection {
+        Some(collection) => {
+            value.with_collection(collection);
+        }
+        None => (),
+    }
+
+    Ok((key, value))
+}
+
+fn seek<S: Seek>(seeker: &mut S, pos: SeekFrom) -> PersistenceResult<u64> {
+    match seeker.seek(pos) {
+        Ok(pos) => Ok(pos),
+        Err(_) => Err(PersistenceError::FileReadError),
+    }
+}
+
+
+// Files are written to a temporary file first and then moved into place, so
+// that a crash halfway through never clobbers a good file
+fn get_tmp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+fn create_tmp_file(path: &str) -> PersistenceResult<BufWriter<File>> {
+    match File::create(&get_tmp_path(path)) {
+        Ok(file) => Ok(BufWriter::new(file)),
+        Err(_) => Err(PersistenceError::FileOpenError),
+    }
+}
+
+fn commit_tmp_file(mut writer: BufWriter<File>,
+                   path: &str)
+                   -> PersistenceResult<()> {
+    // Make sure the contents are on disk before the rename is
+    match writer.flush() {
+        Ok(_) => (),
+        Err(_) => return Err(PersistenceError::FileWriteError),
+    }
+    match writer.get_ref().sync_all() {
+        Ok(_) => (),
+        Err(_) => return Err(PersistenceError::FileWriteError),
+    }
+
+    match fs::rename(&get_tmp_path(path), path) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(PersistenceError::FileWriteError),
+    }
+}
+
+
+// A snapshot being written to a file a few items at a time (see
+// SnapshotWriter). The file only replaces the one at the path once the
+// snapshot is finished, one that is dropped before then is thrown away.
+pub struct SnapshotFile {
+    path: String,
+    writer: Option<SnapshotWriter<BufWriter<File>>>,
+    committed: bool,
+}
+
+impl SnapshotFile {
+    pub fn create(cache: &Cache,
+                  path: &str)
+                  -> PersistenceResult<SnapshotFile> {
+        let file = try!(create_tmp_file(path));
+        let writer = try!(SnapshotWriter::new(cache, file));
+
+        Ok(SnapshotFile {
+            path: path.to_string(),
+            writer: Some(writer),
+            committed: false,
+        })
+    }
+
+    pub fn step(&mut self,
+                cache: &Cache,
+                max_bytes: usize)
+                -> PersistenceResult<bool> {
+        self.writer.as_mut().unwrap().step(cache, max_bytes)
+    }
+
+    // Returns the number of items written
+    pub fn finish(mut self, cache: &Cache) -> PersistenceResult<u64> {
+        let writer = self.writer.take().unwrap();
+        let (count, file) = try!(writer.finish(cache));
+        try!(commit_tmp_file(file, &self.path));
+        self.committed = true;
+        Ok(count)
+    }
+}
+
+impl Drop for SnapshotFile {
+    fn drop(&mut self) {
+        // Unfinished (or failed), the file is of no use
+        if !self.committed {
+            let _ = fs::remove_file(&get_tmp_path(&self.path));
+        }
+    }
+}
+
+
+pub fn save_snapshot(cache: &Cache, path: &str) -> PersistenceResult<u64> {
+    let snapshot = try!(SnapshotFile::create(cache, path));
+    snapshot.finish(cache)
+}
+
+pub fn load_snapshot(cache: &mut Cache, path: &str) -> PersistenceResult<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Err(PersistenceError::FileOpenError),
+    };
+
+    read_snapshot_from(cache, BufReader::new(file))
+}
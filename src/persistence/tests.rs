@@ -0,0 +1,727 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use platform::time::time_now;
+use storage::Cache;
+use storage::Chunks;
+use storage::Collection;
+use storage::ExtStore;
+use storage::Key;
+use storage::ListEnd;
+use storage::Tag;
+use storage::Value;
+use storage::chunks::CHUNK_SIZE;
+use storage::tag_versions::get_bucket;
+use testlib::tempfile::get_temp_path;
+
+use super::Change;
+use super::Edit;
+use super::FsyncPolicy;
+use super::Mutation;
+use super::MutationLog;
+use super::PersistenceError;
+use super::SnapshotFile;
+use super::encoding::Checksum;
+use super::encoding::Decoder;
+use super::encoding::Encoder;
+use super::load_snapshot;
+use super::mutation_log::read_log;
+use super::read_snapshot;
+use super::replay_log;
+use super::save_snapshot;
+use super::snapshot::SNAPSHOT_MAGIC;
+use super::snapshot::SNAPSHOT_VERSION;
+use super::write_snapshot;
+
+
+fn make_snapshot(cache: &Cache) -> Vec<u8> {
+    let mut bytes = vec![];
+    write_snapshot(cache, &mut bytes).unwrap();
+    bytes
+}
+
+
+// Encoding
+
+#[test]
+fn test_encoding_roundtrip() {
+    let mut enc = Encoder::new(vec![]);
+    enc.write_u16(0xabcd).unwrap();
+    enc.write_u32(0xdeadbeef).unwrap();
+    enc.write_u64(0x0102030405060708).unwrap();
+    enc.write_f64(1.25).unwrap();
+    enc.write_blob(&[1, 2, 3]).unwrap();
+    enc.write_tags(&[Tag::new("a", 3)]).unwrap();
+    let bytes = enc.into_inner();
+
+    let mut dec = Decoder::new(&bytes[..]);
+    assert_eq!(0xabcd, dec.read_u16().unwrap());
+    assert_eq!(0xdeadbeef, dec.read_u32().unwrap());
+    assert_eq!(0x0102030405060708, dec.read_u64().unwrap());
+    assert_eq!(1.25, dec.read_f64().unwrap());
+    assert_eq!(vec![1, 2, 3], dec.read_blob().unwrap());
+    assert_eq!(vec![Tag::new("a", 3)], dec.read_tags().unwrap());
+    assert_eq!(0, dec.remaining());
+
+    // Reading past the end
+    assert_eq!(PersistenceError::Truncated, dec.read_u16().unwrap_err());
+}
+
+#[test]
+fn test_encoding_items() {
+    let mut list = Collection::list();
+    list.push(ListEnd::Tail, vec![1], None).unwrap();
+    list.push(ListEnd::Tail, vec![2], None).unwrap();
+    let mut hash = Collection::hash();
+    hash.set_field(vec![1], vec![2, 3]).unwrap();
+
+    let mut enc = Encoder::new(vec![]);
+    enc.write_item(&Value::new(vec![1, 2, 3])).unwrap();
+    for collection in vec![list.clone(), hash.clone()] {
+        let mut value = Value::empty();
+        value.set_collection(collection);
+        enc.write_item(&value).unwrap();
+    }
+    let bytes = enc.into_inner();
+
+    let mut dec = Decoder::new(&bytes[..]);
+    assert_eq!((vec![1, 2, 3], None), dec.read_item().unwrap());
+    assert_eq!((vec![], Some(list)), dec.read_item().unwrap());
+    assert_eq!((vec![], Some(hash)), dec.read_item().unwrap());
+    assert_eq!(0, dec.remaining());
+}
+
+#[test]
+fn test_decoding_bogus_blob_length() {
+    // A blob claiming to be much longer than the buffer
+    let mut enc = Encoder::new(vec![]);
+    enc.write_u64(0xffffffffffff).unwrap();
+    let bytes = enc.into_inner();
+
+    let mut dec = Decoder::new(&bytes[..]);
+    assert_eq!(PersistenceError::Truncated, dec.read_blob().unwrap_err());
+}
+
+
+// Snapshots
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut cache = Cache::new(1024);
+
+    let mut value1 = Value::new(vec![1, 2]);
+    value1.set_flags(15);
+    value1.set_exptime(time_now() + 100.0);
+    cache.set(Key::new(vec![1]), value1).unwrap();
+    cache.set(Key::new(vec![2]), Value::new(vec![3])).unwrap();
+    cache.set(Key::new(vec![3]), Value::new(vec![4, 5, 6])).unwrap();
+
+    // Access the first key to make it the most recently used
+    cache.get(&Key::new(vec![1])).unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    let mut restored = Cache::new(1024);
+    assert_eq!(3, read_snapshot(&mut restored, &bytes).unwrap());
+    assert_eq!(cache.get_stats().bytes, restored.get_stats().bytes);
+
+    // Everything came back in the same LRU order with the same metadata
+    let before: Vec<(&Key, &Value)> = cache.iter().collect();
+    let after: Vec<(&Key, &Value)> = restored.iter().collect();
+    assert_eq!(before.len(), after.len());
+    for (&(k1, v1), &(k2, v2)) in before.iter().zip(after.iter()) {
+        assert_eq!(k1, k2);
+        assert_eq!(v1, v2);
+        assert_eq!(v1.get_exptime(), v2.get_exptime());
+        assert_eq!(v1.get_atime(), v2.get_atime());
+        assert_eq!(v1.get_cas_id(), v2.get_cas_id());
+    }
+}
+
+#[test]
+fn test_snapshot_tags() {
+    let mut cache = Cache::new(1024);
+
+    let mut value = Value::new(vec![1]);
+    value.set_tags(vec!["a".to_string(), "b".to_string()]);
+    cache.set(Key::new(vec![1]), value.clone()).unwrap();
+    cache.invalidate_tag("b");
+    cache.set(Key::new(vec![2]), value.clone()).unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    // The tag versions come back too, so the first item stays dead
+    let mut restored = Cache::new(1024);
+    assert_eq!(1, read_snapshot(&mut restored, &bytes).unwrap());
+    assert_eq!(1, restored.get_tag_version("b"));
+    assert!(restored.get(&Key::new(vec![1])).is_err());
+    assert!(restored.get(&Key::new(vec![2])).is_ok());
+
+    // ...and the restored item can still be invalidated
+    restored.invalidate_tag("a");
+    assert!(restored.get(&Key::new(vec![2])).is_err());
+}
+
+#[test]
+fn test_snapshot_tag_floors() {
+    let mut cache = Cache::new(1024);
+
+    // Invalidate tags until the first one had to be forgotten, then store
+    // an item whose tag shares the raised floor
+    let mut i = 0;
+    while cache.get_tags_forgotten() == 0 {
+        cache.invalidate_tag(&format!("t{}", i));
+        i += 1;
+    }
+    let tag = (0..)
+                  .map(|i| format!("tag{}", i))
+                  .find(|tag| get_bucket(tag) == get_bucket("t0"))
+                  .unwrap();
+    let mut value = Value::new(vec![1]);
+    value.set_tags(vec![tag]);
+    cache.set(Key::new(vec![1]), value).unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    // The floor comes back too, so the item is still alive and t0 stays
+    // invalidated
+    let mut restored = Cache::new(1024);
+    assert_eq!(1, read_snapshot(&mut restored, &bytes).unwrap());
+    assert_eq!(cache.get_tag_version("t0"), restored.get_tag_version("t0"));
+    assert!(restored.get(&Key::new(vec![1])).is_ok());
+}
+
+#[test]
+fn test_snapshot_collections() {
+    let mut cache = Cache::new(1024);
+
+    let mut set = Collection::set();
+    set.add(vec![1]).unwrap();
+    set.add(vec![2]).unwrap();
+    cache.update_collection(&Key::new(vec![1]), Some(set.clone()), |_| Ok(()))
+         .unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    let mut restored = Cache::new(1024);
+    assert_eq!(1, read_snapshot(&mut restored, &bytes).unwrap());
+    assert_eq!(cache.get_stats().bytes, restored.get_stats().bytes);
+    let value = restored.get(&Key::new(vec![1])).unwrap();
+    assert_eq!(Some(&set), value.get_collection());
+}
+
+#[test]
+fn test_snapshot_chunked_value() {
+    let mut cache = Cache::new(1 << 24);
+    cache.with_value_maxlen(1 << 23);
+
+    let mut value = Value::new(vec![1; CHUNK_SIZE + 10]);
+    value.prepend_item(Chunks::new(vec![2; CHUNK_SIZE]));
+    cache.set(Key::new(vec![1]), value.clone()).unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    // It comes back with the same bytes
+    let mut restored = Cache::new(1 << 24);
+    restored.with_value_maxlen(1 << 23);
+    assert_eq!(1, read_snapshot(&mut restored, &bytes).unwrap());
+    assert_eq!(value, *restored.get(&Key::new(vec![1])).unwrap());
+}
+
+#[test]
+fn test_snapshot_extstore() {
+    let value = Value::new(vec![1; 1000]);
+    let item_size = Key::new(vec![1]).mem_size() as u64 +
+                    value.mem_size() as u64;
+
+    // Two of the values are written out to disk to make room for the third
+    let mut cache = Cache::new(item_size * 2);
+    let path = get_temp_path("snapshot-extstore");
+    cache.with_extstore(ExtStore::open(&path, 65536).unwrap());
+    for i in 1..4 {
+        cache.set(Key::new(vec![i]), value.clone()).unwrap();
+    }
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().items);
+
+    let bytes = make_snapshot(&cache);
+
+    // They are persisted with their data all the same
+    let mut restored = Cache::new(item_size * 3);
+    assert_eq!(3, read_snapshot(&mut restored, &bytes).unwrap());
+    for i in 1..4 {
+        assert_eq!(value, *restored.get(&Key::new(vec![i])).unwrap());
+    }
+}
+
+#[test]
+fn test_snapshot_skips_expired() {
+    // Hand craft a snapshot with one live and one expired item
+    let mut enc = Encoder::new(vec![]);
+    enc.write_bytes(SNAPSHOT_MAGIC).unwrap();
+    enc.write_u32(SNAPSHOT_VERSION).unwrap();
+    for &(key, exptime) in [(1, -1.0), (2, time_now() - 1.0)].iter() {
+        enc.write_bytes(&[1]).unwrap();
+        enc.write_blob(&[key]).unwrap();
+        enc.write_item(&Value::new(vec![9])).unwrap();
+        enc.write_u16(0).unwrap();
+        enc.write_f64(exptime).unwrap();
+        enc.write_f64(time_now()).unwrap();
+        enc.write_u64(1).unwrap();
+        enc.write_tags(&[]).unwrap();
+    }
+    enc.write_bytes(&[0]).unwrap();
+    enc.write_tags(&[]).unwrap();
+    enc.write_u32(0).unwrap(); // no floors
+    let checksum = enc.get_checksum();
+    enc.write_u64(checksum).unwrap();
+    let bytes = enc.into_inner();
+
+    let mut restored = Cache::new(1024);
+    assert_eq!(1, read_snapshot(&mut restored, &bytes).unwrap());
+    assert!(restored.get(&Key::new(vec![1])).is_ok());
+    assert!(restored.get(&Key::new(vec![2])).is_err());
+}
+
+#[test]
+fn test_snapshot_truncated() {
+    let mut cache = Cache::new(1024);
+    cache.set(Key::new(vec![1]), Value::new(vec![1, 2, 3])).unwrap();
+    cache.set(Key::new(vec![2]), Value::new(vec![4, 5, 6])).unwrap();
+
+    let bytes = make_snapshot(&cache);
+
+    // Every possible truncation is rejected and leaves the cache empty
+    for len in 0..bytes.len() {
+        let mut restored = Cache::new(1024);
+        let err = read_snapshot(&mut restored, &bytes[..len]).unwrap_err();
+        assert!(err == PersistenceError::Truncated ||
+                err == PersistenceError::ChecksumMismatch);
+        assert_eq!(0, restored.len());
+    }
+}
+
+#[test]
+fn test_snapshot_corrupted() {
+    let mut cache = Cache::new(1024);
+    cache.set(Key::new(vec![1]), Value::new(vec![1, 2, 3])).unwrap();
+
+    let mut bytes = make_snapshot(&cache);
+    let pos = bytes.len() - 12;
+    bytes[pos] ^= 0xff;
+
+    let mut restored = Cache::new(1024);
+    let err = read_snapshot(&mut restored, &bytes).unwrap_err();
+    assert_eq!(PersistenceError::ChecksumMismatch, err);
+    assert_eq!(0, restored.len());
+}
+
+#[test]
+fn test_snapshot_bad_header() {
+    let cache = Cache::new(1024);
+    let bytes = make_snapshot(&cache);
+
+    // Not a snapshot file at all
+    let mut bogus = bytes.clone();
+    bogus[0] = b'X';
+    let err = read_snapshot(&mut Cache::new(1024), &bogus).unwrap_err();
+    assert_eq!(PersistenceError::InvalidFormat, err);
+
+    // A version from the future
+    let mut future = bytes.clone();
+    future[8] = 99;
+    let err = read_snapshot(&mut Cache::new(1024), &future).unwrap_err();
+    assert_eq!(PersistenceError::UnsupportedVersion, err);
+}
+
+#[test]
+fn test_snapshot_bad_atime() {
+    let mut cache = Cache::new(1024);
+    cache.set(Key::new(vec![1]), Value::new(vec![1, 2, 3])).unwrap();
+    let mut bytes = make_snapshot(&cache);
+
+    // The atime follows the header, the item marker, the key, the item, the
+    // flags and the exptime
+    let pos = 12 + 1 + (8 + 1) + (1 + 8 + 3) + 2 + 8;
+    let mut enc = Encoder::new(vec![]);
+    enc.write_f64(::std::f64::NAN).unwrap();
+    bytes[pos..pos + 8].copy_from_slice(&enc.into_inner());
+
+    // Fix up the checksum so that it's the atime that gets rejected
+    let body_len = bytes.len() - 8;
+    let mut checksum = Checksum::new();
+    checksum.update(&bytes[..body_len]);
+    let mut enc = Encoder::new(vec![]);
+    enc.write_u64(checksum.value()).unwrap();
+    bytes[body_len..].copy_from_slice(&enc.into_inner());
+
+    let mut restored = Cache::new(1024);
+    let err = read_snapshot(&mut restored, &bytes).unwrap_err();
+    assert_eq!(PersistenceError::InvalidFormat, err);
+    assert_eq!(0, restored.len());
+}
+
+#[test]
+fn test_snapshot_file() {
+    let path = get_temp_path("snapshot");
+
+    let mut cache = Cache::new(1024);
+    cache.set(Key::new(vec![1]), Value::new(vec![1, 2, 3])).unwrap();
+    assert_eq!(1, save_snapshot(&cache, &path).unwrap());
+
+    let mut restored = Cache::new(1024);
+    assert_eq!(1, load_snapshot(&mut restored, &path).unwrap());
+    let value = restored.get(&Key::new(vec![1])).unwrap();
+    assert_eq!(Value::new(vec![1, 2, 3]), *value);
+
+    fs::remove_file(&path).unwrap();
+
+    // The file is gone now
+    let err = load_snapshot(&mut restored, &path).unwrap_err();
+    assert_eq!(PersistenceError::FileOpenError, err);
+}
+
+#[test]
+fn test_snapshot_file_steps() {
+    let path = get_temp_path("snapshot-steps");
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut cache = Cache::new(1024);
+    for i in 1..4 {
+        cache.set(Key::new(vec![i]), Value::new(vec![i; 10])).unwrap();
+    }
+
+    // The first item is written...
+    let mut snapshot = SnapshotFile::create(&cache, &path).unwrap();
+    assert_eq!(false, snapshot.step(&cache, 1).unwrap());
+    assert!(fs::metadata(&tmp_path).is_ok());
+
+    // ...so it's captured as it was, while the rest are captured as they
+    // are when the snapshot gets to them
+    cache.set(Key::new(vec![1]), Value::new(vec![7])).unwrap();
+    cache.remove(&Key::new(vec![3])).unwrap();
+    cache.set(Key::new(vec![4]), Value::new(vec![4])).unwrap();
+
+    assert_eq!(true, snapshot.step(&cache, 1000).unwrap());
+    assert_eq!(3, snapshot.finish(&cache).unwrap());
+    assert!(fs::metadata(&tmp_path).is_err());
+
+    let mut restored = Cache::new(1024);
+    assert_eq!(3, load_snapshot(&mut restored, &path).unwrap());
+    let value = restored.get(&Key::new(vec![1])).unwrap();
+    assert_eq!(Value::new(vec![1; 10]), *value);
+    assert!(restored.get(&Key::new(vec![3])).is_err());
+    assert!(restored.get(&Key::new(vec![4])).is_ok());
+
+    // A snapshot that is given up on leaves the last one as it was
+    let snapshot = SnapshotFile::create(&cache, &path).unwrap();
+    drop(snapshot);
+    assert!(fs::metadata(&tmp_path).is_err());
+    assert_eq!(3, load_snapshot(&mut Cache::new(1024), &path).unwrap());
+
+    fs::remove_file(&path).unwrap();
+}
+
+
+// Mutation log
+
+fn read_log_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap()
+}
+
+#[test]
+fn test_mutation_log_roundtrip() {
+    let path = get_temp_path("log-roundtrip");
+
+    let mut value = Value::new(vec![1, 2]);
+    value.set_flags(15);
+    value.set_exptime(time_now() + 100.0);
+
+    let edit = Edit {
+        change: Change::Append(vec![3]),
+        prev_cas_id: 2,
+        cas_id: 5,
+        flags: 16,
+        exptime: time_now() + 200.0,
+        atime: time_now(),
+    };
+
+    {
+        let mut log = MutationLog::open(&path, FsyncPolicy::Always, true)
+                          .unwrap();
+        assert!(!log.has_records());
+
+        log.append_put(&Key::new(vec![1]), &value).unwrap();
+        log.append_remove(&Key::new(vec![2])).unwrap();
+        log.append_flush_all(12.5).unwrap();
+        log.append_flush_namespace("users", 13.5).unwrap();
+        log.append_invalidate_tag("a", 2, &[(7, 1)]).unwrap();
+        log.append_delete_prefix(&[3]).unwrap();
+        log.append_edit(&Key::new(vec![1]), &edit).unwrap();
+        assert!(log.has_records());
+    }
+
+    let (mutations, len) = read_log(&read_log_file(&path)).unwrap();
+    assert_eq!(vec![Mutation::Put(Key::new(vec![1]), value),
+                    Mutation::Remove(Key::new(vec![2])),
+                    Mutation::FlushAll(12.5),
+                    Mutation::FlushNamespace("users".to_string(), 13.5),
+                    Mutation::InvalidateTag("a".to_string(), 2, vec![(7, 1)]),
+                    Mutation::DeletePrefix(vec![3]),
+                    Mutation::Edit(Key::new(vec![1]), edit)],
+               mutations);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_collection_edits() {
+    let path = get_temp_path("log-collection-edits");
+
+    let edit = |change: Change| {
+        Edit {
+            change: change,
+            prev_cas_id: 2,
+            cas_id: 3,
+            flags: 0,
+            exptime: -1.0,
+            atime: 12.5,
+        }
+    };
+    let edits = vec![edit(Change::ListPush(ListEnd::Head, vec![1], None)),
+                     edit(Change::ListPush(ListEnd::Tail, vec![2], Some(3))),
+                     edit(Change::ListPop(ListEnd::Tail)),
+                     edit(Change::SetAdd(vec![3])),
+                     edit(Change::SetRemove(vec![4])),
+                     edit(Change::HashSet(vec![5], vec![6])),
+                     edit(Change::HashDelete(vec![7]))];
+
+    {
+        let mut log = MutationLog::open(&path, FsyncPolicy::Always, true)
+                          .unwrap();
+        for edit in edits.iter() {
+            log.append_edit(&Key::new(vec![1]), edit).unwrap();
+        }
+    }
+
+    let (mutations, _) = read_log(&read_log_file(&path)).unwrap();
+    let expected: Vec<Mutation> = edits.into_iter()
+                                       .map(|edit| {
+                                           Mutation::Edit(Key::new(vec![1]),
+                                                          edit)
+                                       })
+                                       .collect();
+    assert_eq!(expected, mutations);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_reopen_appends() {
+    let path = get_temp_path("log-reopen");
+
+    for key in 1..3 {
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, false)
+                          .unwrap();
+        log.append_remove(&Key::new(vec![key])).unwrap();
+    }
+
+    let (mutations, _) = read_log(&read_log_file(&path)).unwrap();
+    assert_eq!(vec![Mutation::Remove(Key::new(vec![1])),
+                    Mutation::Remove(Key::new(vec![2]))],
+               mutations);
+
+    // Resetting discards all the records
+    let mut log = MutationLog::open(&path, FsyncPolicy::Never, false).unwrap();
+    log.reset().unwrap();
+    let (mutations, _) = read_log(&read_log_file(&path)).unwrap();
+    assert_eq!(0, mutations.len());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_rotate() {
+    let path = get_temp_path("log-rotate");
+    let rotated_path = get_temp_path("log-rotate.old");
+
+    let mut log = MutationLog::open(&path, FsyncPolicy::EverySec, true)
+                      .unwrap();
+    log.append_remove(&Key::new(vec![1])).unwrap();
+    log.rotate(&rotated_path).unwrap();
+    log.append_remove(&Key::new(vec![2])).unwrap();
+
+    // The old records went with the rotated log
+    let (mutations, _) = read_log(&read_log_file(&rotated_path)).unwrap();
+    assert_eq!(vec![Mutation::Remove(Key::new(vec![1]))], mutations);
+    let (mutations, _) = read_log(&read_log_file(&path)).unwrap();
+    assert_eq!(vec![Mutation::Remove(Key::new(vec![2]))], mutations);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated_path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_torn_write() {
+    let path = get_temp_path("log-torn");
+
+    {
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, true)
+                          .unwrap();
+        log.append_put(&Key::new(vec![1]), &Value::new(vec![9])).unwrap();
+        log.append_put(&Key::new(vec![2]), &Value::new(vec![8])).unwrap();
+    }
+    let good_len = fs::metadata(&path).unwrap().len();
+
+    // Simulate a crash halfway through writing a record
+    {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[40, 0, 0, 0, 1, 2, 3]).unwrap();
+    }
+
+    // The intact records are replayed and the torn one is cut off
+    let mut cache = Cache::new(1024);
+    assert_eq!(2, replay_log(&mut cache, &path).unwrap());
+    assert_eq!(2, cache.len());
+    assert_eq!(good_len, fs::metadata(&path).unwrap().len());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_corrupted_record() {
+    let path = get_temp_path("log-corrupted");
+
+    {
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, true)
+                          .unwrap();
+        log.append_remove(&Key::new(vec![1])).unwrap();
+        log.append_remove(&Key::new(vec![2])).unwrap();
+    }
+
+    // Flip a bit in the key of the last record
+    let mut bytes = read_log_file(&path);
+    let pos = bytes.len() - 9;
+    bytes[pos] ^= 0x01;
+
+    // Reading stops at the bad record
+    let (mutations, _) = read_log(&bytes).unwrap();
+    assert_eq!(vec![Mutation::Remove(Key::new(vec![1]))], mutations);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_bad_header() {
+    let err = read_log(b"EMCSNAP\0\x01\0\0\0").unwrap_err();
+    assert_eq!(PersistenceError::InvalidFormat, err);
+
+    let err = read_log(b"EMCLOG\0\0\x09\0\0\0").unwrap_err();
+    assert_eq!(PersistenceError::UnsupportedVersion, err);
+
+    let err = read_log(b"EMCLOG").unwrap_err();
+    assert_eq!(PersistenceError::Truncated, err);
+}
+
+#[test]
+fn test_mutation_apply() {
+    let mut cache = Cache::new(1024);
+
+    Mutation::Put(Key::new(vec![1]), Value::new(vec![9])).apply(&mut cache);
+    assert_eq!(Value::new(vec![9]), *cache.get(&Key::new(vec![1])).unwrap());
+
+    // Putting a dead value removes the stale one
+    let mut value = Value::new(vec![8]);
+    value.set_exptime(time_now() - 1.0);
+    Mutation::Put(Key::new(vec![1]), value).apply(&mut cache);
+    assert_eq!(0, cache.len());
+
+    Mutation::Put(Key::new(vec![2]), Value::new(vec![7])).apply(&mut cache);
+    Mutation::Remove(Key::new(vec![2])).apply(&mut cache);
+    assert_eq!(0, cache.len());
+
+    Mutation::FlushAll(12.5).apply(&mut cache);
+    assert_eq!(12.5, cache.get_flush_exptime());
+
+    // Flushing a namespace that isn't configured is ignored
+    cache.with_namespace("users", 512);
+    Mutation::FlushNamespace("users".to_string(), 13.5).apply(&mut cache);
+    Mutation::FlushNamespace("other".to_string(), 14.5).apply(&mut cache);
+    assert_eq!(Some(13.5), cache.get_namespace_flush_exptime("users"));
+    assert_eq!(12.5, cache.get_flush_exptime());
+
+    // Tag versions only ever move forward
+    Mutation::InvalidateTag("a".to_string(), 2, vec![]).apply(&mut cache);
+    Mutation::InvalidateTag("a".to_string(), 1, vec![]).apply(&mut cache);
+    assert_eq!(2, cache.get_tag_version("a"));
+
+    // ...and so do the floors of forgotten tags
+    let bucket = get_bucket("b");
+    let floors = vec![(bucket, 2)];
+    Mutation::InvalidateTag("c".to_string(), 3, floors).apply(&mut cache);
+    let floors = vec![(bucket, 1)];
+    Mutation::InvalidateTag("c".to_string(), 3, floors).apply(&mut cache);
+    assert_eq!(2, cache.get_tag_version("b"));
+
+    // Starting over, since everything stored so far is flushed
+    let mut cache = Cache::new(1024);
+    Mutation::Put(Key::new(vec![3, 1]), Value::new(vec![6])).apply(&mut cache);
+    Mutation::Put(Key::new(vec![3, 2]), Value::new(vec![5])).apply(&mut cache);
+    Mutation::Put(Key::new(vec![4]), Value::new(vec![4])).apply(&mut cache);
+    Mutation::DeletePrefix(vec![3]).apply(&mut cache);
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn test_mutation_apply_edit() {
+    let mut cache = Cache::new(1024);
+    let key = Key::new(vec![1]);
+
+    let mut value = Value::new(vec![1]);
+    value.restore_cas_id(3);
+    cache.restore(key.clone(), value).unwrap();
+
+    let edit = |change, prev_cas_id, cas_id| {
+        Edit {
+            change: change,
+            prev_cas_id: prev_cas_id,
+            cas_id: cas_id,
+            flags: 7,
+            exptime: -1.0,
+            atime: time_now(),
+        }
+    };
+
+    // Edits of the value that is there are made
+    Mutation::Edit(key.clone(), edit(Change::Append(vec![2]), 3, 5))
+        .apply(&mut cache);
+    Mutation::Edit(key.clone(), edit(Change::Prepend(vec![0]), 5, 6))
+        .apply(&mut cache);
+    let value = cache.peek(&key).unwrap().into_owned();
+    assert_eq!(vec![0, 1, 2], value.get_item().to_vec());
+    assert_eq!(7, *value.get_flags());
+    assert_eq!(6, *value.get_cas_id());
+
+    // ...but not those that are in already, or made to another value
+    Mutation::Edit(key.clone(), edit(Change::Append(vec![2]), 3, 5))
+        .apply(&mut cache);
+    Mutation::Edit(key.clone(), edit(Change::Counter(4), 8, 9))
+        .apply(&mut cache);
+    let value = cache.peek(&key).unwrap().into_owned();
+    assert_eq!(vec![0, 1, 2], value.get_item().to_vec());
+
+    Mutation::Edit(key.clone(), edit(Change::Counter(4), 6, 7))
+        .apply(&mut cache);
+    assert_eq!(Some(4), cache.peek(&key).unwrap().get_counter());
+
+    // Touching the value to expire it removes it
+    let mut touch = edit(Change::Touch, 7, 8);
+    touch.exptime = time_now() - 1.0;
+    Mutation::Edit(key.clone(), touch).apply(&mut cache);
+    assert_eq!(0, cache.len());
+
+    // There's nothing to edit
+    Mutation::Edit(key.clone(), edit(Change::Touch, 8, 9)).apply(&mut cache);
+    assert_eq!(0, cache.len());
+}
@@ -0,0 +1,4 @@
+use super::errors::PersistenceError;
+
+
+pub type PersistenceResult<T> = Result<T, PersistenceError>;
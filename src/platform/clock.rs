@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use super::time::time_now;
+
+
+// Where the cache gets the time from to decide whether items are still
+// alive. Everything in the cache that's compared against exptimes goes
+// through the clock, so that tests can move time along instead of waiting.
+pub trait Clock: Send {
+    fn now(&self) -> f64; // unixtime
+}
+
+
+// The time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        time_now()
+    }
+}
+
+
+// A clock that only moves when told to. Clones share the same time, so a
+// test can hold on to one while the cache owns another.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<f64>>,
+}
+
+impl ManualClock {
+    pub fn new(now: f64) -> ManualClock {
+        ManualClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    pub fn set(&self, now: f64) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, secs: f64) {
+        *self.now.lock().unwrap() += secs;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> f64 {
+        *self.now.lock().unwrap()
+    }
+}
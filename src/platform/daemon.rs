@@ -0,0 +1,106 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use super::process::get_pid;
+
+
+// Detaches the process from the terminal to carry on in the background.
+// stdin reads from /dev/null, stdout and stderr append to log_path.
+//
+// Forking only copies the calling thread, so this has to happen before any
+// threads are started.
+pub fn daemonize(log_path: &str) -> io::Result<()> {
+    // Open these while the caller can still see an error
+    let null = try!(File::open("/dev/null"));
+    let log = try!(OpenOptions::new()
+                       .create(true)
+                       .append(true)
+                       .open(log_path));
+
+    // The first child starts a new session to lose the terminal, the second
+    // isn't the session leader so it can never get one again
+    try!(fork_and_exit_parent());
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    try!(fork_and_exit_parent());
+
+    try!(redirect(null.as_raw_fd(), libc::STDIN_FILENO));
+    try!(redirect(log.as_raw_fd(), libc::STDOUT_FILENO));
+    try!(redirect(log.as_raw_fd(), libc::STDERR_FILENO));
+
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect(from: RawFd, to: RawFd) -> io::Result<()> {
+    match unsafe { libc::dup2(from, to) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+
+// Holds an exclusive lock on the pidfile for as long as it lives, so a
+// second instance using the same pidfile refuses to start. The lock goes
+// away with the process, even if it crashes, and the file is removed on
+// drop.
+pub struct Pidfile {
+    path: String,
+    file: File,
+}
+
+impl Pidfile {
+    pub fn lock(path: &str) -> io::Result<Pidfile> {
+        let file = try!(OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .open(path));
+
+        let flags = libc::LOCK_EX | libc::LOCK_NB;
+        if unsafe { libc::flock(file.as_raw_fd(), flags) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                let msg = format!("{} is locked by another instance", path);
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+            }
+            return Err(err);
+        }
+
+        Ok(Pidfile {
+            path: path.to_string(),
+            file: file,
+        })
+    }
+
+    // Records our pid. daemonize changes it, so call this afterwards.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        try!(self.file.set_len(0));
+        try!(self.file.seek(SeekFrom::Start(0)));
+        try!(write!(self.file, "{}\n", get_pid()));
+        self.file.flush()
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Best effort, we may no longer have the privileges for it
+        let _ = fs::remove_file(&self.path);
+    }
+}
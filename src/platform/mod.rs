@@ -1,3 +1,9 @@
 // Declare sub modules
+pub mod clock;
+pub mod daemon;
 pub mod process;
+pub mod signals;
 pub mod time;
+
+// internal stuff
+mod tests;  // needed to be part of the compilation unit in test mode
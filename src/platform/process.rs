@@ -1,6 +1,40 @@
+use std::ffi::CString;
+use std::io;
+
 use libc;
 
 
 pub fn get_pid() -> u32 {
     unsafe { libc::getpid() as u32 }
 }
+
+
+// Switches the process over to user and their groups, for when we were
+// started as root. Anyone else has nothing to drop, so nothing changes.
+pub fn drop_privileges(user: &str) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let name = try!(CString::new(user).map_err(|_| no_such_user(user)));
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(no_such_user(user));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    // Groups first, we can't change them once we're no longer root
+    unsafe {
+        if libc::initgroups(name.as_ptr(), gid) < 0 ||
+           libc::setgid(gid) < 0 || libc::setuid(uid) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn no_such_user(user: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound,
+                   format!("no such user: {}", user))
+}
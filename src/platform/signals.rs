@@ -0,0 +1,61 @@
+use std::io;
+use std::mem;
+use std::ptr;
+
+use libc;
+
+
+// The signals the server acts on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Signal {
+    Terminate, // SIGTERM or SIGINT
+    Reload, // SIGHUP
+    DumpStats, // SIGUSR1
+}
+
+
+// Receives signals by waiting for them, rather than having them interrupt
+// whatever thread happens to be running. That way handling a signal is
+// just regular code, without the restrictions of a signal handler.
+pub struct SignalListener {
+    set: libc::sigset_t,
+}
+
+impl SignalListener {
+    // Blocks the signals in the calling thread and in all the threads it
+    // starts afterwards, so create this before starting any
+    pub fn new() -> io::Result<SignalListener> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGHUP);
+            libc::sigaddset(&mut set, libc::SIGUSR1);
+        }
+
+        let rv = unsafe {
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut())
+        };
+        if rv != 0 {
+            return Err(io::Error::from_raw_os_error(rv));
+        }
+
+        Ok(SignalListener { set: set })
+    }
+
+    // Blocks until one of the signals arrives
+    pub fn wait(&self) -> io::Result<Signal> {
+        let mut signum = 0;
+        let rv = unsafe { libc::sigwait(&self.set, &mut signum) };
+        if rv != 0 {
+            return Err(io::Error::from_raw_os_error(rv));
+        }
+
+        match signum {
+            libc::SIGHUP => Ok(Signal::Reload),
+            libc::SIGUSR1 => Ok(Signal::DumpStats),
+            _ => Ok(Signal::Terminate),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+
+use libc;
+
+use testlib::tempfile::get_temp_path;
+
+use super::daemon::Pidfile;
+use super::process::get_pid;
+use super::signals::Signal;
+use super::signals::SignalListener;
+
+
+fn read_file(path: &str) -> String {
+    let mut text = String::new();
+    File::open(path).unwrap().read_to_string(&mut text).unwrap();
+    text
+}
+
+
+// Pidfile
+
+#[test]
+fn test_pidfile_write_pid() {
+    let path = get_temp_path("pidfile-write.pid");
+
+    {
+        let mut pidfile = Pidfile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        assert_eq!(format!("{}\n", get_pid()), read_file(&path));
+
+        // Writing again replaces the pid
+        pidfile.write_pid().unwrap();
+        assert_eq!(format!("{}\n", get_pid()), read_file(&path));
+    }
+
+    // Removed on drop
+    assert!(!Path::new(&path).exists());
+}
+
+#[test]
+fn test_pidfile_second_instance_refused() {
+    let path = get_temp_path("pidfile-locked.pid");
+
+    let mut pidfile = Pidfile::lock(&path).unwrap();
+    pidfile.write_pid().unwrap();
+
+    let err = Pidfile::lock(&path).err().unwrap();
+    assert_eq!(ErrorKind::AlreadyExists, err.kind());
+    // The pid of the running instance is left alone
+    assert_eq!(format!("{}\n", get_pid()), read_file(&path));
+
+    // Free once the first instance is gone
+    drop(pidfile);
+    Pidfile::lock(&path).unwrap();
+}
+
+
+// Signals
+
+#[test]
+fn test_signal_listener() {
+    // Signals are only blocked in this thread, so this is where they stay
+    let signals = SignalListener::new().unwrap();
+
+    let cases = vec![(libc::SIGTERM, Signal::Terminate),
+                     (libc::SIGINT, Signal::Terminate),
+                     (libc::SIGHUP, Signal::Reload),
+                     (libc::SIGUSR1, Signal::DumpStats)];
+    for (signum, signal) in cases {
+        unsafe {
+            libc::pthread_kill(libc::pthread_self(), signum);
+        }
+        assert_eq!(signal, signals.wait().unwrap());
+    }
+}
@@ -4,7 +4,7 @@ use std::time::Duration;
 use time;
 
 
-fn convert_secs_to_duration(duration: f64) -> Duration {
+pub fn convert_secs_to_duration(duration: f64) -> Duration {
     // extract the seconds (before the decimal point)
     let secs: u64 = duration.floor() as u64;
     // obtain the rest (after the decimal point)
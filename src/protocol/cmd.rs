@@ -1,8 +1,25 @@
-// ref: https://github.com/memcached/memcached/blob/master/doc/protocol.txt
+//! The commands a cache executes and the responses it gives, along with the
+//! structs holding their arguments.
+//!
+//! ref: https://github.com/memcached/memcached/blob/master/doc/protocol.txt
+
+use storage::Chunks;
 
 
 // Request structs
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugTime {
+    pub secs: i64, // Shifts the time of the server by this much
+}
+
+impl DebugTime {
+    pub fn new(secs: i64) -> DebugTime {
+        DebugTime { secs: secs }
+    }
+}
+
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Delete {
     pub key: String,
@@ -19,9 +36,26 @@ impl Delete {
 }
 
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeletePrefix {
+    pub prefix: String, // Every key that starts with this is deleted
+    pub noreply: bool,
+}
+
+impl DeletePrefix {
+    pub fn new(prefix: &str, noreply: bool) -> DeletePrefix {
+        DeletePrefix {
+            prefix: prefix.to_string(),
+            noreply: noreply,
+        }
+    }
+}
+
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct FlushAll {
     pub exptime: Option<u32>, // Relative (secs) or absolute (unixtime) expiry time
+    pub namespace: Option<String>, // Only flush the items in this namespace
     pub noreply: bool, // Indicates whether the server should reply to the flush
 }
 
@@ -29,9 +63,15 @@ impl FlushAll {
     pub fn new(exptime: Option<u32>, noreply: bool) -> FlushAll {
         FlushAll {
             exptime: exptime,
+            namespace: None,
             noreply: noreply,
         }
     }
+
+    pub fn with_namespace(&mut self, namespace: &str) -> &mut Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
 }
 
 
@@ -72,11 +112,48 @@ pub enum IncInstr {
 }
 
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum HashFieldInstr {
+    Get, // Retrieve the value of a field
+    Set, // Store the value of a field
+    Delete, // Remove a field
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HashField {
+    pub instr: HashFieldInstr, // Instruction to perform
+    pub key: String,
+    pub field: String,
+    pub data: Vec<u8>, // Only for Set
+    pub noreply: bool,
+}
+
+impl HashField {
+    pub fn new(instr: HashFieldInstr,
+               key: &str,
+               field: &str,
+               data: Vec<u8>,
+               noreply: bool)
+               -> HashField {
+        HashField {
+            instr: instr,
+            key: key.to_string(),
+            field: field.to_string(),
+            data: data,
+            noreply: noreply,
+        }
+    }
+}
+
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Inc {
     pub instr: IncInstr, // Instruction to perform
     pub key: String,
     pub delta: u64,
+    pub initial: Option<u64>, // Creates the key if it's missing
+    pub exptime: u32, // For the key created from initial
     pub noreply: bool,
 }
 
@@ -86,9 +163,139 @@ impl Inc {
             instr: instr,
             key: key.to_string(),
             delta: delta,
+            initial: None,
+            exptime: 0,
+            noreply: noreply,
+        }
+    }
+
+    pub fn with_initial(&mut self, initial: u64) -> &mut Self {
+        self.initial = Some(initial);
+        self
+    }
+
+    pub fn with_exptime(&mut self, exptime: u32) -> &mut Self {
+        self.exptime = exptime;
+        self
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct InvalidateTag {
+    pub tag: String,
+    pub noreply: bool,
+}
+
+impl InvalidateTag {
+    pub fn new(tag: &str, noreply: bool) -> InvalidateTag {
+        InvalidateTag {
+            tag: tag.to_string(),
+            noreply: noreply,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LeaseGet {
+    pub key: String,
+}
+
+impl LeaseGet {
+    pub fn new(key: &str) -> LeaseGet {
+        LeaseGet { key: key.to_string() }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ListSide {
+    Left, // The head of the list
+    Right, // The tail of the list
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListPop {
+    pub side: ListSide,
+    pub key: String,
+}
+
+impl ListPop {
+    pub fn new(side: ListSide, key: &str) -> ListPop {
+        ListPop {
+            side: side,
+            key: key.to_string(),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListPush {
+    pub side: ListSide,
+    pub key: String,
+    pub data: Vec<u8>,
+    pub max_len: Option<usize>, // Trim the other side to this many items
+    pub noreply: bool,
+}
+
+impl ListPush {
+    pub fn new(side: ListSide,
+               key: &str,
+               data: Vec<u8>,
+               noreply: bool)
+               -> ListPush {
+        ListPush {
+            side: side,
+            key: key.to_string(),
+            data: data,
+            max_len: None,
             noreply: noreply,
         }
     }
+
+    pub fn with_max_len(&mut self, max_len: usize) -> &mut Self {
+        self.max_len = Some(max_len);
+        self
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListRange {
+    pub key: String,
+    pub start: i64, // Negative counts from the end
+    pub stop: i64, // Included in the range
+}
+
+impl ListRange {
+    pub fn new(key: &str, start: i64, stop: i64) -> ListRange {
+        ListRange {
+            key: key.to_string(),
+            start: start,
+            stop: stop,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scan {
+    pub cursor: u64, // 0 to start a new scan
+    pub prefix: String, // Only list keys that start with this
+    pub count: Option<usize>, // Check up to this many keys
+}
+
+impl Scan {
+    pub fn new(cursor: u64, prefix: &str, count: Option<usize>) -> Scan {
+        Scan {
+            cursor: cursor,
+            prefix: prefix.to_string(),
+            count: count,
+        }
+    }
 }
 
 
@@ -100,6 +307,7 @@ pub enum SetInstr {
     Append, // Append the data for an existing item
     Prepend, // Prepend the data for an existing item
     Cas, // Compare and swap
+    LeaseSet, // Store only with a valid lease token
 }
 
 
@@ -109,8 +317,10 @@ pub struct Set {
     pub key: String, // Alphanumeric characters
     pub flags: u16, // Arbitrary bit pattern chosen by the client
     pub exptime: u32, // Relative (secs) or absolute (unixtime) expiry time
-    pub data: Vec<u8>, // Binary data
+    pub data: Chunks, // Binary data, read in chunks so big values are too
     pub cas_unique: Option<u64>, // Client cookie used for conditional updates
+    pub lease_token: Option<u64>, // Token handed out by a lease get
+    pub tags: Vec<String>, // Invalidating any of these invalidates the item
     pub noreply: bool, // Indicates whether the server should reply to the set
 }
 
@@ -127,8 +337,10 @@ impl Set {
             key: key.to_string(),
             flags: flags,
             exptime: exptime,
-            data: data,
+            data: Chunks::new(data),
             cas_unique: None,
+            lease_token: None,
+            tags: vec![],
             noreply: noreply,
         }
     }
@@ -137,6 +349,64 @@ impl Set {
         self.cas_unique = Some(cas_unique);
         self
     }
+
+    pub fn with_lease_token(&mut self, lease_token: u64) -> &mut Self {
+        self.lease_token = Some(lease_token);
+        self
+    }
+
+    pub fn with_tags(&mut self, tags: Vec<String>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SetMemberInstr {
+    Add, // Add a member to the set
+    Remove, // Remove a member from the set
+    IsMember, // Check whether it's a member of the set
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SetMember {
+    pub instr: SetMemberInstr, // Instruction to perform
+    pub key: String,
+    pub member: String,
+    pub noreply: bool,
+}
+
+impl SetMember {
+    pub fn new(instr: SetMemberInstr,
+               key: &str,
+               member: &str,
+               noreply: bool)
+               -> SetMember {
+        SetMember {
+            instr: instr,
+            key: key.to_string(),
+            member: member.to_string(),
+            noreply: noreply,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SlowLogInstr {
+    Get(Option<usize>), // the slowest commands, up to this many, newest first
+    Reset, // forget the commands logged so far
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StatsInstr {
+    General, // the general purpose stats
+    Commands, // per command counts, outcomes, latency and sizes
+    HotKeys(Option<usize>), // the most accessed keys, up to this many
+    Namespace(String), // the stats of one namespace
 }
 
 
@@ -181,11 +451,15 @@ pub struct Value {
     pub key: String,
     pub flags: u16,
     pub cas_unique: Option<u64>,
-    pub data: Vec<u8>,
+    pub data: Chunks, // handed to the transport as it is stored
 }
 
 impl Value {
     pub fn new(key: &str, flags: u16, data: Vec<u8>) -> Value {
+        Value::from_chunks(key, flags, Chunks::new(data))
+    }
+
+    pub fn from_chunks(key: &str, flags: u16, data: Chunks) -> Value {
         Value {
             key: key.to_string(),
             flags: flags,
@@ -203,38 +477,217 @@ impl Value {
 
 // High level groupings
 
+/// A command for the cache, as parsed from a client or built by the host
+/// process to run on a `CacheHandle`
 #[derive(Debug, PartialEq, Clone)]
 pub enum Cmd {
+    DebugTime(DebugTime),
     Delete(Delete),
+    DeletePrefix(DeletePrefix),
     FlushAll(FlushAll),
     Get(Get),
+    HashField(HashField),
     Inc(Inc),
+    InvalidateTag(InvalidateTag),
+    LeaseGet(LeaseGet),
+    ListPop(ListPop),
+    ListPush(ListPush),
+    ListRange(ListRange),
     Quit,
+    Scan(Scan),
     Set(Set),
-    Stats,
+    SetMember(SetMember),
+    SlowLog(SlowLogInstr),
+    Stats(StatsInstr),
     Touch(Touch),
+    /// Binds the connection to the namespace
+    UseNamespace(String),
     Version,
 }
 
+impl Cmd {
+    /// The command as clients know it
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            Cmd::DebugTime(_) => "debugtime",
+            Cmd::Delete(_) => "delete",
+            Cmd::DeletePrefix(_) => "delete_prefix",
+            Cmd::FlushAll(ref flush_all) => {
+                match flush_all.namespace {
+                    Some(_) => "flush_namespace",
+                    None => "flush_all",
+                }
+            }
+            Cmd::Get(ref get) => {
+                match get.instr {
+                    GetInstr::Get => "get",
+                    GetInstr::Gets => "gets",
+                }
+            }
+            Cmd::HashField(ref hash_field) => {
+                match hash_field.instr {
+                    HashFieldInstr::Get => "hget",
+                    HashFieldInstr::Set => "hset",
+                    HashFieldInstr::Delete => "hdel",
+                }
+            }
+            Cmd::Inc(ref inc) => {
+                match inc.instr {
+                    IncInstr::Incr => "incr",
+                    IncInstr::Decr => "decr",
+                }
+            }
+            Cmd::InvalidateTag(_) => "invalidate_tag",
+            Cmd::LeaseGet(_) => "lease_get",
+            Cmd::ListPop(ref list_pop) => {
+                match list_pop.side {
+                    ListSide::Left => "lpop",
+                    ListSide::Right => "rpop",
+                }
+            }
+            Cmd::ListPush(ref list_push) => {
+                match list_push.side {
+                    ListSide::Left => "lpush",
+                    ListSide::Right => "rpush",
+                }
+            }
+            Cmd::ListRange(_) => "lrange",
+            Cmd::Quit => "quit",
+            Cmd::Scan(_) => "scan",
+            Cmd::Set(ref set) => {
+                match set.instr {
+                    SetInstr::Set => "set",
+                    SetInstr::Add => "add",
+                    SetInstr::Replace => "replace",
+                    SetInstr::Append => "append",
+                    SetInstr::Prepend => "prepend",
+                    SetInstr::Cas => "cas",
+                    SetInstr::LeaseSet => "lease_set",
+                }
+            }
+            Cmd::SetMember(ref set_member) => {
+                match set_member.instr {
+                    SetMemberInstr::Add => "sadd",
+                    SetMemberInstr::Remove => "srem",
+                    SetMemberInstr::IsMember => "sismember",
+                }
+            }
+            Cmd::SlowLog(_) => "slowlog",
+            Cmd::Stats(_) => "stats",
+            Cmd::Touch(_) => "touch",
+            Cmd::UseNamespace(_) => "use_namespace",
+            Cmd::Version => "version",
+        }
+    }
+
+    /// The keys the command works on, if any
+    pub fn get_keys(&self) -> Vec<&String> {
+        match *self {
+            Cmd::Delete(ref delete) => vec![&delete.key],
+            Cmd::Get(ref get) => get.keys.iter().collect(),
+            Cmd::HashField(ref hash_field) => vec![&hash_field.key],
+            Cmd::Inc(ref inc) => vec![&inc.key],
+            Cmd::LeaseGet(ref lease_get) => vec![&lease_get.key],
+            Cmd::ListPop(ref list_pop) => vec![&list_pop.key],
+            Cmd::ListPush(ref list_push) => vec![&list_push.key],
+            Cmd::ListRange(ref list_range) => vec![&list_range.key],
+            Cmd::Set(ref set) => vec![&set.key],
+            Cmd::SetMember(ref set_member) => vec![&set_member.key],
+            Cmd::Touch(ref touch) => vec![&touch.key],
+            _ => vec![],
+        }
+    }
+
+    // Like get_keys, plus the prefixes that stand for keys
+    fn get_keys_mut(&mut self) -> Vec<&mut String> {
+        match *self {
+            Cmd::Delete(ref mut delete) => vec![&mut delete.key],
+            Cmd::DeletePrefix(ref mut delete_prefix) => {
+                vec![&mut delete_prefix.prefix]
+            }
+            Cmd::Get(ref mut get) => get.keys.iter_mut().collect(),
+            Cmd::HashField(ref mut hash_field) => vec![&mut hash_field.key],
+            Cmd::Inc(ref mut inc) => vec![&mut inc.key],
+            Cmd::LeaseGet(ref mut lease_get) => vec![&mut lease_get.key],
+            Cmd::ListPop(ref mut list_pop) => vec![&mut list_pop.key],
+            Cmd::ListPush(ref mut list_push) => vec![&mut list_push.key],
+            Cmd::ListRange(ref mut list_range) => vec![&mut list_range.key],
+            Cmd::Scan(ref mut scan) => vec![&mut scan.prefix],
+            Cmd::Set(ref mut set) => vec![&mut set.key],
+            Cmd::SetMember(ref mut set_member) => vec![&mut set_member.key],
+            Cmd::Touch(ref mut touch) => vec![&mut touch.key],
+            _ => vec![],
+        }
+    }
+
+    /// Moves the command into the namespace of a connection bound to it, as
+    /// if the client had put the namespace and the delimiter in front of
+    /// every key. A flush_all only flushes that namespace.
+    pub fn bind_namespace(&mut self, name: &str, delimiter: u8) {
+        match *self {
+            Cmd::FlushAll(ref mut flush_all) if flush_all.namespace
+                                                        .is_none() => {
+                flush_all.namespace = Some(name.to_string());
+                return;
+            }
+            _ => (),
+        }
+
+        let prefix = format!("{}{}", name, delimiter as char);
+        for key in self.get_keys_mut() {
+            key.insert_str(0, &prefix);
+        }
+    }
+
+    /// The bytes of data the client sends along
+    pub fn get_payload_len(&self) -> usize {
+        match *self {
+            Cmd::HashField(ref hash_field) => hash_field.data.len(),
+            Cmd::ListPush(ref list_push) => list_push.data.len(),
+            Cmd::Set(ref set) => set.data.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// The outcome of a command
 #[derive(Debug, PartialEq, Clone)]
 pub enum Resp {
-    // A sentinel value to indicate that there is nothing to return to the
-    // client (in case of noreply)
+    /// A sentinel value to indicate that there is nothing to return to the
+    /// client (in case of noreply)
     Empty,
 
     Error,
     ClientError(String),
     ServerError(String),
 
-    Deleted, // The item was deleted successfully
-    Exists, // The cas item has been modified
-    Ok, // FlushAll succeeded
-    NotFound, // The cas item does not exist
-    NotStored, // Precondition not met
-    Stored, // The item was stored successfully
-    Touched, // The item was touched successfully
-
-    IntValue(u64), // Result of an incr/decr
+    /// The item was deleted successfully
+    Deleted,
+    /// The cas item has been modified
+    Exists,
+    /// FlushAll, InvalidateTag or DebugTime succeeded
+    Ok,
+    /// The cas item does not exist
+    NotFound,
+    /// Precondition not met
+    NotStored,
+    /// The item was stored successfully
+    Stored,
+    /// The item was touched successfully
+    Touched,
+    /// The item is being filled by another client, try again shortly
+    Wait,
+
+    /// Number of items removed by a delete_prefix
+    DeletedItems(u64),
+    /// Result of an incr/decr
+    IntValue(u64),
+    /// Cursor to continue a scan from and its keys
+    Keys(u64, Vec<String>),
+    /// Token for filling the item that was missed
+    Lease(u64),
+    /// The item is being filled, use this value meanwhile
+    Stale(Value),
     Stats(Vec<Stat>),
     Values(Vec<Value>),
 
@@ -262,4 +715,41 @@ impl Resp {
             _ => None,
         }
     }
+
+    /// Takes the namespace back off the keys handed to a client that is
+    /// bound to it, see `Cmd::bind_namespace`
+    pub fn unbind_namespace(&mut self, name: &str, delimiter: u8) {
+        let prefix = format!("{}{}", name, delimiter as char);
+        let strip = |key: &mut String| {
+            if key.starts_with(&prefix) {
+                key.drain(..prefix.len());
+            }
+        };
+
+        match *self {
+            Resp::Keys(_, ref mut keys) => {
+                for key in keys.iter_mut() {
+                    strip(key);
+                }
+            }
+            Resp::Stale(ref mut value) => strip(&mut value.key),
+            Resp::Values(ref mut values) => {
+                for value in values.iter_mut() {
+                    strip(&mut value.key);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// The bytes of data the client gets back
+    pub fn get_payload_len(&self) -> usize {
+        match *self {
+            Resp::Stale(ref value) => value.data.len(),
+            Resp::Values(ref values) => {
+                values.iter().map(|value| value.data.len()).sum()
+            }
+            _ => 0,
+        }
+    }
 }
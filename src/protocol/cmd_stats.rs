@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use metrics::Duration;
+use metrics::LogHistogram;
+
+use super::cmd::Resp;
+use super::cmd::Stat;
+
+
+// Upper bounds (in bytes) of the payload size buckets, anything bigger goes
+// in the last one
+pub const SIZE_BUCKETS: &'static [(usize, &'static str)] = &[(64, "64"),
+                                                             (256, "256"),
+                                                             (1024, "1k"),
+                                                             (4096, "4k"),
+                                                             (16384, "16k"),
+                                                             (65536, "64k"),
+                                                             (262144, "256k"),
+                                                             (1048576, "1m")];
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Outcome {
+    Hit,
+    Miss,
+    Stored,
+    NotStored,
+    Error,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Outcome::Hit => "hit",
+            Outcome::Miss => "miss",
+            Outcome::Stored => "stored",
+            Outcome::NotStored => "not_stored",
+            Outcome::Error => "error",
+        }
+    }
+
+    // What the response says about the command, None if it's neither here
+    // nor there (like a version or an ok)
+    pub fn of(resp: &Resp) -> Option<Outcome> {
+        match *resp {
+            Resp::Values(ref values) => {
+                match values.is_empty() {
+                    true => Some(Outcome::Miss),
+                    false => Some(Outcome::Hit),
+                }
+            }
+            Resp::Stale(_) => Some(Outcome::Hit),
+            Resp::Lease(_) => Some(Outcome::Miss),
+            Resp::Wait => Some(Outcome::Miss),
+            Resp::NotFound => Some(Outcome::Miss),
+            Resp::Deleted => Some(Outcome::Hit),
+            Resp::Touched => Some(Outcome::Hit),
+            Resp::IntValue(_) => Some(Outcome::Hit),
+            Resp::Stored => Some(Outcome::Stored),
+            Resp::NotStored => Some(Outcome::NotStored),
+            Resp::Exists => Some(Outcome::NotStored),
+            Resp::Error => Some(Outcome::Error),
+            Resp::ClientError(_) => Some(Outcome::Error),
+            Resp::ServerError(_) => Some(Outcome::Error),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct CommandCounters {
+    count: u64,
+    hits: u64,
+    misses: u64,
+    stored: u64,
+    not_stored: u64,
+    errors: u64,
+    latency: LogHistogram,
+    sizes: Vec<u64>, // per size bucket, one more than there are bounds
+}
+
+impl CommandCounters {
+    fn new() -> CommandCounters {
+        CommandCounters {
+            count: 0,
+            hits: 0,
+            misses: 0,
+            stored: 0,
+            not_stored: 0,
+            errors: 0,
+            latency: LogHistogram::new(),
+            sizes: vec![0; SIZE_BUCKETS.len() + 1],
+        }
+    }
+}
+
+
+// Counts every command by name since we started: how often it ran, how it
+// turned out, how long it took and how much data it carried
+#[derive(Debug, Clone)]
+pub struct CommandStats {
+    commands: BTreeMap<&'static str, CommandCounters>, // sorted by name
+}
+
+impl CommandStats {
+    pub fn new() -> CommandStats {
+        CommandStats { commands: BTreeMap::new() }
+    }
+
+
+    // payload is the bytes of data sent or received, 0 if none. keys is
+    // how many keys a get asked for: it hits or misses once per key, so
+    // partial misses show.
+    pub fn record(&mut self,
+                  name: &'static str,
+                  resp: &Resp,
+                  duration: Duration,
+                  payload: usize,
+                  keys: usize) {
+        let counters = self.commands
+                           .entry(name)
+                           .or_insert_with(CommandCounters::new);
+
+        counters.count += 1;
+        match (Outcome::of(resp), resp) {
+            (Some(_), &Resp::Values(ref values)) if keys > 1 => {
+                let hits = values.len().min(keys);
+                counters.hits += hits as u64;
+                counters.misses += (keys - hits) as u64;
+            }
+            (Some(Outcome::Hit), _) => counters.hits += 1,
+            (Some(Outcome::Miss), _) => counters.misses += 1,
+            (Some(Outcome::Stored), _) => counters.stored += 1,
+            (Some(Outcome::NotStored), _) => counters.not_stored += 1,
+            (Some(Outcome::Error), _) => counters.errors += 1,
+            (None, _) => (),
+        }
+        counters.latency.record(duration);
+
+        if payload > 0 {
+            let pos = SIZE_BUCKETS.iter()
+                                  .position(|&(bound, _)| payload <= bound)
+                                  .unwrap_or(SIZE_BUCKETS.len());
+            counters.sizes[pos] += 1;
+        }
+    }
+
+    // The lines of stats commands:
+    //
+    // get:count 3
+    // get:hit 2
+    // ...
+    // get:size_le_64 1
+    pub fn get_stats(&self) -> Vec<Stat> {
+        let mut stats = vec![];
+
+        for (name, counters) in self.commands.iter() {
+            let mut add = |key: &str, value: String| {
+                stats.push(Stat::new(&format!("{}:{}", name, key), value));
+            };
+
+            add("count", counters.count.to_string());
+            add("hit", counters.hits.to_string());
+            add("miss", counters.misses.to_string());
+            add("stored", counters.stored.to_string());
+            add("not_stored", counters.not_stored.to_string());
+            add("error", counters.errors.to_string());
+
+            let latency = &counters.latency;
+            add("avg_us", format_micros(latency.get_mean()));
+            add("p50_us", format_micros(latency.get_quantile(0.5)));
+            add("p99_us", format_micros(latency.get_quantile(0.99)));
+            add("max_us", format_micros(latency.get_max()));
+
+            // Only commands that carry data have sizes
+            if counters.sizes.iter().all(|count| *count == 0) {
+                continue;
+            }
+            for (i, count) in counters.sizes.iter().enumerate() {
+                let key = match SIZE_BUCKETS.get(i) {
+                    Some(&(_, label)) => format!("size_le_{}", label),
+                    None => "size_large".to_string(),
+                };
+                add(&key, count.to_string());
+            }
+        }
+
+        stats
+    }
+}
+
+
+fn format_micros(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => format!("{:.1}", duration * 1e6),
+        None => "0".to_string(),
+    }
+}
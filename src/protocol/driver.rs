@@ -1,29 +1,72 @@
+use std::collections::HashMap;
+
 use common::consts::get_version_string;
+use persistence::Change;
+use persistence::Edit;
+use persistence::MutationLog;
+use persistence::PersistenceResult;
 use platform::process::get_pid;
 use platform::time::time_now;
 use storage::Cache;
 use storage::CacheError;
+use storage::Collection;
 use storage::Key;
+use storage::ListEnd;
 use storage::Value;
 use tcp_transport::stats::TransportStats;
 
 use super::cmd::Cmd;
+use super::cmd::DebugTime;
 use super::cmd::Delete;
+use super::cmd::DeletePrefix;
 use super::cmd::FlushAll;
 use super::cmd::Get;
 use super::cmd::GetInstr;
+use super::cmd::HashField;
+use super::cmd::HashFieldInstr;
 use super::cmd::Inc;
 use super::cmd::IncInstr;
+use super::cmd::InvalidateTag;
+use super::cmd::LeaseGet;
+use super::cmd::ListPop;
+use super::cmd::ListPush;
+use super::cmd::ListRange;
+use super::cmd::ListSide;
 use super::cmd::Resp;
+use super::cmd::Scan;
 use super::cmd::Set;
 use super::cmd::SetInstr;
+use super::cmd::SetMember;
+use super::cmd::SetMemberInstr;
+use super::cmd::SlowLogInstr;
 use super::cmd::Stat;
+use super::cmd::StatsInstr;
 use super::cmd::Touch;
 use super::cmd::Value as CmdValue;
-use super::util::bytes_to_u64;
+use super::cmd_stats::CommandStats;
+use super::hotkeys::Access;
+use super::hotkeys::HOT_KEYS_TOP;
+use super::hotkeys::HOT_KEYS_TRACKED;
+use super::hotkeys::HOT_KEYS_WINDOW;
+use super::hotkeys::HotKeys;
+use super::leases::LEASE_TTL;
+use super::leases::LeaseGrant;
+use super::leases::Leases;
+use super::slowlog::SLOW_LOG_MAX_LEN;
+use super::slowlog::SLOW_LOG_THRESHOLD;
+use super::slowlog::SLOW_LOG_TOP;
+use super::slowlog::SlowLog;
 use super::util::convert_exptime;
 use super::util::from_cache_err;
-use super::util::u64_to_bytes;
+
+
+// Keys checked by a scan when the client doesn't say, and the most it may
+// ask for, so that a single scan never holds up other commands for long
+const SCAN_COUNT_DEFAULT: usize = 10;
+const SCAN_COUNT_MAX: usize = 1000;
+
+// The window (in seconds) that the crawler rate is measured over
+const CRAWLER_RATE_WINDOW: f64 = 60.0;
 
 
 // For use to get an early exit from a function. The first parameter is a bool
@@ -92,6 +135,152 @@ macro_rules! maybe_reply_expr {
 }
 
 
+// What a mutating command may have changed, so that its effect can be
+// recorded in the mutation log once it has been applied, and so that it can
+// be counted towards the hot keys
+enum MutationTarget {
+    Key(Key),
+    Namespace(String),
+    Prefix(Vec<u8>),
+    Tag(String),
+    All,
+}
+
+impl MutationTarget {
+    fn of(cmd: &Cmd) -> Option<MutationTarget> {
+        let key = match *cmd {
+            Cmd::Delete(ref delete) => &delete.key,
+            Cmd::Inc(ref inc) => &inc.key,
+            Cmd::Set(ref set) => &set.key,
+            Cmd::Touch(ref touch) => &touch.key,
+            Cmd::HashField(ref hash_field) if hash_field.instr !=
+                                              HashFieldInstr::Get => {
+                &hash_field.key
+            }
+            Cmd::ListPop(ref list_pop) => &list_pop.key,
+            Cmd::ListPush(ref list_push) => &list_push.key,
+            Cmd::SetMember(ref set_member) if set_member.instr !=
+                                              SetMemberInstr::IsMember => {
+                &set_member.key
+            }
+            Cmd::FlushAll(ref flush_all) => {
+                return match flush_all.namespace {
+                    Some(ref name) => {
+                        Some(MutationTarget::Namespace(name.clone()))
+                    }
+                    None => Some(MutationTarget::All),
+                };
+            }
+            Cmd::DeletePrefix(ref delete_prefix) => {
+                let prefix = delete_prefix.prefix.clone().into_bytes();
+                return Some(MutationTarget::Prefix(prefix));
+            }
+            Cmd::InvalidateTag(ref invalidate_tag) => {
+                return Some(MutationTarget::Tag(invalidate_tag.tag.clone()));
+            }
+            _ => return None,
+        };
+
+        Some(MutationTarget::Key(Key::new(key.clone().into_bytes())))
+    }
+}
+
+
+// Commands that edit the value under their key, which the mutation log
+// records as the edit rather than the whole value
+enum KeyEdit {
+    Append(Vec<u8>),
+    Prepend(Vec<u8>),
+    Touch,
+    Counter,
+    ListPush(ListEnd, Vec<u8>, Option<usize>),
+    ListPop(ListEnd),
+    SetAdd(Vec<u8>),
+    SetRemove(Vec<u8>),
+    HashSet(Vec<u8>, Vec<u8>),
+    HashDelete(Vec<u8>),
+}
+
+impl KeyEdit {
+    fn of(cmd: &Cmd) -> Option<KeyEdit> {
+        match *cmd {
+            Cmd::Set(ref set) if set.instr == SetInstr::Append => {
+                Some(KeyEdit::Append(set.data.to_vec()))
+            }
+            Cmd::Set(ref set) if set.instr == SetInstr::Prepend => {
+                Some(KeyEdit::Prepend(set.data.to_vec()))
+            }
+            Cmd::Touch(_) => Some(KeyEdit::Touch),
+            Cmd::Inc(_) => Some(KeyEdit::Counter),
+            Cmd::ListPush(ref list_push) => {
+                Some(KeyEdit::ListPush(get_list_end(&list_push.side),
+                                       list_push.data.clone(),
+                                       list_push.max_len))
+            }
+            Cmd::ListPop(ref list_pop) => {
+                Some(KeyEdit::ListPop(get_list_end(&list_pop.side)))
+            }
+            Cmd::SetMember(ref set_member) => {
+                let member = set_member.member.clone().into_bytes();
+                match set_member.instr {
+                    SetMemberInstr::Add => Some(KeyEdit::SetAdd(member)),
+                    SetMemberInstr::Remove => {
+                        Some(KeyEdit::SetRemove(member))
+                    }
+                    SetMemberInstr::IsMember => None,
+                }
+            }
+            Cmd::HashField(ref hash_field) => {
+                let field = hash_field.field.clone().into_bytes();
+                match hash_field.instr {
+                    HashFieldInstr::Set => {
+                        Some(KeyEdit::HashSet(field, hash_field.data.clone()))
+                    }
+                    HashFieldInstr::Delete => {
+                        Some(KeyEdit::HashDelete(field))
+                    }
+                    HashFieldInstr::Get => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Describes the edit for the log, given the value it left behind
+    fn into_edit(self, prev_cas_id: u64, value: &Value) -> Option<Edit> {
+        let change = match self {
+            KeyEdit::Append(data) => Change::Append(data),
+            KeyEdit::Prepend(data) => Change::Prepend(data),
+            KeyEdit::Touch => Change::Touch,
+            KeyEdit::Counter => {
+                match value.get_counter() {
+                    Some(num) => Change::Counter(num),
+                    None => return None,
+                }
+            }
+            KeyEdit::ListPush(end, item, max_len) => {
+                Change::ListPush(end, item, max_len)
+            }
+            KeyEdit::ListPop(end) => Change::ListPop(end),
+            KeyEdit::SetAdd(member) => Change::SetAdd(member),
+            KeyEdit::SetRemove(member) => Change::SetRemove(member),
+            KeyEdit::HashSet(field, item) => Change::HashSet(field, item),
+            KeyEdit::HashDelete(field) => Change::HashDelete(field),
+        };
+
+        Some(Edit {
+            change: change,
+            prev_cas_id: prev_cas_id,
+            cas_id: *value.get_cas_id(),
+            flags: *value.get_flags(),
+            exptime: *value.get_exptime(),
+            atime: *value.get_atime(),
+        })
+    }
+}
+
+
+#[derive(Clone)]
 struct DriverStats {
     cmd_get: u64,
     cmd_set: u64,
@@ -106,6 +295,10 @@ struct DriverStats {
     cas_badval: u64,
     touch_misses: u64,
     touch_hits: u64,
+    lease_grants: u64,
+    lease_waits: u64,
+    lease_stale: u64,
+    lease_rejects: u64,
 }
 
 impl DriverStats {
@@ -124,33 +317,303 @@ impl DriverStats {
             cas_badval: 0,
             touch_hits: 0,
             touch_misses: 0,
+            lease_grants: 0,
+            lease_waits: 0,
+            lease_stale: 0,
+            lease_rejects: 0,
         }
     }
+
+    // Adds what changed between two snapshots of some other stats
+    fn add_delta(&mut self, before: &DriverStats, after: &DriverStats) {
+        self.cmd_get += after.cmd_get - before.cmd_get;
+        self.cmd_set += after.cmd_set - before.cmd_set;
+        self.cmd_flush += after.cmd_flush - before.cmd_flush;
+        self.cmd_touch += after.cmd_touch - before.cmd_touch;
+        self.incr_hits += after.incr_hits - before.incr_hits;
+        self.incr_misses += after.incr_misses - before.incr_misses;
+        self.decr_hits += after.decr_hits - before.decr_hits;
+        self.decr_misses += after.decr_misses - before.decr_misses;
+        self.cas_misses += after.cas_misses - before.cas_misses;
+        self.cas_hits += after.cas_hits - before.cas_hits;
+        self.cas_badval += after.cas_badval - before.cas_badval;
+        self.touch_hits += after.touch_hits - before.touch_hits;
+        self.touch_misses += after.touch_misses - before.touch_misses;
+        self.lease_grants += after.lease_grants - before.lease_grants;
+        self.lease_waits += after.lease_waits - before.lease_waits;
+        self.lease_stale += after.lease_stale - before.lease_stale;
+        self.lease_rejects += after.lease_rejects - before.lease_rejects;
+    }
+}
+
+
+// Measures the rate the crawler checks items at over a sliding window, the
+// same way HotKeys does for accesses
+struct CrawlerRate {
+    window_start: f64, // unixtime
+    current: u64, // items checked in the current window
+    previous: u64, // ...and in the one before
+}
+
+impl CrawlerRate {
+    fn new(now: f64) -> CrawlerRate {
+        CrawlerRate {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn record(&mut self, items_checked: u64, now: f64) {
+        let elapsed = now - self.window_start;
+        if elapsed >= 2.0 * CRAWLER_RATE_WINDOW {
+            self.previous = 0;
+            self.current = 0;
+            self.window_start = now;
+        } else if elapsed >= CRAWLER_RATE_WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += CRAWLER_RATE_WINDOW;
+        }
+
+        self.current += items_checked;
+    }
+
+    // Items checked per second over the last window length
+    fn get(&self, now: f64) -> f64 {
+        let elapsed = now - self.window_start;
+        let (current, previous, elapsed) = if elapsed < CRAWLER_RATE_WINDOW {
+            (self.current, self.previous, elapsed)
+        } else if elapsed < 2.0 * CRAWLER_RATE_WINDOW {
+            (0, self.current, elapsed - CRAWLER_RATE_WINDOW)
+        } else {
+            return 0.0;
+        };
+
+        // How much of the previous window still falls within the last window
+        // length
+        let overlap = (1.0 - elapsed / CRAWLER_RATE_WINDOW).max(0.0);
+        (current as f64 + previous as f64 * overlap) / CRAWLER_RATE_WINDOW
+    }
 }
 
 
 pub struct Driver {
     cache: Cache,
     time_start: f64,
+    debug_time: bool, // whether clients may shift the time of the cache
+    crawler_rate: CrawlerRate,
+
+    log: Option<MutationLog>, // records every mutation if enabled
+    hot_keys: Option<HotKeys>, // tracks the most accessed keys if enabled
+    leases: Leases, // keys that are being filled after a miss
+    slow_log: SlowLog, // filled by the transports
 
     stats: DriverStats,
+    command_stats: CommandStats, // per command, since we started
+    namespace_stats: HashMap<String, DriverStats>,
     transport_stats: TransportStats, // this is a global snapshot
 }
 
 impl Driver {
     pub fn new(cache: Cache) -> Driver {
+        let now = cache.now();
+
         Driver {
             cache: cache,
+            command_stats: CommandStats::new(),
+            crawler_rate: CrawlerRate::new(now),
+            debug_time: false,
+            hot_keys: Some(HotKeys::new(HOT_KEYS_TRACKED,
+                                        HOT_KEYS_WINDOW,
+                                        now)),
+            leases: Leases::new(LEASE_TTL),
+            log: None,
+            namespace_stats: HashMap::new(),
+            slow_log: SlowLog::new(SLOW_LOG_THRESHOLD, SLOW_LOG_MAX_LEN),
             stats: DriverStats::new(),
-            time_start: time_now(),
+            time_start: now,
             transport_stats: TransportStats::new(),
         }
     }
 
 
 
+    // Lets clients shift the time of the cache with debugtime, eg. so that
+    // tests can let items expire without waiting. Never enable this on a
+    // server that's in use.
+    pub fn with_debug_time(&mut self, enabled: bool) -> &mut Driver {
+        self.debug_time = enabled;
+        self
+    }
+
+    pub fn with_hot_keys(&mut self, enabled: bool) -> &mut Driver {
+        self.hot_keys = match enabled {
+            true => {
+                Some(HotKeys::new(HOT_KEYS_TRACKED,
+                                  HOT_KEYS_WINDOW,
+                                  self.cache.now()))
+            }
+            false => None,
+        };
+        self
+    }
+
+    // Lists the commands in the log for slowlog get, the log is meant to be
+    // shared with whoever times the commands
+    pub fn with_slow_log(&mut self, slow_log: SlowLog) -> &mut Driver {
+        self.slow_log = slow_log;
+        self
+    }
+
+    pub fn with_mutation_log(&mut self, log: MutationLog) -> &mut Driver {
+        self.log = Some(log);
+        self
+    }
+
+    pub fn get_mutation_log_mut(&mut self) -> Option<&mut MutationLog> {
+        self.log.as_mut()
+    }
+
+
+    fn record_hot_key(&mut self, key: &Key, access: Access) {
+        match self.hot_keys {
+            Some(ref mut hot_keys) => {
+                hot_keys.record(key, access, self.cache.now())
+            }
+            None => (),
+        }
+    }
+
+    // The namespaces a command works on, so that it can be counted towards
+    // their stats as well
+    fn get_cmd_namespaces(&self, cmd: &Cmd) -> Vec<String> {
+        let keys = match *cmd {
+            Cmd::FlushAll(ref flush_all) => {
+                return match flush_all.namespace {
+                    Some(ref name) if self.cache
+                                          .get_namespace(name)
+                                          .is_some() => vec![name.clone()],
+                    _ => vec![],
+                };
+            }
+            _ => cmd.get_keys(),
+        };
+
+        let mut names: Vec<String> = vec![];
+
+        for key in keys {
+            let key = Key::new(key.clone().into_bytes());
+            match self.cache.get_namespace_name(&key) {
+                Some(name) if !names.iter().any(|n| n == name) => {
+                    names.push(name.to_string());
+                }
+                _ => (),
+            }
+        }
+
+        names
+    }
+
+    // The leases hold on to stale values, which count towards the memory
+    // of the cache (in the namespace of their keys) as much as the values it
+    // holds itself
+    fn charge_leases(&mut self) {
+        for (key, size) in self.leases.take_charges() {
+            self.cache.charge_outside(&key, size);
+        }
+    }
+
+    // Whatever the command wrote to is no longer missing, and a client that
+    // was handed a lease on it would only overwrite it with an older value
+    fn invalidate_leases(&mut self, target: &MutationTarget) {
+        match *target {
+            MutationTarget::Key(ref key) => self.leases.invalidate(key),
+            MutationTarget::Namespace(ref name) => {
+                let cache = &self.cache;
+                self.leases.invalidate_if(|key| {
+                    cache.get_namespace_name(key) == Some(name)
+                });
+            }
+            MutationTarget::Prefix(ref prefix) => {
+                self.leases.invalidate_if(|key| key.item.starts_with(prefix));
+            }
+            // We don't keep track of which keys carry the tag
+            MutationTarget::Tag(_) => self.leases.invalidate_all(),
+            MutationTarget::All => self.leases.invalidate_all(),
+        }
+    }
+
+    // Takes the edit the command is about to make, along with the cas id of
+    // the value it's made to
+    fn get_key_edit(&self,
+                    cmd: &Cmd,
+                    target: &Option<MutationTarget>)
+                    -> Option<(KeyEdit, u64)> {
+        let key = match (&self.log, target) {
+            (&Some(_), &Some(MutationTarget::Key(ref key))) => key,
+            _ => return None,
+        };
+
+        match (KeyEdit::of(cmd), self.cache.peek_stored(key)) {
+            (Some(edit), Some(value)) => Some((edit, *value.get_cas_id())),
+            _ => None,
+        }
+    }
+
+    fn log_mutation(&mut self,
+                    target: MutationTarget,
+                    key_edit: Option<(KeyEdit, u64)>)
+                    -> PersistenceResult<()> {
+        let log = match self.log {
+            Some(ref mut log) => log,
+            None => return Ok(()),
+        };
+
+        match target {
+            MutationTarget::Key(key) => {
+                // An edit of a value that is there is recorded as such
+                let edit = match (key_edit, self.cache.peek_stored(&key)) {
+                    (Some((edit, prev_cas_id)), Some(value)) => {
+                        edit.into_edit(prev_cas_id, value)
+                    }
+                    _ => None,
+                };
+                match edit {
+                    Some(edit) => return log.append_edit(&key, &edit),
+                    None => (),
+                }
+
+                // Otherwise record the state the command left the item in
+                match self.cache.peek(&key) {
+                    Some(value) => log.append_put(&key, &value),
+                    None => log.append_remove(&key),
+                }
+            }
+            MutationTarget::Namespace(name) => {
+                match self.cache.get_namespace_flush_exptime(&name) {
+                    Some(exptime) => {
+                        log.append_flush_namespace(&name, exptime)
+                    }
+                    None => Ok(()),
+                }
+            }
+            MutationTarget::Prefix(prefix) => {
+                log.append_delete_prefix(&prefix)
+            }
+            MutationTarget::Tag(tag) => {
+                let version = self.cache.get_tag_version(&tag);
+                let floors = self.cache.take_raised_tag_floors();
+                log.append_invalidate_tag(&tag, version, &floors)
+            }
+            MutationTarget::All => {
+                log.append_flush_all(self.cache.get_flush_exptime())
+            }
+        }
+    }
+
     fn set_exptime(&self, value: &mut Value, exptime: u32) {
-        match convert_exptime(exptime) {
+        match convert_exptime(exptime, self.cache.now()) {
             Some(tm) => {
                 value.set_exptime(tm);
             }
@@ -171,8 +634,9 @@ impl Driver {
                               Err(ref err) => Some(from_cache_err(err)),
                           });
 
-        let mut value = Value::new(set.data);
+        let mut value = Value::from_chunks(set.data);
         value.set_flags(set.flags);
+        value.set_tags(set.tags);
         self.set_exptime(&mut value, set.exptime);
 
         let rv = self.cache.set(key, value);
@@ -187,6 +651,16 @@ impl Driver {
     fn do_append(&mut self, set: Set) -> Resp {
         let key = Key::new(set.key.into_bytes());
 
+        // Collections have no data to append to
+        let is_collection = self.cache.holds_collection(&key);
+        maybe_reply_stmt!(!set.noreply,
+                          match is_collection {
+                              true => {
+                                  Some(from_cache_err(&CacheError::WrongType))
+                              }
+                              false => None,
+                          });
+
         // Load the value
         let rv = self.cache.remove(&key);
 
@@ -206,10 +680,7 @@ impl Driver {
         self.set_exptime(&mut value, set.exptime);
 
         // Append the data we just received to the blob that is there
-        {
-            let mut blob = value.get_item_mut();
-            blob.extend(set.data);
-        }
+        value.append_item(set.data);
 
         let rv = self.cache.set(key, value);
 
@@ -265,6 +736,7 @@ impl Driver {
         // Set all the data the client sent
         value.set_item(set.data);
         value.set_flags(set.flags);
+        value.set_tags(set.tags);
         self.set_exptime(&mut value, set.exptime);
 
         let rv = self.cache.set(key, value);
@@ -289,16 +761,29 @@ impl Driver {
                           })
     }
 
+    fn do_delete_prefix(&mut self, delete_prefix: DeletePrefix) -> Resp {
+        let prefix = delete_prefix.prefix.into_bytes();
+
+        let cnt = self.cache.delete_prefix(&prefix);
+
+        maybe_reply_expr!(!delete_prefix.noreply, Resp::DeletedItems(cnt))
+    }
+
     fn do_flush_all(&mut self, flush_all: FlushAll) -> Resp {
         // Update stats
         self.stats.cmd_flush += 1;
 
         let exptime: f64 = match flush_all.exptime {
-            Some(ref exptime) => convert_exptime(*exptime).unwrap(),
-            None => time_now(),
+            Some(ref exptime) => {
+                convert_exptime(*exptime, self.cache.now()).unwrap()
+            }
+            None => self.cache.now(),
         };
 
-        let rv = self.cache.flush_all(exptime);
+        let rv = match flush_all.namespace {
+            Some(ref name) => self.cache.flush_namespace(name, exptime),
+            None => self.cache.flush_all(exptime),
+        };
 
         maybe_reply_expr!(!flush_all.noreply,
                           match rv {
@@ -312,20 +797,31 @@ impl Driver {
         self.stats.cmd_get += 1;
 
         let mut values = vec![];
+        let single_key = get.keys.len() == 1;
 
         for key in get.keys {
             let key_str = key.clone();
 
             let key_st = Key::new(key.into_bytes());
+            self.record_hot_key(&key_st, Access::Read);
+
             let rv = self.cache.get(&key_st);
 
             match rv {
+                // Collections are read with their own commands. Asking for
+                // just the collection is an error, in a multi-get it's
+                // skipped so the other keys are still returned.
+                Ok(ref value) if value.is_collection() => {
+                    if single_key {
+                        return from_cache_err(&CacheError::WrongType);
+                    }
+                }
                 Ok(value) => {
                     let mut val_st = CmdValue {
                         key: key_str,
                         flags: value.get_flags().clone(),
                         cas_unique: None,
-                        data: value.get_item().clone(),
+                        data: value.get_item().into_owned(),
                     };
 
                     if get.instr == GetInstr::Gets {
@@ -342,86 +838,265 @@ impl Driver {
         Resp::Values(values)
     }
 
-    fn do_inc(&mut self, inc: Inc) -> Resp {
-        let key = Key::new(inc.key.clone().into_bytes());
-
-        {
-            // Check the value first
-            let rv = self.cache.get(&key);
-            maybe_reply_stmt!(!inc.noreply,
-                              match rv {
-                                  Ok(_) => {
-                                      // Update stats
-                                      match inc.instr {
-                                          IncInstr::Decr => {
-                                              self.stats.decr_hits += 1;
-                                          }
-                                          IncInstr::Incr => {
-                                              self.stats.incr_hits += 1;
-                                          }
-                                      };
-
-                                      None
-                                  }
-                                  Err(CacheError::KeyNotFound) => {
-                                      // Update stats
-                                      match inc.instr {
-                                          IncInstr::Decr => {
-                                              self.stats.decr_misses += 1;
-                                          }
-                                          IncInstr::Incr => {
-                                              self.stats.incr_misses += 1;
-                                          }
-                                      };
-
-                                      Some(Resp::NotFound)
-                                  }
-                                  Err(ref err) => Some(from_cache_err(err)),
-                              });
+    fn do_hash_field(&mut self, hash_field: HashField) -> Resp {
+        let key = Key::new(hash_field.key.clone().into_bytes());
+        let field = hash_field.field.into_bytes();
+
+        let rv = match hash_field.instr {
+            HashFieldInstr::Get => {
+                self.record_hot_key(&key, Access::Read);
+
+                let rv = self.cache.read_collection(&key, |hash| {
+                    hash.get_field(&field).map(|item| item.cloned())
+                });
+                match rv {
+                    Ok(Some(item)) => {
+                        let value = CmdValue::new(&hash_field.key, 0, item);
+                        Ok(Resp::Values(vec![value]))
+                    }
+                    Ok(None) |
+                    Err(CacheError::KeyNotFound) => Ok(Resp::Values(vec![])),
+                    Err(err) => Err(err),
+                }
+            }
+            HashFieldInstr::Set => {
+                let data = hash_field.data;
+                self.cache
+                    .update_collection(&key, Some(Collection::hash()), |hash| {
+                        hash.set_field(field, data)
+                    })
+                    .map(|_| Resp::Stored)
+            }
+            HashFieldInstr::Delete => {
+                let rv = self.cache.update_collection(&key, None, |hash| {
+                    hash.delete_field(&field)
+                });
+                match rv {
+                    Ok(true) => Ok(Resp::Deleted),
+                    Ok(false) |
+                    Err(CacheError::KeyNotFound) => Ok(Resp::NotFound),
+                    Err(err) => Err(err),
+                }
+            }
+        };
 
-            let value = rv.unwrap();
-            // Does it represent a number?
-            maybe_reply_stmt!(!inc.noreply,
-                              match bytes_to_u64(value.get_item()) {
-                                  Some(_) => None,
-                                  None => {
-                                      Some(Resp::ClientError("Not a number"
-                                                                 .to_string()))
-                                  }
-                              });
-        }
+        maybe_reply_expr!(!hash_field.noreply,
+                          match rv {
+                              Ok(resp) => resp,
+                              Err(ref err) => from_cache_err(err),
+                          })
+    }
 
-        // Update the value
-        let rv = self.cache.remove(&key);
-        let mut value = rv.unwrap();
+    fn do_inc(&mut self, inc: Inc) -> Resp {
+        let key = Key::new(inc.key.clone().into_bytes());
 
-        // Apply incr/decr
-        let mut num = bytes_to_u64(value.get_item()).unwrap();
-        match inc.instr {
+        // Apply incr/decr to the stored number in place
+        let delta = inc.delta;
+        let rv = match inc.instr {
             IncInstr::Decr => {
                 // saturates (stays at 0), does not underflow
-                num = num.saturating_sub(inc.delta);
+                self.cache
+                    .update_counter(&key, |num| num.saturating_sub(delta))
             }
             IncInstr::Incr => {
                 // overflows
-                num = num.wrapping_add(inc.delta);
+                self.cache
+                    .update_counter(&key, |num| num.wrapping_add(delta))
             }
         };
-        value.set_item(u64_to_bytes(&num));
 
-        // Set it
-        let rv = self.cache.set(key, value);
+        // Update stats
+        match rv {
+            Ok(_) |
+            Err(CacheError::NotANumber) => {
+                match inc.instr {
+                    IncInstr::Decr => self.stats.decr_hits += 1,
+                    IncInstr::Incr => self.stats.incr_hits += 1,
+                }
+            }
+            Err(CacheError::KeyNotFound) => {
+                match inc.instr {
+                    IncInstr::Decr => self.stats.decr_misses += 1,
+                    IncInstr::Incr => self.stats.incr_misses += 1,
+                }
+            }
+            Err(_) => (),
+        }
+
+        // A missing key is created if the client gave an initial value
+        let rv = match (rv, inc.initial) {
+            (Err(CacheError::KeyNotFound), Some(initial)) => {
+                let mut value = Value::empty();
+                value.set_counter(initial);
+                self.set_exptime(&mut value, inc.exptime);
+                self.cache.set(key, value).map(|_| initial)
+            }
+            (rv, _) => rv,
+        };
 
         maybe_reply_expr!(!inc.noreply,
                           match rv {
-                              Ok(_) => Resp::IntValue(num),
+                              Ok(num) => Resp::IntValue(num),
+                              Err(CacheError::KeyNotFound) => Resp::NotFound,
+                              Err(ref err) => from_cache_err(err),
+                          })
+    }
+
+    fn do_invalidate_tag(&mut self, invalidate_tag: InvalidateTag) -> Resp {
+        self.cache.invalidate_tag(&invalidate_tag.tag);
+
+        maybe_reply_expr!(!invalidate_tag.noreply, Resp::Ok)
+    }
+
+    fn do_lease_get(&mut self, lease_get: LeaseGet) -> Resp {
+        // Update stats
+        self.stats.cmd_get += 1;
+
+        let key = Key::new(lease_get.key.clone().into_bytes());
+        self.record_hot_key(&key, Access::Read);
+
+        // A dead value can still stand in while the key is being filled, so
+        // grab it before the lookup discards it. Only the client that is
+        // handed the lease leaves it behind, the others don't need a copy.
+        let stale = match self.leases.is_held(&key, self.cache.now()) {
+            true => None,
+            false => {
+                self.cache
+                    .peek_stale(&key)
+                    .filter(|value| !value.is_collection())
+                    .map(|value| value.into_owned())
+            }
+        };
+
+        match self.cache.get(&key) {
+            Ok(ref value) if value.is_collection() => {
+                return from_cache_err(&CacheError::WrongType);
+            }
+            Ok(value) => {
+                let value = CmdValue::from_chunks(&lease_get.key,
+                                                  *value.get_flags(),
+                                                  value.get_item()
+                                                       .into_owned());
+                return Resp::Values(vec![value]);
+            }
+            Err(CacheError::KeyNotFound) => (),
+            Err(ref err) => return from_cache_err(err),
+        }
+
+        match self.leases.acquire(&key, stale, self.cache.now()) {
+            LeaseGrant::Granted(token) => {
+                // Update stats
+                self.stats.lease_grants += 1;
+
+                Resp::Lease(token)
+            }
+            LeaseGrant::Stale(value) => {
+                // Update stats
+                self.stats.lease_stale += 1;
+
+                Resp::Stale(CmdValue::from_chunks(&lease_get.key,
+                                                  *value.get_flags(),
+                                                  value.get_item()
+                                                       .into_owned()))
+            }
+            LeaseGrant::Wait => {
+                // Update stats
+                self.stats.lease_waits += 1;
+
+                Resp::Wait
+            }
+        }
+    }
+
+    fn do_lease_set(&mut self, set: Set) -> Resp {
+        let key = Key::new(set.key.clone().into_bytes());
+
+        // Only the client holding the lease gets to fill the key
+        let token = set.lease_token.unwrap();
+        let is_valid = self.leases.release(&key, token, self.cache.now());
+        if !is_valid {
+            // Update stats
+            self.stats.lease_rejects += 1;
+        }
+        maybe_reply_stmt!(!set.noreply,
+                          match is_valid {
+                              true => None,
+                              false => Some(Resp::NotStored),
+                          });
+
+        self.do_set(set)
+    }
+
+    fn do_list_pop(&mut self, list_pop: ListPop) -> Resp {
+        let key = Key::new(list_pop.key.clone().into_bytes());
+        let end = get_list_end(&list_pop.side);
+
+        let rv = self.cache
+                     .update_collection(&key, None, |list| list.pop(end));
+
+        match rv {
+            Ok(Some(item)) => {
+                Resp::Values(vec![CmdValue::new(&list_pop.key, 0, item)])
+            }
+            Ok(None) |
+            Err(CacheError::KeyNotFound) => Resp::Values(vec![]),
+            Err(ref err) => from_cache_err(err),
+        }
+    }
+
+    fn do_list_push(&mut self, list_push: ListPush) -> Resp {
+        let key = Key::new(list_push.key.into_bytes());
+        let end = get_list_end(&list_push.side);
+
+        let data = list_push.data;
+        let max_len = list_push.max_len;
+        let empty = Some(Collection::list());
+        let rv = self.cache.update_collection(&key, empty, |list| {
+            list.push(end, data, max_len)
+        });
+
+        maybe_reply_expr!(!list_push.noreply,
+                          match rv {
+                              Ok(len) => Resp::IntValue(len as u64),
                               Err(ref err) => from_cache_err(err),
                           })
     }
 
+    fn do_list_range(&mut self, list_range: ListRange) -> Resp {
+        let key = Key::new(list_range.key.clone().into_bytes());
+        self.record_hot_key(&key, Access::Read);
+
+        let start = list_range.start;
+        let stop = list_range.stop;
+        let rv = self.cache
+                     .read_collection(&key, |list| list.range(start, stop));
+
+        match rv {
+            Ok(items) => {
+                Resp::Values(items.into_iter()
+                                  .map(|item| {
+                                      CmdValue::new(&list_range.key, 0, item)
+                                  })
+                                  .collect())
+            }
+            Err(CacheError::KeyNotFound) => Resp::Values(vec![]),
+            Err(ref err) => from_cache_err(err),
+        }
+    }
+
     fn do_prepend(&mut self, set: Set) -> Resp {
         let key = Key::new(set.key.into_bytes());
 
+        // Collections have no data to prepend to
+        let is_collection = self.cache.holds_collection(&key);
+        maybe_reply_stmt!(!set.noreply,
+                          match is_collection {
+                              true => {
+                                  Some(from_cache_err(&CacheError::WrongType))
+                              }
+                              false => None,
+                          });
+
         // Load the value
         let rv = self.cache.remove(&key);
 
@@ -441,11 +1116,7 @@ impl Driver {
         self.set_exptime(&mut value, set.exptime);
 
         // Prepend the data we just received to the blob that is there
-        let mut new_item = Vec::with_capacity(set.data.len() +
-                                              value.get_item().len());
-        new_item.extend(set.data);
-        new_item.extend(value.get_item());
-        value.set_item(new_item);
+        value.prepend_item(set.data);
 
         let rv = self.cache.set(key, value);
 
@@ -468,8 +1139,9 @@ impl Driver {
                               Err(ref err) => Some(from_cache_err(err)),
                           });
 
-        let mut value = Value::new(set.data);
+        let mut value = Value::from_chunks(set.data);
         value.set_flags(set.flags);
+        value.set_tags(set.tags);
         self.set_exptime(&mut value, set.exptime);
 
         let rv = self.cache.set(key, value);
@@ -481,6 +1153,27 @@ impl Driver {
                           })
     }
 
+    fn do_scan(&mut self, scan: Scan) -> Resp {
+        let count = scan.count
+                        .unwrap_or(SCAN_COUNT_DEFAULT)
+                        .min(SCAN_COUNT_MAX);
+
+        let rv = self.cache.scan(scan.cursor, scan.prefix.as_bytes(), count);
+
+        match rv {
+            Ok((cursor, keys)) => {
+                let keys = keys.iter()
+                               .map(|key| {
+                                   String::from_utf8_lossy(&key.item)
+                                       .to_string()
+                               })
+                               .collect();
+                Resp::Keys(cursor, keys)
+            }
+            Err(ref err) => from_cache_err(err),
+        }
+    }
+
     fn do_set(&mut self, set: Set) -> Resp {
         // Update stats
         self.stats.cmd_set += 1;
@@ -499,6 +1192,7 @@ impl Driver {
         // Set all the data the client sent
         value.set_item(set.data);
         value.set_flags(set.flags);
+        value.set_tags(set.tags);
         self.set_exptime(&mut value, set.exptime);
 
         let rv = self.cache.set(key, value);
@@ -510,12 +1204,63 @@ impl Driver {
                           })
     }
 
+    fn do_set_member(&mut self, set_member: SetMember) -> Resp {
+        let key = Key::new(set_member.key.into_bytes());
+        let member = set_member.member.into_bytes();
+
+        let rv = match set_member.instr {
+            SetMemberInstr::Add => {
+                self.cache
+                    .update_collection(&key, Some(Collection::set()), |set| {
+                        set.add(member)
+                    })
+                    .map(|is_added| {
+                        match is_added {
+                            true => Resp::Stored,
+                            false => Resp::NotStored,
+                        }
+                    })
+            }
+            SetMemberInstr::Remove => {
+                let rv = self.cache.update_collection(&key, None, |set| {
+                    set.remove(&member)
+                });
+                match rv {
+                    Ok(true) => Ok(Resp::Deleted),
+                    Ok(false) |
+                    Err(CacheError::KeyNotFound) => Ok(Resp::NotFound),
+                    Err(err) => Err(err),
+                }
+            }
+            SetMemberInstr::IsMember => {
+                self.record_hot_key(&key, Access::Read);
+
+                let rv = self.cache.read_collection(&key, |set| {
+                    set.contains(&member)
+                });
+                match rv {
+                    Ok(true) => Ok(Resp::IntValue(1)),
+                    Ok(false) |
+                    Err(CacheError::KeyNotFound) => Ok(Resp::IntValue(0)),
+                    Err(err) => Err(err),
+                }
+            }
+        };
+
+        maybe_reply_expr!(!set_member.noreply,
+                          match rv {
+                              Ok(resp) => resp,
+                              Err(ref err) => from_cache_err(err),
+                          })
+    }
+
     fn do_stats(&self) -> Resp {
         let storage = self.cache.get_stats();
 
         let pid = get_pid().to_string();
-        let uptime = ((time_now() - self.time_start) as u64).to_string();
-        let time = (time_now() as u64).to_string();
+        let now = self.cache.now();
+        let uptime = ((now - self.time_start) as u64).to_string();
+        let time = (now as u64).to_string();
         let version = get_version_string();
         let cmd_get = self.stats.cmd_get.to_string();
         let cmd_set = self.stats.cmd_set.to_string();
@@ -534,6 +1279,10 @@ impl Driver {
         let cas_badval = self.stats.cas_badval.to_string();
         let touch_hits = self.stats.touch_hits.to_string();
         let touch_misses = self.stats.touch_misses.to_string();
+        let lease_grants = self.stats.lease_grants.to_string();
+        let lease_waits = self.stats.lease_waits.to_string();
+        let lease_stale = self.stats.lease_stale.to_string();
+        let lease_rejects = self.stats.lease_rejects.to_string();
         let bytes_read = self.transport_stats.bytes_read.to_string();
         let bytes_written = self.transport_stats.bytes_written.to_string();
         let limit_maxbytes = self.cache.capacity.to_string();
@@ -542,6 +1291,22 @@ impl Driver {
         let total_items = storage.total_items.to_string();
         let evictions = storage.evictions.to_string();
         let reclaimed = storage.reclaimed.to_string();
+        let crawler_items_checked = storage.crawler_items_checked.to_string();
+        let crawler_reclaimed = storage.crawler_reclaimed.to_string();
+        let crawler_rate = (self.crawler_rate.get(now) as u64).to_string();
+        let compress_ratio = {
+            // How many times smaller the compressed values got
+            let ratio = match storage.compress_bytes_out {
+                0 => 1.0,
+                out => storage.compress_bytes_in as f64 / out as f64,
+            };
+            format!("{:.2}", ratio)
+        };
+        let compress_time = format!("{:.6}", storage.compress_time);
+        let decompress_time = format!("{:.6}", storage.decompress_time);
+        let delete_prefix_items = storage.delete_prefix_items.to_string();
+        let scan_items_checked = storage.scan_items_checked.to_string();
+        let tags_forgotten = self.cache.get_tags_forgotten().to_string();
 
         let st_pid = Stat::new("pid", pid);
         let st_uptime = Stat::new("uptime", uptime);
@@ -564,6 +1329,10 @@ impl Driver {
         let st_cas_badval = Stat::new("cas_badval", cas_badval);
         let st_touch_hits = Stat::new("touch_hits", touch_hits);
         let st_touch_misses = Stat::new("touch_misses", touch_misses);
+        let st_lease_grants = Stat::new("lease_grants", lease_grants);
+        let st_lease_waits = Stat::new("lease_waits", lease_waits);
+        let st_lease_stale = Stat::new("lease_stale", lease_stale);
+        let st_lease_rejects = Stat::new("lease_rejects", lease_rejects);
         let st_bytes_read = Stat::new("bytes_read", bytes_read);
         let st_bytes_written = Stat::new("bytes_written", bytes_written);
         let st_limit_maxbytes = Stat::new("limit_maxbytes", limit_maxbytes);
@@ -572,36 +1341,210 @@ impl Driver {
         let st_total_items = Stat::new("total_items", total_items);
         let st_evictions = Stat::new("evictions", evictions);
         let st_reclaimed = Stat::new("reclaimed", reclaimed);
+        let st_crawler_items_checked = Stat::new("crawler_items_checked",
+                                                 crawler_items_checked);
+        let st_crawler_reclaimed = Stat::new("crawler_reclaimed",
+                                             crawler_reclaimed);
+        let st_crawler_rate = Stat::new("crawler_rate", crawler_rate);
+        let st_compress_ratio = Stat::new("compress_ratio", compress_ratio);
+        let st_compress_time = Stat::new("compress_time", compress_time);
+        let st_decompress_time = Stat::new("decompress_time",
+                                           decompress_time);
+        let st_delete_prefix_items = Stat::new("delete_prefix_items",
+                                               delete_prefix_items);
+        let st_scan_items_checked = Stat::new("scan_items_checked",
+                                              scan_items_checked);
+        let st_tags_forgotten = Stat::new("tags_forgotten", tags_forgotten);
+
+        let mut stats = vec![st_pid,
+                             st_uptime,
+                             st_time,
+                             st_version,
+                             st_cmd_get,
+                             st_cmd_set,
+                             st_cmd_flush,
+                             st_cmd_touch,
+                             st_get_hits,
+                             st_get_misses,
+                             st_delete_hits,
+                             st_delete_misses,
+                             st_incr_hits,
+                             st_incr_misses,
+                             st_decr_hits,
+                             st_decr_misses,
+                             st_cas_hits,
+                             st_cas_misses,
+                             st_cas_badval,
+                             st_touch_hits,
+                             st_touch_misses,
+                             st_lease_grants,
+                             st_lease_waits,
+                             st_lease_stale,
+                             st_lease_rejects,
+                             st_bytes_read,
+                             st_bytes_written,
+                             st_limit_maxbytes,
+                             st_bytes,
+                             st_curr_items,
+                             st_total_items,
+                             st_evictions,
+                             st_reclaimed,
+                             st_crawler_items_checked,
+                             st_crawler_reclaimed,
+                             st_crawler_rate,
+                             st_compress_ratio,
+                             st_compress_time,
+                             st_decompress_time,
+                             st_delete_prefix_items,
+                             st_scan_items_checked,
+                             st_tags_forgotten];
+
+        // The disk tier is only reported on if there is one
+        match self.cache.get_extstore() {
+            Some(extstore) => {
+                let ext = extstore.get_stats();
+                let ext_stats = vec![
+                    ("extstore_limit_maxbytes", extstore.get_capacity()),
+                    ("extstore_bytes", ext.bytes),
+                    ("extstore_items", ext.items),
+                    ("extstore_bytes_written", ext.bytes_written),
+                    ("extstore_bytes_read", ext.bytes_read),
+                    ("extstore_hits", ext.hits),
+                    ("extstore_misses", ext.misses),
+                    ("extstore_compactions", ext.compactions),
+                    ("extstore_dropped", ext.dropped),
+                ];
+                for (name, value) in ext_stats {
+                    stats.push(Stat::new(name, value.to_string()));
+                }
+            }
+            None => (),
+        }
+
+        Resp::Stats(stats)
+    }
+
+    fn do_slow_log_get(&self, cnt: Option<usize>) -> Resp {
+        let entries = self.slow_log.get(cnt.unwrap_or(SLOW_LOG_TOP));
+
+        let mut stats = vec![];
+
+        for entry in entries {
+            let prefix = format!("slowlog:{}", entry.id);
+            let mut add = |key: &str, value: String| {
+                stats.push(Stat::new(&format!("{}:{}", prefix, key), value));
+            };
 
-        Resp::Stats(vec![st_pid,
-                         st_uptime,
-                         st_time,
-                         st_version,
-                         st_cmd_get,
-                         st_cmd_set,
-                         st_cmd_flush,
-                         st_cmd_touch,
-                         st_get_hits,
-                         st_get_misses,
-                         st_delete_hits,
-                         st_delete_misses,
-                         st_incr_hits,
-                         st_incr_misses,
-                         st_decr_hits,
-                         st_decr_misses,
-                         st_cas_hits,
-                         st_cas_misses,
-                         st_cas_badval,
-                         st_touch_hits,
-                         st_touch_misses,
-                         st_bytes_read,
-                         st_bytes_written,
-                         st_limit_maxbytes,
-                         st_bytes,
-                         st_curr_items,
-                         st_total_items,
-                         st_evictions,
-                         st_reclaimed])
+            let mut keys = entry.keys.join(" ");
+            if entry.more_keys > 0 {
+                keys.push_str(&format!(" (+{} more)", entry.more_keys));
+            }
+
+            add("at", format!("{:.6}", entry.at));
+            add("duration_us", format_micros(entry.get_duration()));
+            add("read_us", format_micros(entry.read));
+            add("queue_us", format_micros(entry.queue));
+            add("exec_us", format_micros(entry.exec));
+            add("write_us", format_micros(entry.write));
+            add("conn", entry.conn.to_string());
+            add("cmd", entry.cmd.to_string());
+            add("keys", keys);
+            add("payload", entry.payload.to_string());
+        }
+
+        Resp::Stats(stats)
+    }
+
+    fn do_slow_log_reset(&self) -> Resp {
+        self.slow_log.reset();
+        Resp::Ok
+    }
+
+    fn do_stats_commands(&self) -> Resp {
+        Resp::Stats(self.command_stats.get_stats())
+    }
+
+    fn do_stats_hot_keys(&mut self, cnt: Option<usize>) -> Resp {
+        let hot_keys = match self.hot_keys {
+            Some(ref mut hot_keys) => {
+                hot_keys.get_top(cnt.unwrap_or(HOT_KEYS_TOP),
+                                 self.cache.now())
+            }
+            None => {
+                let msg = "hot key tracking is disabled";
+                return Resp::ServerError(msg.to_string());
+            }
+        };
+
+        let mut stats = vec![];
+
+        for (i, hot_key) in hot_keys.iter().enumerate() {
+            let prefix = format!("hotkey:{}", i + 1);
+            let key = String::from_utf8_lossy(&hot_key.key.item).to_string();
+            let read_rate = format!("{:.2}", hot_key.read_rate);
+            let write_rate = format!("{:.2}", hot_key.write_rate);
+
+            stats.push(Stat::new(&format!("{}:key", prefix), key));
+            stats.push(Stat::new(&format!("{}:read_rate", prefix), read_rate));
+            stats.push(Stat::new(&format!("{}:write_rate", prefix),
+                                 write_rate));
+        }
+
+        Resp::Stats(stats)
+    }
+
+    fn do_stats_namespace(&self, name: &str) -> Resp {
+        let partition = match self.cache.get_namespace(name) {
+            Some(partition) => partition,
+            None => return from_cache_err(&CacheError::NamespaceNotFound),
+        };
+        let storage = partition.get_stats();
+
+        // Nothing has been counted until the first command comes along
+        let empty = DriverStats::new();
+        let stats = self.namespace_stats.get(name).unwrap_or(&empty);
+
+        let compress_ratio = match storage.compress_bytes_out {
+            0 => 1.0,
+            out => storage.compress_bytes_in as f64 / out as f64,
+        };
+
+        let stats = vec![
+            Stat::new("cmd_get", stats.cmd_get.to_string()),
+            Stat::new("cmd_set", stats.cmd_set.to_string()),
+            Stat::new("cmd_flush", stats.cmd_flush.to_string()),
+            Stat::new("cmd_touch", stats.cmd_touch.to_string()),
+            Stat::new("get_hits", storage.get_hits.to_string()),
+            Stat::new("get_misses", storage.get_misses.to_string()),
+            Stat::new("delete_hits", storage.delete_hits.to_string()),
+            Stat::new("delete_misses", storage.delete_misses.to_string()),
+            Stat::new("incr_hits", stats.incr_hits.to_string()),
+            Stat::new("incr_misses", stats.incr_misses.to_string()),
+            Stat::new("decr_hits", stats.decr_hits.to_string()),
+            Stat::new("decr_misses", stats.decr_misses.to_string()),
+            Stat::new("cas_hits", stats.cas_hits.to_string()),
+            Stat::new("cas_misses", stats.cas_misses.to_string()),
+            Stat::new("cas_badval", stats.cas_badval.to_string()),
+            Stat::new("touch_hits", stats.touch_hits.to_string()),
+            Stat::new("touch_misses", stats.touch_misses.to_string()),
+            Stat::new("limit_maxbytes", partition.get_capacity().to_string()),
+            Stat::new("bytes", storage.bytes.to_string()),
+            Stat::new("curr_items", partition.len().to_string()),
+            Stat::new("total_items", storage.total_items.to_string()),
+            Stat::new("evictions", storage.evictions.to_string()),
+            Stat::new("reclaimed", storage.reclaimed.to_string()),
+            Stat::new("crawler_items_checked",
+                      storage.crawler_items_checked.to_string()),
+            Stat::new("crawler_reclaimed",
+                      storage.crawler_reclaimed.to_string()),
+            Stat::new("compress_ratio", format!("{:.2}", compress_ratio)),
+            Stat::new("delete_prefix_items",
+                      storage.delete_prefix_items.to_string()),
+            Stat::new("scan_items_checked",
+                      storage.scan_items_checked.to_string()),
+        ];
+
+        Resp::Stats(stats)
     }
 
     pub fn do_touch(&mut self, touch: Touch) -> Resp {
@@ -653,18 +1596,137 @@ impl Driver {
                           })
     }
 
+    fn do_debug_time(&mut self, debug_time: DebugTime) -> Resp {
+        // To clients it's as if the command didn't exist
+        if !self.debug_time {
+            return Resp::Error;
+        }
+
+        self.cache.shift_time(debug_time.secs as f64);
+
+        Resp::Ok
+    }
+
+    // The transport binds the connection, we only check that there's such
+    // a namespace to bind it to
+    fn do_use_namespace(&self, name: &str) -> Resp {
+        match self.cache.get_namespace(name) {
+            Some(_) => Resp::Ok,
+            None => from_cache_err(&CacheError::NamespaceNotFound),
+        }
+    }
+
     pub fn do_version(&self) -> Resp {
         Resp::Version(get_version_string())
     }
 
 
     pub fn run(&mut self, cmd: Cmd) -> Resp {
+        let target = match (&self.log, &self.hot_keys) {
+            (&None, &None) if self.leases.is_empty() => None,
+            _ => MutationTarget::of(&cmd),
+        };
+
+        match target {
+            Some(MutationTarget::Key(ref key)) => {
+                self.record_hot_key(key, Access::Write);
+            }
+            _ => (),
+        }
+
+        let namespaces = match self.cache.has_namespaces() {
+            true => self.get_cmd_namespaces(&cmd),
+            false => vec![],
+        };
+        let stats_before = match namespaces.is_empty() {
+            true => None,
+            false => Some(self.stats.clone()),
+        };
+
+        // A lease set releases its own lease, if the token is any good
+        let is_lease_set = match cmd {
+            Cmd::Set(ref set) => set.instr == SetInstr::LeaseSet,
+            _ => false,
+        };
+
+        let name = cmd.get_name();
+        let payload_in = cmd.get_payload_len();
+        let keys = match cmd {
+            Cmd::Get(ref get) => get.keys.len(),
+            _ => 1,
+        };
+
+        let changes_before = self.cache.get_change_count();
+        let key_edit = self.get_key_edit(&cmd, &target);
+
+        let time_st = time_now();
+        let resp = self.execute(cmd);
+        let duration = time_now() - time_st;
+
+        let payload = payload_in + resp.get_payload_len();
+        self.command_stats.record(name, &resp, duration, payload, keys);
+
+        // Count the command towards the namespaces it worked on
+        match stats_before {
+            Some(before) => {
+                for name in namespaces {
+                    self.namespace_stats
+                        .entry(name)
+                        .or_insert_with(DriverStats::new)
+                        .add_delta(&before, &self.stats);
+                }
+            }
+            None => (),
+        }
+
+        match target {
+            Some(ref target) if !is_lease_set => {
+                self.invalidate_leases(target);
+            }
+            _ => (),
+        }
+        self.charge_leases();
+
+        // Commands that changed nothing, like an add of a key that is there
+        // already, leave nothing to record
+        let changed = self.cache.get_change_count() != changes_before;
+
+        match target {
+            Some(target) if changed => {
+                match self.log_mutation(target, key_edit) {
+                    // The change was made but we can't promise it will
+                    // survive a restart
+                    Err(_) if resp != Resp::Empty => {
+                        let msg = "failed to write mutation log";
+                        Resp::ServerError(msg.to_string())
+                    }
+                    _ => resp,
+                }
+            }
+            _ => resp,
+        }
+    }
+
+    fn execute(&mut self, cmd: Cmd) -> Resp {
         match cmd {
+            Cmd::DebugTime(debug_time) => self.do_debug_time(debug_time),
             Cmd::Delete(del) => self.do_delete(del),
+            Cmd::DeletePrefix(delete_prefix) => {
+                self.do_delete_prefix(delete_prefix)
+            }
             Cmd::FlushAll(flush_all) => self.do_flush_all(flush_all),
             Cmd::Get(get) => self.do_get(get),
+            Cmd::HashField(hash_field) => self.do_hash_field(hash_field),
             Cmd::Inc(inc) => self.do_inc(inc),
+            Cmd::InvalidateTag(invalidate_tag) => {
+                self.do_invalidate_tag(invalidate_tag)
+            }
+            Cmd::LeaseGet(lease_get) => self.do_lease_get(lease_get),
+            Cmd::ListPop(list_pop) => self.do_list_pop(list_pop),
+            Cmd::ListPush(list_push) => self.do_list_push(list_push),
+            Cmd::ListRange(list_range) => self.do_list_range(list_range),
             Cmd::Quit => Resp::Empty,  // handled at transport level
+            Cmd::Scan(scan) => self.do_scan(scan),
             Cmd::Set(set) => {
                 match set.instr {
                     SetInstr::Add => self.do_add(set),
@@ -673,15 +1735,68 @@ impl Driver {
                     SetInstr::Replace => self.do_replace(set),
                     SetInstr::Set => self.do_set(set),
                     SetInstr::Cas => self.do_cas(set),
+                    SetInstr::LeaseSet => self.do_lease_set(set),
                 }
             }
-            Cmd::Stats => self.do_stats(),
+            Cmd::SetMember(set_member) => self.do_set_member(set_member),
+            Cmd::SlowLog(SlowLogInstr::Get(cnt)) => self.do_slow_log_get(cnt),
+            Cmd::SlowLog(SlowLogInstr::Reset) => self.do_slow_log_reset(),
+            Cmd::Stats(StatsInstr::General) => self.do_stats(),
+            Cmd::Stats(StatsInstr::Commands) => self.do_stats_commands(),
+            Cmd::Stats(StatsInstr::HotKeys(cnt)) => {
+                self.do_stats_hot_keys(cnt)
+            }
+            Cmd::Stats(StatsInstr::Namespace(name)) => {
+                self.do_stats_namespace(&name)
+            }
             Cmd::Touch(touch) => self.do_touch(touch),
+            Cmd::UseNamespace(name) => self.do_use_namespace(&name),
             Cmd::Version => self.do_version(),
         }
     }
 
+    pub fn get_slow_log(&self) -> &SlowLog {
+        &self.slow_log
+    }
+
+    pub fn get_cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn get_cache_mut(&mut self) -> &mut Cache {
+        &mut self.cache
+    }
+
+    pub fn crawl(&mut self) -> u64 {
+        // Leases that were never filled are no longer of any use
+        self.leases.expire(self.cache.now());
+        self.charge_leases();
+
+        // The store on disk is compacted a step at a time as well
+        self.cache.compact_extstore();
+
+        let checked_before = self.cache.get_stats().crawler_items_checked;
+        let reclaimed = self.cache.crawl();
+        let checked = self.cache.get_stats().crawler_items_checked -
+                      checked_before;
+        self.crawler_rate.record(checked, self.cache.now());
+
+        reclaimed
+    }
+
     pub fn update_transport_stats(&mut self, stats: TransportStats) {
         self.transport_stats = stats;
     }
 }
+
+
+fn format_micros(secs: f64) -> String {
+    ((secs * 1e6) as u64).to_string()
+}
+
+fn get_list_end(side: &ListSide) -> ListEnd {
+    match *side {
+        ListSide::Left => ListEnd::Head,
+        ListSide::Right => ListEnd::Tail,
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::mem;
+use std::slice::Iter;
+
+use storage::Key;
+
+
+// How many keys we keep track of, the top ones are accurate as long as they
+// stand out from the rest
+pub const HOT_KEYS_TRACKED: usize = 100;
+
+// The window (in seconds) that access rates are measured over
+pub const HOT_KEYS_WINDOW: f64 = 60.0;
+
+// How many keys are reported unless asked for a different number
+pub const HOT_KEYS_TOP: usize = 10;
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+
+// A key that is accessed a lot, with its estimated access rates
+#[derive(Debug, PartialEq, Clone)]
+pub struct HotKey {
+    pub key: Key,
+    pub read_rate: f64, // reads per second
+    pub write_rate: f64, // writes per second
+}
+
+
+#[derive(Debug, Clone)]
+struct Counter {
+    key: Key,
+    count: u64, // reads + writes, including the inherited error
+    reads: u64,
+    writes: u64,
+}
+
+
+// The Space-Saving algorithm: tracks the top keys in a fixed number of
+// counters. When a key that isn't tracked comes along it takes over the
+// counter with the lowest count, inheriting that count as its error. A key
+// that is accessed more often than 1/capacity of the time is guaranteed to
+// be tracked.
+//
+// The counters form a min heap on count so that finding the lowest one and
+// bumping a count are both O(log capacity).
+struct SpaceSaving {
+    capacity: usize,
+    heap: Vec<Counter>,
+    positions: HashMap<Key, usize>, // key -> index in heap
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity,
+            heap: Vec::with_capacity(capacity),
+            positions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn record(&mut self, key: &Key, access: Access) {
+        let pos = match self.positions.get(key) {
+            Some(pos) => *pos,
+            None => self.admit(key),
+        };
+
+        {
+            let counter = &mut self.heap[pos];
+            counter.count += 1;
+            match access {
+                Access::Read => counter.reads += 1,
+                Access::Write => counter.writes += 1,
+            }
+        }
+
+        // The count went up, so the counter may need to move down the heap
+        self.sift_down(pos);
+    }
+
+    // Finds a counter for a key that isn't tracked yet
+    fn admit(&mut self, key: &Key) -> usize {
+        if self.heap.len() < self.capacity {
+            self.heap.push(Counter {
+                key: key.clone(),
+                count: 0,
+                reads: 0,
+                writes: 0,
+            });
+            let pos = self.heap.len() - 1;
+            self.positions.insert(key.clone(), pos);
+
+            // A fresh counter has the lowest count of all
+            self.sift_up(pos);
+            return self.positions[key];
+        }
+
+        // Take over the counter with the lowest count
+        let evicted = self.heap[0].key.clone();
+        self.positions.remove(&evicted);
+        self.positions.insert(key.clone(), 0);
+
+        let counter = &mut self.heap[0];
+        counter.key = key.clone();
+        counter.reads = 0;
+        counter.writes = 0;
+
+        0
+    }
+
+    fn get(&self, key: &Key) -> Option<&Counter> {
+        self.positions.get(key).map(|pos| &self.heap[*pos])
+    }
+
+    fn iter(&self) -> Iter<Counter> {
+        self.heap.iter()
+    }
+
+    fn sift_up(&mut self, mut pos: usize) {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if self.heap[parent].count <= self.heap[pos].count {
+                break;
+            }
+
+            self.swap(parent, pos);
+            pos = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut pos: usize) {
+        loop {
+            let left = 2 * pos + 1;
+            let right = left + 1;
+
+            let mut smallest = pos;
+            if left < self.heap.len() &&
+               self.heap[left].count < self.heap[smallest].count {
+                smallest = left;
+            }
+            if right < self.heap.len() &&
+               self.heap[right].count < self.heap[smallest].count {
+                smallest = right;
+            }
+
+            if smallest == pos {
+                break;
+            }
+
+            self.swap(smallest, pos);
+            pos = smallest;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        *self.positions.get_mut(&self.heap[a].key).unwrap() = a;
+        *self.positions.get_mut(&self.heap[b].key).unwrap() = b;
+    }
+}
+
+
+// Tracks the most accessed keys over a sliding window. Accesses are counted
+// in fixed windows, and the rate over the last window length is estimated
+// from the current window plus the part of the previous window that still
+// overlaps it.
+pub struct HotKeys {
+    capacity: usize, // number of keys tracked
+    window: f64, // in seconds
+
+    window_start: f64, // unixtime
+    current: SpaceSaving,
+    previous: SpaceSaving,
+}
+
+impl HotKeys {
+    pub fn new(capacity: usize, window: f64, now: f64) -> HotKeys {
+        HotKeys {
+            capacity: capacity,
+            window: window,
+            window_start: now,
+            current: SpaceSaving::new(capacity),
+            previous: SpaceSaving::new(capacity),
+        }
+    }
+
+    pub fn record(&mut self, key: &Key, access: Access, now: f64) {
+        self.rotate(now);
+        self.current.record(key, access);
+    }
+
+    // Returns up to cnt keys with the highest estimated access rates, hottest
+    // first
+    pub fn get_top(&mut self, cnt: usize, now: f64) -> Vec<HotKey> {
+        self.rotate(now);
+
+        // How much of the previous window still falls within the last window
+        // length
+        let elapsed = now - self.window_start;
+        let overlap = (1.0 - elapsed / self.window).max(0.0);
+
+        let mut hot_keys = vec![];
+
+        for counter in self.current.iter() {
+            let (reads, writes) = match self.previous.get(&counter.key) {
+                Some(prev) => {
+                    (counter.reads as f64 + prev.reads as f64 * overlap,
+                     counter.writes as f64 + prev.writes as f64 * overlap)
+                }
+                None => (counter.reads as f64, counter.writes as f64),
+            };
+            hot_keys.push(self.make_hot_key(&counter.key, reads, writes));
+        }
+
+        for counter in self.previous.iter() {
+            if self.current.get(&counter.key).is_some() {
+                continue;
+            }
+
+            let reads = counter.reads as f64 * overlap;
+            let writes = counter.writes as f64 * overlap;
+            if reads + writes > 0.0 {
+                hot_keys.push(self.make_hot_key(&counter.key, reads, writes));
+            }
+        }
+
+        hot_keys.sort_by(|a, b| {
+            let rate_a = a.read_rate + a.write_rate;
+            let rate_b = b.read_rate + b.write_rate;
+            rate_b.partial_cmp(&rate_a).unwrap()
+        });
+        hot_keys.truncate(cnt);
+
+        hot_keys
+    }
+
+    fn make_hot_key(&self, key: &Key, reads: f64, writes: f64) -> HotKey {
+        HotKey {
+            key: key.clone(),
+            read_rate: reads / self.window,
+            write_rate: writes / self.window,
+        }
+    }
+
+    fn rotate(&mut self, now: f64) {
+        let elapsed = now - self.window_start;
+        if elapsed < self.window {
+            return;
+        }
+
+        // If more than a whole window has gone by without any accesses the
+        // previous window is empty too
+        if elapsed < 2.0 * self.window {
+            self.previous = mem::replace(&mut self.current,
+                                         SpaceSaving::new(self.capacity));
+            self.window_start += self.window;
+        } else {
+            self.previous = SpaceSaving::new(self.capacity);
+            self.current = SpaceSaving::new(self.capacity);
+            self.window_start = now;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use storage::Key;
+
+    use super::Access;
+    use super::HotKeys;
+
+
+    fn key(id: u8) -> Key {
+        Key::new(vec![id])
+    }
+
+    #[test]
+    fn test_top_keys() {
+        let mut hot_keys = HotKeys::new(4, 10.0, 0.0);
+
+        // One key stands out among a long tail of keys seen once
+        for i in 0..100 {
+            hot_keys.record(&key(1), Access::Read, 1.0);
+            hot_keys.record(&key(10 + i), Access::Read, 1.0);
+        }
+        for _ in 0..20 {
+            hot_keys.record(&key(2), Access::Write, 1.0);
+        }
+
+        let top = hot_keys.get_top(2, 1.0);
+        assert_eq!(2, top.len());
+        assert_eq!(key(1), top[0].key);
+        assert_eq!(10.0, top[0].read_rate);
+        assert_eq!(0.0, top[0].write_rate);
+        assert_eq!(key(2), top[1].key);
+        assert_eq!(0.0, top[1].read_rate);
+        assert!(top[1].write_rate >= 2.0);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut hot_keys = HotKeys::new(4, 10.0, 0.0);
+
+        for _ in 0..100 {
+            hot_keys.record(&key(1), Access::Read, 5.0);
+        }
+
+        // Halfway through the next window half of it still counts
+        let top = hot_keys.get_top(1, 15.0);
+        assert_eq!(5.0, top[0].read_rate);
+
+        // A whole window later it's gone
+        let top = hot_keys.get_top(1, 25.0);
+        assert_eq!(0, top.len());
+    }
+
+    #[test]
+    fn test_idle_longer_than_window() {
+        let mut hot_keys = HotKeys::new(4, 10.0, 0.0);
+        hot_keys.record(&key(1), Access::Read, 1.0);
+
+        hot_keys.record(&key(2), Access::Read, 100.0);
+        let top = hot_keys.get_top(10, 100.0);
+        assert_eq!(1, top.len());
+        assert_eq!(key(2), top[0].key);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::mem;
+
+use storage::Key;
+use storage::Value;
+
+
+// How long (in seconds) a lease is good for. A client that was handed a lease
+// and never came back to fill the key holds up the other clients no longer
+// than this.
+pub const LEASE_TTL: f64 = 10.0;
+
+
+// What a client that missed a key should do about it
+#[derive(Debug, PartialEq, Clone)]
+pub enum LeaseGrant {
+    Granted(u64), // fill the key, using this token
+    Stale(Value), // someone else is filling it, use this value meanwhile
+    Wait, // someone else is filling it, try again shortly
+}
+
+
+struct Lease {
+    token: u64,
+    expires_at: f64, // unixtime
+    stale: Option<Value>, // the dead value the key held, if any
+}
+
+
+// Facebook style leases: of all the clients that miss a key only the first
+// one is handed a token to fill it with, so that the others don't all go to
+// the backing store at the same time. A token is only good until the key is
+// written to by other means, so that a client that was slow to come back
+// can't overwrite a newer value with the one it computed.
+pub struct Leases {
+    ttl: f64, // in seconds
+    last_token: u64,
+    leases: HashMap<Key, Lease>,
+    // Every lease handed out with its token, in the order they run out in
+    // since they're all good for the same time. Leases that were given up
+    // early stay in here until they would have run out.
+    expiry: VecDeque<(f64, Key, u64)>,
+    mem_size: usize, // kept up to date as leases come and go
+    // The memory taken or given back since the driver last charged it to
+    // the cache, by the key it's held for
+    charges: HashMap<Key, isize>,
+}
+
+impl Leases {
+    pub fn new(ttl: f64) -> Leases {
+        Leases {
+            ttl: ttl,
+            last_token: 0,
+            leases: HashMap::new(),
+            expiry: VecDeque::new(),
+            mem_size: 0,
+            charges: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+
+    // Whether a client is filling the key, in which case it's no use
+    // getting a stale value for acquire
+    pub fn is_held(&self, key: &Key, now: f64) -> bool {
+        match self.leases.get(key) {
+            Some(lease) => lease.expires_at > now,
+            None => false,
+        }
+    }
+
+    // The leases with their stale values
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
+
+    // Hands over the memory taken or given back by key since the last call,
+    // which the driver charges to the cache
+    pub fn take_charges(&mut self) -> HashMap<Key, isize> {
+        mem::replace(&mut self.charges, HashMap::new())
+    }
+
+    fn charge(&mut self, key: &Key, size: isize) {
+        self.mem_size = (self.mem_size as isize + size) as usize;
+        *self.charges.entry(key.clone()).or_insert(0) += size;
+    }
+
+    fn lease_size(key: &Key, lease: &Lease) -> usize {
+        let stale_size = match lease.stale {
+            Some(ref value) => value.mem_size(),
+            None => 0,
+        };
+
+        key.mem_size() + mem::size_of::<Lease>() + stale_size
+    }
+
+    fn expiry_size(key: &Key) -> usize {
+        key.mem_size() + mem::size_of::<(f64, u64)>()
+    }
+
+    fn insert(&mut self, key: &Key, lease: Lease) {
+        let size = Leases::lease_size(key, &lease) as isize;
+        self.charge(key, size);
+        self.leases.insert(key.clone(), lease);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Lease> {
+        let lease = self.leases.remove(key);
+        match lease {
+            Some(ref lease) => {
+                let size = Leases::lease_size(key, lease) as isize;
+                self.charge(key, -size);
+            }
+            None => (),
+        }
+        lease
+    }
+
+    // Called when a client misses the key. stale is the dead value the key
+    // still held, if any, which the other clients get to use while the key
+    // is being filled.
+    pub fn acquire(&mut self,
+                   key: &Key,
+                   stale: Option<Value>,
+                   now: f64)
+                   -> LeaseGrant {
+        match self.leases.get(key) {
+            Some(lease) if lease.expires_at > now => {
+                return match lease.stale {
+                    Some(ref value) => LeaseGrant::Stale(value.clone()),
+                    None => LeaseGrant::Wait,
+                };
+            }
+            _ => (),
+        }
+
+        // The lease ran out, but its stale value is still as good
+        let prev_stale = match self.remove(key) {
+            Some(lease) => lease.stale,
+            None => None,
+        };
+
+        self.last_token += 1;
+        let lease = Lease {
+            token: self.last_token,
+            expires_at: now + self.ttl,
+            stale: stale.or(prev_stale),
+        };
+        self.charge(key, Leases::expiry_size(key) as isize);
+        self.expiry.push_back((lease.expires_at, key.clone(), lease.token));
+        self.insert(key, lease);
+
+        LeaseGrant::Granted(self.last_token)
+    }
+
+    // Gives up the lease on the key if the token is valid, ie. the holder
+    // has come back to fill it. Returns false if it's not valid.
+    pub fn release(&mut self, key: &Key, token: u64, now: f64) -> bool {
+        let is_valid = match self.leases.get(key) {
+            Some(lease) => lease.token == token && lease.expires_at > now,
+            None => false,
+        };
+
+        if is_valid {
+            self.remove(key);
+        }
+
+        is_valid
+    }
+
+    // The key has been written to, so a lease on it is void
+    pub fn invalidate(&mut self, key: &Key) {
+        self.remove(key);
+    }
+
+    pub fn invalidate_if<F>(&mut self, pred: F)
+        where F: Fn(&Key) -> bool
+    {
+        let keys: Vec<Key> = self.leases
+                                 .keys()
+                                 .filter(|key| pred(key))
+                                 .cloned()
+                                 .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    pub fn invalidate_all(&mut self) {
+        let keys: Vec<Key> = self.leases.keys().cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+
+        for (_, key, _) in mem::replace(&mut self.expiry, VecDeque::new()) {
+            self.charge(&key, -(Leases::expiry_size(&key) as isize));
+        }
+    }
+
+    // Drops the leases that have run out. Only those are looked at, so it's
+    // cheap enough to do after every command.
+    pub fn expire(&mut self, now: f64) {
+        loop {
+            let token = match self.expiry.front() {
+                Some(&(expires_at, _, token)) if expires_at <= now => token,
+                _ => break,
+            };
+
+            let (_, key, _) = self.expiry.pop_front().unwrap();
+            self.charge(&key, -(Leases::expiry_size(&key) as isize));
+
+            // Unless it was given up and handed out again since
+            let is_current = match self.leases.get(&key) {
+                Some(lease) => lease.token == token,
+                None => false,
+            };
+            if is_current {
+                self.remove(&key);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use storage::Key;
+    use storage::Value;
+
+    use super::LeaseGrant;
+    use super::Leases;
+
+
+    fn key(id: u8) -> Key {
+        Key::new(vec![id])
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let mut leases = Leases::new(10.0);
+
+        // The first client to miss gets the lease, the others wait
+        assert_eq!(LeaseGrant::Granted(1), leases.acquire(&key(1), None, 0.0));
+        assert_eq!(LeaseGrant::Wait, leases.acquire(&key(1), None, 1.0));
+        assert_eq!(LeaseGrant::Granted(2), leases.acquire(&key(2), None, 1.0));
+
+        // Only the right token is accepted, and only once
+        assert!(!leases.release(&key(1), 2, 2.0));
+        assert!(leases.release(&key(1), 1, 2.0));
+        assert!(!leases.release(&key(1), 1, 2.0));
+        assert!(leases.release(&key(2), 2, 2.0));
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn test_stale_value() {
+        let mut leases = Leases::new(10.0);
+
+        let stale = Value::new(vec![9]);
+        let grant = leases.acquire(&key(1), Some(stale.clone()), 0.0);
+        assert_eq!(LeaseGrant::Granted(1), grant);
+
+        // The others get the stale value to make do with
+        let grant = leases.acquire(&key(1), None, 1.0);
+        assert_eq!(LeaseGrant::Stale(stale.clone()), grant);
+
+        // ...even after the lease runs out and is handed out again
+        let grant = leases.acquire(&key(1), None, 11.0);
+        assert_eq!(LeaseGrant::Granted(2), grant);
+        let grant = leases.acquire(&key(1), None, 12.0);
+        assert_eq!(LeaseGrant::Stale(stale), grant);
+    }
+
+    #[test]
+    fn test_expired_lease() {
+        let mut leases = Leases::new(10.0);
+        leases.acquire(&key(1), None, 0.0);
+
+        // A token that has run out is no good
+        assert!(!leases.release(&key(1), 1, 11.0));
+
+        leases.expire(11.0);
+        assert!(leases.is_empty());
+        assert_eq!(0, leases.mem_size());
+    }
+
+    #[test]
+    fn test_expire_handed_out_again() {
+        let mut leases = Leases::new(10.0);
+        leases.acquire(&key(1), Some(Value::new(vec![9; 100])), 0.0);
+        assert!(leases.mem_size() > 100);
+
+        // Given up and handed out again, so the first one running out
+        // doesn't take the second with it
+        leases.invalidate(&key(1));
+        leases.acquire(&key(1), None, 5.0);
+        leases.expire(11.0);
+        assert!(leases.is_held(&key(1), 11.0));
+
+        leases.expire(15.0);
+        assert!(leases.is_empty());
+        assert_eq!(0, leases.mem_size());
+    }
+
+    #[test]
+    fn test_charges() {
+        let mut leases = Leases::new(10.0);
+        leases.acquire(&key(1), Some(Value::new(vec![9; 100])), 0.0);
+        leases.acquire(&key(2), None, 0.0);
+
+        // The memory is handed over by the key it's held for, and only once
+        let charges = leases.take_charges();
+        assert_eq!(2, charges.len());
+        assert!(charges[&key(1)] > charges[&key(2)] + 100);
+        assert!(leases.take_charges().is_empty());
+
+        // ...and given back once the leases are gone
+        leases.invalidate_all();
+        let charges = leases.take_charges();
+        assert!(charges[&key(1)] < -100);
+        assert!(charges[&key(2)] < 0);
+        assert_eq!(0, leases.mem_size());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut leases = Leases::new(10.0);
+        leases.acquire(&key(1), None, 0.0);
+        leases.acquire(&key(2), None, 0.0);
+
+        leases.invalidate(&key(1));
+        assert!(!leases.release(&key(1), 1, 1.0));
+
+        leases.invalidate_if(|key| key.item[0] == 2);
+        assert!(leases.is_empty());
+    }
+}
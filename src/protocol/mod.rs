@@ -1,6 +1,10 @@
 // Declare sub modules
 pub mod cmd;
+pub mod cmd_stats;
 pub mod driver;
+pub mod hotkeys;
+pub mod leases;
+pub mod slowlog;
 pub mod util;
 
 // internal stuff
@@ -10,3 +14,6 @@ mod tests_bench;  // needed to be part of the compilation unit in test mode
 
 // Export our public api
 pub use self::driver::Driver;
+pub use self::slowlog::SlowLog;
+pub use self::slowlog::SlowLogDraft;
+pub use self::slowlog::SlowLogEntry;
@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use super::cmd::Cmd;
+
+
+// A command that takes at least this long (in seconds) is logged, unless
+// told otherwise
+pub const SLOW_LOG_THRESHOLD: f64 = 0.01;
+
+// How many commands are kept, the oldest are dropped to make room
+pub const SLOW_LOG_MAX_LEN: usize = 128;
+
+// How many commands are listed unless asked for a different number
+pub const SLOW_LOG_TOP: usize = 10;
+
+// A command with many keys or long keys is logged with only the first few,
+// each cut short
+pub const SLOW_LOG_KEYS: usize = 8;
+pub const SLOW_LOG_KEY_LEN: usize = 64;
+
+
+// A command that was slow, with where the time went. Times are in seconds.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SlowLogEntry {
+    pub id: u64, // given out by the log, one higher for every entry
+    pub at: f64, // unixtime when the command started to arrive
+    pub read: f64, // reading and parsing the command
+    pub queue: f64, // waiting for the driver to get to it
+    pub exec: f64, // executing it
+    pub write: f64, // writing the response
+    pub conn: u64, // the id of the connection
+    pub cmd: &'static str,
+    pub keys: Vec<String>, // the first SLOW_LOG_KEYS keys, cut short
+    pub more_keys: usize, // how many keys were left out
+    pub payload: usize, // bytes of data sent and received
+}
+
+impl SlowLogEntry {
+    pub fn new(cmd: &Cmd, conn: u64) -> SlowLogEntry {
+        let mut draft = SlowLogDraft::new();
+        draft.keep(cmd);
+        draft.to_entry(conn)
+    }
+
+    pub fn with_timings(&mut self,
+                        at: f64,
+                        read: f64,
+                        queue: f64,
+                        exec: f64,
+                        write: f64)
+                        -> &mut Self {
+        self.at = at;
+        self.read = read;
+        self.queue = queue;
+        self.exec = exec;
+        self.write = write;
+        self
+    }
+
+    pub fn with_payload(&mut self, payload: usize) -> &mut Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn get_duration(&self) -> f64 {
+        self.read + self.queue + self.exec + self.write
+    }
+}
+
+
+// What a transport keeps of a command while it's away at the driver, in
+// case it turns out slow. The same draft is reused for every command of a
+// connection, so keeping the keys doesn't allocate once the buffers have
+// grown to size.
+pub struct SlowLogDraft {
+    cmd: &'static str,
+    keys: Vec<String>, // only the first keys_len are in use
+    keys_len: usize,
+    more_keys: usize,
+    payload: usize,
+}
+
+impl SlowLogDraft {
+    pub fn new() -> SlowLogDraft {
+        SlowLogDraft {
+            cmd: "",
+            keys: vec![],
+            keys_len: 0,
+            more_keys: 0,
+            payload: 0,
+        }
+    }
+
+    // Replaces whatever was kept of the previous command
+    pub fn keep(&mut self, cmd: &Cmd) {
+        let keys = cmd.get_keys();
+        self.cmd = cmd.get_name();
+        self.keys_len = 0;
+        self.more_keys = keys.len().saturating_sub(SLOW_LOG_KEYS);
+        self.payload = cmd.get_payload_len();
+
+        for key in keys.iter().take(SLOW_LOG_KEYS) {
+            if self.keys_len == self.keys.len() {
+                self.keys.push(String::new());
+            }
+
+            let buf = &mut self.keys[self.keys_len];
+            buf.clear();
+            buf.extend(key.chars().take(SLOW_LOG_KEY_LEN));
+            self.keys_len += 1;
+        }
+    }
+
+    pub fn to_entry(&self, conn: u64) -> SlowLogEntry {
+        SlowLogEntry {
+            id: 0,
+            at: 0.0,
+            read: 0.0,
+            queue: 0.0,
+            exec: 0.0,
+            write: 0.0,
+            conn: conn,
+            cmd: self.cmd,
+            keys: self.keys[..self.keys_len].to_vec(),
+            more_keys: self.more_keys,
+            payload: self.payload,
+        }
+    }
+}
+
+
+struct SlowLogEntries {
+    entries: VecDeque<SlowLogEntry>, // oldest first
+    next_id: u64,
+}
+
+// The settings are read by every transport after every command, so they're
+// kept out of the lock, which is only taken for commands that were slow
+struct SlowLogInner {
+    entries: Mutex<SlowLogEntries>,
+    threshold: AtomicU64, // the bits of the f64
+    max_len: AtomicUsize,
+}
+
+
+// The slowest commands of late, like the SLOWLOG of Redis. Shared between
+// the transports, which time the commands from start to finish, and the
+// driver, which lists them for the slowlog command.
+#[derive(Clone)]
+pub struct SlowLog {
+    inner: Arc<SlowLogInner>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: f64, max_len: usize) -> SlowLog {
+        let entries = SlowLogEntries {
+            entries: VecDeque::new(),
+            next_id: 1,
+        };
+        let inner = SlowLogInner {
+            entries: Mutex::new(entries),
+            threshold: AtomicU64::new(threshold.to_bits()),
+            max_len: AtomicUsize::new(max_len),
+        };
+
+        SlowLog { inner: Arc::new(inner) }
+    }
+
+    // A max_len of 0 turns the log off
+    pub fn configure(&self, threshold: f64, max_len: usize) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        self.inner.threshold.store(threshold.to_bits(), Ordering::Relaxed);
+        self.inner.max_len.store(max_len, Ordering::Relaxed);
+
+        while entries.entries.len() > max_len {
+            entries.entries.pop_front();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.max_len.load(Ordering::Relaxed) > 0
+    }
+
+    // Whether a command that took this long is logged. Doesn't lock, so
+    // it's cheap to ask before building the entry.
+    pub fn is_slow(&self, duration: f64) -> bool {
+        let threshold = self.inner.threshold.load(Ordering::Relaxed);
+        self.is_enabled() && duration >= f64::from_bits(threshold)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().unwrap().entries.len()
+    }
+
+
+    // Keeps the entry if it took at least as long as the threshold.
+    // Returns whether it did.
+    pub fn add(&self, mut entry: SlowLogEntry) -> bool {
+        if !self.is_slow(entry.get_duration()) {
+            return false;
+        }
+
+        let mut entries = self.inner.entries.lock().unwrap();
+        // Reconfigured in the meantime
+        let max_len = self.inner.max_len.load(Ordering::Relaxed);
+        if max_len == 0 {
+            return false;
+        }
+
+        entry.id = entries.next_id;
+        entries.next_id += 1;
+
+        entries.entries.push_back(entry);
+        while entries.entries.len() > max_len {
+            entries.entries.pop_front();
+        }
+
+        true
+    }
+
+    // Up to cnt entries, newest first
+    pub fn get(&self, cnt: usize) -> Vec<SlowLogEntry> {
+        let entries = self.inner.entries.lock().unwrap();
+        entries.entries.iter().rev().take(cnt).cloned().collect()
+    }
+
+    // The ids keep counting up, so entries seen before a reset are never
+    // confused with the ones after
+    pub fn reset(&self) {
+        self.inner.entries.lock().unwrap().entries.clear();
+    }
+}
@@ -1,23 +1,51 @@
+use std::fs;
+
 use common::consts::get_version_string;
+use persistence::FsyncPolicy;
+use persistence::MutationLog;
+use persistence::load_snapshot;
+use persistence::replay_log;
+use persistence::save_snapshot;
+use platform::clock::Clock;
+use platform::clock::ManualClock;
 use platform::process::get_pid;
-use platform::time::sleep_secs;
 use platform::time::time_now;
 use storage::Cache;
+use storage::ExtStore;
 use storage::Key as SKey;
 use storage::Value as SValue;
+use testlib::tempfile::get_temp_path;
 
 use super::Driver;
+use super::SlowLog;
+use super::SlowLogDraft;
+use super::SlowLogEntry;
 use super::cmd::Cmd;
+use super::cmd::DebugTime;
 use super::cmd::Delete;
+use super::cmd::DeletePrefix;
 use super::cmd::FlushAll;
 use super::cmd::Get;
 use super::cmd::GetInstr;
+use super::cmd::HashField;
+use super::cmd::HashFieldInstr;
 use super::cmd::Inc;
 use super::cmd::IncInstr;
+use super::cmd::InvalidateTag;
+use super::cmd::LeaseGet;
+use super::cmd::ListPop;
+use super::cmd::ListPush;
+use super::cmd::ListRange;
+use super::cmd::ListSide;
 use super::cmd::Resp;
+use super::cmd::Scan;
 use super::cmd::Set;
 use super::cmd::SetInstr;
+use super::cmd::SetMember;
+use super::cmd::SetMemberInstr;
+use super::cmd::SlowLogInstr;
 use super::cmd::Stat;
+use super::cmd::StatsInstr;
 use super::cmd::Touch;
 use super::cmd::Value;
 
@@ -38,7 +66,7 @@ fn test_cmd_add() {
     // Make sure it was added
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![8, 9], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![8, 9], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
 
     // Try using add to overwrite an existing key
@@ -56,7 +84,7 @@ fn test_cmd_add() {
     // Make sure it was not overwritten
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![8, 9], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![8, 9], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
 
     // Add with noreply
@@ -68,7 +96,7 @@ fn test_cmd_add() {
     // Make sure it was added
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![11], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![11], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(5, resp.get_first_value().unwrap().flags);
 }
 
@@ -77,7 +105,7 @@ fn test_cmd_add() {
 
 #[test]
 fn test_cmd_append() {
-    let cache = Cache::new(100);
+    let cache = Cache::new(1024);
     let mut driver = Driver::new(cache);
 
     // Try to append to an invalid key
@@ -107,7 +135,7 @@ fn test_cmd_append() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![8, 9, 10], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![8, 9, 10], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
 
     // Append again, in noreply mode
@@ -119,11 +147,44 @@ fn test_cmd_append() {
     // Make sure it was updated again
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![8, 9, 10, 11], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![8, 9, 10, 11],
+               resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(5, resp.get_first_value().unwrap().flags);
 }
 
 
+#[test]
+fn test_cmd_append_large() {
+    let mut cache = Cache::new(1 << 24);
+    cache.with_value_maxlen(1 << 23);
+    let mut driver = Driver::new(cache);
+
+    // Grow a value well beyond a single chunk from both ends
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![1; 1 << 21], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Append, "x", 0, 0, vec![2; 1 << 21], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Prepend, "x", 0, 0, vec![3; 1 << 21], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    let mut expected = vec![3; 1 << 21];
+    expected.extend(vec![1; 1 << 21]);
+    expected.extend(vec![2; 1 << 21]);
+
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    let resp = driver.run(cmd);
+    assert_eq!(expected, resp.get_first_value().unwrap().data.to_vec());
+
+    // Handed back in chunks, the way it's stored
+    assert_eq!(6, resp.get_first_value().unwrap().data.chunk_count());
+
+    // Beyond the limit
+    let set = Set::new(SetInstr::Append, "x", 0, 0, vec![4; 1 << 22], false);
+    let resp = driver.run(Cmd::Set(set));
+    assert_eq!(Resp::ServerError("object too large for cache".to_string()),
+               resp);
+}
+
 // Cas
 
 #[test]
@@ -166,7 +227,7 @@ fn test_cmd_cas() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Gets, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![10], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![10], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
     let cas_unique2 = resp.get_first_value().unwrap().cas_unique.unwrap();
 
@@ -180,7 +241,7 @@ fn test_cmd_cas() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![11], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![11], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(7, resp.get_first_value().unwrap().flags);
 
     // Try to update it with a stale cas token
@@ -192,6 +253,229 @@ fn test_cmd_cas() {
 }
 
 
+
+// Collections
+
+fn list_push(side: ListSide, key: &str, data: Vec<u8>) -> Cmd {
+    Cmd::ListPush(ListPush::new(side, key, data, false))
+}
+
+#[test]
+fn test_cmd_list() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // Popping from a missing list finds nothing
+    let cmd = Cmd::ListPop(ListPop::new(ListSide::Left, "x"));
+    assert_eq!(Resp::Values(vec![]), driver.run(cmd));
+
+    // Pushing creates it
+    let resp = driver.run(list_push(ListSide::Right, "x", vec![2]));
+    assert_eq!(Resp::IntValue(1), resp);
+    let resp = driver.run(list_push(ListSide::Right, "x", vec![3]));
+    assert_eq!(Resp::IntValue(2), resp);
+    let resp = driver.run(list_push(ListSide::Left, "x", vec![1]));
+    assert_eq!(Resp::IntValue(3), resp);
+
+    // Pushing beyond the max length trims the other side
+    let mut list_push = ListPush::new(ListSide::Left, "x", vec![0], true);
+    list_push.with_max_len(3);
+    assert_eq!(Resp::Empty, driver.run(Cmd::ListPush(list_push)));
+
+    let cmd = Cmd::ListRange(ListRange::new("x", 0, -1));
+    let values = vec![Value::new("x", 0, vec![0]),
+                      Value::new("x", 0, vec![1]),
+                      Value::new("x", 0, vec![2])];
+    assert_eq!(Resp::Values(values), driver.run(cmd));
+
+    let cmd = Cmd::ListPop(ListPop::new(ListSide::Right, "x"));
+    let values = vec![Value::new("x", 0, vec![2])];
+    assert_eq!(Resp::Values(values), driver.run(cmd));
+
+    // A missing list has an empty range
+    let cmd = Cmd::ListRange(ListRange::new("y", 0, -1));
+    assert_eq!(Resp::Values(vec![]), driver.run(cmd));
+}
+
+#[test]
+fn test_cmd_set_member() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    let cmd = Cmd::SetMember(SetMember::new(SetMemberInstr::Add,
+                                            "x",
+                                            "a",
+                                            false));
+    assert_eq!(Resp::Stored, driver.run(cmd.clone()));
+    assert_eq!(Resp::NotStored, driver.run(cmd));
+
+    let cmd = Cmd::SetMember(SetMember::new(SetMemberInstr::IsMember,
+                                            "x",
+                                            "a",
+                                            false));
+    assert_eq!(Resp::IntValue(1), driver.run(cmd.clone()));
+
+    let remove = Cmd::SetMember(SetMember::new(SetMemberInstr::Remove,
+                                               "x",
+                                               "a",
+                                               false));
+    assert_eq!(Resp::Deleted, driver.run(remove.clone()));
+    assert_eq!(Resp::NotFound, driver.run(remove));
+    assert_eq!(Resp::IntValue(0), driver.run(cmd));
+
+    // The emptied set is gone
+    assert_eq!(0, driver.get_cache().len());
+}
+
+#[test]
+fn test_cmd_hash_field() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    let get = Cmd::HashField(HashField::new(HashFieldInstr::Get,
+                                            "x",
+                                            "f",
+                                            vec![],
+                                            false));
+    assert_eq!(Resp::Values(vec![]), driver.run(get.clone()));
+
+    let set = Cmd::HashField(HashField::new(HashFieldInstr::Set,
+                                            "x",
+                                            "f",
+                                            vec![1, 2],
+                                            false));
+    assert_eq!(Resp::Stored, driver.run(set));
+
+    let values = vec![Value::new("x", 0, vec![1, 2])];
+    assert_eq!(Resp::Values(values), driver.run(get.clone()));
+
+    let delete = Cmd::HashField(HashField::new(HashFieldInstr::Delete,
+                                               "x",
+                                               "f",
+                                               vec![],
+                                               true));
+    assert_eq!(Resp::Empty, driver.run(delete));
+    assert_eq!(Resp::Values(vec![]), driver.run(get));
+}
+
+#[test]
+fn test_cmd_collections_wrong_type() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let wrong_type = "key holds the wrong kind of value".to_string();
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'1'], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    assert_eq!(Resp::IntValue(1),
+               driver.run(list_push(ListSide::Left, "y", vec![1])));
+
+    // Collection commands don't work on plain values...
+    let resp = driver.run(list_push(ListSide::Left, "x", vec![1]));
+    assert_eq!(Resp::ClientError(wrong_type.clone()), resp);
+
+    // ...or on other kinds of collections
+    let cmd = Cmd::SetMember(SetMember::new(SetMemberInstr::Add,
+                                            "y",
+                                            "a",
+                                            false));
+    assert_eq!(Resp::ClientError(wrong_type.clone()), driver.run(cmd));
+
+    // ...and plain commands don't work on collections
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    assert_eq!(Resp::ClientError(wrong_type.clone()), driver.run(cmd));
+    let cmd = Cmd::Inc(Inc::new(IncInstr::Incr, "y", 1, false));
+    assert_eq!(Resp::ClientError(wrong_type.clone()), driver.run(cmd));
+    let set = Set::new(SetInstr::Append, "y", 0, 0, vec![1], false);
+    assert_eq!(Resp::ClientError(wrong_type), driver.run(Cmd::Set(set)));
+
+    // ...though a multi-get skips the collection and returns the rest
+    let keys = vec!["x".to_string(), "y".to_string()];
+    let cmd = Cmd::Get(Get::new(GetInstr::Get, keys));
+    let resp = driver.run(cmd);
+    assert_eq!(Resp::Values(vec![Value::new("x", 0, vec![b'1'])]), resp);
+
+    // ...but a set replaces the collection
+    let set = Set::new(SetInstr::Set, "y", 0, 0, vec![1], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    let resp = driver.run(cmd);
+    assert_eq!(vec![1], resp.get_first_value().unwrap().data.to_vec());
+}
+
+#[test]
+fn test_cmd_collections_replay() {
+    let path = get_temp_path("driver-log-collections");
+
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    driver.run(list_push(ListSide::Right, "x", vec![1]));
+    driver.run(list_push(ListSide::Right, "x", vec![2]));
+    driver.run(Cmd::ListPop(ListPop::new(ListSide::Left, "x")));
+    driver.run(Cmd::HashField(HashField::new(HashFieldInstr::Set,
+                                             "y",
+                                             "f",
+                                             vec![3],
+                                             false)));
+
+    // The collections survive a restart as they were left
+    let mut cache = Cache::new(1024);
+    assert_eq!(4, replay_log(&mut cache, &path).unwrap());
+    let items = cache.read_collection(&SKey::new(b"x".to_vec()),
+                                      |list| list.range(0, -1))
+                     .unwrap();
+    assert_eq!(vec![vec![2]], items);
+    let item = cache.read_collection(&SKey::new(b"y".to_vec()),
+                                     |hash| {
+                                         hash.get_field(b"f")
+                                             .map(|item| item.cloned())
+                                     })
+                    .unwrap();
+    assert_eq!(Some(vec![3]), item);
+
+    fs::remove_file(&path).unwrap();
+}
+
+
+// Compression
+
+#[test]
+fn test_cmd_compressed_values() {
+    let mut cache = Cache::new(4096);
+    cache.with_compress_threshold(16);
+    let mut driver = Driver::new(cache);
+
+    let blob = vec![b'a'; 100];
+
+    // Set a key that will be compressed
+    let set = Set::new(SetInstr::Set, "x", 3, 0, blob.clone(), false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    // Append and prepend work on the plain data
+    let set = Set::new(SetInstr::Append, "x", 0, 0, vec![b'z'], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Prepend, "x", 0, 0, vec![b'y'], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    let mut expected = vec![b'y'];
+    expected.extend(blob.clone());
+    expected.push(b'z');
+
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    let resp = driver.run(cmd);
+    assert_eq!(expected, resp.get_first_value().unwrap().data.to_vec());
+
+    // So does incr, on a number long enough to be compressed
+    let number = vec![b'0'; 20];
+    let set = Set::new(SetInstr::Set, "n", 0, 0, number, false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let inc = Inc::new(IncInstr::Incr, "n", 5, false);
+    assert_eq!(Resp::IntValue(5), driver.run(Cmd::Inc(inc)));
+}
+
+
 // Decr
 
 #[test]
@@ -226,7 +510,7 @@ fn test_cmd_decr() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![b'1'], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![b'1'], resp.get_first_value().unwrap().data.to_vec());
 
     // Decr it again - noreply
     let inc = Inc::new(IncInstr::Decr, "x", 1, true);
@@ -237,7 +521,7 @@ fn test_cmd_decr() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![b'0'], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![b'0'], resp.get_first_value().unwrap().data.to_vec());
 
     // Try to underflow it
     let inc = Inc::new(IncInstr::Decr, "x", 1, false);
@@ -310,6 +594,43 @@ fn test_cmd_delete() {
 }
 
 
+#[test]
+fn test_cmd_delete_prefix() {
+    let path = get_temp_path("driver-log-prefix");
+
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    for key in &["user:1", "user:2", "item:1"] {
+        let set = Set::new(SetInstr::Set, key, 0, 0, vec![1], false);
+        assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    }
+
+    let cmd = Cmd::DeletePrefix(DeletePrefix::new("user:", false));
+    assert_eq!(Resp::DeletedItems(2), driver.run(cmd));
+
+    // Make sure they're gone
+    let keys = vec!["user:1".to_string(), "user:2".to_string()];
+    let cmd = Cmd::Get(Get::new(GetInstr::Get, keys));
+    assert_eq!(0, driver.run(cmd).get_values().unwrap().len());
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "item:1"));
+    assert_eq!(1, driver.run(cmd).get_values().unwrap().len());
+
+    // Again, but now with noreply flag
+    let cmd = Cmd::DeletePrefix(DeletePrefix::new("item:", true));
+    assert_eq!(Resp::Empty, driver.run(cmd));
+
+    // The deletions survive a restart
+    let mut cache = Cache::new(1024);
+    assert_eq!(5, replay_log(&mut cache, &path).unwrap());
+    assert_eq!(0, cache.len());
+
+    fs::remove_file(&path).unwrap();
+}
+
+
 // FlushAll
 
 #[test]
@@ -355,6 +676,73 @@ fn test_flush_all() {
     assert_eq!(0, resp.get_values().unwrap().len());
 }
 
+#[test]
+fn test_flush_namespace() {
+    let mut cache = Cache::new(1024);
+    cache.with_namespace("a", 512);
+    let mut driver = Driver::new(cache);
+
+    for key_name in ["a:x", "b:x"].iter() {
+        let set = Set::new(SetInstr::Set, key_name, 0, 0, vec![1], false);
+        let resp = driver.run(Cmd::Set(set));
+        assert_eq!(resp, Resp::Stored);
+    }
+
+    // Flush only the namespace
+    let mut flush_all = FlushAll::new(None, false);
+    flush_all.with_namespace("a");
+    let resp = driver.run(Cmd::FlushAll(flush_all));
+    assert_eq!(resp, Resp::Ok);
+
+    // Only the key in the namespace is dead
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "a:x"));
+    let resp = driver.run(cmd);
+    assert_eq!(0, resp.get_values().unwrap().len());
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "b:x"));
+    let resp = driver.run(cmd);
+    assert_eq!(1, resp.get_values().unwrap().len());
+
+    // Flush a namespace that doesn't exist
+    let mut flush_all = FlushAll::new(None, false);
+    flush_all.with_namespace("b");
+    let resp = driver.run(Cmd::FlushAll(flush_all));
+    assert_eq!(resp, Resp::ClientError("no such namespace".to_string()));
+}
+
+#[test]
+fn test_use_namespace() {
+    let mut cache = Cache::new(1024);
+    cache.with_namespace("a", 512);
+    let mut driver = Driver::new(cache);
+
+    let resp = driver.run(Cmd::UseNamespace("a".to_string()));
+    assert_eq!(resp, Resp::Ok);
+    let resp = driver.run(Cmd::UseNamespace("b".to_string()));
+    assert_eq!(resp, Resp::ClientError("no such namespace".to_string()));
+
+    // A connection bound to the namespace stores its keys there...
+    let mut cmd = Cmd::Set(Set::new(SetInstr::Set, "x", 0, 0, vec![1], false));
+    cmd.bind_namespace("a", b':');
+    assert_eq!(Resp::Stored, driver.run(cmd));
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "a:x"));
+    assert_eq!(1, driver.run(cmd).get_values().unwrap().len());
+
+    // ...and gets them back without the namespace
+    let mut cmd = Cmd::Get(Get::new(GetInstr::Get,
+                                    vec!["x".to_string(), "y".to_string()]));
+    cmd.bind_namespace("a", b':');
+    let mut resp = driver.run(cmd);
+    resp.unbind_namespace("a", b':');
+    assert_eq!(resp, Resp::Values(vec![Value::new("x", 0, vec![1])]));
+
+    // Flushing only flushes the namespace
+    let mut cmd = Cmd::FlushAll(FlushAll::new(None, false));
+    cmd.bind_namespace("a", b':');
+    let mut flush_all = FlushAll::new(None, false);
+    flush_all.with_namespace("a");
+    assert_eq!(Cmd::FlushAll(flush_all), cmd);
+}
+
 
 // Get and Set
 
@@ -381,7 +769,7 @@ fn test_cmd_set_and_get_a_key() {
     let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
     let resp = driver.run(cmd);
     assert_eq!(15, resp.get_first_value().unwrap().flags);
-    assert_eq!(blob, resp.get_first_value().unwrap().data);
+    assert_eq!(blob, resp.get_first_value().unwrap().data.to_vec());
 
     // Set a key with noreply flag
     let set = Set::new(SetInstr::Set, "y", 15, 0, blob.clone(), true);
@@ -393,7 +781,7 @@ fn test_cmd_set_and_get_a_key() {
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
     let resp = driver.run(cmd);
     assert_eq!(15, resp.get_first_value().unwrap().flags);
-    assert_eq!(blob, resp.get_first_value().unwrap().data);
+    assert_eq!(blob, resp.get_first_value().unwrap().data.to_vec());
 }
 
 #[test]
@@ -499,7 +887,7 @@ fn test_cmd_incr() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![b'2'], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![b'2'], resp.get_first_value().unwrap().data.to_vec());
 
     // Incr it again - noreply
     let inc = Inc::new(IncInstr::Incr, "x", 1, true);
@@ -510,7 +898,7 @@ fn test_cmd_incr() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![b'3'], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![b'3'], resp.get_first_value().unwrap().data.to_vec());
 
     // Overflow it
     let inc = Inc::new(IncInstr::Incr, "x", 0xffffffffffffffff, false);
@@ -531,12 +919,440 @@ fn test_cmd_incr() {
     assert_eq!(resp, Resp::ClientError("Not a number".to_string()));
 }
 
+#[test]
+fn test_cmd_incr_initial() {
+    let cache = Cache::new(4096);
+    let mut driver = Driver::new(cache);
+
+    // A missing key is created with the initial value
+    let mut inc = Inc::new(IncInstr::Incr, "x", 4, false);
+    inc.with_initial(10);
+    let resp = driver.run(Cmd::Inc(inc.clone()));
+    assert_eq!(resp, Resp::IntValue(10));
+
+    // ...and incremented from then on
+    let resp = driver.run(Cmd::Inc(inc));
+    assert_eq!(resp, Resp::IntValue(14));
+
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    let resp = driver.run(cmd);
+    assert_eq!(b"14".to_vec(), resp.get_first_value().unwrap().data.to_vec());
+
+    // The same goes for decr
+    let mut inc = Inc::new(IncInstr::Decr, "y", 4, true);
+    inc.with_initial(0);
+    let resp = driver.run(Cmd::Inc(inc));
+    assert_eq!(resp, Resp::Empty);
+
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    let resp = driver.run(cmd);
+    assert_eq!(b"0".to_vec(), resp.get_first_value().unwrap().data.to_vec());
+}
+
+#[test]
+fn test_cmd_incr_initial_exptime() {
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(4096);
+    cache.with_clock(Box::new(clock.clone()));
+    let mut driver = Driver::new(cache);
+
+    // The key created from the initial value expires in 3s
+    let mut inc = Inc::new(IncInstr::Incr, "x", 1, false);
+    inc.with_initial(10).with_exptime(3);
+    let resp = driver.run(Cmd::Inc(inc.clone()));
+    assert_eq!(resp, Resp::IntValue(10));
+
+    // Incrementing it leaves the exptime as it was
+    clock.advance(1.5);
+    let resp = driver.run(Cmd::Inc(inc.clone()));
+    assert_eq!(resp, Resp::IntValue(11));
+
+    // Once expired, it starts over from the initial value
+    clock.advance(2.0);
+    let resp = driver.run(Cmd::Inc(inc));
+    assert_eq!(resp, Resp::IntValue(10));
+}
+
+
+// InvalidateTag
+
+#[test]
+fn test_cmd_invalidate_tag() {
+    let path = get_temp_path("driver-log-tags");
+
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    let mut set = Set::new(SetInstr::Set, "x", 0, 0, vec![1], false);
+    set.with_tags(vec!["a".to_string()]);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Set, "y", 0, 0, vec![2], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    let cmd = Cmd::InvalidateTag(InvalidateTag::new("a", false));
+    assert_eq!(Resp::Ok, driver.run(cmd));
+
+    // Only the tagged item misses
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    assert_eq!(0, driver.run(cmd).get_values().unwrap().len());
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    assert_eq!(1, driver.run(cmd).get_values().unwrap().len());
+
+    // Invalidate - noreply
+    let cmd = Cmd::InvalidateTag(InvalidateTag::new("a", true));
+    assert_eq!(Resp::Empty, driver.run(cmd));
+
+    // The invalidation survives a restart
+    let mut cache = Cache::new(1024);
+    assert_eq!(4, replay_log(&mut cache, &path).unwrap());
+    assert_eq!(2, cache.get_tag_version("a"));
+    assert!(cache.get(&SKey::new(b"x".to_vec())).is_err());
+    assert!(cache.get(&SKey::new(b"y".to_vec())).is_ok());
+
+    fs::remove_file(&path).unwrap();
+}
+
+
+// Leases
+
+fn lease_set(key: &str, data: Vec<u8>, token: u64) -> Cmd {
+    let mut set = Set::new(SetInstr::LeaseSet, key, 0, 0, data, false);
+    set.with_lease_token(token);
+    Cmd::Set(set)
+}
+
+#[test]
+fn test_cmd_lease() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // The first client to miss gets a lease, the others have to wait
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    assert_eq!(Resp::Lease(1), driver.run(cmd));
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    assert_eq!(Resp::Wait, driver.run(cmd));
+
+    // Only the lease holder can fill the key
+    assert_eq!(Resp::NotStored, driver.run(lease_set("x", vec![1], 2)));
+    assert_eq!(Resp::Stored, driver.run(lease_set("x", vec![1], 1)));
+    assert_eq!(Resp::NotStored, driver.run(lease_set("x", vec![2], 1)));
+
+    // Now everyone gets the value
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    let exp = Value::new("x", 0, vec![1]);
+    assert_eq!(Resp::Values(vec![exp]), driver.run(cmd));
+
+    let stats = driver.run(Cmd::Stats(StatsInstr::General));
+    let stats = stats.get_stats().unwrap();
+    assert_eq!("1", get_stat(stats, "lease_grants"));
+    assert_eq!("1", get_stat(stats, "lease_waits"));
+    assert_eq!("2", get_stat(stats, "lease_rejects"));
+}
+
+#[test]
+fn test_cmd_lease_stale() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // Set a key that has expired already
+    let exptime = time_now() as u32 - 10;
+    let set = Set::new(SetInstr::Set, "x", 3, exptime, vec![1], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    assert_eq!(Resp::Lease(1), driver.run(cmd));
+
+    // While the key is being filled the old value stands in
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    let exp = Value::new("x", 3, vec![1]);
+    assert_eq!(Resp::Stale(exp), driver.run(cmd));
+
+    // ...and takes up memory of the cache until the key is filled
+    let held = driver.get_cache().get_stats().bytes;
+    assert!(held > 0);
+    assert_eq!(Resp::Stored, driver.run(lease_set("x", vec![2], 1)));
+    let cmd = Cmd::Delete(Delete::new("x", false));
+    assert_eq!(Resp::Deleted, driver.run(cmd));
+    assert!(driver.get_cache().get_stats().bytes < held);
+}
+
+#[test]
+fn test_cmd_lease_stale_namespace() {
+    let mut cache = Cache::new(2048);
+    cache.with_namespace("a", 1024);
+    let mut driver = Driver::new(cache);
+
+    let set = Set::new(SetInstr::Set, "y", 0, 0, vec![1; 600], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let outside = driver.get_cache().get_stats().bytes;
+
+    let exptime = time_now() as u32 - 10;
+    let set = Set::new(SetInstr::Set, "a:x", 0, exptime, vec![1; 600], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let cmd = Cmd::LeaseGet(LeaseGet::new("a:x"));
+    assert_eq!(Resp::Lease(1), driver.run(cmd));
+
+    // The stale value takes up memory of the namespace, not of the keys
+    // outside of it
+    let held = driver.get_cache()
+                     .get_namespace("a")
+                     .unwrap()
+                     .get_stats()
+                     .bytes;
+    assert!(held > 600);
+    assert_eq!(outside + held, driver.get_cache().get_stats().bytes);
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    assert_eq!(1, driver.run(cmd).get_values().unwrap().len());
+
+    // ...and gives it back once the key is filled
+    assert_eq!(Resp::Stored, driver.run(lease_set("a:x", vec![2], 1)));
+    let cmd = Cmd::Delete(Delete::new("a:x", false));
+    assert_eq!(Resp::Deleted, driver.run(cmd));
+    let left = driver.get_cache()
+                     .get_namespace("a")
+                     .unwrap()
+                     .get_stats()
+                     .bytes;
+    assert!(left < held - 600);
+    assert_eq!(outside + left, driver.get_cache().get_stats().bytes);
+}
+
+#[test]
+fn test_cmd_lease_invalidated() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    assert_eq!(Resp::Lease(1), driver.run(cmd));
+
+    // A delete means the lease holder's value would be out of date
+    let cmd = Cmd::Delete(Delete::new("x", false));
+    assert_eq!(Resp::NotFound, driver.run(cmd));
+    assert_eq!(Resp::NotStored, driver.run(lease_set("x", vec![1], 1)));
+
+    // ...and so does a regular set
+    let cmd = Cmd::LeaseGet(LeaseGet::new("x"));
+    assert_eq!(Resp::Lease(2), driver.run(cmd));
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![2], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    assert_eq!(Resp::NotStored, driver.run(lease_set("x", vec![1], 2)));
+
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    let exp = Value::new("x", 0, vec![2]);
+    assert_eq!(Resp::Values(vec![exp]), driver.run(cmd));
+}
+
+
+// Mutation log
+
+#[test]
+fn test_mutation_log_replay() {
+    let path = get_temp_path("driver-log");
+
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    let set = Set::new(SetInstr::Set, "x", 4, 0, vec![b'1'], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Set, "y", 0, 0, vec![8, 9], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let inc = Inc::new(IncInstr::Incr, "x", 5, false);
+    assert_eq!(Resp::IntValue(6), driver.run(Cmd::Inc(inc)));
+    let delete = Delete::new("y", false);
+    assert_eq!(Resp::Deleted, driver.run(Cmd::Delete(delete)));
+
+    // Reads are not logged
+    driver.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+
+    // Replaying the log into an empty cache reproduces the same state
+    let mut cache = Cache::new(1024);
+    assert_eq!(4, replay_log(&mut cache, &path).unwrap());
+    assert_eq!(1, cache.len());
+    let value = cache.get(&SKey::new(b"x".to_vec())).unwrap();
+    assert_eq!(b"6".to_vec(), value.get_item().to_vec());
+    assert_eq!(4, *value.get_flags());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_skips_failed_commands() {
+    let path = get_temp_path("driver-log-failed");
+
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![1], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    // None of these change anything, whether they reply or not
+    let set = Set::new(SetInstr::Add, "x", 0, 0, vec![2], false);
+    assert_eq!(Resp::NotStored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Add, "x", 0, 0, vec![2], true);
+    assert_eq!(Resp::Empty, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Replace, "y", 0, 0, vec![2], true);
+    assert_eq!(Resp::Empty, driver.run(Cmd::Set(set)));
+    let delete = Delete::new("y", false);
+    assert_eq!(Resp::NotFound, driver.run(Cmd::Delete(delete)));
+
+    let mut cache = Cache::new(1024);
+    assert_eq!(1, replay_log(&mut cache, &path).unwrap());
+    let value = cache.get(&SKey::new(b"x".to_vec())).unwrap();
+    assert_eq!(vec![1], value.get_item().to_vec());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_edits() {
+    let path = get_temp_path("driver-log-edits");
+    let start_path = get_temp_path("driver-log-edits-start");
+    let halfway_path = get_temp_path("driver-log-edits-halfway");
+
+    let cache = Cache::new(1 << 20);
+    let mut driver = Driver::new(cache);
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![b'a'; 100000], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let set = Set::new(SetInstr::Set, "n", 0, 0, b"5".to_vec(), false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    save_snapshot(driver.get_cache(), &start_path).unwrap();
+
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    for i in 0..100 {
+        if i == 50 {
+            save_snapshot(driver.get_cache(), &halfway_path).unwrap();
+        }
+        let set = Set::new(SetInstr::Append, "x", 0, 0, vec![b'b'], false);
+        assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+        let inc = Inc::new(IncInstr::Incr, "n", 1, false);
+        driver.run(Cmd::Inc(inc));
+    }
+    let set = Set::new(SetInstr::Prepend, "x", 0, 0, vec![b'c'], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    let touch = Touch::new("x", 100, false);
+    assert_eq!(Resp::Touched, driver.run(Cmd::Touch(touch)));
+
+    // The value itself isn't logged over and over, not even once
+    let log_size = fs::metadata(&path).unwrap().len();
+    assert!(log_size < 100000, "log is {} bytes", log_size);
+
+    let expected = driver.get_cache()
+                         .peek(&SKey::new(b"x".to_vec()))
+                         .unwrap()
+                         .into_owned();
+
+    // Replaying on top of the snapshot the log starts from, and on top of
+    // one that has half the edits already, gives the same result
+    for snapshot_path in vec![&start_path, &halfway_path] {
+        let mut cache = Cache::new(1 << 20);
+        load_snapshot(&mut cache, snapshot_path).unwrap();
+        assert_eq!(202, replay_log(&mut cache, &path).unwrap());
+
+        let value = cache.peek(&SKey::new(b"x".to_vec())).unwrap();
+        assert_eq!(100101, value.len());
+        assert_eq!(b"ca".to_vec(), value.get_item().to_vec()[..2].to_vec());
+        assert_eq!(expected.get_exptime(), value.get_exptime());
+        assert_eq!(expected.get_cas_id(), value.get_cas_id());
+
+        let value = cache.peek(&SKey::new(b"n".to_vec())).unwrap();
+        assert_eq!(Some(105), value.get_counter());
+    }
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&start_path).unwrap();
+    fs::remove_file(&halfway_path).unwrap();
+}
+
+#[test]
+fn test_mutation_log_collection_edits() {
+    let path = get_temp_path("driver-log-collection-edits");
+    let start_path = get_temp_path("driver-log-collection-edits-start");
+
+    let cache = Cache::new(1 << 20);
+    let mut driver = Driver::new(cache);
+
+    let big = String::from_utf8(vec![b'a'; 100000]).unwrap();
+    driver.run(list_push(ListSide::Right, "l", big.clone().into_bytes()));
+    driver.run(list_push(ListSide::Right, "e", vec![1]));
+    let sadd = |member: &str| {
+        Cmd::SetMember(SetMember::new(SetMemberInstr::Add, "s", member, false))
+    };
+    let srem = |member: &str| {
+        Cmd::SetMember(SetMember::new(SetMemberInstr::Remove,
+                                      "s",
+                                      member,
+                                      false))
+    };
+    let hset = |field: &str, data: Vec<u8>| {
+        Cmd::HashField(HashField::new(HashFieldInstr::Set,
+                                      "h",
+                                      field,
+                                      data,
+                                      false))
+    };
+    let hdel = |field: &str| {
+        Cmd::HashField(HashField::new(HashFieldInstr::Delete,
+                                      "h",
+                                      field,
+                                      vec![],
+                                      false))
+    };
+    assert_eq!(Resp::Stored, driver.run(sadd(&big)));
+    assert_eq!(Resp::Stored, driver.run(hset("big", big.clone().into_bytes())));
+    save_snapshot(driver.get_cache(), &start_path).unwrap();
+
+    let log = MutationLog::open(&path, FsyncPolicy::Never, true).unwrap();
+    driver.with_mutation_log(log);
+
+    for i in 0..50 {
+        let mut push = ListPush::new(ListSide::Left, "l", vec![i], false);
+        push.with_max_len(40);
+        driver.run(Cmd::ListPush(push));
+        driver.run(sadd(&format!("m{}", i)));
+        driver.run(hset(&format!("f{}", i), vec![i]));
+    }
+    driver.run(Cmd::ListPop(ListPop::new(ListSide::Left, "l")));
+    driver.run(Cmd::ListPop(ListPop::new(ListSide::Left, "e")));
+    assert_eq!(Resp::Deleted, driver.run(srem("m0")));
+    assert_eq!(Resp::Deleted, driver.run(hdel("f0")));
+
+    // The collections themselves aren't logged over and over
+    let log_size = fs::metadata(&path).unwrap().len();
+    assert!(log_size < 100000, "log is {} bytes", log_size);
+
+    let mut cache = Cache::new(1 << 20);
+    load_snapshot(&mut cache, &start_path).unwrap();
+    assert_eq!(154, replay_log(&mut cache, &path).unwrap());
+
+    // Replaying gives the same collections, and the emptied list is gone
+    for key in vec![b"l", b"s", b"h"] {
+        let key = SKey::new(key.to_vec());
+        let expected = driver.get_cache().peek(&key).unwrap();
+        let value = cache.peek(&key).unwrap();
+        assert_eq!(expected.get_collection(), value.get_collection());
+        assert_eq!(expected.get_cas_id(), value.get_cas_id());
+    }
+    assert!(cache.peek(&SKey::new(b"e".to_vec())).is_none());
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&start_path).unwrap();
+}
+
 
 // Prepend
 
 #[test]
 fn test_cmd_prepend() {
-    let cache = Cache::new(100);
+    let cache = Cache::new(1024);
     let mut driver = Driver::new(cache);
 
     // Try to prepend to an invalid key
@@ -566,7 +1382,7 @@ fn test_cmd_prepend() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![10, 8, 9], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![10, 8, 9], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
 
     // Prepend again, in noreply mode
@@ -578,7 +1394,8 @@ fn test_cmd_prepend() {
     // Make sure it was updated again
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![11, 10, 8, 9], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![11, 10, 8, 9],
+               resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(5, resp.get_first_value().unwrap().flags);
 }
 
@@ -617,7 +1434,7 @@ fn test_cmd_replace() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![10], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![10], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(4, resp.get_first_value().unwrap().flags);
 
     // Replace a valid key in noreply mode
@@ -629,11 +1446,166 @@ fn test_cmd_replace() {
     // Make sure it was updated
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
-    assert_eq!(vec![11], resp.get_first_value().unwrap().data);
+    assert_eq!(vec![11], resp.get_first_value().unwrap().data.to_vec());
     assert_eq!(6, resp.get_first_value().unwrap().flags);
 }
 
 
+// Scan
+
+#[test]
+fn test_cmd_scan() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    for key in &["user:1", "user:2", "user:3", "item:1"] {
+        let set = Set::new(SetInstr::Set, key, 0, 0, vec![1], false);
+        assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+    }
+
+    // Page through the keys two at a time
+    let cmd = Cmd::Scan(Scan::new(0, "user:", Some(2)));
+    let keys = vec!["user:1".to_string(), "user:2".to_string()];
+    assert_eq!(Resp::Keys(1, keys), driver.run(cmd));
+
+    let cmd = Cmd::Scan(Scan::new(1, "user:", Some(2)));
+    let keys = vec!["user:3".to_string()];
+    assert_eq!(Resp::Keys(0, keys), driver.run(cmd));
+
+    // The scan is complete, so the cursor is no longer valid
+    let cmd = Cmd::Scan(Scan::new(1, "user:", Some(2)));
+    let resp = driver.run(cmd);
+    assert_eq!(Resp::ClientError("no such cursor".to_string()), resp);
+
+    // Without a prefix and a count we get a page of all the keys, in order
+    let cmd = Cmd::Scan(Scan::new(0, "", None));
+    let keys = vec!["item:1".to_string(),
+                    "user:1".to_string(),
+                    "user:2".to_string(),
+                    "user:3".to_string()];
+    assert_eq!(Resp::Keys(0, keys), driver.run(cmd));
+}
+
+
+// Slow log
+
+#[test]
+fn test_cmd_slow_log() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // The log is shared with whoever times the commands
+    let slow_log = SlowLog::new(0.01, 2);
+    driver.with_slow_log(slow_log.clone());
+
+    let keys = (0..10).map(|i| format!("key{}", i)).collect();
+    let cmd = Cmd::Get(Get::new(GetInstr::Get, keys));
+    let mut entry = SlowLogEntry::new(&cmd, 3);
+    entry.with_timings(1500000000.5, 0.001, 0.002, 0.02, 0.003)
+         .with_payload(100);
+    assert!(slow_log.add(entry));
+
+    // Too fast to be logged
+    let mut entry = SlowLogEntry::new(&Cmd::Version, 3);
+    entry.with_timings(1500000001.0, 0.001, 0.0, 0.001, 0.001);
+    assert!(!slow_log.add(entry));
+
+    let resp = driver.run(Cmd::SlowLog(SlowLogInstr::Get(None)));
+    assert_eq!(Resp::Stats(vec![
+                   Stat::new("slowlog:1:at", "1500000000.500000".to_string()),
+                   Stat::new("slowlog:1:duration_us", "26000".to_string()),
+                   Stat::new("slowlog:1:read_us", "1000".to_string()),
+                   Stat::new("slowlog:1:queue_us", "2000".to_string()),
+                   Stat::new("slowlog:1:exec_us", "20000".to_string()),
+                   Stat::new("slowlog:1:write_us", "3000".to_string()),
+                   Stat::new("slowlog:1:conn", "3".to_string()),
+                   Stat::new("slowlog:1:cmd", "get".to_string()),
+                   Stat::new("slowlog:1:keys",
+                             "key0 key1 key2 key3 key4 key5 key6 key7 \
+                              (+2 more)"
+                                 .to_string()),
+                   Stat::new("slowlog:1:payload", "100".to_string())]),
+               resp);
+
+    // The oldest are dropped to make room, the newest are listed first
+    for _ in 0..2 {
+        let mut entry = SlowLogEntry::new(&Cmd::Version, 4);
+        entry.with_timings(1500000002.0, 0.0, 0.0, 0.5, 0.0);
+        assert!(slow_log.add(entry));
+    }
+    assert_eq!(2, slow_log.len());
+
+    let resp = driver.run(Cmd::SlowLog(SlowLogInstr::Get(Some(1))));
+    let stats = resp.get_stats().unwrap();
+    assert_eq!(10, stats.len());
+    assert_eq!("slowlog:3:at", stats[0].key);
+
+    // Reset forgets them, but the ids keep counting
+    let resp = driver.run(Cmd::SlowLog(SlowLogInstr::Reset));
+    assert_eq!(Resp::Ok, resp);
+    let resp = driver.run(Cmd::SlowLog(SlowLogInstr::Get(None)));
+    assert_eq!(Resp::Stats(vec![]), resp);
+
+    let mut entry = SlowLogEntry::new(&Cmd::Version, 4);
+    entry.with_timings(1500000003.0, 0.0, 0.0, 0.5, 0.0);
+    assert!(slow_log.add(entry));
+    assert_eq!(4, slow_log.get(10)[0].id);
+
+    // Turned off nothing is kept
+    slow_log.configure(0.01, 0);
+    assert_eq!(0, slow_log.len());
+    assert!(!slow_log.is_enabled());
+}
+
+#[test]
+fn test_slow_log_entry_truncates_keys() {
+    let key = "k".repeat(100);
+    let cmd = Cmd::Get(Get::one(GetInstr::Get, &key));
+    let entry = SlowLogEntry::new(&cmd, 1);
+
+    assert_eq!(vec!["k".repeat(64)], entry.keys);
+    assert_eq!(0, entry.more_keys);
+    assert_eq!("get", entry.cmd);
+
+    // The data that's sent along counts towards the payload
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![1; 20], false);
+    let entry = SlowLogEntry::new(&Cmd::Set(set), 1);
+    assert_eq!(20, entry.payload);
+}
+
+#[test]
+fn test_slow_log_draft_reused() {
+    let mut draft = SlowLogDraft::new();
+
+    let keys = (0..10).map(|i| format!("key{}", i)).collect();
+    draft.keep(&Cmd::Get(Get::new(GetInstr::Get, keys)));
+    let entry = draft.to_entry(1);
+    assert_eq!(8, entry.keys.len());
+    assert_eq!(2, entry.more_keys);
+
+    // Nothing of the previous command is left over
+    draft.keep(&Cmd::Get(Get::one(GetInstr::Get, "x")));
+    let entry = draft.to_entry(2);
+    assert_eq!(vec!["x".to_string()], entry.keys);
+    assert_eq!(0, entry.more_keys);
+    assert_eq!(2, entry.conn);
+}
+
+#[test]
+fn test_slow_log_is_slow() {
+    let slow_log = SlowLog::new(0.01, 2);
+    assert!(slow_log.is_slow(0.01));
+    assert!(!slow_log.is_slow(0.005));
+
+    slow_log.configure(0.001, 2);
+    assert!(slow_log.is_slow(0.005));
+
+    // Turned off nothing is slow
+    slow_log.configure(0.001, 0);
+    assert!(!slow_log.is_slow(1.0));
+}
+
+
 // Stats
 
 #[test]
@@ -652,7 +1624,7 @@ fn test_cmd_stats() {
     driver.run(cmd);
 
     // Run stats
-    let cmd = Cmd::Stats;
+    let cmd = Cmd::Stats(StatsInstr::General);
     let resp = driver.run(cmd);
 
     // We need to know the bytecount, so figure out how much space the item we
@@ -683,6 +1655,10 @@ fn test_cmd_stats() {
     let st_cas_badval = Stat::new("cas_badval", "0".to_string());
     let st_touch_hits = Stat::new("touch_hits", "0".to_string());
     let st_touch_misses = Stat::new("touch_misses", "0".to_string());
+    let st_lease_grants = Stat::new("lease_grants", "0".to_string());
+    let st_lease_waits = Stat::new("lease_waits", "0".to_string());
+    let st_lease_stale = Stat::new("lease_stale", "0".to_string());
+    let st_lease_rejects = Stat::new("lease_rejects", "0".to_string());
     let st_bytes_read = Stat::new("bytes_read", "0".to_string());
     let st_bytes_written = Stat::new("bytes_written", "0".to_string());
     let st_limit_maxbytes = Stat::new("limit_maxbytes", "100".to_string());
@@ -690,6 +1666,20 @@ fn test_cmd_stats() {
     let st_total_items = Stat::new("total_items", "1".to_string());
     let st_evictions = Stat::new("evictions", "0".to_string());
     let st_reclaimed = Stat::new("reclaimed", "0".to_string());
+    let st_crawler_items_checked = Stat::new("crawler_items_checked",
+                                             "0".to_string());
+    let st_crawler_reclaimed = Stat::new("crawler_reclaimed", "0".to_string());
+    let st_crawler_rate = Stat::new("crawler_rate", "0".to_string());
+    let st_compress_ratio = Stat::new("compress_ratio", "1.00".to_string());
+    let st_compress_time = Stat::new("compress_time",
+                                     "0.000000".to_string());
+    let st_decompress_time = Stat::new("decompress_time",
+                                       "0.000000".to_string());
+    let st_delete_prefix_items = Stat::new("delete_prefix_items",
+                                           "0".to_string());
+    let st_scan_items_checked = Stat::new("scan_items_checked",
+                                          "0".to_string());
+    let st_tags_forgotten = Stat::new("tags_forgotten", "0".to_string());
 
     let stats = resp.get_stats().unwrap();
     assert_eq!(*stats,
@@ -714,6 +1704,10 @@ fn test_cmd_stats() {
                      st_cas_badval,
                      st_touch_hits,
                      st_touch_misses,
+                     st_lease_grants,
+                     st_lease_waits,
+                     st_lease_stale,
+                     st_lease_rejects,
                      st_bytes_read,
                      st_bytes_written,
                      st_limit_maxbytes,
@@ -721,17 +1715,223 @@ fn test_cmd_stats() {
                      st_curr_items,
                      st_total_items,
                      st_evictions,
-                     st_reclaimed]));
+                     st_reclaimed,
+                     st_crawler_items_checked,
+                     st_crawler_reclaimed,
+                     st_crawler_rate,
+                     st_compress_ratio,
+                     st_compress_time,
+                     st_decompress_time,
+                     st_delete_prefix_items,
+                     st_scan_items_checked,
+                     st_tags_forgotten]));
+}
+
+
+#[test]
+fn test_cmd_stats_hot_keys() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // Read one key a lot and write another a bit
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![1], false);
+    driver.run(Cmd::Set(set));
+    for _ in 0..30 {
+        driver.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+    }
+    for _ in 0..6 {
+        let set = Set::new(SetInstr::Set, "y", 0, 0, vec![2], false);
+        driver.run(Cmd::Set(set));
+    }
+    driver.run(Cmd::Get(Get::one(GetInstr::Get, "z")));
+
+    let cmd = Cmd::Stats(StatsInstr::HotKeys(Some(2)));
+    let resp = driver.run(cmd);
+    let stats = resp.get_stats().unwrap();
+
+    // Rates are over a 60 second window
+    assert_eq!(*stats,
+               vec![Stat::new("hotkey:1:key", "x".to_string()),
+                    Stat::new("hotkey:1:read_rate", "0.50".to_string()),
+                    Stat::new("hotkey:1:write_rate", "0.02".to_string()),
+                    Stat::new("hotkey:2:key", "y".to_string()),
+                    Stat::new("hotkey:2:read_rate", "0.00".to_string()),
+                    Stat::new("hotkey:2:write_rate", "0.10".to_string())]);
+
+    // Now turn it off
+    driver.with_hot_keys(false);
+    let cmd = Cmd::Stats(StatsInstr::HotKeys(None));
+    let resp = driver.run(cmd);
+    let msg = "hot key tracking is disabled".to_string();
+    assert_eq!(Resp::ServerError(msg), resp);
+}
+
+#[test]
+fn test_cmd_stats_commands() {
+    let cache = Cache::new(1024);
+    let mut driver = Driver::new(cache);
+
+    // Nothing has run yet
+    let resp = driver.run(Cmd::Stats(StatsInstr::Commands));
+    assert_eq!(Resp::Stats(vec![]), resp);
+
+    let set = Set::new(SetInstr::Set, "x", 0, 0, vec![1; 100], false);
+    driver.run(Cmd::Set(set));
+    let set = Set::new(SetInstr::Add, "x", 0, 0, vec![2], false);
+    driver.run(Cmd::Set(set));
+    driver.run(Cmd::Get(Get::one(GetInstr::Get, "x")));
+    driver.run(Cmd::Get(Get::one(GetInstr::Get, "y")));
+    driver.run(Cmd::Inc(Inc::new(IncInstr::Incr, "x", 1, false)));
+
+    // One hit and two misses, even though something came back
+    let keys = vec!["x".to_string(), "y".to_string(), "z".to_string()];
+    driver.run(Cmd::Get(Get::new(GetInstr::Gets, keys)));
+
+    let resp = driver.run(Cmd::Stats(StatsInstr::Commands));
+    let stats = resp.get_stats().unwrap();
+
+    // The get that hit sent back 100 bytes, the miss nothing
+    assert_eq!("2", get_stat(stats, "get:count"));
+    assert_eq!("1", get_stat(stats, "get:hit"));
+    assert_eq!("1", get_stat(stats, "get:miss"));
+    assert_eq!("0", get_stat(stats, "get:size_le_64"));
+    assert_eq!("1", get_stat(stats, "get:size_le_256"));
+    assert_eq!("1", get_stat(stats, "gets:count"));
+    assert_eq!("1", get_stat(stats, "gets:hit"));
+    assert_eq!("2", get_stat(stats, "gets:miss"));
+
+    // A stored and a not stored, with 1 and 100 bytes
+    assert_eq!("1", get_stat(stats, "set:count"));
+    assert_eq!("1", get_stat(stats, "set:stored"));
+    assert_eq!("1", get_stat(stats, "set:size_le_256"));
+    assert_eq!("1", get_stat(stats, "add:not_stored"));
+    assert_eq!("1", get_stat(stats, "add:size_le_64"));
+    assert_eq!("0", get_stat(stats, "add:size_large"));
+
+    // Not a number, so it's an error
+    assert_eq!("1", get_stat(stats, "incr:error"));
+    assert!(!stats.iter().any(|stat| stat.key == "incr:size_le_64"));
+
+    // Every command has its latency
+    for key in &["avg_us", "p50_us", "p99_us", "max_us"] {
+        let value = get_stat(stats, &format!("get:{}", key));
+        assert!(value.parse::<f64>().unwrap() >= 0.0);
+    }
+
+    // The stats commands itself is counted too, in name order
+    let resp = driver.run(Cmd::Stats(StatsInstr::Commands));
+    let stats = resp.get_stats().unwrap();
+    assert_eq!("2", get_stat(stats, "stats:count"));
+    assert_eq!("add:count", stats[0].key);
+}
+
+fn get_stat(stats: &Vec<Stat>, key: &str) -> String {
+    stats.iter().find(|stat| stat.key == key).unwrap().value.clone()
+}
+
+#[test]
+fn test_cmd_stats_crawler_rate() {
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(1 << 20);
+    cache.with_clock(Box::new(clock.clone())).with_crawler_batch(600);
+    let mut driver = Driver::new(cache);
+
+    for i in 0..600 {
+        let key = format!("k{}", i);
+        let set = Set::new(SetInstr::Set, &key, 0, 0, vec![1], false);
+        driver.run(Cmd::Set(set));
+    }
+    driver.crawl();
+
+    // The rate is over the last minute, not the server's lifetime
+    let crawler_rate = |driver: &mut Driver| {
+        let resp = driver.run(Cmd::Stats(StatsInstr::General));
+        get_stat(resp.get_stats().unwrap(), "crawler_rate")
+    };
+    assert_eq!("10", crawler_rate(&mut driver));
+    clock.advance(90.0);
+    assert_eq!("5", crawler_rate(&mut driver));
+    clock.advance(120.0);
+    assert_eq!("0", crawler_rate(&mut driver));
 }
 
+#[test]
+fn test_cmd_stats_namespace() {
+    let mut cache = Cache::new(2048);
+    cache.with_namespace("a", 1024);
+    let mut driver = Driver::new(cache);
+
+    let set = Set::new(SetInstr::Set, "a:x", 0, 0, vec![1], false);
+    driver.run(Cmd::Set(set));
+    let set = Set::new(SetInstr::Set, "b:x", 0, 0, vec![1], false);
+    driver.run(Cmd::Set(set));
+
+    // A get of keys in and outside of the namespace counts once for it
+    let keys = vec!["a:x".to_string(), "a:y".to_string(), "b:x".to_string()];
+    driver.run(Cmd::Get(Get::new(GetInstr::Get, keys)));
+    driver.run(Cmd::Inc(Inc::new(IncInstr::Incr, "b:x", 1, false)));
+
+    let cmd = Cmd::Stats(StatsInstr::Namespace("a".to_string()));
+    let resp = driver.run(cmd);
+    let stats = resp.get_stats().unwrap();
+
+    assert_eq!("1", get_stat(stats, "cmd_get"));
+    assert_eq!("1", get_stat(stats, "cmd_set"));
+    assert_eq!("1", get_stat(stats, "get_hits"));
+    assert_eq!("1", get_stat(stats, "get_misses"));
+    assert_eq!("0", get_stat(stats, "incr_hits"));
+    assert_eq!("1024", get_stat(stats, "limit_maxbytes"));
+    assert_eq!("1", get_stat(stats, "curr_items"));
+
+    // The general stats cover everything
+    let resp = driver.run(Cmd::Stats(StatsInstr::General));
+    let stats = resp.get_stats().unwrap();
+    assert_eq!("1", get_stat(stats, "cmd_get"));
+    assert_eq!("1", get_stat(stats, "incr_hits"));
+    assert_eq!("2", get_stat(stats, "curr_items"));
+
+    let cmd = Cmd::Stats(StatsInstr::Namespace("b".to_string()));
+    let resp = driver.run(cmd);
+    assert_eq!(resp, Resp::ClientError("no such namespace".to_string()));
+}
+
+#[test]
+fn test_cmd_stats_extstore() {
+    let item_size = SKey::new(vec![b'a']).mem_size() +
+                    SValue::new(vec![1; 1000]).mem_size();
+    let mut cache = Cache::new(item_size as u64 * 2);
+    let path = get_temp_path("driver-extstore");
+    cache.with_extstore(ExtStore::open(&path, 65536).unwrap());
+    let mut driver = Driver::new(cache);
+
+    // Two of the values are written out to make room for the third
+    for key in vec!["a", "b", "c"] {
+        let set = Set::new(SetInstr::Set, key, 0, 0, vec![1; 1000], false);
+        driver.run(Cmd::Set(set));
+    }
+
+    // One of them is read back
+    let keys = vec!["a".to_string()];
+    let resp = driver.run(Cmd::Get(Get::new(GetInstr::Get, keys)));
+    assert_eq!(1, resp.get_values().unwrap().len());
+
+    let resp = driver.run(Cmd::Stats(StatsInstr::General));
+    let stats = resp.get_stats().unwrap();
+    assert_eq!("0", get_stat(stats, "evictions"));
+    assert_eq!("65536", get_stat(stats, "extstore_limit_maxbytes"));
+    assert_eq!("2", get_stat(stats, "extstore_items"));
+    assert_eq!("2008", get_stat(stats, "extstore_bytes"));
+    assert_eq!("1", get_stat(stats, "extstore_hits"));
+    assert_eq!("0", get_stat(stats, "extstore_misses"));
+}
 
 // Touch
 
-// this is a slow test that relies on sleeps
-#[ignore]
 #[test]
 fn test_cmd_touch() {
-    let cache = Cache::new(100);
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(100);
+    cache.with_clock(Box::new(clock.clone()));
     let mut driver = Driver::new(cache);
 
     // Try to touch an invalid key
@@ -752,8 +1952,8 @@ fn test_cmd_touch() {
     let resp = driver.run(cmd);
     assert_eq!(resp, Resp::Stored);
 
-    // sleep 1.5 secs - not long enough to expire key
-    sleep_secs(1.5);
+    // wait 1.5 secs - not long enough to expire key
+    clock.advance(1.5);
 
     // Touch the key to keep it alive (set same exptime)
     let touch = Touch::new("x", 3, false);
@@ -767,16 +1967,16 @@ fn test_cmd_touch() {
     let resp = driver.run(cmd);
     assert_eq!(resp, Resp::Empty);
 
-    // sleep 1.5 secs - the key would have expired by now without being touched
-    sleep_secs(1.5);
+    // wait 1.5 secs - the key would have expired by now without being touched
+    clock.advance(1.5);
 
     // It's still there
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
     let resp = driver.run(cmd);
     assert_eq!(1, resp.get_values().unwrap().len());
 
-    // sleep 2.5 secs - long enough to expire after the touch
-    sleep_secs(2.5);
+    // wait 2.5 secs - long enough to expire after the touch
+    clock.advance(2.5);
 
     // It's gone
     let cmd = Cmd::Get(Get::one(GetInstr::Get, "x"));
@@ -785,6 +1985,42 @@ fn test_cmd_touch() {
 }
 
 
+// DebugTime
+
+#[test]
+fn test_cmd_debug_time() {
+    let cache = Cache::new(100);
+    let mut driver = Driver::new(cache);
+
+    // Set a key that expires in 10s
+    let set = Set::new(SetInstr::Set, "x", 0, 10, vec![1], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    // Clients may not shift the time unless it's enabled
+    let cmd = Cmd::DebugTime(DebugTime::new(20));
+    assert_eq!(Resp::Error, driver.run(cmd.clone()));
+
+    let get = Cmd::Get(Get::one(GetInstr::Get, "x"));
+    assert_eq!(1, driver.run(get.clone()).get_values().unwrap().len());
+
+    // Move past the exptime - the key is gone
+    driver.with_debug_time(true);
+    assert_eq!(Resp::Ok, driver.run(cmd));
+    assert_eq!(0, driver.run(get).get_values().unwrap().len());
+
+    // Relative exptimes are taken from the shifted time
+    let set = Set::new(SetInstr::Set, "y", 0, 10, vec![1], false);
+    assert_eq!(Resp::Stored, driver.run(Cmd::Set(set)));
+
+    let get = Cmd::Get(Get::one(GetInstr::Get, "y"));
+    assert_eq!(1, driver.run(get.clone()).get_values().unwrap().len());
+
+    let cmd = Cmd::DebugTime(DebugTime::new(11));
+    assert_eq!(Resp::Ok, driver.run(cmd));
+    assert_eq!(0, driver.run(get).get_values().unwrap().len());
+}
+
+
 // Version
 
 #[test]
@@ -801,11 +2037,11 @@ fn test_cmd_version() {
 
 // Item expiration cases
 
-// this is a slow test that relies on sleeps
-#[ignore]
 #[test]
 fn test_cmd_relative_exptime() {
-    let cache = Cache::new(100);
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(100);
+    cache.with_clock(Box::new(clock.clone()));
     let mut driver = Driver::new(cache);
 
     let key_name = "x";
@@ -820,10 +2056,10 @@ fn test_cmd_relative_exptime() {
     // Retrieve it right away - succeeds
     let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
     let resp = driver.run(cmd);
-    assert_eq!(blob, resp.get_first_value().unwrap().data);
+    assert_eq!(blob, resp.get_first_value().unwrap().data.to_vec());
 
-    // sleep 1.5 secs - long enough to expire key
-    sleep_secs(1.5);
+    // wait 1.5 secs - long enough to expire key
+    clock.advance(1.5);
 
     // Retrieve the key again - it's gone
     let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
@@ -831,16 +2067,16 @@ fn test_cmd_relative_exptime() {
     assert_eq!(0, resp.get_values().unwrap().len());
 }
 
-// this is a slow test that relies on sleeps
-#[ignore]
 #[test]
 fn test_cmd_absolute_exptime() {
-    let cache = Cache::new(100);
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(100);
+    cache.with_clock(Box::new(clock.clone()));
     let mut driver = Driver::new(cache);
 
     let key_name = "x";
     let blob = vec![1, 2, 3];
-    let exp = time_now().round() as u32 + 1;
+    let exp = clock.now().round() as u32 + 1;
 
     // Set a key with exptime of 1 second
     let set = Set::new(SetInstr::Set, key_name, 0, exp, blob.clone(), false);
@@ -851,10 +2087,10 @@ fn test_cmd_absolute_exptime() {
     // Retrieve it right away - succeeds
     let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
     let resp = driver.run(cmd);
-    assert_eq!(blob, resp.get_first_value().unwrap().data);
+    assert_eq!(blob, resp.get_first_value().unwrap().data.to_vec());
 
-    // sleep 2.5 secs - long enough to expire key
-    sleep_secs(2.5);
+    // wait 2.5 secs - long enough to expire key
+    clock.advance(2.5);
 
     // Retrieve the key again - it's gone
     let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
@@ -52,6 +52,6 @@ fn bench_cmd_get_key(b: &mut Bencher) {
         let cmd = Cmd::Get(Get::one(GetInstr::Get, key_name));
         let resp = driver.run(cmd);
         assert_eq!(15, resp.get_first_value().unwrap().flags);
-        assert_eq!(blob, resp.get_first_value().unwrap().data);
+        assert_eq!(blob, resp.get_first_value().unwrap().data.to_vec());
     })
 }
@@ -1,31 +1,9 @@
-use platform::time::time_now;
 use storage::CacheError;
 
 use super::cmd::Resp;
 
 
-pub fn bytes_to_u64(bytes: &Vec<u8>) -> Option<u64> {
-    match String::from_utf8(bytes.clone()) {
-        Ok(st) => {
-            match st.parse::<u64>() {
-                Ok(num) => Some(num),
-                Err(_) => None,
-            }
-        }
-        Err(_) => None,
-    }
-}
-
-pub fn u64_to_bytes(num: &u64) -> Vec<u8> {
-    let arr = num.to_string().into_bytes();
-
-    let mut bytes = vec![];
-    bytes.extend(arr);
-    bytes
-}
-
-
-pub fn convert_exptime(exptime: u32) -> Option<f64> {
+pub fn convert_exptime(exptime: u32, now: f64) -> Option<f64> {
     // If exptime is greater than zero it means it's set, otherwise unset
     if exptime > 0 {
         let tm;
@@ -36,7 +14,7 @@ pub fn convert_exptime(exptime: u32) -> Option<f64> {
 
         } else {
             // Otherwise it's relative from now
-            tm = time_now() + exptime as f64;
+            tm = now + exptime as f64;
         }
 
         return Some(tm);
@@ -53,6 +31,18 @@ pub fn from_cache_err(err: &CacheError) -> Resp {
         CacheError::ValueTooLong => {
             Resp::ServerError("object too large for cache".to_string())
         }
+        CacheError::NamespaceNotFound => {
+            Resp::ClientError("no such namespace".to_string())
+        }
+        CacheError::CursorNotFound => {
+            Resp::ClientError("no such cursor".to_string())
+        }
+        CacheError::NotANumber => {
+            Resp::ClientError("Not a number".to_string())
+        }
+        CacheError::WrongType => {
+            Resp::ClientError("key holds the wrong kind of value".to_string())
+        }
         _ => Resp::Error,
     }
 }
@@ -63,48 +53,22 @@ mod tests {
     use platform::time::time_now;
     use testlib::cmp::eq_f64;
 
-    use super::bytes_to_u64;
     use super::convert_exptime;
-    use super::u64_to_bytes;
-
-
-    #[test]
-    fn test_bytes_to_u64() {
-        // whitespace
-        assert_eq!(None, bytes_to_u64(&vec![b' ', b'2']));
-
-        // alpha
-        assert_eq!(None, bytes_to_u64(&vec![b'0', b'x', b'2']));
-
-        // negative
-        assert_eq!(None, bytes_to_u64(&vec![b'-', b'2']));
 
-        // too long for u64
-        assert_eq!(None, bytes_to_u64(&vec![b'1'; 255]));
-
-        // ok
-        assert_eq!(12, bytes_to_u64(&vec![b'1', b'2']).unwrap());
-    }
-
-    #[test]
-    fn test_u64_to_bytes() {
-        // any u64 is representable as bytes so there are no boundary conditions to
-        // check
-        assert_eq!(vec![b'1', b'2'], u64_to_bytes(&12));
-    }
 
     #[test]
     fn test_convert_exptime() {
-        assert_eq!(None, convert_exptime(0));
+        let now = time_now();
+        assert_eq!(None, convert_exptime(0, now));
 
         // big enough to be a timestamp
         let val = (60 * 60 * 24 * 30) + 1;
-        assert_eq!(Some(val as f64), convert_exptime(val));
+        assert_eq!(Some(val as f64), convert_exptime(val, now));
 
         // not big enough to be a timestamp - it's a relative time
         let val = 5;
-        let expected = time_now() + val as f64;
-        let actual = convert_exptime(val).unwrap();
+        let expected = now + val as f64;
+        let actual = convert_exptime(val, now).unwrap();
         assert!(eq_f64(expected, actual, 0.01));
     }
 }
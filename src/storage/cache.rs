@@ -1,13 +1,47 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::collections::Bound;
+use std::collections::HashMap;
+use std::collections::hash_map::Iter;
+use std::mem;
+use std::rc::Rc;
+use std::str;
+
 use linked_hash_map::LinkedHashMap;
 
+use platform::clock::Clock;
+use platform::clock::SystemClock;
 use platform::time::time_now;
 
+use super::collection::Collection;
 use super::errors::CacheError;
+use super::extstore::ExtStore;
 use super::key::Key;
+use super::listener::Listener;
+use super::listener::Listeners;
+use super::listener::RemovalReason;
+use super::tag_versions::TAG_VERSIONS_MAX;
+use super::tag_versions::TAG_VERSIONS_MAX_MEM;
+use super::tag_versions::TagVersions;
 use super::typedefs::CacheResult;
 use super::value::Value;
 
 
+// Scans that are in progress at the same time. Clients may abandon a scan at
+// any point, so beyond this the oldest scan is dropped to make room.
+const SCANS_MAX_OPEN: usize = 16;
+
+// Values looked at for writing out to disk when making room, starting with
+// the least recently used. Like eviction, this only ever looks at the oldest
+// few values, so that it takes the same time however big the cache is.
+const EXT_WRITE_OUT_MAX: usize = 64;
+
+// Bytes copied per step when compacting the store on disk. Steps are taken
+// in between commands, so this bounds how long a client may have to wait.
+const EXT_COMPACT_STEP: u64 = 1024 * 1024;
+
+
+#[derive(Debug, Clone)]
 pub struct CacheStats {
     pub bytes: u64, // Bytes currently stored
     pub evictions: u64, // Number of items removed to make space for new items
@@ -17,6 +51,14 @@ pub struct CacheStats {
     pub delete_hits: u64,
     pub reclaimed: u64, // Number of times an entry was reclaimed to store a new entry
     pub total_items: u64, // Total items stored since server started
+    pub crawler_items_checked: u64, // Items inspected by the expiry crawler
+    pub crawler_reclaimed: u64, // Dead items removed by the expiry crawler
+    pub compress_bytes_in: u64, // Bytes of values that were compressed
+    pub compress_bytes_out: u64, // ...and what they were compressed to
+    pub compress_time: f64, // Seconds spent compressing values
+    pub decompress_time: f64, // Seconds spent decompressing values
+    pub delete_prefix_items: u64, // Live items removed by delete_prefix
+    pub scan_items_checked: u64, // Items inspected by scans
 }
 
 impl CacheStats {
@@ -30,9 +72,36 @@ impl CacheStats {
             get_misses: 0,
             reclaimed: 0,
             total_items: 0,
+            crawler_items_checked: 0,
+            crawler_reclaimed: 0,
+            compress_bytes_in: 0,
+            compress_bytes_out: 0,
+            compress_time: 0.0,
+            decompress_time: 0.0,
+            delete_prefix_items: 0,
+            scan_items_checked: 0,
         }
     }
 
+    fn add(&mut self, other: &CacheStats) {
+        self.bytes += other.bytes;
+        self.delete_hits += other.delete_hits;
+        self.delete_misses += other.delete_misses;
+        self.evictions += other.evictions;
+        self.get_hits += other.get_hits;
+        self.get_misses += other.get_misses;
+        self.reclaimed += other.reclaimed;
+        self.total_items += other.total_items;
+        self.crawler_items_checked += other.crawler_items_checked;
+        self.crawler_reclaimed += other.crawler_reclaimed;
+        self.compress_bytes_in += other.compress_bytes_in;
+        self.compress_bytes_out += other.compress_bytes_out;
+        self.compress_time += other.compress_time;
+        self.decompress_time += other.decompress_time;
+        self.delete_prefix_items += other.delete_prefix_items;
+        self.scan_items_checked += other.scan_items_checked;
+    }
+
     fn bytes_add(&mut self, key: &Key, value: &Value) {
         self.bytes += key.mem_size() as u64;
         self.bytes += value.mem_size() as u64;
@@ -45,82 +114,208 @@ impl CacheStats {
 }
 
 
-pub struct Cache {
-    pub capacity: u64, // in bytes
-    storage: LinkedHashMap<Key, Value>,
-    item_lifetime: f64, // in seconds, <0 for unlimited
+// Where a scan has got to. Partitions are walked one after the other, each
+// in the order of its keys.
+struct Scan {
+    prefix: Vec<u8>,
+    partitions: Vec<Option<String>>, // left to walk, None for the default
+    after: Option<Key>, // the last key checked in the first of them
+}
+
+
+// Where a walk over all the items has got to, see Cache::walk
+pub struct Walk {
+    partitions: Vec<Option<String>>, // left to walk, None for the default
+    after: Option<Key>, // the last key returned in the first of them
+}
+
+impl Walk {
+    pub fn is_done(&self) -> bool {
+        self.partitions.is_empty()
+    }
+}
+
+
+// Everything that needs to know when an item leaves the cache: the
+// listeners, and the store on disk, which lets go of the data of the value
+struct OnRemoval<'a> {
+    listeners: &'a mut Listeners,
+    extstore: &'a mut Option<ExtStore>,
+}
+
+impl<'a> OnRemoval<'a> {
+    fn new(listeners: &'a mut Listeners,
+           extstore: &'a mut Option<ExtStore>)
+           -> OnRemoval<'a> {
+        OnRemoval {
+            listeners: listeners,
+            extstore: extstore,
+        }
+    }
+
+    fn notify(&mut self, key: &Key, value: &Value, reason: RemovalReason) {
+        let id = value.get_ext_id();
+        let (id, extstore) = match (id, self.extstore.as_mut()) {
+            (Some(id), Some(extstore)) => (id, extstore),
+            _ => return self.listeners.notify(key, value, reason),
+        };
+
+        // The listeners get the data too, so it's read back while it's there
+        if !self.listeners.is_empty() {
+            match extstore.read(id) {
+                Ok(chunks) => {
+                    let mut value = value.clone();
+                    value.read_in(chunks);
+                    self.listeners.notify(key, &value, reason);
+                }
+                Err(_) => self.listeners.notify(key, value, reason),
+            }
+        }
+
+        extstore.release(id);
+    }
+}
+
+
+// A slice of the cache with its own memory quota, LRU order and stats. Every
+// namespace is stored in a partition of its own, so that making space in one
+// never evicts items from another.
+pub struct Partition {
+    capacity: u64, // in bytes
+    storage: LinkedHashMap<Rc<Key>, Value>,
+    // The same keys in order, so that a walk over them (like a scan) can
+    // stop at any point and pick up where it left off later on. The keys
+    // are shared with the map, so like the bookkeeping of the map itself
+    // this isn't counted in the bytes stored.
+    keys: BTreeSet<Rc<Key>>,
     global_exptime: f64, // unixtime, <0 for unset
 
-    key_maxlen: u64, // in bytes
-    value_maxlen: u64, // in bytes
+    // The last key the crawler checked, the current pass carries on after
+    // it. None to start a new pass.
+    crawler_cursor: Option<Key>,
 
     stats: CacheStats,
 }
 
-impl Cache {
-    pub fn new(capacity: u64) -> Cache {
-        Cache {
+impl Partition {
+    fn new(capacity: u64) -> Partition {
+        Partition {
             capacity: capacity,
+            crawler_cursor: None,
             global_exptime: -1.0,
-            item_lifetime: -1.0,
-            key_maxlen: 250, // 250b
+            keys: BTreeSet::new(),
             stats: CacheStats::new(),
-            value_maxlen: 1048576, // 1mb
             storage: LinkedHashMap::new(),
         }
     }
 
-    pub fn with_item_lifetime(&mut self, item_lifetime: f64) -> &mut Cache {
-        self.item_lifetime = item_lifetime;
-        self
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
     }
 
-    pub fn with_key_maxlen(&mut self, key_maxlen: u64) -> &mut Cache {
-        self.key_maxlen = key_maxlen;
-        self
+    pub fn get_stats(&self) -> &CacheStats {
+        &self.stats
     }
 
-    pub fn with_value_maxlen(&mut self, value_maxlen: u64) -> &mut Cache {
-        self.value_maxlen = value_maxlen;
-        self
+    pub fn len(&self) -> usize {
+        self.storage.len()
     }
 
 
-    pub fn get_stats(&self) -> &CacheStats {
-        &self.stats
-    }
+    fn compress_value(&mut self, value: &mut Value, compress_threshold: u64) {
+        if compress_threshold == 0 ||
+           (value.len() as u64) < compress_threshold {
+            return;
+        }
 
+        let len_before = value.len();
+        let started_at = time_now();
+        let compressed = value.compress();
 
-    fn check_key_len(&self, key: &Key) -> bool {
-        key.len() as u64 <= self.key_maxlen
+        // Update stats
+        self.stats.compress_time += time_now() - started_at;
+        if compressed {
+            self.stats.compress_bytes_in += len_before as u64;
+            self.stats.compress_bytes_out += value.len() as u64;
+        }
     }
 
-    fn check_value_len(&self, value: &Value) -> bool {
-        value.len() as u64 <= self.value_maxlen
+    fn decompress_value(&mut self, value: &mut Value) -> CacheResult<()> {
+        if !value.is_compressed() {
+            return Ok(());
+        }
+
+        let started_at = time_now();
+        let rv = value.decompress();
+
+        // Update stats
+        self.stats.decompress_time += time_now() - started_at;
+
+        rv
     }
 
 
-    fn evict_oldest(&mut self) -> CacheResult<(Key, Value)> {
-        let opt = self.storage.pop_back();
+    // Takes (or gives back) memory held outside of the storage, evicting the
+    // oldest items if that leaves too little for them
+    fn charge(&mut self, size: isize, on_removal: &mut OnRemoval) {
+        self.stats.bytes = (self.stats.bytes as i64 + size as i64) as u64;
+
+        while self.stats.bytes > self.capacity {
+            match self.evict_oldest(on_removal) {
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self, on_removal: &mut OnRemoval) -> CacheResult<()> {
+        let opt = self.storage.pop_front();
 
         match opt {
             Some((key, value)) => {
+                self.keys.remove(&key);
+
                 // Update stats
                 self.stats.bytes_subtract(&key, &value);
                 self.stats.evictions += 1;
 
-                Ok((key, value))
+                on_removal.notify(&key, &value, RemovalReason::Evicted);
+
+                Ok(())
             }
             None => Err(CacheError::EvictionFailed),
         }
     }
 
-    fn value_is_alive(&self, value: &Value) -> bool {
+    fn value_is_alive(&self,
+                      value: &Value,
+                      now: f64,
+                      item_lifetime: f64,
+                      tag_versions: &TagVersions)
+                      -> bool {
+        self.death_reason(value, now, item_lifetime, tag_versions).is_none()
+    }
+
+    // Returns why the value is dead, or None if it's still alive
+    fn death_reason(&self,
+                    value: &Value,
+                    now: f64,
+                    item_lifetime: f64,
+                    tag_versions: &TagVersions)
+                    -> Option<RemovalReason> {
+        // If any of its tags has been invalidated since the value was stored
+        // then it's dead
+        for tag in value.get_tags() {
+            if tag.version != tag_versions.get(&tag.name) {
+                return Some(RemovalReason::Flushed);
+            }
+        }
+
         // If we have a global exptime set, then any item touched before it is
         // dead
         if self.global_exptime > 0.0 {
             if *value.get_atime() < self.global_exptime {
-                return false;
+                return Some(RemovalReason::Flushed);
             }
         }
 
@@ -129,107 +324,344 @@ impl Cache {
         if *value.get_exptime() > 0.0 {
             if self.global_exptime > 0.0 {
                 if *value.get_exptime() < self.global_exptime {
-                    return false;
+                    return Some(RemovalReason::Flushed);
                 }
             }
 
-            if *value.get_exptime() < time_now() {
-                return false;
+            if *value.get_exptime() < now {
+                return Some(RemovalReason::Expired);
             } else {
-                return true;
+                return None;
             }
         }
 
         // if we have no lifetime setting then values are always live
-        if self.item_lifetime < 0.0 {
-            return true;
+        if item_lifetime < 0.0 {
+            return None;
         }
 
         // otherwise use lifetime to determine liveness
-        *value.get_atime() + self.item_lifetime > time_now()
+        match *value.get_atime() + item_lifetime > now {
+            true => None,
+            false => Some(RemovalReason::Expired),
+        }
     }
 
 
-    pub fn contains_key(&mut self, key: &Key) -> CacheResult<bool> {
-        let result = self.get(key);
+    fn crawl(&mut self,
+             crawler_batch: u64,
+             now: f64,
+             item_lifetime: f64,
+             tag_versions: &TagVersions,
+             on_removal: &mut OnRemoval)
+             -> u64 {
+        // A pass goes over the keys in order, a batch at a time, so only
+        // the batch is ever copied. Keys added behind the cursor wait for
+        // the next pass, which starts once this one has reached the end.
+        let (batch, next) = self.keys_after(b"",
+                                            self.crawler_cursor.as_ref(),
+                                            crawler_batch as usize);
+        self.crawler_cursor = next;
+
+        let mut reclaimed = 0;
+
+        for key in batch {
+            let reason = match self.storage.get(&key) {
+                Some(value) => {
+                    self.death_reason(value, now, item_lifetime, tag_versions)
+                }
+                None => continue,
+            };
 
-        match result {
-            // We know how to interpret found and not found
-            Ok(_) => Ok(true),
-            Err(CacheError::KeyNotFound) => Ok(false),
+            // Update stats
+            self.stats.crawler_items_checked += 1;
 
-            // Some other error
-            Err(x) => Err(x),
+            match reason {
+                Some(reason) => {
+                    let value = self.storage.remove(&key).unwrap();
+                    self.keys.remove(&key);
+
+                    // Update stats
+                    self.stats.bytes_subtract(&key, &value);
+                    self.stats.crawler_reclaimed += 1;
+
+                    on_removal.notify(&key, &value, reason);
+                    reclaimed += 1;
+                }
+                None => (),
+            }
         }
-    }
 
-    pub fn flush_all(&mut self, exptime: f64) -> CacheResult<()> {
-        self.global_exptime = exptime;
-        Ok(())
+        reclaimed
     }
 
-    pub fn get(&mut self, key: &Key) -> CacheResult<&Value> {
-        // Check key size
-        if !self.check_key_len(key) {
-            return Err(CacheError::KeyTooLong);
+    // Removes the keys that start with the prefix. Only those keys are
+    // looked at, one at a time, however many others there are.
+    fn delete_prefix(&mut self,
+                     prefix: &[u8],
+                     now: f64,
+                     item_lifetime: f64,
+                     tag_versions: &TagVersions,
+                     on_removal: &mut OnRemoval)
+                     -> u64 {
+        let prefix_key = Key::new(prefix.to_vec());
+        let mut deleted = 0;
+
+        loop {
+            // The first one left, since the ones before it are gone
+            let key = match self.keys
+                                .range::<Key, _>((Bound::Included(&prefix_key),
+                                                  Bound::Unbounded))
+                                .next() {
+                Some(key) if key.item.starts_with(prefix) => key.clone(),
+                _ => break,
+            };
+
+            let value = self.storage.remove(&key).unwrap();
+            self.keys.remove(&key);
+
+            // Update stats
+            self.stats.bytes_subtract(&key, &value);
+
+            // Dead items are removed as well, but they were gone already
+            match self.death_reason(&value, now, item_lifetime, tag_versions) {
+                Some(reason) => on_removal.notify(&key, &value, reason),
+                None => {
+                    self.stats.delete_prefix_items += 1;
+                    deleted += 1;
+
+                    on_removal.notify(&key, &value, RemovalReason::Deleted);
+                }
+            }
         }
 
-        // Pop the value first
-        let opt = self.storage.remove(key);
+        deleted
+    }
+
+    // Up to count keys that start with the prefix, in order, starting after
+    // the key given. Returns them along with the key to carry on after next
+    // time, if there are any more.
+    fn keys_after(&self,
+                  prefix: &[u8],
+                  after: Option<&Key>,
+                  count: usize)
+                  -> (Vec<Rc<Key>>, Option<Key>) {
+        let prefix_key = Key::new(prefix.to_vec());
+        let start = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Included(&prefix_key),
+        };
+
+        let mut iter = self.keys
+                           .range::<Key, _>((start, Bound::Unbounded))
+                           .take_while(|key| key.item.starts_with(prefix));
+        let keys: Vec<Rc<Key>> = iter.by_ref().take(count).cloned().collect();
+
+        // Are there any more after the last one?
+        let last = match (iter.next(), keys.last()) {
+            (Some(_), Some(key)) => Some((**key).clone()),
+            _ => None,
+        };
+
+        (keys, last)
+    }
 
-        // We didn't find it
-        if opt.is_none() {
-            self.stats.get_misses += 1;
-            return Err(CacheError::KeyNotFound);
+    // Checks up to count keys that start with the prefix, in order, starting
+    // after the key given. Returns the number of keys checked, the live ones
+    // among them, and the key to carry on after next time, if there are any
+    // more.
+    fn scan(&mut self,
+            prefix: &[u8],
+            after: Option<&Key>,
+            count: usize,
+            now: f64,
+            item_lifetime: f64,
+            tag_versions: &TagVersions)
+            -> (usize, Vec<Key>, Option<Key>) {
+        let (checked, last) = self.keys_after(prefix, after, count);
+
+        let mut keys = vec![];
+        for key in checked.iter() {
+            let value = self.storage.get(key).unwrap();
+            if self.value_is_alive(value, now, item_lifetime, tag_versions) {
+                keys.push((**key).clone());
+            }
         }
 
-        // From here on we can assume we did find it
-        let mut value = opt.unwrap();
+        // Update stats
+        self.stats.scan_items_checked += checked.len() as u64;
 
-        // The value has been successfully removed - update stats
-        self.stats.bytes_subtract(key, &value);
+        (checked.len(), keys, last)
+    }
 
-        // Now check if the value is still alive
-        if !self.value_is_alive(&value) {
-            self.stats.get_misses += 1;
-            return Err(CacheError::KeyNotFound);
-        }
+    fn get(&mut self,
+           key: &Key,
+           now: f64,
+           item_lifetime: f64,
+           tag_versions: &TagVersions,
+           on_removal: &mut OnRemoval)
+           -> CacheResult<Cow<Value>> {
+        let reason = match self.storage.get(key) {
+            Some(value) => {
+                self.death_reason(value, now, item_lifetime, tag_versions)
+            }
+            None => {
+                // We didn't find it
+                self.stats.get_misses += 1;
+                return Err(CacheError::KeyNotFound);
+            }
+        };
 
-        // Update the value to mark that it's been accessed just now
-        value.touch();
+        // It's no longer alive
+        match reason {
+            Some(reason) => {
+                let value = self.storage.remove(key).unwrap();
+                self.keys.remove(key);
 
-        // We are going to re-instate the key - update stats
-        self.stats.bytes_add(key, &value);
+                // Update stats
+                self.stats.bytes_subtract(key, &value);
+                self.stats.get_misses += 1;
+
+                on_removal.notify(key, &value, reason);
+                return Err(CacheError::KeyNotFound);
+            }
+            None => (),
+        }
+
+        // Update stats
         self.stats.get_hits += 1;
 
-        // Now we reinsert the key to refresh it
-        self.storage.insert(key.clone(), value);
+        // Mark that it's been accessed just now, which also makes it the most
+        // recently used
+        let value = self.storage.get_refresh(key).unwrap();
+        value.touch(now);
+
+        // Hand out the data in plain form, the stored value stays compressed
+        if value.is_compressed() {
+            let started_at = time_now();
+            let rv = value.decompressed();
+
+            // Update stats
+            self.stats.decompress_time += time_now() - started_at;
 
-        // Load since we need to return it
-        let value = self.storage.get(key).unwrap();
+            return rv;
+        }
 
         // Return success
-        Ok(value)
+        Ok(Cow::Borrowed(value))
     }
 
-    pub fn len(&self) -> usize {
-        self.storage.len()
+    // Removes the value to be modified and put back by the caller, who
+    // calls forget if it isn't put back. A dead value is discarded instead.
+    fn take_alive(&mut self,
+                  key: &Key,
+                  now: f64,
+                  item_lifetime: f64,
+                  tag_versions: &TagVersions,
+                  on_removal: &mut OnRemoval)
+                  -> Option<Value> {
+        let value = match self.storage.remove(key) {
+            Some(value) => value,
+            None => return None,
+        };
+
+        // Update stats
+        self.stats.bytes_subtract(key, &value);
+
+        match self.death_reason(&value, now, item_lifetime, tag_versions) {
+            Some(reason) => {
+                self.keys.remove(key);
+                on_removal.notify(key, &value, reason);
+                None
+            }
+            None => Some(value),
+        }
     }
 
-    pub fn remove(&mut self, key: &Key) -> CacheResult<Value> {
-        // Check key size
-        if !self.check_key_len(key) {
-            return Err(CacheError::KeyTooLong);
+    // Drops the key of a value taken out with take_alive for good
+    fn forget(&mut self, key: &Key) {
+        self.keys.remove(key);
+    }
+
+    // Applies op to the number the value holds, in place. The value only
+    // ever shrinks by becoming a counter, so there is no need to reclaim.
+    fn update_counter<F>(&mut self,
+                         key: &Key,
+                         op: F,
+                         now: f64,
+                         item_lifetime: f64,
+                         tag_versions: &TagVersions,
+                         on_removal: &mut OnRemoval)
+                         -> CacheResult<u64>
+        where F: FnOnce(u64) -> u64
+    {
+        let reason = match self.storage.get(key) {
+            Some(value) => {
+                self.death_reason(value, now, item_lifetime, tag_versions)
+            }
+            None => return Err(CacheError::KeyNotFound),
+        };
+
+        match reason {
+            Some(reason) => {
+                let value = self.storage.remove(key).unwrap();
+                self.keys.remove(key);
+
+                // Update stats
+                self.stats.bytes_subtract(key, &value);
+
+                on_removal.notify(key, &value, reason);
+                return Err(CacheError::KeyNotFound);
+            }
+            None => (),
         }
 
+        // Updated where it is, looking it up refreshes it
+        let value = self.storage.get_refresh(key).unwrap();
+        let num = match value.get_counter() {
+            None if value.is_collection() => return Err(CacheError::WrongType),
+            Some(num) => op(num),
+            None => return Err(CacheError::NotANumber),
+        };
+
+        // Bytes turned into a counter no longer take up space
+        let size_before = value.mem_size() as u64;
+        value.set_counter(num);
+        value.touch(now);
+        self.stats.bytes = self.stats.bytes + value.mem_size() as u64 -
+                           size_before;
+
+        Ok(num)
+    }
+
+    // Stores a value taken out with take_alive again, as it was. It took up
+    // the space before, so there is no need to make room.
+    fn put_back(&mut self, key: &Key, value: Value) {
+        // Update stats
+        self.stats.bytes_add(key, &value);
+
+        let key = self.keys.get(key).unwrap().clone();
+        self.storage.insert(key, value);
+    }
+
+    fn remove(&mut self,
+              key: &Key,
+              on_removal: &mut OnRemoval)
+              -> CacheResult<Value> {
         let opt = self.storage.remove(key);
 
         match opt {
-            Some(value) => {
+            Some(mut value) => {
+                self.keys.remove(key);
+
                 // Update stats
                 self.stats.delete_hits += 1;
                 self.stats.bytes_subtract(key, &value);
 
+                on_removal.notify(key, &value, RemovalReason::Deleted);
+
+                try!(self.decompress_value(&mut value));
+
                 Ok((value))
             }
             None => {
@@ -241,77 +673,1228 @@ impl Cache {
         }
     }
 
-    pub fn set(&mut self, key: Key, mut value: Value) -> CacheResult<()> {
-        // Check key & value sizes
-        if !self.check_key_len(&key) {
-            return Err(CacheError::KeyTooLong);
-        }
-        if !self.check_value_len(&value) {
-            return Err(CacheError::ValueTooLong);
-        }
-
-        // Does this item even fit into our cache at all?
+    // A value that is put back after it was taken out to be modified is
+    // not new, and isn't counted as such
+    fn insert(&mut self,
+              key: Key,
+              value: Value,
+              is_new: bool,
+              on_removal: &mut OnRemoval)
+              -> CacheResult<()> {
+        // Does this item even fit into our partition at all?
         if key.mem_size() as u64 + value.mem_size() as u64 > self.capacity {
+            self.forget_missing(&key);
             return Err(CacheError::CapacityExceeded);
         }
 
-        // Do we already store this key?
-        if self.storage.contains_key(&key) {
-            let mut plus_delta = 0u64;
-            {
-                // Load the existing value
-                let prev_value = self.storage.get(&key).unwrap();
-
-                // We're updating the key, possibly with a different size value
+        // Do we already store this key? Then we're replacing the value,
+        // possibly with a different size one. Taking it out first also makes
+        // sure it isn't evicted below.
+        match self.storage.remove(&key) {
+            Some(prev_value) => {
+                // Update stats
                 self.stats.bytes_subtract(&key, &prev_value);
 
-                // Figure out how much more space we need to store the new value
-                if value.mem_size() > prev_value.mem_size() {
-                    plus_delta = value.mem_size() as u64 -
-                                 prev_value.mem_size() as u64;
-                }
+                on_removal.notify(&key, &prev_value, RemovalReason::Replaced);
             }
+            None => (),
+        }
 
-            // Would the new value exceed our capacity? Then we need to reclaim
-            loop {
-                if self.stats.bytes + key.mem_size() as u64 + plus_delta <=
-                   self.capacity {
-                    break;
-                }
-
-                try!(self.evict_oldest());
-
-                // Update stats
-                self.stats.reclaimed += 1;
+        // Do we have space for the new item?
+        loop {
+            if self.stats.bytes + key.mem_size() as u64 +
+               value.mem_size() as u64 <= self.capacity {
+                break;
             }
 
-        } else {
-            // Do we have space for the new item?
-            loop {
-                if self.stats.bytes + key.mem_size() as u64 +
-                   value.mem_size() as u64 <=
-                   self.capacity {
-                    break;
+            match self.evict_oldest(on_removal) {
+                Ok(_) => (),
+                Err(err) => {
+                    self.forget_missing(&key);
+                    return Err(err);
                 }
-
-                try!(self.evict_oldest());
-
-                // Update stats
-                self.stats.reclaimed += 1;
             }
+
+            // Update stats
+            self.stats.reclaimed += 1;
         }
 
         // Update stats
         self.stats.bytes_add(&key, &value);
-        self.stats.total_items += 1;
-
-        // Update atime for value
-        value.touch();
+        if is_new {
+            self.stats.total_items += 1;
+        }
 
-        // Store the value
+        // Store the value, under the key in the index if it's there already
+        let key = match self.keys.get(&key) {
+            Some(key) => key.clone(),
+            None => {
+                let key = Rc::new(key);
+                self.keys.insert(key.clone());
+                key
+            }
+        };
         self.storage.insert(key, value);
 
         // Return success
         Ok(())
     }
+
+    // A key that was taken out to be put back by insert is gone if that
+    // fails
+    fn forget_missing(&mut self, key: &Key) {
+        if !self.storage.contains_key(key) {
+            self.keys.remove(key);
+        }
+    }
+}
+
+
+pub struct Cache {
+    pub capacity: u64, // in bytes, over all partitions
+    item_lifetime: f64, // in seconds, <0 for unlimited
+
+    clock: Box<Clock>, // tells whether items are still alive
+    time_shift: f64, // in seconds, added to the time of the clock
+
+    key_maxlen: u64, // in bytes
+    value_maxlen: u64, // in bytes
+
+    crawler_batch: u64, // items checked per crawl increment, 0 to disable
+
+    compress_threshold: u64, // compress values at least this big, 0 to disable
+
+    extstore: Option<ExtStore>, // second tier on disk, if any
+    ext_item_min: u64, // write out values at least this big
+
+    namespace_delimiter: u8, // separates the namespace from the rest of a key
+    namespaces: HashMap<String, Partition>,
+    default: Partition, // keys outside of any namespace
+
+    tag_versions: TagVersions, // tags that have been invalidated
+
+    scans: HashMap<u64, Scan>, // by cursor
+    last_scan_cursor: u64,
+
+    listeners: Listeners, // called when items leave the cache
+
+    changes: u64, // bumped by every command that changes what is stored
+}
+
+impl Cache {
+    pub fn new(capacity: u64) -> Cache {
+        Cache {
+            capacity: capacity,
+            changes: 0,
+            clock: Box::new(SystemClock),
+            compress_threshold: 0,
+            crawler_batch: 100,
+            default: Partition::new(capacity),
+            ext_item_min: 512, // 512b
+            extstore: None,
+            item_lifetime: -1.0,
+            key_maxlen: 250, // 250b
+            last_scan_cursor: 0,
+            listeners: Listeners::new(),
+            namespace_delimiter: b':',
+            namespaces: HashMap::new(),
+            scans: HashMap::new(),
+            tag_versions: TagVersions::new(TAG_VERSIONS_MAX,
+                                          TAG_VERSIONS_MAX_MEM),
+            time_shift: 0.0,
+            value_maxlen: 1048576, // 1mb
+        }
+    }
+
+    pub fn with_clock(&mut self, clock: Box<Clock>) -> &mut Cache {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_compress_threshold(&mut self,
+                                   compress_threshold: u64)
+                                   -> &mut Cache {
+        self.compress_threshold = compress_threshold;
+        self
+    }
+
+    pub fn with_crawler_batch(&mut self, crawler_batch: u64) -> &mut Cache {
+        self.crawler_batch = crawler_batch;
+        self
+    }
+
+    // Values that would otherwise be evicted to make room are written out to
+    // the store instead, as long as they are at least ext_item_min long.
+    // Smaller ones don't free up enough memory to be worth it.
+    pub fn with_extstore(&mut self, extstore: ExtStore) -> &mut Cache {
+        self.extstore = Some(extstore);
+        self
+    }
+
+    pub fn with_ext_item_min(&mut self, ext_item_min: u64) -> &mut Cache {
+        self.ext_item_min = ext_item_min;
+        self
+    }
+
+    pub fn with_item_lifetime(&mut self, item_lifetime: f64) -> &mut Cache {
+        self.item_lifetime = item_lifetime;
+        self
+    }
+
+    pub fn with_key_maxlen(&mut self, key_maxlen: u64) -> &mut Cache {
+        self.key_maxlen = key_maxlen;
+        self
+    }
+
+    // Keys that start with the name of a namespace followed by the delimiter
+    // are stored in that namespace. Its quota is taken from the space left
+    // for keys outside of any namespace, and can't be more than that (the
+    // options reject quotas that add up to more than the whole cache).
+    pub fn with_namespace(&mut self, name: &str, quota: u64) -> &mut Cache {
+        let quota = quota.min(self.default.capacity);
+        self.default.capacity -= quota;
+        self.namespaces.insert(name.to_string(), Partition::new(quota));
+        self
+    }
+
+    // Calls the listener with every item that leaves the cache, along with
+    // the reason why. Items that expire or are flushed are only found to be
+    // dead when they're accessed or crawled, so that's when they're reported.
+    // Values are handed over in plain form, and data that was written out to
+    // disk is read back for this. Only data that was dropped from disk to
+    // make room there is handed over empty, since there's nothing to read.
+    pub fn with_listener(&mut self, listener: Listener) -> &mut Cache {
+        self.listeners.add(listener);
+        self
+    }
+
+    pub fn with_namespace_delimiter(&mut self, delimiter: u8) -> &mut Cache {
+        self.namespace_delimiter = delimiter;
+        self
+    }
+
+    pub fn with_value_maxlen(&mut self, value_maxlen: u64) -> &mut Cache {
+        self.value_maxlen = value_maxlen;
+        self
+    }
+
+
+    // Tells whether anything was changed in between two calls, eg. whether a
+    // command took effect. Evictions and expiry don't count.
+    pub fn get_change_count(&self) -> u64 {
+        self.changes
+    }
+
+    // The stats summed up over all partitions
+    pub fn get_stats(&self) -> CacheStats {
+        let mut stats = self.default.stats.clone();
+        for partition in self.namespaces.values() {
+            stats.add(&partition.stats);
+        }
+
+        stats
+    }
+
+    // The time as far as the cache is concerned, ie. that of the clock plus
+    // any shift
+    pub fn now(&self) -> f64 {
+        self.clock.now() + self.time_shift
+    }
+
+    // Moves the time of the cache forward (or back), eg. to let items expire
+    // without waiting for them to
+    pub fn shift_time(&mut self, secs: f64) {
+        self.time_shift += secs;
+    }
+
+    pub fn get_extstore(&self) -> Option<&ExtStore> {
+        self.extstore.as_ref()
+    }
+
+    pub fn has_namespaces(&self) -> bool {
+        !self.namespaces.is_empty()
+    }
+
+    pub fn get_namespace(&self, name: &str) -> Option<&Partition> {
+        self.namespaces.get(name)
+    }
+
+    // Returns the name of the namespace the key is stored in, if any
+    pub fn get_namespace_name(&self, key: &Key) -> Option<&str> {
+        let name = match self.get_namespace_prefix(key) {
+            Some(name) => name,
+            None => return None,
+        };
+
+        match self.namespaces.get_key_value(name) {
+            Some((name, _)) => Some(name),
+            None => None,
+        }
+    }
+
+    fn get_namespace_prefix<'a>(&self, key: &'a Key) -> Option<&'a str> {
+        let delimiter = self.namespace_delimiter;
+        let pos = match key.item.iter().position(|byte| *byte == delimiter) {
+            Some(pos) => pos,
+            None => return None,
+        };
+
+        str::from_utf8(&key.item[..pos]).ok()
+    }
+
+    fn get_partition(&self, key: &Key) -> &Partition {
+        if self.namespaces.is_empty() {
+            return &self.default;
+        }
+
+        match self.get_namespace_prefix(key) {
+            Some(name) => self.namespaces.get(name).unwrap_or(&self.default),
+            None => &self.default,
+        }
+    }
+
+    fn get_partition_mut(&mut self, key: &Key) -> &mut Partition {
+        let name = self.get_namespace_prefix(key);
+        select_partition(&mut self.default, &mut self.namespaces, name)
+    }
+
+
+    fn check_key_len(&self, key: &Key) -> bool {
+        key.len() as u64 <= self.key_maxlen
+    }
+
+    fn check_value_len(&self, value: &Value) -> bool {
+        value.len() as u64 <= self.value_maxlen
+    }
+
+
+    pub fn item_is_alive(&self, key: &Key, value: &Value) -> bool {
+        !self.is_lost(value) &&
+        self.get_partition(key).value_is_alive(value,
+                                               self.now(),
+                                               self.item_lifetime,
+                                               &self.tag_versions)
+    }
+
+    // Tells whether the data of the value was written out to disk and has
+    // since been dropped from there to make space
+    fn is_lost(&self, value: &Value) -> bool {
+        match (value.get_ext_id(), &self.extstore) {
+            (Some(id), &Some(ref extstore)) => !extstore.contains(id),
+            (Some(_), &None) => true,
+            (None, _) => false,
+        }
+    }
+
+    // Removes the value stored under the key if its data was dropped from
+    // disk, so that from here on it's treated like any other missing item
+    fn discard_lost(&mut self, key: &Key) {
+        let id = match self.get_partition(key).storage.get(key) {
+            Some(value) => {
+                match value.get_ext_id() {
+                    Some(id) => id,
+                    None => return,
+                }
+            }
+            None => return,
+        };
+
+        let found = match self.extstore {
+            Some(ref mut extstore) => extstore.check(id),
+            None => false,
+        };
+        if found {
+            return;
+        }
+
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        let value = partition.storage.remove(key).unwrap();
+        partition.keys.remove(key);
+
+        // Update stats
+        partition.stats.bytes_subtract(key, &value);
+
+        // It was dropped to make room on disk
+        self.listeners.notify(key, &value, RemovalReason::Evicted);
+    }
+
+    // Writes out the least recently used values, so that the partition has
+    // room for the value to be stored under the key without evicting
+    // anything. If that can't be done we leave it to eviction, which would
+    // otherwise drop the values we just wrote out first.
+    fn write_out_cold(&mut self, key: &Key, value: &Value) {
+        let ext_item_min = self.ext_item_min;
+
+        let keys = {
+            let partition = self.get_partition(key);
+
+            // The value stored under the key now is about to be replaced
+            let stored = match partition.storage.get(key) {
+                Some(prev) => key.mem_size() + prev.mem_size(),
+                None => 0,
+            };
+            let wanted = partition.stats.bytes - stored as u64 +
+                         key.mem_size() as u64 +
+                         value.mem_size() as u64;
+            if wanted <= partition.capacity {
+                return;
+            }
+
+            let mut needed = wanted - partition.capacity;
+            let mut keys = vec![];
+            for (cold_key, cold_value) in partition.storage
+                                                   .iter()
+                                                   .take(EXT_WRITE_OUT_MAX) {
+                if needed == 0 {
+                    break;
+                }
+                if **cold_key == *key || !cold_value.can_write_out() ||
+                   (cold_value.len() as u64) < ext_item_min {
+                    continue;
+                }
+
+                needed = needed.saturating_sub(cold_value.len() as u64);
+                keys.push((**cold_key).clone());
+            }
+
+            if needed > 0 {
+                return;
+            }
+            keys
+        };
+
+        for key in keys {
+            match self.write_out(&key) {
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+    }
+
+    // Writes the data of the value stored under the key out to disk. If
+    // there's no room left there, compaction is started to make some and
+    // the value stays where it is for now.
+    fn write_out(&mut self, key: &Key) -> CacheResult<()> {
+        let needed = {
+            let value = self.get_partition(key).storage.get(key).unwrap();
+            let chunks = value.get_item();
+            match self.extstore.as_ref().unwrap().has_room(&chunks) {
+                true => 0,
+                false => ExtStore::disk_len(&chunks),
+            }
+        };
+
+        if needed > 0 {
+            let extstore = self.extstore.as_mut().unwrap();
+            try!(extstore.start_compaction(needed));
+            return Err(CacheError::CapacityExceeded);
+        }
+
+        // Borrow the partition and the store side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        let extstore = self.extstore.as_mut().unwrap();
+
+        let value = partition.storage.get_mut(key).unwrap();
+        let id = try!(extstore.write(&value.get_item()));
+
+        // Update stats
+        partition.stats.bytes_subtract(key, value);
+        value.write_out(id);
+        partition.stats.bytes_add(key, value);
+
+        Ok(())
+    }
+
+    // Takes back the data of the value stored under the key from disk, eg.
+    // so that it can be modified in place
+    fn read_in_place(&mut self, key: &Key) -> CacheResult<()> {
+        // Borrow the partition and the store side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+
+        let value = match partition.storage.get_mut(key) {
+            Some(value) if value.is_external() => value,
+            _ => return Ok(()),
+        };
+        // The data is held in memory from here on, so the store lets go of it
+        let chunks = match self.extstore {
+            Some(ref mut extstore) => {
+                let id = value.get_ext_id().unwrap();
+                let chunks = try!(extstore.fetch(id));
+                extstore.release(id);
+                chunks
+            }
+            None => return Err(CacheError::KeyNotFound),
+        };
+
+        // Update stats
+        partition.stats.bytes_subtract(key, value);
+        value.read_in(chunks);
+        partition.stats.bytes_add(key, value);
+
+        Ok(())
+    }
+
+    // Returns the value in plain form, with its data read back from disk if
+    // it was written out. Doesn't count as an access.
+    pub fn load_value<'a>(&self,
+                          value: &'a Value)
+                          -> CacheResult<Cow<'a, Value>> {
+        let id = match value.get_ext_id() {
+            Some(id) => id,
+            None => return value.decompressed(),
+        };
+
+        let chunks = match self.extstore {
+            Some(ref extstore) => try!(extstore.read(id)),
+            None => return Err(CacheError::KeyNotFound),
+        };
+
+        let mut value = value.clone();
+        value.read_in(chunks);
+        try!(value.decompress());
+        Ok(Cow::Owned(value))
+    }
+
+
+    pub fn contains_key(&mut self, key: &Key) -> CacheResult<bool> {
+        let result = self.get(key);
+
+        match result {
+            // We know how to interpret found and not found
+            Ok(_) => Ok(true),
+            Err(CacheError::KeyNotFound) => Ok(false),
+
+            // Some other error
+            Err(x) => Err(x),
+        }
+    }
+
+    // Takes the next step of compacting the store on disk, if it is being
+    // compacted. Returns true once there's nothing left to do.
+    pub fn compact_extstore(&mut self) -> bool {
+        match self.extstore {
+            Some(ref mut extstore) => {
+                match extstore.compact_step(EXT_COMPACT_STEP) {
+                    Ok(done) => done,
+                    // Given up on, nothing left to do
+                    Err(_) => true,
+                }
+            }
+            None => true,
+        }
+    }
+
+    // Walks the store in bounded increments and removes items that are no
+    // longer alive, so that dead items don't hold on to memory until someone
+    // happens to ask for them. Each call checks at most crawler_batch items
+    // per partition and returns the number of items reclaimed.
+    pub fn crawl(&mut self) -> u64 {
+        if self.crawler_batch == 0 {
+            return 0;
+        }
+
+        let crawler_batch = self.crawler_batch;
+        let now = self.now();
+        let item_lifetime = self.item_lifetime;
+
+        let tag_versions = &self.tag_versions;
+        let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                            &mut self.extstore);
+
+        let mut reclaimed = self.default.crawl(crawler_batch,
+                                               now,
+                                               item_lifetime,
+                                               tag_versions,
+                                               &mut on_removal);
+        for partition in self.namespaces.values_mut() {
+            reclaimed += partition.crawl(crawler_batch,
+                                         now,
+                                         item_lifetime,
+                                         tag_versions,
+                                         &mut on_removal);
+        }
+
+        reclaimed
+    }
+
+    // Removes all the keys that start with the prefix and returns the number
+    // of live items removed. A prefix that lies within a namespace only
+    // needs to be looked for in that namespace.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> u64 {
+        let now = self.now();
+        let item_lifetime = self.item_lifetime;
+        let tag_versions = &self.tag_versions;
+
+        let prefix_key = Key::new(prefix.to_vec());
+        let name = self.get_namespace_prefix(&prefix_key);
+
+        let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                            &mut self.extstore);
+
+        let deleted = match name {
+            Some(name) if self.namespaces.contains_key(name) => {
+                let partition = self.namespaces.get_mut(name).unwrap();
+                partition.delete_prefix(prefix,
+                                        now,
+                                        item_lifetime,
+                                        tag_versions,
+                                        &mut on_removal)
+            }
+            _ => {
+                let mut deleted = self.default.delete_prefix(prefix,
+                                                             now,
+                                                             item_lifetime,
+                                                             tag_versions,
+                                                             &mut on_removal);
+                for partition in self.namespaces.values_mut() {
+                    deleted += partition.delete_prefix(prefix,
+                                                       now,
+                                                       item_lifetime,
+                                                       tag_versions,
+                                                       &mut on_removal);
+                }
+                deleted
+            }
+        };
+
+        if deleted > 0 {
+            self.changes += 1;
+        }
+        deleted
+    }
+
+    pub fn flush_all(&mut self, exptime: f64) -> CacheResult<()> {
+        self.changes += 1;
+        self.default.global_exptime = exptime;
+        for partition in self.namespaces.values_mut() {
+            partition.global_exptime = exptime;
+        }
+        Ok(())
+    }
+
+    pub fn flush_namespace(&mut self,
+                           name: &str,
+                           exptime: f64)
+                           -> CacheResult<()> {
+        match self.namespaces.get_mut(name) {
+            Some(partition) => {
+                partition.global_exptime = exptime;
+                self.changes += 1;
+                Ok(())
+            }
+            None => Err(CacheError::NamespaceNotFound),
+        }
+    }
+
+    pub fn get(&mut self, key: &Key) -> CacheResult<Cow<Value>> {
+        // Check key size
+        if !self.check_key_len(key) {
+            return Err(CacheError::KeyTooLong);
+        }
+
+        // Data that was dropped from disk is as good as gone
+        self.discard_lost(key);
+
+        let now = self.now();
+
+        // Borrow the partition, the tag versions and the store side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+
+        let is_external = match partition.storage.get(key) {
+            Some(value) => value.is_external(),
+            None => false,
+        };
+        if !is_external {
+            let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                                &mut self.extstore);
+            return partition.get(key,
+                                 now,
+                                 self.item_lifetime,
+                                 &self.tag_versions,
+                                 &mut on_removal);
+        }
+
+        // The value stored stays on disk, only the copy is read back
+        let mut value = {
+            let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                                &mut self.extstore);
+            try!(partition.get(key,
+                               now,
+                               self.item_lifetime,
+                               &self.tag_versions,
+                               &mut on_removal))
+                .into_owned()
+        };
+        try!(read_in(partition, &mut self.extstore, &mut value));
+
+        Ok(Cow::Owned(value))
+    }
+
+    // Applies op to the number stored under the key and returns the result
+    pub fn update_counter<F>(&mut self, key: &Key, op: F) -> CacheResult<u64>
+        where F: FnOnce(u64) -> u64
+    {
+        // Check key size
+        if !self.check_key_len(key) {
+            return Err(CacheError::KeyTooLong);
+        }
+
+        // The number is updated in place, so it needs to be in memory
+        self.discard_lost(key);
+        try!(self.read_in_place(key));
+
+        let now = self.now();
+
+        // Borrow the partition and the tag versions side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                            &mut self.extstore);
+        let num = try!(partition.update_counter(key,
+                                                op,
+                                                now,
+                                                self.item_lifetime,
+                                                &self.tag_versions,
+                                                &mut on_removal));
+
+        self.changes += 1;
+        Ok(num)
+    }
+
+    // Applies op to the collection stored under the key and returns the
+    // result. A missing key starts out as the empty collection, or fails
+    // with KeyNotFound if there is none. A collection that is left empty is
+    // removed.
+    pub fn update_collection<F, R>(&mut self,
+                                   key: &Key,
+                                   empty: Option<Collection>,
+                                   op: F)
+                                   -> CacheResult<R>
+        where F: FnOnce(&mut Collection) -> CacheResult<R>
+    {
+        // Check key size
+        if !self.check_key_len(key) {
+            return Err(CacheError::KeyTooLong);
+        }
+
+        // Data that was dropped from disk is as good as gone
+        self.discard_lost(key);
+
+        let now = self.now();
+        let value = {
+            let name = self.get_namespace_prefix(key);
+            let partition = select_partition(&mut self.default,
+                                             &mut self.namespaces,
+                                             name);
+            let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                                &mut self.extstore);
+            match partition.take_alive(key,
+                                       now,
+                                       self.item_lifetime,
+                                       &self.tag_versions,
+                                       &mut on_removal) {
+                Some(value) if !value.is_collection() => {
+                    partition.put_back(key, value);
+                    return Err(CacheError::WrongType);
+                }
+                value => value,
+            }
+        };
+
+        let (mut value, is_new) = match (value, empty) {
+            (Some(value), _) => (value, false),
+            (None, Some(collection)) => {
+                let mut value = Value::empty();
+                value.set_collection(collection);
+                (value, true)
+            }
+            (None, None) => return Err(CacheError::KeyNotFound),
+        };
+
+        // The collection may only grow as far as it can still be stored, so
+        // that putting it back can't fail. Growing adds at most two vectors
+        // (the field and the item of a hash).
+        let max_data_len = {
+            let data_len = value.len() as u64;
+            let overhead = (key.mem_size() + value.mem_size() +
+                            2 * mem::size_of::<Vec<u8>>()) as u64 -
+                           data_len;
+            let capacity = self.get_partition(key).capacity;
+            let room = capacity.saturating_sub(overhead);
+            room.min(self.value_maxlen) as usize
+        };
+        let rv = value.update_collection(|collection| {
+            collection.set_max_data_len(max_data_len);
+            op(collection)
+        });
+
+        // Nothing left of it
+        match value.get_collection() {
+            Some(collection) if collection.is_empty() => {
+                if !is_new {
+                    self.get_partition_mut(key).forget(key);
+                    self.listeners.notify(key, &value, RemovalReason::Deleted);
+                    self.changes += 1;
+                }
+                return rv;
+            }
+            _ => (),
+        }
+
+        // It's as it was, so it still fits where it was
+        if rv.is_err() {
+            self.get_partition_mut(key).put_back(key, value);
+            return rv;
+        }
+
+        // Put it back, making room for it if it has grown
+        value.touch(now);
+        if self.extstore.is_some() {
+            self.write_out_cold(key, &value);
+        }
+
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        try!(partition.insert(key.clone(),
+                              value,
+                              is_new,
+                              &mut OnRemoval::new(&mut self.listeners,
+                                                  &mut self.extstore)));
+
+        self.changes += 1;
+        rv
+    }
+
+    // Applies op to the collection stored under the key without modifying
+    // it. Counts as an access like get does.
+    pub fn read_collection<F, R>(&mut self, key: &Key, op: F) -> CacheResult<R>
+        where F: FnOnce(&Collection) -> CacheResult<R>
+    {
+        let value = try!(self.get(key));
+
+        match value.get_collection() {
+            Some(collection) => op(collection),
+            None => Err(CacheError::WrongType),
+        }
+    }
+
+    // Tells whether the key holds a collection, without counting it as an
+    // access
+    pub fn holds_collection(&self, key: &Key) -> bool {
+        match self.get_partition(key).storage.get(key) {
+            Some(value) => {
+                value.is_collection() && self.item_is_alive(key, value)
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_flush_exptime(&self) -> f64 {
+        self.default.global_exptime
+    }
+
+    pub fn get_namespace_flush_exptime(&self, name: &str) -> Option<f64> {
+        self.namespaces.get(name).map(|partition| partition.global_exptime)
+    }
+
+    pub fn get_tag_version(&self, tag: &str) -> u64 {
+        self.tag_versions.get(tag)
+    }
+
+    // Iterates over the tags that have been invalidated, with their versions
+    pub fn iter_tag_versions(&self) -> Iter<String, u64> {
+        self.tag_versions.iter()
+    }
+
+    // The floors of the tags that aren't remembered, by bucket (see
+    // TagVersions). Only those that have been raised are returned.
+    pub fn get_tag_floors(&self) -> Vec<(usize, u64)> {
+        self.tag_versions.get_floors()
+    }
+
+    // Takes the floors raised since the last call
+    pub fn take_raised_tag_floors(&mut self) -> Vec<(usize, u64)> {
+        self.tag_versions.take_raised_floors()
+    }
+
+    // How many invalidated tags had to be forgotten, which makes the items
+    // carrying tags of the same bucket die early
+    pub fn get_tags_forgotten(&self) -> u64 {
+        self.tag_versions.get_forgotten()
+    }
+
+    // Makes every item carrying the tag dead from now on. Rather than finding
+    // the items we bump the version of the tag, which they no longer match.
+    // Returns the new version.
+    //
+    // Tags are shared by all the namespaces, so the versions aren't charged
+    // to any of them. They have a budget of their own instead (see
+    // TAG_VERSIONS_MAX_MEM).
+    pub fn invalidate_tag(&mut self, tag: &str) -> u64 {
+        self.changes += 1;
+        self.tag_versions.invalidate(tag)
+    }
+
+    // Sets the version of a tag as recovered from persistent storage
+    pub fn restore_tag_version(&mut self, tag: &str, version: u64) {
+        self.tag_versions.restore(tag, version);
+    }
+
+    // Sets the floor of a bucket of tags as recovered from persistent
+    // storage. Without it the items carrying those tags would be dead.
+    pub fn restore_tag_floor(&mut self, bucket: usize, floor: u64) {
+        self.tag_versions.restore_floor(bucket, floor);
+    }
+
+    // Charges memory that is held on behalf of the cache but outside of it,
+    // eg. the stale values kept for leases, to the partition of the key it's
+    // held for. The size is what was taken (or given back, if negative)
+    // since the last charge.
+    pub fn charge_outside(&mut self, key: &Key, size: isize) {
+        // Borrow the partition and the listeners side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                            &mut self.extstore);
+        partition.charge(size, &mut on_removal);
+    }
+
+    // Looks up a live value without counting it as an access
+    pub fn peek(&self, key: &Key) -> Option<Cow<Value>> {
+        let partition = self.get_partition(key);
+
+        match partition.storage.get(key) {
+            Some(value) if partition.value_is_alive(value,
+                                                    self.now(),
+                                                    self.item_lifetime,
+                                                    &self.tag_versions) => {
+                self.load_value(value).ok()
+            }
+            _ => None,
+        }
+    }
+
+    // Looks up a live value as it is stored, ie. maybe compressed or written
+    // out to disk, which is all it takes to read its metadata
+    pub fn peek_stored(&self, key: &Key) -> Option<&Value> {
+        let partition = self.get_partition(key);
+
+        match partition.storage.get(key) {
+            Some(value) if partition.value_is_alive(value,
+                                                    self.now(),
+                                                    self.item_lifetime,
+                                                    &self.tag_versions) => {
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    // Looks up a value that is still stored but no longer alive, eg. so that
+    // it can stand in while a fresh one is being computed
+    pub fn peek_stale(&self, key: &Key) -> Option<Cow<Value>> {
+        let partition = self.get_partition(key);
+
+        match partition.storage.get(key) {
+            Some(value) if !partition.value_is_alive(value,
+                                                     self.now(),
+                                                     self.item_lifetime,
+                                                     &self.tag_versions) => {
+                self.load_value(value).ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.namespaces
+            .values()
+            .fold(self.default.len(), |len, partition| len + partition.len())
+    }
+
+    pub fn remove(&mut self, key: &Key) -> CacheResult<Value> {
+        // Check key size
+        if !self.check_key_len(key) {
+            return Err(CacheError::KeyTooLong);
+        }
+
+        // Data that was dropped from disk is as good as gone. Data that
+        // wasn't is read back before the store lets go of it.
+        self.discard_lost(key);
+        try!(self.read_in_place(key));
+
+        // Borrow the partition and the store side by side
+        let name = self.get_namespace_prefix(key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        let mut on_removal = OnRemoval::new(&mut self.listeners,
+                                            &mut self.extstore);
+        let value = try!(partition.remove(key, &mut on_removal));
+
+        self.changes += 1;
+        Ok(value)
+    }
+
+    // Iterates over all items, partition by partition, in order of least to
+    // most recently used. Values are as stored, ie. possibly compressed or
+    // written out to disk (see load_value).
+    pub fn iter<'a>(&'a self)
+                    -> impl Iterator<Item = (&'a Key, &'a Value)> + 'a {
+        Some(&self.default)
+            .into_iter()
+            .chain(self.namespaces.values())
+            .flat_map(|partition| partition.storage.iter())
+            .map(|(key, value)| (&**key, value))
+    }
+
+    // Pages through the live keys that start with the prefix. A cursor of 0
+    // starts a new scan and the cursor returned continues it, until it comes
+    // back as 0 when the scan is complete. Each call checks at most count
+    // keys, carrying on from where the last one stopped. Keys are walked in
+    // order, so every key that is stored for the whole scan is returned
+    // once, while one stored in the meantime is returned only if it comes
+    // after where the scan has got to.
+    pub fn scan(&mut self,
+                cursor: u64,
+                prefix: &[u8],
+                count: usize)
+                -> CacheResult<(u64, Vec<Key>)> {
+        let (cursor, mut scan) = match cursor {
+            0 => self.start_scan(prefix),
+            _ => {
+                match self.scans.remove(&cursor) {
+                    Some(scan) => (cursor, scan),
+                    None => return Err(CacheError::CursorNotFound),
+                }
+            }
+        };
+
+        let now = self.now();
+        let mut keys = vec![];
+        let mut left = count;
+
+        while left > 0 && !scan.partitions.is_empty() {
+            // Borrow the partition and the tag versions side by side
+            let partition = select_partition(&mut self.default,
+                                             &mut self.namespaces,
+                                             scan.partitions[0].as_ref()
+                                                 .map(|name| name.as_str()));
+
+            let (checked, live, after) = partition.scan(&scan.prefix,
+                                                        scan.after.as_ref(),
+                                                        left,
+                                                        now,
+                                                        self.item_lifetime,
+                                                        &self.tag_versions);
+            left -= checked;
+            keys.extend(live);
+
+            // Move on to the next partition once this one is done
+            scan.after = after;
+            if scan.after.is_none() {
+                scan.partitions.remove(0);
+            }
+        }
+
+        // The scan is complete
+        if scan.partitions.is_empty() {
+            return Ok((0, keys));
+        }
+
+        self.scans.insert(cursor, scan);
+        Ok((cursor, keys))
+    }
+
+    fn start_scan(&mut self, prefix: &[u8]) -> (u64, Scan) {
+        // Make room by dropping the scan that was started first
+        if self.scans.len() >= SCANS_MAX_OPEN {
+            let oldest = *self.scans.keys().min().unwrap();
+            self.scans.remove(&oldest);
+        }
+
+        self.last_scan_cursor += 1;
+
+        // A prefix that lies within a namespace only needs to be looked for
+        // in that namespace
+        let prefix_key = Key::new(prefix.to_vec());
+        let partitions = match self.get_namespace_prefix(&prefix_key) {
+            Some(name) if self.namespaces.contains_key(name) => {
+                vec![Some(name.to_string())]
+            }
+            _ => self.get_partition_names(),
+        };
+
+        let scan = Scan {
+            prefix: prefix.to_vec(),
+            partitions: partitions,
+            after: None,
+        };
+        (self.last_scan_cursor, scan)
+    }
+
+    // The default partition first, then the namespaces by name
+    fn get_partition_names(&self) -> Vec<Option<String>> {
+        let mut names: Vec<Option<String>> =
+            self.namespaces.keys().cloned().map(Some).collect();
+        names.sort();
+        names.insert(0, None);
+        names
+    }
+
+    // Walks over all the keys one at a time, like a scan that is held by
+    // the caller rather than by a client. Items that are stored for the
+    // whole walk are returned once, dead or alive.
+    pub fn start_walk(&self) -> Walk {
+        Walk {
+            partitions: self.get_partition_names(),
+            after: None,
+        }
+    }
+
+    // Returns the key after where the walk has got to, None once it's done
+    pub fn walk(&self, walk: &mut Walk) -> Option<Key> {
+        while !walk.is_done() {
+            let name = walk.partitions[0].as_ref().map(|name| name.as_str());
+            let partition = match name {
+                Some(name) => self.namespaces.get(name).unwrap(),
+                None => &self.default,
+            };
+
+            let (mut found, _) = partition.keys_after(&[],
+                                                      walk.after.as_ref(),
+                                                      1);
+            match found.pop() {
+                Some(key) => {
+                    walk.after = Some((*key).clone());
+                    return walk.after.clone();
+                }
+                None => {
+                    // Move on to the next partition once this one is done
+                    walk.partitions.remove(0);
+                    walk.after = None;
+                }
+            }
+        }
+
+        None
+    }
+
+    // Stores an item recovered from persistent storage as it was, ie. without
+    // marking it as accessed. Returns false if the item is no longer alive
+    // and was therefore skipped.
+    pub fn restore(&mut self, key: Key, value: Value) -> CacheResult<bool> {
+        if !self.item_is_alive(&key, &value) {
+            return Ok(false);
+        }
+
+        try!(self.insert(key, value, true));
+        Ok(true)
+    }
+
+    pub fn set(&mut self, key: Key, mut value: Value) -> CacheResult<()> {
+        // Update atime for value
+        value.touch(self.now());
+
+        // The value lives until one of its tags is invalidated
+        value.stamp_tags(&self.tag_versions);
+
+        self.insert(key, value, true)
+    }
+
+    fn insert(&mut self,
+              key: Key,
+              mut value: Value,
+              is_new: bool)
+              -> CacheResult<()> {
+        // Check key & value sizes
+        if !self.check_key_len(&key) {
+            return Err(CacheError::KeyTooLong);
+        }
+        if !self.check_value_len(&value) {
+            return Err(CacheError::ValueTooLong);
+        }
+
+        let compress_threshold = self.compress_threshold;
+        {
+            let partition = self.get_partition_mut(&key);
+
+            // From here on we only deal with the size it takes to store it
+            partition.compress_value(&mut value, compress_threshold);
+        }
+
+        // Rather than evicting the least recently used values to make room
+        // for it, write them out to disk if we can
+        if self.extstore.is_some() {
+            self.write_out_cold(&key, &value);
+        }
+
+        // Borrow the partition, the listeners and the store side by side
+        let name = self.get_namespace_prefix(&key);
+        let partition = select_partition(&mut self.default,
+                                         &mut self.namespaces,
+                                         name);
+        try!(partition.insert(key,
+                              value,
+                              is_new,
+                              &mut OnRemoval::new(&mut self.listeners,
+                                                  &mut self.extstore)));
+
+        self.changes += 1;
+        Ok(())
+    }
+}
+
+
+// Reads the data of a value that was written out to disk back in, and puts
+// it in plain form. The value stored in the partition stays on disk.
+fn read_in(partition: &mut Partition,
+           extstore: &mut Option<ExtStore>,
+           value: &mut Value)
+           -> CacheResult<()> {
+    let id = match value.get_ext_id() {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    let chunks = match *extstore {
+        Some(ref mut extstore) => try!(extstore.fetch(id)),
+        None => return Err(CacheError::KeyNotFound),
+    };
+
+    value.read_in(chunks);
+    partition.decompress_value(value)
+}
+
+// Picks the partition for a key given the namespace prefix of the key, if
+// any. Takes the fields rather than the cache itself, so that the other
+// fields of the cache can be borrowed at the same time.
+fn select_partition<'a>(default: &'a mut Partition,
+                        namespaces: &'a mut HashMap<String, Partition>,
+                        name: Option<&str>)
+                        -> &'a mut Partition {
+    match name {
+        Some(name) if namespaces.contains_key(name) => {
+            namespaces.get_mut(name).unwrap()
+        }
+        _ => default,
+    }
 }
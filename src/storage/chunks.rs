@@ -0,0 +1,143 @@
+use std::slice::Iter;
+
+
+// Big values are split into chunks of at most this size, so that they don't
+// need one huge contiguous allocation and can grow at either end without
+// copying what is already there.
+pub const CHUNK_SIZE: usize = 1048576; // 1mb
+
+
+#[derive(Debug, Clone)]
+pub struct Chunks {
+    chunks: Vec<Vec<u8>>,
+    len: usize, // total over all chunks
+}
+
+impl PartialEq for Chunks {
+    // Compare the bytes, regardless of where the chunk boundaries are
+    fn eq(&self, other: &Chunks) -> bool {
+        self.len == other.len &&
+        self.chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .eq(other.chunks.iter().flat_map(|chunk| chunk.iter()))
+    }
+}
+
+impl Chunks {
+    pub fn new(bytes: Vec<u8>) -> Chunks {
+        let mut chunks = Chunks::empty();
+        chunks.append(bytes);
+        chunks
+    }
+
+    pub fn empty() -> Chunks {
+        Chunks {
+            chunks: vec![],
+            len: 0,
+        }
+    }
+
+    // Takes the chunks as they are, eg. when they have been transformed one
+    // by one
+    pub fn from_chunks(chunks: Vec<Vec<u8>>) -> Chunks {
+        let len = chunks.iter().map(|chunk| chunk.len()).sum();
+
+        Chunks {
+            chunks: chunks,
+            len: len,
+        }
+    }
+
+
+    pub fn append(&mut self, mut bytes: Vec<u8>) {
+        self.len += bytes.len();
+
+        // Top up the last chunk first
+        let mut rest = vec![];
+        match self.chunks.last_mut() {
+            Some(last) if last.len() < CHUNK_SIZE => {
+                let room = CHUNK_SIZE - last.len();
+                if bytes.len() > room {
+                    rest = bytes.split_off(room);
+                }
+                last.extend(bytes);
+            }
+            _ => {
+                rest = bytes;
+            }
+        }
+
+        self.chunks.extend(split_into_chunks(rest));
+    }
+
+    // Appends the chunks one by one, so there is never more than a chunk to
+    // copy at once
+    pub fn append_chunks(&mut self, other: Chunks) {
+        for chunk in other.chunks {
+            self.append(chunk);
+        }
+    }
+
+    pub fn prepend_chunks(&mut self, other: Chunks) {
+        for chunk in other.chunks.into_iter().rev() {
+            self.prepend(chunk);
+        }
+    }
+
+    pub fn prepend(&mut self, bytes: Vec<u8>) {
+        self.len += bytes.len();
+
+        // Merge with the first chunk if it fits, so that many small prepends
+        // don't leave behind many small chunks
+        let fits = match self.chunks.first() {
+            Some(first) => first.len() + bytes.len() <= CHUNK_SIZE,
+            None => false,
+        };
+        if fits {
+            let mut first = bytes;
+            first.extend(&self.chunks[0]);
+            self.chunks[0] = first;
+            return;
+        }
+
+        let mut chunks = split_into_chunks(bytes);
+        chunks.extend(self.chunks.drain(..));
+        self.chunks = chunks;
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn iter(&self) -> Iter<Vec<u8>> {
+        self.chunks.iter()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len);
+        for chunk in &self.chunks {
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+}
+
+
+fn split_into_chunks(bytes: Vec<u8>) -> Vec<Vec<u8>> {
+    if bytes.is_empty() {
+        return vec![];
+    }
+
+    // The common case is a small value, which we can keep as it is
+    if bytes.len() <= CHUNK_SIZE {
+        return vec![bytes];
+    }
+
+    bytes.chunks(CHUNK_SIZE).map(|chunk| chunk.to_vec()).collect()
+}
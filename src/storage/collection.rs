@@ -0,0 +1,309 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::mem;
+use std::usize;
+
+use super::errors::CacheError;
+use super::typedefs::CacheResult;
+
+
+// Which end of a list to push to or pop from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Head,
+    Tail,
+}
+
+
+// The items of a collection, by kind
+#[derive(Debug, Clone, PartialEq)]
+pub enum Members {
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+
+// A value that holds several items and is updated an item at a time, rather
+// than rewritten as a whole. Each operation only applies to one kind of
+// collection and fails with WrongType on the others.
+#[derive(Debug, Clone)]
+pub struct Collection {
+    members: Members,
+    data_len: usize, // kept up to date as items come and go
+    // The most data it may hold. Operations that would take it beyond that
+    // fail with ValueTooLong and leave it as it was.
+    max_data_len: usize,
+}
+
+impl PartialEq for Collection {
+    // The limit is no part of what the client stores
+    fn eq(&self, other: &Collection) -> bool {
+        self.members == other.members
+    }
+}
+
+impl Collection {
+    fn new(members: Members) -> Collection {
+        Collection {
+            members: members,
+            data_len: 0,
+            max_data_len: usize::MAX,
+        }
+    }
+
+    pub fn list() -> Collection {
+        Collection::new(Members::List(VecDeque::new()))
+    }
+
+    pub fn set() -> Collection {
+        Collection::new(Members::Set(HashSet::new()))
+    }
+
+    pub fn hash() -> Collection {
+        Collection::new(Members::Hash(HashMap::new()))
+    }
+
+
+    pub fn get_members(&self) -> &Members {
+        &self.members
+    }
+
+    // The number of items, or fields in the case of a hash
+    pub fn len(&self) -> usize {
+        match self.members {
+            Members::List(ref list) => list.len(),
+            Members::Set(ref set) => set.len(),
+            Members::Hash(ref hash) => hash.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The length of all the data held, which is what the value size limit
+    // applies to
+    pub fn data_len(&self) -> usize {
+        self.data_len
+    }
+
+    pub fn set_max_data_len(&mut self, max_data_len: usize) {
+        self.max_data_len = max_data_len;
+    }
+
+    pub fn mem_size(&self) -> usize {
+        // Hashes hold two vectors per field
+        let vecs = match self.members {
+            Members::Hash(_) => 2 * self.len(),
+            _ => self.len(),
+        };
+
+        mem::size_of::<Self>() + vecs * mem::size_of::<Vec<u8>>() +
+        self.data_len
+    }
+
+    fn check_data_len(&self, added: usize, removed: usize) -> CacheResult<()> {
+        match self.data_len + added - removed <= self.max_data_len {
+            true => Ok(()),
+            false => Err(CacheError::ValueTooLong),
+        }
+    }
+
+
+    // Lists
+
+    // Pushes an item and returns the new length. If the list grows beyond
+    // max_len the items at the other end are dropped.
+    pub fn push(&mut self,
+                end: ListEnd,
+                item: Vec<u8>,
+                max_len: Option<usize>)
+                -> CacheResult<usize> {
+        let max_len = max_len.unwrap_or(usize::MAX);
+
+        // Work out what is dropped before anything is changed, so that a
+        // list that would grow too long is left as it was
+        let removed = {
+            let list = match self.members {
+                Members::List(ref list) => list,
+                _ => return Err(CacheError::WrongType),
+            };
+
+            let drop_cnt = (list.len() + 1).saturating_sub(max_len);
+            let mut removed: usize = match end {
+                ListEnd::Head => {
+                    list.iter().rev().take(drop_cnt).map(|it| it.len()).sum()
+                }
+                ListEnd::Tail => {
+                    list.iter().take(drop_cnt).map(|it| it.len()).sum()
+                }
+            };
+            // Nothing is kept at all
+            if drop_cnt > list.len() {
+                removed += item.len();
+            }
+            removed
+        };
+        try!(self.check_data_len(item.len(), removed));
+
+        self.data_len = self.data_len + item.len() - removed;
+
+        let list = match self.members {
+            Members::List(ref mut list) => list,
+            _ => unreachable!(),
+        };
+
+        match end {
+            ListEnd::Head => list.push_front(item),
+            ListEnd::Tail => list.push_back(item),
+        }
+
+        while list.len() > max_len {
+            match end {
+                ListEnd::Head => list.pop_back(),
+                ListEnd::Tail => list.pop_front(),
+            };
+        }
+
+        Ok(list.len())
+    }
+
+    pub fn pop(&mut self, end: ListEnd) -> CacheResult<Option<Vec<u8>>> {
+        let item = match self.members {
+            Members::List(ref mut list) => {
+                match end {
+                    ListEnd::Head => list.pop_front(),
+                    ListEnd::Tail => list.pop_back(),
+                }
+            }
+            _ => return Err(CacheError::WrongType),
+        };
+
+        match item {
+            Some(ref item) => self.data_len -= item.len(),
+            None => (),
+        }
+        Ok(item)
+    }
+
+    // Returns the items from start to stop, both included. Negative indexes
+    // count from the end of the list, so that 0 to -1 is the whole list.
+    pub fn range(&self, start: i64, stop: i64) -> CacheResult<Vec<Vec<u8>>> {
+        let list = match self.members {
+            Members::List(ref list) => list,
+            _ => return Err(CacheError::WrongType),
+        };
+
+        let len = list.len() as i64;
+        let start = match start < 0 {
+            true => cmp::max(len + start, 0),
+            false => start,
+        };
+        let stop = match stop < 0 {
+            true => len + stop,
+            false => cmp::min(stop, len - 1),
+        };
+
+        if start > stop {
+            return Ok(vec![]);
+        }
+
+        Ok(list.iter()
+               .skip(start as usize)
+               .take((stop - start + 1) as usize)
+               .cloned()
+               .collect())
+    }
+
+
+    // Sets
+
+    // Returns false if it was a member already
+    pub fn add(&mut self, member: Vec<u8>) -> CacheResult<bool> {
+        match self.members {
+            Members::Set(ref set) if set.contains(&member) => return Ok(false),
+            Members::Set(_) => (),
+            _ => return Err(CacheError::WrongType),
+        }
+        try!(self.check_data_len(member.len(), 0));
+
+        self.data_len += member.len();
+        match self.members {
+            Members::Set(ref mut set) => Ok(set.insert(member)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Returns false if it wasn't a member
+    pub fn remove(&mut self, member: &[u8]) -> CacheResult<bool> {
+        let removed = match self.members {
+            Members::Set(ref mut set) => set.remove(member),
+            _ => return Err(CacheError::WrongType),
+        };
+
+        if removed {
+            self.data_len -= member.len();
+        }
+        Ok(removed)
+    }
+
+    pub fn contains(&self, member: &[u8]) -> CacheResult<bool> {
+        match self.members {
+            Members::Set(ref set) => Ok(set.contains(member)),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+
+    // Hashes
+
+    pub fn get_field(&self, field: &[u8]) -> CacheResult<Option<&Vec<u8>>> {
+        match self.members {
+            Members::Hash(ref hash) => Ok(hash.get(field)),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn set_field(&mut self,
+                     field: Vec<u8>,
+                     item: Vec<u8>)
+                     -> CacheResult<()> {
+        // The field stays if it's there already, only its item is replaced
+        let removed = match self.members {
+            Members::Hash(ref hash) => {
+                match hash.get(&field) {
+                    Some(prev) => field.len() + prev.len(),
+                    None => 0,
+                }
+            }
+            _ => return Err(CacheError::WrongType),
+        };
+        try!(self.check_data_len(field.len() + item.len(), removed));
+
+        self.data_len = self.data_len + field.len() + item.len() - removed;
+        match self.members {
+            Members::Hash(ref mut hash) => hash.insert(field, item),
+            _ => unreachable!(),
+        };
+        Ok(())
+    }
+
+    // Returns false if there was no such field
+    pub fn delete_field(&mut self, field: &[u8]) -> CacheResult<bool> {
+        let item = match self.members {
+            Members::Hash(ref mut hash) => hash.remove(field),
+            _ => return Err(CacheError::WrongType),
+        };
+
+        match item {
+            Some(item) => {
+                self.data_len -= field.len() + item.len();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
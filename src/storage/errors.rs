@@ -1,8 +1,14 @@
 #[derive(Debug, PartialEq)]
 pub enum CacheError {
     CapacityExceeded,
+    CursorNotFound,
+    DiskError,
     EvictionFailed,
     KeyNotFound,
     KeyTooLong,
+    NamespaceNotFound,
+    NotANumber,
+    ValueCorrupt,
     ValueTooLong,
+    WrongType,
 }
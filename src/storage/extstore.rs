@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::collections::Bound;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use super::chunks::Chunks;
+use super::errors::CacheError;
+use super::typedefs::CacheResult;
+
+
+// Every chunk of a value is preceded by its length on disk, so that chunks
+// that were compressed one by one can be told apart when read back
+const CHUNK_HEADER_LEN: u64 = 4;
+
+// Extents are checksummed with FNV-1a, so that data damaged on disk is
+// caught when read back
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+
+#[derive(Debug, Clone)]
+pub struct ExtStoreStats {
+    pub items: u64, // Values currently stored on disk
+    pub bytes: u64, // Size of the file, including values no longer used
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub hits: u64, // Values read back from disk
+    pub misses: u64, // Values that had been dropped from disk when read
+    pub compactions: u64,
+    pub dropped: u64, // Values dropped from disk to make space
+}
+
+impl ExtStoreStats {
+    pub fn new() -> ExtStoreStats {
+        ExtStoreStats {
+            items: 0,
+            bytes: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            hits: 0,
+            misses: 0,
+            compactions: 0,
+            dropped: 0,
+        }
+    }
+}
+
+
+// Where the data of a value is stored in the file
+#[derive(Debug, Clone)]
+struct Extent {
+    offset: u64,
+    len: u64,
+    checksum: u64, // of the bytes as written
+}
+
+
+// A compaction in progress: the data still in use is copied to a new file a
+// step at a time, from the oldest to the newest, and the new file replaces
+// the old one once everything has been copied
+struct Compaction {
+    path: String,
+    file: File,
+    extents: BTreeMap<u64, Extent>, // copied so far, by id
+    last_id: u64, // the last one copied
+    offset: u64, // where the next one goes
+}
+
+
+// A second tier of storage for values that are big and rarely read. Their
+// data is appended to a file on local disk and the value kept in memory only
+// holds the id it was stored under. The file is only ever appended to, so
+// values that are overwritten or removed leave their data behind until the
+// file fills up and is compacted. The cache releases the data of values as
+// they go, so the store always knows which data is still in use.
+//
+// The contents are of no use without the values pointing to them, so the
+// file starts out empty and is removed when the store is dropped.
+pub struct ExtStore {
+    path: String,
+    file: File,
+    capacity: u64, // in bytes
+
+    extents: BTreeMap<u64, Extent>, // by id, ie. from oldest to newest
+    last_id: u64,
+    live_bytes: u64, // the part of the file still in use
+
+    compaction: Option<Compaction>,
+
+    stats: ExtStoreStats,
+}
+
+impl ExtStore {
+    pub fn open(path: &str, capacity: u64) -> CacheResult<ExtStore> {
+        let file = try!(create_file(path));
+
+        Ok(ExtStore {
+            path: path.to_string(),
+            file: file,
+            capacity: capacity,
+            extents: BTreeMap::new(),
+            last_id: 0,
+            live_bytes: 0,
+            compaction: None,
+            stats: ExtStoreStats::new(),
+        })
+    }
+
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn get_stats(&self) -> &ExtStoreStats {
+        &self.stats
+    }
+
+    // The space the data takes up on disk
+    pub fn disk_len(chunks: &Chunks) -> u64 {
+        chunks.len() as u64 + chunks.chunk_count() as u64 * CHUNK_HEADER_LEN
+    }
+
+    // Tells whether the data fits at the end of the file as it is
+    pub fn has_room(&self, chunks: &Chunks) -> bool {
+        self.stats.bytes + ExtStore::disk_len(chunks) <= self.capacity
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.extents.contains_key(&id)
+    }
+
+    // Like contains, but on behalf of a client. If the data isn't there then
+    // the value it belongs to is gone, which counts as a miss.
+    pub fn check(&mut self, id: u64) -> bool {
+        let found = self.contains(id);
+
+        // Update stats
+        if !found {
+            self.stats.misses += 1;
+        }
+
+        found
+    }
+
+    // Appends the data to the file and returns the id it is stored under.
+    // Fails if there is no room for it, which is what compaction is for.
+    // Compaction is started well before that, once the file is close to
+    // full, so that there is room to write to while it's under way.
+    pub fn write(&mut self, chunks: &Chunks) -> CacheResult<u64> {
+        if !self.has_room(chunks) {
+            return Err(CacheError::CapacityExceeded);
+        }
+
+        let len = ExtStore::disk_len(chunks);
+        let mut bytes = Vec::with_capacity(len as usize);
+        for chunk in chunks.iter() {
+            bytes.extend(&(chunk.len() as u32).to_le_bytes());
+            bytes.extend(chunk);
+        }
+
+        let extent = Extent {
+            offset: self.stats.bytes,
+            len: bytes.len() as u64,
+            checksum: checksum(&bytes),
+        };
+        match self.file.write_all_at(&bytes, extent.offset) {
+            Ok(_) => (),
+            Err(_) => return Err(CacheError::DiskError),
+        }
+
+        self.last_id += 1;
+        self.extents.insert(self.last_id, extent);
+        self.live_bytes += bytes.len() as u64;
+
+        // Update stats
+        self.stats.items += 1;
+        self.stats.bytes += bytes.len() as u64;
+        self.stats.bytes_written += bytes.len() as u64;
+
+        // If it can't be started now, it will be when there's no room left
+        if self.stats.bytes + self.capacity / 8 > self.capacity {
+            let _ = self.start_compaction(0);
+        }
+
+        Ok(self.last_id)
+    }
+
+    // Reads the data stored under the id, without counting it as a read,
+    // eg. to persist it
+    pub fn read(&self, id: u64) -> CacheResult<Chunks> {
+        match self.extents.get(&id) {
+            Some(extent) => read_extent(&self.file, extent),
+            None => Err(CacheError::KeyNotFound),
+        }
+    }
+
+    // Reads the data stored under the id on behalf of a client
+    pub fn fetch(&mut self, id: u64) -> CacheResult<Chunks> {
+        let rv = self.read(id);
+
+        // Update stats
+        match rv {
+            Ok(ref chunks) => {
+                self.stats.hits += 1;
+                self.stats.bytes_read += ExtStore::disk_len(chunks);
+            }
+            Err(_) => self.stats.misses += 1,
+        }
+
+        rv
+    }
+
+    // Lets go of the data stored under the id, which no value uses anymore.
+    // The space it takes up is only reclaimed by compacting.
+    pub fn release(&mut self, id: u64) {
+        let extent = match self.extents.remove(&id) {
+            Some(extent) => extent,
+            None => return,
+        };
+        self.live_bytes -= extent.len;
+
+        // It may have been copied already
+        match self.compaction {
+            Some(ref mut compaction) => {
+                compaction.extents.remove(&id);
+            }
+            None => (),
+        }
+
+        // Update stats
+        self.stats.items -= 1;
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    // Starts rewriting the file with only the data that is still in use, so
+    // that there will be room for needed bytes. If the data in use doesn't
+    // leave room for that, the oldest data is dropped first. We make an
+    // eighth of the capacity more room than needed, so that a full store
+    // isn't compacted again on every write. The rewriting itself is done by
+    // compact_step.
+    pub fn start_compaction(&mut self, needed: u64) -> CacheResult<()> {
+        if self.compaction.is_some() {
+            return Ok(());
+        }
+
+        let target = self.capacity.saturating_sub(needed + self.capacity / 8);
+        while self.live_bytes > target {
+            let id = match self.extents.keys().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            self.release(id);
+
+            // Update stats
+            self.stats.dropped += 1;
+        }
+
+        let path = format!("{}.compact", self.path);
+        let file = try!(create_file(&path));
+
+        self.compaction = Some(Compaction {
+            path: path,
+            file: file,
+            extents: BTreeMap::new(),
+            last_id: 0,
+            offset: 0,
+        });
+
+        Ok(())
+    }
+
+    // Copies at least max_bytes of the data in use to the new file (unless
+    // there isn't that much left), and switches over to it once it's all
+    // there. Data written in the meantime is copied as well, since it comes
+    // after what has been copied so far. Returns true once the compaction
+    // is complete, or if there is none. If the disk fails us, the
+    // compaction is given up and may be started again.
+    pub fn compact_step(&mut self, max_bytes: u64) -> CacheResult<bool> {
+        let rv = self.copy_step(max_bytes);
+
+        if rv.is_err() {
+            match self.compaction.take() {
+                Some(compaction) => {
+                    let _ = fs::remove_file(&compaction.path);
+                }
+                None => (),
+            }
+        }
+
+        rv
+    }
+
+    fn copy_step(&mut self, max_bytes: u64) -> CacheResult<bool> {
+        let mut copied = 0;
+
+        {
+            let compaction = match self.compaction {
+                Some(ref mut compaction) => compaction,
+                None => return Ok(true),
+            };
+
+            let start = Bound::Excluded(compaction.last_id);
+            for (id, extent) in self.extents.range((start, Bound::Unbounded)) {
+                if copied >= max_bytes {
+                    return Ok(false);
+                }
+
+                let bytes = try!(read_bytes(&self.file, extent));
+                let offset = compaction.offset;
+                match compaction.file.write_all_at(&bytes, offset) {
+                    Ok(_) => (),
+                    Err(_) => return Err(CacheError::DiskError),
+                }
+
+                let len = extent.len;
+                let extent = Extent {
+                    offset: offset,
+                    len: len,
+                    checksum: extent.checksum,
+                };
+                compaction.extents.insert(*id, extent);
+                compaction.last_id = *id;
+                compaction.offset += len;
+                copied += len;
+            }
+        }
+
+        // Everything is there, start using the new file
+        match fs::rename(&self.compaction.as_ref().unwrap().path, &self.path) {
+            Ok(_) => (),
+            Err(_) => return Err(CacheError::DiskError),
+        }
+        let compaction = self.compaction.take().unwrap();
+        self.file = compaction.file;
+        self.extents = compaction.extents;
+
+        // Update stats
+        self.stats.bytes = compaction.offset;
+        self.stats.compactions += 1;
+
+        Ok(true)
+    }
+}
+
+impl Drop for ExtStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+
+        match self.compaction {
+            Some(ref compaction) => {
+                let _ = fs::remove_file(&compaction.path);
+            }
+            None => (),
+        }
+    }
+}
+
+
+fn create_file(path: &str) -> CacheResult<File> {
+    let rv = OpenOptions::new()
+                 .read(true)
+                 .write(true)
+                 .create(true)
+                 .truncate(true)
+                 .open(path);
+
+    match rv {
+        Ok(file) => Ok(file),
+        Err(_) => Err(CacheError::DiskError),
+    }
+}
+
+fn read_bytes(file: &File, extent: &Extent) -> CacheResult<Vec<u8>> {
+    let mut bytes = vec![0; extent.len as usize];
+    match file.read_exact_at(&mut bytes, extent.offset) {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err(CacheError::DiskError),
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+fn read_extent(file: &File, extent: &Extent) -> CacheResult<Chunks> {
+    let bytes = try!(read_bytes(file, extent));
+    if checksum(&bytes) != extent.checksum {
+        return Err(CacheError::DiskError);
+    }
+
+    // Split it back up into the chunks it was written as
+    let mut chunks = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let header = CHUNK_HEADER_LEN as usize;
+        if pos + header > bytes.len() {
+            return Err(CacheError::DiskError);
+        }
+
+        let mut len_bytes = [0; 4];
+        len_bytes.copy_from_slice(&bytes[pos..pos + header]);
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        pos += header;
+
+        if pos + len > bytes.len() {
+            return Err(CacheError::DiskError);
+        }
+        chunks.push(bytes[pos..pos + len].to_vec());
+        pos += len;
+    }
+
+    Ok(Chunks::from_chunks(chunks))
+}
@@ -1,7 +1,7 @@
 use std::mem;
 
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 pub struct Key {
     pub item: Vec<u8>,
 }
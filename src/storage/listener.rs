@@ -0,0 +1,54 @@
+use super::key::Key;
+use super::value::Value;
+
+
+// Why an item left the cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemovalReason {
+    Evicted, // to make room for other items
+    Expired, // its exptime or the item lifetime passed
+    Deleted, // removed on purpose, eg. by remove or delete_prefix
+    Replaced, // another value was stored under its key
+    Flushed, // by flush_all, flush_namespace or invalidate_tag
+}
+
+
+pub type Listener = Box<FnMut(&Key, &Value, RemovalReason) + Send>;
+
+
+// The functions to call whenever an item leaves the cache. With none
+// registered notifying them costs no more than checking that the list is
+// empty.
+pub struct Listeners {
+    listeners: Vec<Listener>,
+}
+
+impl Listeners {
+    pub fn new() -> Listeners {
+        Listeners { listeners: vec![] }
+    }
+
+    pub fn add(&mut self, listener: Listener) {
+        self.listeners.push(listener);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    // Values are handed over in plain form. One that doesn't decompress is
+    // corrupt, and there's nothing of use to hand over.
+    pub fn notify(&mut self, key: &Key, value: &Value, reason: RemovalReason) {
+        if self.listeners.is_empty() {
+            return;
+        }
+
+        let value = match value.decompressed() {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        for listener in self.listeners.iter_mut() {
+            listener(key, &value, reason);
+        }
+    }
+}
@@ -2,8 +2,13 @@
 pub mod macros;  // must be listed first since macros are order dependent
 
 pub mod cache;
+pub mod chunks;
+pub mod collection;
 pub mod errors;
+pub mod extstore;
 pub mod key;
+pub mod listener;
+pub mod tag_versions;
 pub mod typedefs;
 pub mod value;
 
@@ -14,7 +19,16 @@ mod tests_bench;  // needed to be part of the compilation unit in test mode
 
 // Export our public api
 pub use self::cache::Cache;
+pub use self::cache::Walk;
+pub use self::chunks::Chunks;
+pub use self::collection::Collection;
+pub use self::collection::ListEnd;
+pub use self::collection::Members;
 pub use self::errors::CacheError;
+pub use self::extstore::ExtStore;
 pub use self::key::Key;
+pub use self::listener::RemovalReason;
+pub use self::tag_versions::TagVersions;
 pub use self::typedefs::CacheResult;
+pub use self::value::Tag;
 pub use self::value::Value;
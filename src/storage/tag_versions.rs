@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::hash_map::Iter;
+use std::mem;
+
+
+// How many invalidated tags are remembered, and how much memory they may
+// take up. Beyond this the ones that were invalidated longest ago are
+// forgotten.
+pub const TAG_VERSIONS_MAX: usize = 65536;
+pub const TAG_VERSIONS_MAX_MEM: usize = 4 * 1024 * 1024;
+
+// Tags that aren't remembered are spread over this many floors (see
+// TagVersions)
+pub const TAG_FLOOR_BUCKETS: usize = 1024;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+
+// The versions of the tags that have been invalidated. Versions come from one
+// counter shared by all tags, so no two invalidations ever hand out the same
+// version.
+//
+// A tag that isn't remembered is at the floor of its bucket: the version of
+// the last tag in the bucket that was forgotten, 0 to begin with. Forgetting
+// a tag thus moves it (and every other tag in its bucket that isn't
+// remembered) to a version it never had before, so a value that was dead
+// never comes back to life. Values carrying those tags may die early though,
+// which is fine for a cache, and it's only those of one bucket at a time.
+// The buckets go by a hash of the name that stays the same across restarts.
+pub struct TagVersions {
+    versions: HashMap<String, u64>,
+    by_version: BTreeMap<u64, String>, // oldest first
+    last_version: u64, // the last version handed out
+    floors: Vec<u64>, // by bucket
+    raised: BTreeMap<usize, u64>, // floors raised since they were taken
+    forgotten: u64, // tags forgotten so far
+    max_len: usize,
+    max_mem: usize, // in bytes
+    mem_size: usize, // kept up to date as tags come and go
+}
+
+impl TagVersions {
+    pub fn new(max_len: usize, max_mem: usize) -> TagVersions {
+        TagVersions {
+            versions: HashMap::new(),
+            by_version: BTreeMap::new(),
+            last_version: 0,
+            floors: vec![0; TAG_FLOOR_BUCKETS],
+            raised: BTreeMap::new(),
+            forgotten: 0,
+            max_len: max_len,
+            max_mem: max_mem,
+            mem_size: 0,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        match self.versions.get(name) {
+            Some(version) => *version,
+            None => self.floors[get_bucket(name)],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    // Iterates over the tags that are remembered, with their versions
+    pub fn iter<'a>(&'a self) -> Iter<'a, String, u64> {
+        self.versions.iter()
+    }
+
+    // The floors that have been raised, by bucket
+    pub fn get_floors(&self) -> Vec<(usize, u64)> {
+        self.floors
+            .iter()
+            .enumerate()
+            .filter(|&(_, floor)| *floor > 0)
+            .map(|(bucket, floor)| (bucket, *floor))
+            .collect()
+    }
+
+    // Takes the floors raised since the last call, eg. to record them in the
+    // mutation log along with the invalidation that raised them
+    pub fn take_raised_floors(&mut self) -> Vec<(usize, u64)> {
+        let raised = mem::replace(&mut self.raised, BTreeMap::new());
+        raised.into_iter().collect()
+    }
+
+    // How many tags had to be forgotten to stay within the budget
+    pub fn get_forgotten(&self) -> u64 {
+        self.forgotten
+    }
+
+    // The name is held twice, once for each way of looking it up
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
+
+    fn entry_size(name: &str) -> usize {
+        2 * (mem::size_of::<String>() + name.len() + mem::size_of::<u64>())
+    }
+
+
+    // Moves the tag to a new version and returns it
+    pub fn invalidate(&mut self, name: &str) -> u64 {
+        let version = self.last_version + 1;
+        self.set(name, version);
+        version
+    }
+
+    // Sets the version of a tag as recovered from persistent storage. Tags
+    // only ever move forward.
+    pub fn restore(&mut self, name: &str, version: u64) {
+        if version > self.get(name) {
+            self.set(name, version);
+        }
+    }
+
+    // Sets the floor of a bucket as recovered from persistent storage. Like
+    // tags, floors only ever move forward.
+    pub fn restore_floor(&mut self, bucket: usize, floor: u64) {
+        if bucket < self.floors.len() && floor > self.floors[bucket] {
+            self.floors[bucket] = floor;
+            self.last_version = self.last_version.max(floor);
+        }
+    }
+
+    fn set(&mut self, name: &str, version: u64) {
+        match self.versions.insert(name.to_string(), version) {
+            Some(prev) => {
+                self.by_version.remove(&prev);
+            }
+            None => self.mem_size += TagVersions::entry_size(name),
+        }
+        self.by_version.insert(version, name.to_string());
+        self.last_version = self.last_version.max(version);
+
+        while self.versions.len() > self.max_len ||
+              self.mem_size > self.max_mem {
+            self.forget_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        let version = match self.by_version.keys().next() {
+            Some(version) => *version,
+            None => return,
+        };
+
+        let name = self.by_version.remove(&version).unwrap();
+        self.versions.remove(&name);
+        self.mem_size -= TagVersions::entry_size(&name);
+        self.forgotten += 1;
+
+        let bucket = get_bucket(&name);
+        self.floors[bucket] = self.floors[bucket].max(version);
+        self.raised.insert(bucket, self.floors[bucket]);
+    }
+}
+
+
+// The bucket of the floor a tag is at when it isn't remembered
+pub fn get_bucket(name: &str) -> usize {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in name.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    (hash % TAG_FLOOR_BUCKETS as u64) as usize
+}
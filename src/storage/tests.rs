@@ -1,10 +1,26 @@
-use platform::time::sleep_secs;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use platform::clock::Clock;
+use platform::clock::ManualClock;
 use platform::time::time_now;
+use testlib::tempfile::get_temp_path;
 
 use super::Cache;
 use super::CacheError;
+use super::Chunks;
+use super::Collection;
+use super::ExtStore;
 use super::Key;
+use super::ListEnd;
+use super::RemovalReason;
+use super::TagVersions;
 use super::Value;
+use super::chunks::CHUNK_SIZE;
+use super::tag_versions::get_bucket;
 
 
 #[test]
@@ -12,7 +28,7 @@ fn test_cas_id() {
     let mut value = value!(1);
     assert_eq!(0, *value.get_cas_id());
 
-    value.set_item(vec![2]);
+    value.set_item(Chunks::new(vec![2]));
     assert_eq!(1, *value.get_cas_id());
 
     value.set_flags(15);
@@ -22,7 +38,7 @@ fn test_cas_id() {
     assert_eq!(3, *value.get_cas_id());
 
     // Touch is never due to a client changing it, just us
-    value.touch();
+    value.touch(time_now());
     assert_eq!(3, *value.get_cas_id());
 }
 
@@ -151,6 +167,32 @@ fn test_multiple_evictions() {
     assert_eq!(cache.get_stats().evictions, 2);
 }
 
+#[test]
+fn test_evictions_least_recently_used_first() {
+    // Get a cache just big enough to store two items with short key/val
+    let item_size = key!(1).mem_size() as u64 + value!(1).mem_size() as u64;
+    let mut cache = Cache::new(item_size * 2);
+
+    cache.set(key!(1), value!(8)).unwrap();
+    cache.set(key!(2), value!(9)).unwrap();
+
+    // Reading the first key makes the second the least recently used...
+    cache.get(&key!(1)).unwrap();
+
+    // ...so that's the one to go to make room
+    cache.set(key!(3), value!(10)).unwrap();
+    assert_eq!(1, cache.get_stats().evictions);
+    assert!(cache.contains_key(&key!(1)).unwrap());
+    assert!(!cache.contains_key(&key!(2)).unwrap());
+    assert!(cache.contains_key(&key!(3)).unwrap());
+
+    // ...and then the next oldest, which is the first key again
+    cache.set(key!(4), value!(11)).unwrap();
+    assert!(!cache.contains_key(&key!(1)).unwrap());
+    assert!(cache.contains_key(&key!(3)).unwrap());
+    assert!(cache.contains_key(&key!(4)).unwrap());
+}
+
 #[test]
 fn test_exceed_item_size_limits() {
     let mut cache = Cache::new(1024);
@@ -188,6 +230,81 @@ fn test_exceed_item_size_limits() {
     }
 }
 
+#[test]
+fn test_chunks_append() {
+    let mut chunks = Chunks::new(vec![1; CHUNK_SIZE - 1]);
+    assert_eq!(1, chunks.chunk_count());
+
+    // The last chunk is topped up before a new one is started
+    chunks.append(vec![2; 3]);
+    assert_eq!(CHUNK_SIZE + 2, chunks.len());
+    assert_eq!(2, chunks.chunk_count());
+
+    let mut expected = vec![1; CHUNK_SIZE - 1];
+    expected.extend(vec![2; 3]);
+    assert_eq!(expected, chunks.to_vec());
+}
+
+#[test]
+fn test_chunks_prepend() {
+    let mut chunks = Chunks::new(vec![1; 10]);
+
+    // Small prepends are merged into the first chunk
+    chunks.prepend(vec![2; 10]);
+    assert_eq!(1, chunks.chunk_count());
+
+    chunks.prepend(vec![3; CHUNK_SIZE * 2]);
+    assert_eq!(CHUNK_SIZE * 2 + 20, chunks.len());
+    assert_eq!(3, chunks.chunk_count());
+
+    let mut expected = vec![3; CHUNK_SIZE * 2];
+    expected.extend(vec![2; 10]);
+    expected.extend(vec![1; 10]);
+    assert_eq!(expected, chunks.to_vec());
+}
+
+#[test]
+fn test_chunks_eq() {
+    // Equal bytes are equal chunks wherever the boundaries are
+    let mut chunks = Chunks::new(vec![1; 10]);
+    chunks.prepend(vec![1; CHUNK_SIZE]);
+    assert_eq!(2, chunks.chunk_count());
+    assert_eq!(Chunks::new(vec![1; CHUNK_SIZE + 10]), chunks);
+
+    assert!(Chunks::new(vec![1, 2]) != Chunks::new(vec![1, 3]));
+    assert!(Chunks::new(vec![1, 2]) != Chunks::new(vec![1, 2, 3]));
+}
+
+#[test]
+fn test_store_chunked_value() {
+    let mut cache = Cache::new(16 * CHUNK_SIZE as u64);
+
+    // Too big by default
+    let value = Value::new(vec![7; CHUNK_SIZE * 3 + 5]);
+    let rv = cache.set(key!(1), value.clone());
+    assert_eq!(rv.unwrap_err(), CacheError::ValueTooLong);
+
+    cache.with_value_maxlen(4 * CHUNK_SIZE as u64);
+    cache.set(key!(1), value.clone()).unwrap();
+    assert_eq!(4, cache.get(&key!(1)).unwrap().get_item().chunk_count());
+
+    // Append and prepend across chunk boundaries
+    let mut value = cache.remove(&key!(1)).unwrap();
+    value.append_item(Chunks::new(vec![8; CHUNK_SIZE]));
+    value.prepend_item(Chunks::new(vec![6; 10]));
+    let rv = cache.set(key!(1), value.clone());
+    assert_eq!(rv.unwrap_err(), CacheError::ValueTooLong);
+
+    cache.with_value_maxlen(5 * CHUNK_SIZE as u64);
+    cache.set(key!(1), value.clone()).unwrap();
+
+    let mut expected = vec![6; 10];
+    expected.extend(vec![7; CHUNK_SIZE * 3 + 5]);
+    expected.extend(vec![8; CHUNK_SIZE]);
+    let value_found = cache.get(&key!(1)).unwrap();
+    assert_eq!(expected, value_found.get_item().to_vec());
+}
+
 #[test]
 fn test_key_expired_lifetime() {
     // our cache has a lifetime of 0 secs - all keys are dead on store
@@ -225,13 +342,13 @@ fn test_key_explicit_exptime() {
     assert_eq!(rv.unwrap_err(), CacheError::KeyNotFound);
 }
 
-// this is a slow test that relies on sleeps
-#[ignore]
 #[test]
 fn test_key_kept_alive_on_access() {
     // our cache has a lifetime of 2 secs
+    let clock = ManualClock::new(time_now());
     let mut cache = Cache::new(1024);
-    cache.with_item_lifetime(2.0);
+    cache.with_item_lifetime(2.0)
+         .with_clock(Box::new(clock.clone()));
 
     let key = key!(1);
     let value = value!(9);
@@ -239,38 +356,38 @@ fn test_key_kept_alive_on_access() {
     let rv = cache.set(key.clone(), value.clone());
     assert!(rv.is_ok());
 
-    // sleep 1.5 secs - not long enough to expire key
-    sleep_secs(1.5);
+    // wait 1.5 secs - not long enough to expire key
+    clock.advance(1.5);
 
     // access key - it's there
     assert!(cache.get(&key).is_ok());
 
-    // sleep 1 secs - not long enough to expire key
-    sleep_secs(1.0);
+    // wait 1 secs - not long enough to expire key
+    clock.advance(1.0);
 
     // access key - it's now been 2.5s since it was set, but it's been accessed
     // so we've kept it alive
     assert!(cache.get(&key).is_ok());
 
-    // sleep 2.5 secs - long enough to expire key
-    sleep_secs(2.5);
+    // wait 2.5 secs - long enough to expire key
+    clock.advance(2.5);
 
     // access key - it's gone
     assert!(cache.get(&key).is_err());
 }
 
-// this is a slow test that relies on sleeps
-#[ignore]
 #[test]
 fn test_flush_all() {
     // our cache has a lifetime of 2 secs
+    let clock = ManualClock::new(time_now());
     let mut cache = Cache::new(1024);
-    cache.with_item_lifetime(2.0);
+    cache.with_item_lifetime(2.0)
+         .with_clock(Box::new(clock.clone()));
 
     // this item lives for 3s
     let key1 = key!(1);
     let mut value1 = value!(9);
-    value1.set_exptime(time_now() + 3.0);
+    value1.set_exptime(clock.now() + 3.0);
     let rv = cache.set(key1.clone(), value1.clone());
     assert!(rv.is_ok());
 
@@ -281,10 +398,10 @@ fn test_flush_all() {
     assert!(rv.is_ok());
 
     // make all items dead in one second
-    cache.flush_all(time_now() + 1.0).unwrap();
+    cache.flush_all(clock.now() + 1.0).unwrap();
 
-    // sleep until flush time kicks in
-    sleep_secs(1.5);
+    // wait until flush time kicks in
+    clock.advance(1.5);
 
     // access both keys - both have expired
     assert!(cache.get(&key1).is_err());
@@ -300,6 +417,1239 @@ fn test_flush_all() {
     assert!(cache.get(&key3).is_ok());
 }
 
+#[test]
+fn test_shift_time() {
+    let clock = ManualClock::new(time_now());
+    let mut cache = Cache::new(1024);
+    cache.with_clock(Box::new(clock.clone()));
+
+    // this item lives for 10s
+    let mut value = value!(9);
+    value.set_exptime(clock.now() + 10.0);
+    cache.set(key!(1), value).unwrap();
+
+    cache.shift_time(5.0);
+    assert_eq!(clock.now() + 5.0, cache.now());
+    assert!(cache.get(&key!(1)).is_ok());
+
+    // the shift adds up
+    cache.shift_time(6.0);
+    assert!(cache.get(&key!(1)).is_err());
+}
+
+#[test]
+fn test_crawler_reclaims_expired() {
+    // our crawler checks two items per run
+    let mut cache = Cache::new(1024);
+    cache.with_crawler_batch(2);
+
+    // this item has expired already
+    let mut value1 = value!(9);
+    value1.set_exptime(time_now() - 1.0);
+    cache.set(key!(1), value1).unwrap();
+
+    // this item is still alive
+    cache.set(key!(2), value!(8)).unwrap();
+
+    // this item has expired already
+    let mut value3 = value!(7);
+    value3.set_exptime(time_now() - 1.0);
+    cache.set(key!(3), value3).unwrap();
+
+    let item_size = key!(1).mem_size() as u64 + value!(1).mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size * 3);
+
+    // the first run checks the first two keys
+    assert_eq!(1, cache.crawl());
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get_stats().bytes, item_size * 2);
+    assert_eq!(cache.get_stats().crawler_items_checked, 2);
+    assert_eq!(cache.get_stats().crawler_reclaimed, 1);
+
+    // the second run finishes the pass
+    assert_eq!(1, cache.crawl());
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.get_stats().bytes, item_size);
+    assert_eq!(cache.get_stats().crawler_items_checked, 3);
+    assert_eq!(cache.get_stats().crawler_reclaimed, 2);
+
+    // the live item is still there and was never counted as a miss
+    assert!(cache.get(&key!(2)).is_ok());
+    assert_eq!(cache.get_stats().get_misses, 0);
+}
+
+#[test]
+fn test_crawler_carries_on_after_changes() {
+    let mut cache = Cache::new(1024);
+    cache.with_crawler_batch(2);
+
+    for i in 1..5 {
+        cache.set(key!(i), value!(i)).unwrap();
+    }
+
+    // the pass stops after key 2
+    assert_eq!(0, cache.crawl());
+
+    // the key it stopped at goes away and one behind it expires
+    cache.remove(&key!(2)).unwrap();
+    let mut value = value!(9);
+    value.set_exptime(time_now() - 1.0);
+    cache.set(key!(1), value.clone()).unwrap();
+    cache.set(key!(4), value).unwrap();
+
+    // the pass finishes with keys 3 and 4
+    assert_eq!(1, cache.crawl());
+    assert_eq!(cache.get_stats().crawler_items_checked, 4);
+
+    // and the next one starts over from the first key
+    assert_eq!(1, cache.crawl());
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_crawler_reclaims_flushed() {
+    let mut cache = Cache::new(1024);
+
+    cache.set(key!(1), value!(9)).unwrap();
+    cache.set(key!(2), value!(8)).unwrap();
+
+    // invalidate everything stored so far
+    cache.flush_all(time_now() + 1.0).unwrap();
+
+    // the crawler removes both items in a single run
+    assert_eq!(2, cache.crawl());
+    assert_eq!(cache.len(), 0);
+    assert_eq!(cache.get_stats().bytes, 0);
+}
+
+#[test]
+fn test_crawler_disabled() {
+    let mut cache = Cache::new(1024);
+    cache.with_crawler_batch(0);
+
+    let mut value = value!(9);
+    value.set_exptime(time_now() - 1.0);
+    cache.set(key!(1), value).unwrap();
+
+    // nothing is checked or reclaimed
+    assert_eq!(0, cache.crawl());
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.get_stats().crawler_items_checked, 0);
+}
+
+#[test]
+fn test_namespace_quota() {
+    let item_size = Key::new(b"a:1".to_vec()).mem_size() as u64 +
+                    value!(1).mem_size() as u64;
+
+    // a has room for two items, the rest goes to everything else
+    let mut cache = Cache::new(item_size * 5);
+    cache.with_namespace("a", item_size * 2);
+    let ns = cache.get_namespace("a").unwrap();
+    assert_eq!(item_size * 2, ns.get_capacity());
+
+    cache.set(Key::new(b"b:1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"b:2".to_vec()), value!(2)).unwrap();
+
+    // a's items only take up the space set aside for a
+    for i in 1..5 {
+        cache.set(Key::new(format!("a:{}", i).into_bytes()), value!(i))
+             .unwrap();
+    }
+
+    // a had to make space for its own items...
+    let ns = cache.get_namespace("a").unwrap();
+    assert_eq!(2, ns.len());
+    assert_eq!(item_size * 2, ns.get_stats().bytes);
+    assert_eq!(2, ns.get_stats().evictions);
+
+    // ...without touching the other keys
+    assert!(cache.get(&Key::new(b"b:1".to_vec())).is_ok());
+    assert!(cache.get(&Key::new(b"b:2".to_vec())).is_ok());
+    assert_eq!(4, cache.len());
+    assert_eq!(item_size * 4, cache.get_stats().bytes);
+    assert_eq!(2, cache.get_stats().evictions);
+}
+
+#[test]
+fn test_namespace_lookup() {
+    let mut cache = Cache::new(1024);
+    cache.with_namespace("a", 512)
+         .with_namespace_delimiter(b'/');
+
+    let key = Key::new(b"a/1".to_vec());
+    assert_eq!(Some("a"), cache.get_namespace_name(&key));
+    assert_eq!(None, cache.get_namespace_name(&Key::new(b"a:1".to_vec())));
+    assert_eq!(None, cache.get_namespace_name(&Key::new(b"b/1".to_vec())));
+    assert_eq!(None, cache.get_namespace_name(&Key::new(b"a".to_vec())));
+
+    // Keys are found in the namespace they were stored in
+    cache.set(Key::new(b"a/1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"b/1".to_vec()), value!(2)).unwrap();
+    assert_eq!(1, cache.get_namespace("a").unwrap().len());
+    assert_eq!(value!(1), *cache.get(&Key::new(b"a/1".to_vec())).unwrap());
+    assert_eq!(value!(2), *cache.get(&Key::new(b"b/1".to_vec())).unwrap());
+    assert_eq!(1, cache.get_namespace("a").unwrap().get_stats().get_hits);
+    assert_eq!(2, cache.get_stats().get_hits);
+}
+
+#[test]
+fn test_flush_namespace() {
+    let mut cache = Cache::new(1024);
+    cache.with_namespace("a", 512);
+
+    cache.set(Key::new(b"a:1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"b:1".to_vec()), value!(2)).unwrap();
+
+    // invalidate everything stored in a so far
+    cache.flush_namespace("a", time_now() + 1.0).unwrap();
+    assert!(cache.get(&Key::new(b"a:1".to_vec())).is_err());
+    assert!(cache.get(&Key::new(b"b:1".to_vec())).is_ok());
+
+    let rv = cache.flush_namespace("b", time_now());
+    assert_eq!(CacheError::NamespaceNotFound, rv.unwrap_err());
+}
+
+#[test]
+fn test_invalidate_tag() {
+    let mut cache = Cache::new(1024);
+
+    let mut value1 = value!(1);
+    value1.set_tags(vec!["a".to_string(), "b".to_string()]);
+    cache.set(key!(1), value1.clone()).unwrap();
+
+    let mut value2 = value!(2);
+    value2.set_tags(vec!["b".to_string()]);
+    cache.set(key!(2), value2).unwrap();
+
+    cache.set(key!(3), value!(3)).unwrap();
+
+    // Only the item carrying the tag is gone
+    assert_eq!(1, cache.invalidate_tag("a"));
+    assert!(cache.get(&key!(1)).is_err());
+    assert!(cache.get(&key!(2)).is_ok());
+    assert!(cache.get(&key!(3)).is_ok());
+
+    // Items tagged after the invalidation are not affected by it
+    cache.set(key!(1), value1).unwrap();
+    assert!(cache.get(&key!(1)).is_ok());
+
+    // The dead items are reclaimed by the crawler. Versions are handed out
+    // to all tags from the same counter.
+    assert_eq!(2, cache.invalidate_tag("b"));
+    assert_eq!(2, cache.crawl());
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn test_tags_mem_size() {
+    let mut cache = Cache::new(1024);
+
+    let mut value = value!(1);
+    let size_before = value.mem_size();
+    value.set_tags(vec!["abc".to_string()]);
+    assert!(value.mem_size() > size_before + 3);
+
+    // The tags on the item are accounted for...
+    cache.set(key!(1), value.clone()).unwrap();
+    let item_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+
+    // ...but the versions of tags that have been invalidated have a budget
+    // of their own
+    cache.invalidate_tag("abc");
+    assert_eq!(cache.get_stats().bytes, item_size);
+}
+
+#[test]
+fn test_tag_versions_max() {
+    let mut versions = TagVersions::new(2, usize::max_value());
+    assert_eq!(0, versions.get("a"));
+
+    assert_eq!(1, versions.invalidate("a"));
+    assert_eq!(2, versions.invalidate("b"));
+    assert_eq!(3, versions.invalidate("a"));
+    let mem_size = versions.mem_size();
+
+    // The tag invalidated longest ago is forgotten, and every tag in its
+    // bucket that isn't remembered moves to its version
+    assert_eq!(4, versions.invalidate("c"));
+    assert_eq!(2, versions.len());
+    assert_eq!(1, versions.get_forgotten());
+    assert_eq!(mem_size, versions.mem_size());
+    assert_eq!(2, versions.get("b"));
+    assert_eq!(3, versions.get("a"));
+    assert_eq!(vec![(get_bucket("b"), 2)], versions.get_floors());
+
+    // Tags of other buckets stay where they were
+    let same = other_tag_in_bucket("b", true);
+    let other = other_tag_in_bucket("b", false);
+    assert_eq!(2, versions.get(&same));
+    assert_eq!(0, versions.get(&other));
+
+    // Restoring an older version than that changes nothing
+    versions.restore("b", 1);
+    assert_eq!(2, versions.get("b"));
+    versions.restore("b", 7);
+    assert_eq!(7, versions.get("b"));
+    assert_eq!(8, versions.invalidate("d"));
+}
+
+#[test]
+fn test_tag_versions_max_mem() {
+    let mut versions = TagVersions::new(100, usize::max_value());
+    versions.invalidate("a");
+    let entry_size = versions.mem_size();
+
+    // Tags are forgotten once they take up more memory than they may
+    let mut versions = TagVersions::new(100, 2 * entry_size);
+    assert_eq!(1, versions.invalidate("a"));
+    assert_eq!(2, versions.invalidate("b"));
+    assert_eq!(3, versions.invalidate("c"));
+    assert_eq!(2, versions.len());
+    assert_eq!(2 * entry_size, versions.mem_size());
+    assert_eq!(1, versions.get("a"));
+    assert_eq!(1, versions.get(&other_tag_in_bucket("a", true)));
+}
+
+// Finds a tag that is (or isn't) in the same bucket as the one given
+fn other_tag_in_bucket(name: &str, same: bool) -> String {
+    (0..)
+        .map(|i| format!("tag{}", i))
+        .find(|other| (get_bucket(other) == get_bucket(name)) == same)
+        .unwrap()
+}
+
+#[test]
+fn test_tags_forgotten() {
+    let mut cache = Cache::new(1024);
+
+    let mut value = value!(1);
+    value.set_tags(vec![other_tag_in_bucket("t0", false)]);
+    cache.set(key!(1), value).unwrap();
+    let mut value = value!(2);
+    value.set_tags(vec![other_tag_in_bucket("t0", true)]);
+    cache.set(key!(2), value).unwrap();
+
+    // Forgetting a tag (the first one, t0) only kills the items whose tags
+    // are in its bucket
+    let mut i = 0;
+    while cache.get_tags_forgotten() == 0 {
+        cache.invalidate_tag(&format!("t{}", i));
+        i += 1;
+    }
+    assert!(cache.get(&key!(1)).is_ok());
+    assert!(cache.get(&key!(2)).is_err());
+}
+
+#[test]
+fn test_tag_versions_dont_evict() {
+    let item_size = key!(1).mem_size() as u64 + value!(1).mem_size() as u64;
+    let mut cache = Cache::new(4 * item_size);
+    cache.with_namespace("a", 2 * item_size);
+    let ns_key = Key::new(b"a:1".to_vec());
+    let ns_item_size = ns_key.mem_size() as u64 + value!(1).mem_size() as u64;
+    cache.set(key!(1), value!(1)).unwrap();
+    cache.set(key!(2), value!(2)).unwrap();
+    cache.set(ns_key.clone(), value!(1)).unwrap();
+
+    // Remembering tags takes no space from the items, in a namespace or
+    // outside of any
+    cache.invalidate_tag("a");
+    assert_eq!(3, cache.len());
+    assert_eq!(2 * item_size + ns_item_size, cache.get_stats().bytes);
+    assert_eq!(0, cache.get_stats().evictions);
+}
+
+#[test]
+fn test_peek_stale() {
+    let mut cache = Cache::new(1024);
+
+    let mut value = value!(1);
+    value.set_exptime(time_now() - 1.0);
+    cache.set(key!(1), value.clone()).unwrap();
+    cache.set(key!(2), value!(2)).unwrap();
+
+    // Only dead values are stale
+    assert_eq!(value, *cache.peek_stale(&key!(1)).unwrap());
+    assert!(cache.peek_stale(&key!(2)).is_none());
+    assert!(cache.peek_stale(&key!(3)).is_none());
+
+    // ...until the cache gets around to discarding them
+    assert!(cache.get(&key!(1)).is_err());
+    assert!(cache.peek_stale(&key!(1)).is_none());
+}
+
+#[test]
+fn test_delete_prefix() {
+    let mut cache = Cache::new(1024);
+
+    cache.set(Key::new(b"user:1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"user:2".to_vec()), value!(2)).unwrap();
+    cache.set(Key::new(b"users".to_vec()), value!(3)).unwrap();
+    cache.set(Key::new(b"item:1".to_vec()), value!(4)).unwrap();
+
+    assert_eq!(2, cache.delete_prefix(b"user:"));
+    assert_eq!(2, cache.len());
+    assert!(cache.get(&Key::new(b"users".to_vec())).is_ok());
+    assert!(cache.get(&Key::new(b"item:1".to_vec())).is_ok());
+
+    // Nothing left to delete
+    assert_eq!(0, cache.delete_prefix(b"user:"));
+    assert_eq!(2, cache.get_stats().delete_prefix_items);
+}
+
+#[test]
+fn test_delete_prefix_namespaces() {
+    let mut cache = Cache::new(1024);
+    cache.with_namespace("a", 512);
+
+    cache.set(Key::new(b"a:1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"a:2".to_vec()), value!(2)).unwrap();
+    cache.set(Key::new(b"ab".to_vec()), value!(3)).unwrap();
+
+    // Within a namespace
+    assert_eq!(1, cache.delete_prefix(b"a:1"));
+    let ns = cache.get_namespace("a").unwrap();
+    assert_eq!(1, ns.len());
+    assert_eq!(1, ns.get_stats().delete_prefix_items);
+
+    // Across namespaces
+    assert_eq!(2, cache.delete_prefix(b"a"));
+    assert_eq!(0, cache.len());
+    assert_eq!(0, cache.get_stats().bytes);
+}
+
+#[test]
+fn test_scan() {
+    let mut cache = Cache::new(1024);
+
+    for i in 0..5 {
+        cache.set(Key::new(format!("a:{}", i).into_bytes()), value!(i))
+             .unwrap();
+    }
+    cache.set(Key::new(b"b:1".to_vec()), value!(5)).unwrap();
+
+    // The first page, in the order of the keys
+    let (cursor, keys) = cache.scan(0, b"a:", 2).unwrap();
+    assert!(cursor > 0);
+    assert_eq!(vec![Key::new(b"a:0".to_vec()), Key::new(b"a:1".to_vec())],
+               keys);
+
+    // Keys that are gone by the time we get to them are skipped
+    cache.remove(&Key::new(b"a:2".to_vec())).unwrap();
+    let (cursor, keys) = cache.scan(cursor, b"a:", 3).unwrap();
+    assert_eq!(vec![Key::new(b"a:3".to_vec()), Key::new(b"a:4".to_vec())],
+               keys);
+
+    // The scan is complete
+    assert_eq!(0, cursor);
+    assert_eq!(4, cache.get_stats().scan_items_checked);
+
+    // Without a prefix we get all the keys
+    let (cursor, keys) = cache.scan(0, b"", 10).unwrap();
+    assert_eq!(0, cursor);
+    assert_eq!(5, keys.len());
+}
+
+#[test]
+fn test_scan_keys_stored_meanwhile() {
+    let mut cache = Cache::new(4096);
+    cache.with_namespace("b", 1024);
+
+    for name in &["a:2", "a:4", "b:1"] {
+        cache.set(Key::new(name.as_bytes().to_vec()), value!(1)).unwrap();
+    }
+
+    let (cursor, keys) = cache.scan(0, b"", 1).unwrap();
+    assert_eq!(vec![Key::new(b"a:2".to_vec())], keys);
+
+    // Only keys after where the scan has got to are visited
+    cache.set(Key::new(b"a:1".to_vec()), value!(1)).unwrap();
+    cache.set(Key::new(b"a:3".to_vec()), value!(1)).unwrap();
+    let (cursor, keys) = cache.scan(cursor, b"", 2).unwrap();
+    assert_eq!(vec![Key::new(b"a:3".to_vec()), Key::new(b"a:4".to_vec())],
+               keys);
+
+    // ...then on to the namespaces
+    let (cursor, keys) = cache.scan(cursor, b"", 2).unwrap();
+    assert_eq!(vec![Key::new(b"b:1".to_vec())], keys);
+    assert_eq!(0, cursor);
+}
+
+#[test]
+fn test_scan_cursor_not_found() {
+    let mut cache = Cache::new(1024);
+    cache.set(key!(1), value!(1)).unwrap();
+    cache.set(key!(2), value!(2)).unwrap();
+
+    // Cursors are not valid until handed out...
+    let rv = cache.scan(1, b"", 1);
+    assert_eq!(CacheError::CursorNotFound, rv.unwrap_err());
+
+    // ...and no longer valid once the scan is complete
+    let (cursor, _) = cache.scan(0, b"", 1).unwrap();
+    let (next_cursor, _) = cache.scan(cursor, b"", 1).unwrap();
+    assert_eq!(0, next_cursor);
+    let rv = cache.scan(cursor, b"", 1);
+    assert_eq!(CacheError::CursorNotFound, rv.unwrap_err());
+}
+
+#[test]
+fn test_update_counter() {
+    let mut cache = Cache::new(1024);
+
+    let mut value = Value::new(b"41".to_vec());
+    value.set_flags(15);
+    cache.set(key!(1), value.clone()).unwrap();
+    let cas_id = *value.get_cas_id();
+
+    assert_eq!(42, cache.update_counter(&key!(1), |num| num + 1).unwrap());
+
+    // The number is stored natively but read back in decimal form
+    let value = cache.get(&key!(1)).unwrap().into_owned();
+    assert_eq!(b"42".to_vec(), value.get_item().to_vec());
+    assert_eq!(2, value.len());
+    assert_eq!(15, *value.get_flags());
+    assert!(*value.get_cas_id() > cas_id);
+
+    // ...and takes up no space beyond the value itself
+    let item_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+    assert_eq!(Value::empty().mem_size(), value.mem_size());
+
+    // Appending to it turns it back into bytes
+    let mut value = value;
+    value.append_item(Chunks::new(b"0".to_vec()));
+    assert_eq!(b"420".to_vec(), value.get_item().to_vec());
+    assert_eq!(Some(420), value.get_counter());
+}
+
+#[test]
+fn test_update_counter_refreshes() {
+    // Get a cache just big enough to store two items with short key/val
+    let item_size = key!(1).mem_size() as u64 + value!(1).mem_size() as u64;
+    let mut cache = Cache::new(item_size * 2);
+
+    cache.set(key!(1), Value::new(b"1".to_vec())).unwrap();
+    cache.set(key!(2), value!(2)).unwrap();
+
+    // Updating the first key makes the second the least recently used...
+    assert_eq!(2, cache.update_counter(&key!(1), |num| num + 1).unwrap());
+
+    // ...so that's the one to go to make room
+    cache.set(key!(3), value!(3)).unwrap();
+    assert_eq!(1, cache.get_stats().evictions);
+    assert!(cache.contains_key(&key!(1)).unwrap());
+    assert!(!cache.contains_key(&key!(2)).unwrap());
+
+    // The bytes still add up after updating in place
+    let counter_size = key!(1).mem_size() as u64 +
+                       cache.get(&key!(1)).unwrap().mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, counter_size + item_size);
+}
+
+#[test]
+fn test_get_counter() {
+    let counter = |bytes: &[u8]| Value::new(bytes.to_vec()).get_counter();
+
+    assert_eq!(Some(12), counter(b"12"));
+
+    // whitespace, alpha, negative
+    assert_eq!(None, counter(b" 2"));
+    assert_eq!(None, counter(b"0x2"));
+    assert_eq!(None, counter(b"-2"));
+
+    // too long for u64
+    assert_eq!(None, counter(&vec![b'1'; 255]));
+}
+
+#[test]
+fn test_update_counter_errors() {
+    let mut cache = Cache::new(1024);
+
+    let rv = cache.update_counter(&key!(1), |num| num + 1);
+    assert_eq!(CacheError::KeyNotFound, rv.unwrap_err());
+
+    cache.set(key!(1), Value::new(b"x1".to_vec())).unwrap();
+    let rv = cache.update_counter(&key!(1), |num| num + 1);
+    assert_eq!(CacheError::NotANumber, rv.unwrap_err());
+
+    // The value is left as it was
+    let value = cache.get(&key!(1)).unwrap();
+    assert_eq!(b"x1".to_vec(), value.get_item().to_vec());
+
+    // Dead values are not updated
+    let mut value = Value::new(b"1".to_vec());
+    value.set_exptime(time_now() - 1.0);
+    cache.set(key!(2), value).unwrap();
+    let rv = cache.update_counter(&key!(2), |num| num + 1);
+    assert_eq!(CacheError::KeyNotFound, rv.unwrap_err());
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn test_collection_list() {
+    let mut list = Collection::list();
+    assert_eq!(1, list.push(ListEnd::Tail, vec![2], None).unwrap());
+    assert_eq!(2, list.push(ListEnd::Tail, vec![3], None).unwrap());
+    assert_eq!(3, list.push(ListEnd::Head, vec![1], None).unwrap());
+    assert_eq!(vec![vec![1], vec![2], vec![3]], list.range(0, -1).unwrap());
+    assert_eq!(vec![vec![2]], list.range(1, 1).unwrap());
+    assert_eq!(vec![vec![2], vec![3]], list.range(-2, 10).unwrap());
+    assert!(list.range(2, 1).unwrap().is_empty());
+
+    // Pushing beyond the max length drops items from the other end
+    assert_eq!(3, list.push(ListEnd::Head, vec![0], Some(3)).unwrap());
+    assert_eq!(vec![vec![0], vec![1], vec![2]], list.range(0, -1).unwrap());
+
+    assert_eq!(Some(vec![2]), list.pop(ListEnd::Tail).unwrap());
+    assert_eq!(Some(vec![0]), list.pop(ListEnd::Head).unwrap());
+
+    // It's not a set
+    assert_eq!(CacheError::WrongType, list.add(vec![1]).unwrap_err());
+}
+
+#[test]
+fn test_collection_set_and_hash() {
+    let mut set = Collection::set();
+    assert!(set.add(vec![1]).unwrap());
+    assert!(!set.add(vec![1]).unwrap());
+    assert!(set.contains(&[1]).unwrap());
+    assert!(set.remove(&[1]).unwrap());
+    assert!(!set.remove(&[1]).unwrap());
+    assert!(set.is_empty());
+
+    let mut hash = Collection::hash();
+    hash.set_field(vec![1], vec![2, 3]).unwrap();
+    assert_eq!(Some(&vec![2, 3]), hash.get_field(&[1]).unwrap());
+    assert_eq!(3, hash.data_len());
+    assert!(hash.delete_field(&[1]).unwrap());
+    assert_eq!(None, hash.get_field(&[1]).unwrap());
+
+    let rv = hash.push(ListEnd::Head, vec![1], None);
+    assert_eq!(CacheError::WrongType, rv.unwrap_err());
+}
+
+#[test]
+fn test_update_collection() {
+    let mut cache = Cache::new(1024);
+
+    // A missing key fails unless there's a collection to start from
+    let rv = cache.update_collection(&key!(1), None, |list| {
+        list.pop(ListEnd::Head)
+    });
+    assert_eq!(CacheError::KeyNotFound, rv.unwrap_err());
+
+    for i in 1..4 {
+        let rv = cache.update_collection(&key!(1),
+                                         Some(Collection::list()),
+                                         |list| {
+                                             list.push(ListEnd::Tail,
+                                                       vec![i],
+                                                       None)
+                                         });
+        assert_eq!(i as usize, rv.unwrap());
+    }
+
+    // The collection is accounted for as a whole
+    let value = cache.get(&key!(1)).unwrap().into_owned();
+    assert_eq!(3, value.len());
+    let item_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+
+    let items = cache.read_collection(&key!(1), |list| list.range(0, -1))
+                     .unwrap();
+    assert_eq!(vec![vec![1], vec![2], vec![3]], items);
+
+    // Emptying it removes the key
+    for _ in 0..3 {
+        cache.update_collection(&key!(1), None, |list| list.pop(ListEnd::Head))
+             .unwrap();
+    }
+    assert_eq!(0, cache.len());
+    assert_eq!(0, cache.get_stats().bytes);
+}
+
+#[test]
+fn test_update_collection_wrong_type() {
+    let mut cache = Cache::new(1024);
+    cache.set(key!(1), value!(1)).unwrap();
+    cache.update_collection(&key!(2), Some(Collection::set()), |set| {
+             set.add(vec![1])
+         })
+         .unwrap();
+    let bytes = cache.get_stats().bytes;
+
+    // Plain values are not collections...
+    let rv = cache.update_collection(&key!(1), Some(Collection::set()), |set| {
+        set.add(vec![1])
+    });
+    assert_eq!(CacheError::WrongType, rv.unwrap_err());
+    let rv = cache.read_collection(&key!(1), |set| set.contains(&[1]));
+    assert_eq!(CacheError::WrongType, rv.unwrap_err());
+    assert!(!cache.holds_collection(&key!(1)));
+
+    // ...sets are not lists, and neither are numbers
+    let rv = cache.update_collection(&key!(2), None, |list| {
+        list.pop(ListEnd::Head)
+    });
+    assert_eq!(CacheError::WrongType, rv.unwrap_err());
+    let rv = cache.update_counter(&key!(2), |num| num + 1);
+    assert_eq!(CacheError::WrongType, rv.unwrap_err());
+    assert!(cache.holds_collection(&key!(2)));
+
+    // Both are left as they were
+    assert_eq!(value!(1), *cache.get(&key!(1)).unwrap());
+    assert!(cache.read_collection(&key!(2), |set| set.contains(&[1]))
+                 .unwrap());
+    assert_eq!(bytes, cache.get_stats().bytes);
+}
+
+#[test]
+fn test_collection_data_len() {
+    let mut list = Collection::list();
+    list.push(ListEnd::Tail, vec![1, 2], None).unwrap();
+    list.push(ListEnd::Tail, vec![3], None).unwrap();
+    assert_eq!(3, list.data_len());
+    list.push(ListEnd::Head, vec![4, 5, 6], Some(2)).unwrap();
+    assert_eq!(5, list.data_len());
+    list.pop(ListEnd::Head).unwrap();
+    assert_eq!(2, list.data_len());
+
+    let mut set = Collection::set();
+    set.add(vec![1, 2]).unwrap();
+    set.add(vec![1, 2]).unwrap();
+    assert_eq!(2, set.data_len());
+    set.remove(&[1, 2]).unwrap();
+    assert_eq!(0, set.data_len());
+
+    // Replacing the item of a field only counts the new item
+    let mut hash = Collection::hash();
+    hash.set_field(vec![1], vec![2, 3]).unwrap();
+    hash.set_field(vec![1], vec![4]).unwrap();
+    assert_eq!(2, hash.data_len());
+    hash.delete_field(&[1]).unwrap();
+    assert_eq!(0, hash.data_len());
+}
+
+#[test]
+fn test_collection_max_data_len() {
+    let mut list = Collection::list();
+    list.set_max_data_len(4);
+    list.push(ListEnd::Tail, vec![1, 2], None).unwrap();
+    list.push(ListEnd::Tail, vec![3, 4], None).unwrap();
+
+    // Too long, so nothing changes
+    let rv = list.push(ListEnd::Tail, vec![5], None);
+    assert_eq!(CacheError::ValueTooLong, rv.unwrap_err());
+    assert_eq!(vec![vec![1, 2], vec![3, 4]], list.range(0, -1).unwrap());
+
+    // ...unless as much is dropped from the other end
+    assert_eq!(2, list.push(ListEnd::Tail, vec![5, 6], Some(2)).unwrap());
+    assert_eq!(vec![vec![3, 4], vec![5, 6]], list.range(0, -1).unwrap());
+    assert_eq!(4, list.data_len());
+
+    let mut set = Collection::set();
+    set.set_max_data_len(2);
+    assert!(set.add(vec![1, 2]).unwrap());
+    assert!(!set.add(vec![1, 2]).unwrap());
+    assert_eq!(CacheError::ValueTooLong, set.add(vec![3]).unwrap_err());
+    assert!(!set.contains(&[3]).unwrap());
+}
+
+#[test]
+fn test_update_collection_too_long() {
+    let mut cache = Cache::new(1024);
+    cache.with_value_maxlen(4);
+
+    for i in 1..5 {
+        cache.update_collection(&key!(1), Some(Collection::list()), |list| {
+                 list.push(ListEnd::Tail, vec![i], None)
+             })
+             .unwrap();
+    }
+    let bytes = cache.get_stats().bytes;
+
+    // The list that would grow too long is kept as it was
+    let rv = cache.update_collection(&key!(1), None, |list| {
+        list.push(ListEnd::Tail, vec![5], None)
+    });
+    assert_eq!(CacheError::ValueTooLong, rv.unwrap_err());
+
+    let items = cache.read_collection(&key!(1), |list| list.range(0, -1))
+                     .unwrap();
+    assert_eq!(vec![vec![1], vec![2], vec![3], vec![4]], items);
+    assert_eq!(bytes, cache.get_stats().bytes);
+
+    // Updating it doesn't make it a new item
+    assert_eq!(1, cache.get_stats().total_items);
+}
+
+#[test]
+fn test_update_collection_capacity() {
+    let mut cache = Cache::new(256);
+
+    cache.update_collection(&key!(1), Some(Collection::list()), |list| {
+             list.push(ListEnd::Tail, vec![1], None)
+         })
+         .unwrap();
+
+    // Doesn't fit into the cache at all, so the list is kept as it was
+    let rv = cache.update_collection(&key!(1), None, |list| {
+        list.push(ListEnd::Tail, vec![2; 256], None)
+    });
+    assert_eq!(CacheError::ValueTooLong, rv.unwrap_err());
+    assert_eq!(1, cache.len());
+
+    let items = cache.read_collection(&key!(1), |list| list.range(0, -1))
+                     .unwrap();
+    assert_eq!(vec![vec![1]], items);
+}
+
+fn get_json_blob(count: usize) -> Vec<u8> {
+    let mut blob = vec![];
+    for i in 0..count {
+        blob.extend(format!("{{\"id\": {}, \"name\": \"item\"}},", i % 10)
+                        .into_bytes());
+    }
+    blob
+}
+
+#[test]
+fn test_compress_value() {
+    let mut cache = Cache::new(65536);
+    cache.with_compress_threshold(64);
+
+    let blob = get_json_blob(100);
+    let mut value = Value::new(blob.clone());
+    value.set_flags(15);
+    cache.set(key!(1), value.clone()).unwrap();
+
+    // The value is stored compressed and accounted for as such
+    let full_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert!(cache.get_stats().bytes < full_size);
+    assert_eq!(cache.get_stats().compress_bytes_in, blob.len() as u64);
+    assert!(cache.get_stats().compress_bytes_out < blob.len() as u64);
+
+    // ...but handed out in plain form
+    assert_eq!(value, *cache.get(&key!(1)).unwrap());
+    assert!(!cache.get(&key!(1)).unwrap().is_compressed());
+    assert_eq!(value, *cache.peek(&key!(1)).unwrap());
+
+    let value_popped = cache.remove(&key!(1)).unwrap();
+    assert_eq!(value, value_popped);
+    assert!(!value_popped.is_compressed());
+    assert_eq!(cache.get_stats().bytes, 0);
+}
+
+#[test]
+fn test_decompress_corrupt_value() {
+    let mut value = Value::new(get_json_blob(100));
+    assert!(value.compress());
+
+    // Damaged data is reported rather than handed out
+    value.write_out(1).read_in(Chunks::new(vec![0xff; 20]));
+    assert_eq!(Err(CacheError::ValueCorrupt), value.decompressed());
+    assert_eq!(Err(CacheError::ValueCorrupt), value.decompress());
+    assert!(value.is_compressed());
+    assert_eq!(None, value.get_counter());
+}
+
+#[test]
+fn test_compress_below_threshold() {
+    let mut cache = Cache::new(65536);
+    cache.with_compress_threshold(64);
+
+    // Too small to be worth it
+    let value = Value::new(get_json_blob(1));
+    cache.set(key!(1), value.clone()).unwrap();
+
+    let item_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+    assert_eq!(cache.get_stats().compress_bytes_in, 0);
+
+    // Compression doesn't make it any smaller
+    cache.with_compress_threshold(1);
+    cache.set(key!(2), value!(1, 2, 3)).unwrap();
+
+    let item_size = item_size + key!(2).mem_size() as u64 +
+                    value!(1, 2, 3).mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+    assert_eq!(cache.get_stats().compress_bytes_in, 0);
+}
+
+#[test]
+fn test_compress_chunked_value() {
+    let mut cache = Cache::new(16 * CHUNK_SIZE as u64);
+    cache.with_compress_threshold(64)
+         .with_value_maxlen(4 * CHUNK_SIZE as u64);
+
+    // Every chunk is compressed on its own
+    let mut value = Value::new(get_json_blob(40000));
+    value.append_item(Chunks::new(get_json_blob(40000)));
+    assert!(value.get_item().chunk_count() > 1);
+    cache.set(key!(1), value.clone()).unwrap();
+    assert!(cache.get_stats().bytes < value.len() as u64 / 2);
+
+    assert_eq!(value, *cache.get(&key!(1)).unwrap());
+}
+
+#[test]
+fn test_compress_disabled() {
+    let mut cache = Cache::new(65536);
+
+    let value = Value::new(get_json_blob(100));
+    cache.set(key!(1), value.clone()).unwrap();
+
+    let item_size = key!(1).mem_size() as u64 + value.mem_size() as u64;
+    assert_eq!(cache.get_stats().bytes, item_size);
+    assert_eq!(cache.get_stats().compress_bytes_in, 0);
+}
+
+fn get_big_value(byte: u8) -> Value {
+    Value::new(vec![byte; 1000])
+}
+
+#[test]
+fn test_extstore() {
+    let path = get_temp_path("extstore");
+    let mut extstore = ExtStore::open(&path, 2500).unwrap();
+
+    // Chunks come back the way they were written
+    let chunks = Chunks::from_chunks(vec![vec![1; 100], vec![2; 50]]);
+    let id = extstore.write(&chunks).unwrap();
+    assert_eq!(chunks, extstore.fetch(id).unwrap());
+    assert_eq!(2, extstore.fetch(id).unwrap().chunk_count());
+    assert_eq!(2, extstore.get_stats().hits);
+    assert_eq!(158, extstore.get_stats().bytes);
+
+    let chunks = Chunks::new(vec![3; 1000]);
+    let id2 = extstore.write(&chunks).unwrap();
+    let id3 = extstore.write(&chunks).unwrap();
+
+    // Full up
+    assert!(!extstore.has_room(&chunks));
+    assert!(extstore.write(&chunks).is_err());
+
+    // Data that is no longer used is let go of, but still takes up space
+    extstore.release(id);
+    assert!(!extstore.contains(id));
+    assert_eq!(2, extstore.get_stats().items);
+    assert!(!extstore.has_room(&chunks));
+
+    // If the data still in use leaves no room, the oldest is dropped
+    extstore.start_compaction(1004).unwrap();
+    assert!(extstore.is_compacting());
+    assert!(!extstore.contains(id2));
+    assert_eq!(1, extstore.get_stats().dropped);
+
+    assert!(extstore.compact_step(1).unwrap());
+    assert!(!extstore.is_compacting());
+    assert_eq!(chunks, extstore.fetch(id3).unwrap());
+    assert_eq!(1, extstore.get_stats().items);
+    assert_eq!(1004, extstore.get_stats().bytes);
+    assert_eq!(1, extstore.get_stats().compactions);
+    assert!(extstore.has_room(&chunks));
+
+    assert!(!extstore.check(id2));
+    assert_eq!(1, extstore.get_stats().misses);
+
+    // The file goes away with the store
+    drop(extstore);
+    assert!(!Path::new(&path).exists());
+}
+
+#[test]
+fn test_extstore_corrupted() {
+    let path = get_temp_path("extstore-corrupted");
+    let mut extstore = ExtStore::open(&path, 10000).unwrap();
+
+    let chunks = Chunks::new(vec![1; 1000]);
+    let id = extstore.write(&chunks).unwrap();
+    let id2 = extstore.write(&chunks).unwrap();
+
+    // Damage the first value on disk
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[2], 500).unwrap();
+
+    assert_eq!(Err(CacheError::DiskError), extstore.fetch(id));
+    assert_eq!(1, extstore.get_stats().misses);
+    assert_eq!(chunks, extstore.fetch(id2).unwrap());
+}
+
+#[test]
+fn test_extstore_compaction_steps() {
+    let path = get_temp_path("extstore-steps");
+    let mut extstore = ExtStore::open(&path, 10000).unwrap();
+
+    let chunks = Chunks::new(vec![1; 1000]);
+    let id = extstore.write(&chunks).unwrap();
+    let id2 = extstore.write(&chunks).unwrap();
+    let id3 = extstore.write(&chunks).unwrap();
+    extstore.release(id);
+
+    // Each step copies at least one value...
+    extstore.start_compaction(0).unwrap();
+    assert!(!extstore.compact_step(1).unwrap());
+
+    // ...and in the meantime the store is used as usual
+    let id4 = extstore.write(&chunks).unwrap();
+    assert!(!extstore.compact_step(1).unwrap());
+    extstore.release(id3);
+    assert!(extstore.compact_step(1).unwrap());
+
+    // What was let go of after it was copied still takes up space in the
+    // new file, until the next compaction
+    assert!(!extstore.contains(id3));
+    assert_eq!(chunks, extstore.fetch(id2).unwrap());
+    assert_eq!(chunks, extstore.fetch(id4).unwrap());
+    assert_eq!(2, extstore.get_stats().items);
+    assert_eq!(3012, extstore.get_stats().bytes);
+    assert_eq!(0, extstore.get_stats().dropped);
+
+    // With nothing to do, a step is done right away
+    assert!(extstore.compact_step(1).unwrap());
+    assert_eq!(1, extstore.get_stats().compactions);
+
+    drop(extstore);
+    assert!(!Path::new(&path).exists());
+    assert!(!Path::new(&format!("{}.compact", path)).exists());
+}
+
+#[test]
+fn test_cache_writes_out_cold_values() {
+    let item_size = key!(1).mem_size() as u64 + get_big_value(1).mem_size() as
+                                                  u64;
+    let mut cache = Cache::new(item_size * 2);
+    let path = get_temp_path("extstore-cache");
+    cache.with_extstore(ExtStore::open(&path, 65536).unwrap());
+
+    cache.set(key!(1), get_big_value(1)).unwrap();
+    cache.set(key!(2), get_big_value(2)).unwrap();
+    cache.set(key!(3), get_big_value(3)).unwrap();
+
+    // The oldest values went to disk rather than being evicted. What is
+    // left in memory of them still takes up some space, so it takes two to
+    // make room for a third.
+    assert_eq!(3, cache.len());
+    assert_eq!(0, cache.get_stats().evictions);
+    assert!(cache.get_stats().bytes <= item_size * 2);
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().items);
+
+    // ...and is read back from there
+    assert_eq!(get_big_value(1), *cache.get(&key!(1)).unwrap());
+    assert_eq!(get_big_value(1), *cache.peek(&key!(1)).unwrap());
+    assert_eq!(1, cache.get_extstore().unwrap().get_stats().hits);
+
+    // Including when it is removed, after which the disk lets go of it
+    assert_eq!(get_big_value(1), cache.remove(&key!(1)).unwrap());
+    assert_eq!(2, cache.len());
+    assert_eq!(1, cache.get_extstore().unwrap().get_stats().items);
+
+    // And when it is overwritten
+    cache.set(key!(2), value!(2)).unwrap();
+    assert_eq!(0, cache.get_extstore().unwrap().get_stats().items);
+}
+
+#[test]
+fn test_cache_writes_out_compressed_values() {
+    let value = Value::new(get_json_blob(100));
+    let mut packed = value.clone();
+    packed.compress();
+    let item_size = key!(1).mem_size() as u64 + packed.mem_size() as u64;
+
+    let mut cache = Cache::new(item_size * 3 / 2);
+    let path = get_temp_path("extstore-compressed");
+    cache.with_compress_threshold(64)
+         .with_extstore(ExtStore::open(&path, 65536).unwrap())
+         .with_ext_item_min(1);
+
+    cache.set(key!(1), value.clone()).unwrap();
+    cache.set(key!(2), value.clone()).unwrap();
+
+    // It stays compressed on disk, but is handed out in plain form
+    assert_eq!(1, cache.get_extstore().unwrap().get_stats().items);
+    assert!(cache.get_extstore().unwrap().get_stats().bytes <
+            value.len() as u64);
+    assert_eq!(value, *cache.get(&key!(1)).unwrap());
+}
+
+#[test]
+fn test_cache_extstore_dropped_values() {
+    let item_size = key!(1).mem_size() as u64 + get_big_value(1).mem_size() as
+                                                  u64;
+    let mut cache = Cache::new(item_size * 2);
+    let path = get_temp_path("extstore-dropped");
+    cache.with_extstore(ExtStore::open(&path, 4096).unwrap());
+
+    // The disk only has room for four values. Once it's close to full it is
+    // compacted in between commands, which drops the oldest values to leave
+    // some room to spare.
+    for i in 1..7 {
+        cache.set(key!(i), get_big_value(i)).unwrap();
+        while !cache.compact_extstore() {}
+    }
+    assert_eq!(6, cache.len());
+    assert_eq!(0, cache.get_stats().evictions);
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().compactions);
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().dropped);
+
+    // Which makes them misses
+    assert_eq!(CacheError::KeyNotFound, cache.get(&key!(1)).unwrap_err());
+    assert_eq!(CacheError::KeyNotFound, cache.remove(&key!(2)).unwrap_err());
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().misses);
+    assert_eq!(4, cache.len());
+    assert!(cache.peek(&key!(2)).is_none());
+
+    for i in 3..7 {
+        assert_eq!(get_big_value(i), *cache.get(&key!(i)).unwrap());
+    }
+}
+
+#[test]
+fn test_cache_extstore_small_values() {
+    let item_size = key!(1).mem_size() as u64 + value!(1, 2).mem_size() as u64;
+    let mut cache = Cache::new(item_size);
+    let path = get_temp_path("extstore-small");
+    cache.with_extstore(ExtStore::open(&path, 65536).unwrap());
+
+    // Too small to be worth writing out, so it's evicted
+    cache.set(key!(1), value!(1, 2)).unwrap();
+    cache.set(key!(2), value!(3, 4)).unwrap();
+    assert_eq!(1, cache.len());
+    assert_eq!(1, cache.get_stats().evictions);
+    assert_eq!(0, cache.get_extstore().unwrap().get_stats().items);
+}
+
+type Removals = Arc<Mutex<Vec<(Key, Value, RemovalReason)>>>;
+
+fn record_removals(cache: &mut Cache) -> Removals {
+    let removals = Arc::new(Mutex::new(vec![]));
+    let removals_clone = removals.clone();
+    cache.with_listener(Box::new(move |key, value, reason| {
+        let removal = (key.clone(), value.clone(), reason);
+        removals_clone.lock().unwrap().push(removal);
+    }));
+    removals
+}
+
+fn take_reasons(removals: &Removals) -> Vec<(Key, RemovalReason)> {
+    removals.lock()
+            .unwrap()
+            .drain(..)
+            .map(|(key, _, reason)| (key, reason))
+            .collect()
+}
+
+#[test]
+fn test_listener_evicted_and_replaced() {
+    let item_size = key!(1).mem_size() as u64 + value!(1, 2).mem_size() as u64;
+    let mut cache = Cache::new(item_size);
+    let removals = record_removals(&mut cache);
+
+    cache.set(key!(1), value!(1, 2)).unwrap();
+    assert!(take_reasons(&removals).is_empty());
+
+    cache.set(key!(1), value!(3, 4)).unwrap();
+    assert_eq!(vec![(key!(1), RemovalReason::Replaced)],
+               take_reasons(&removals));
+
+    cache.set(key!(2), value!(5, 6)).unwrap();
+    assert_eq!(vec![(key!(1), RemovalReason::Evicted)],
+               take_reasons(&removals));
+}
+
+#[test]
+fn test_listener_deleted() {
+    let mut cache = Cache::new(65536);
+    cache.with_compress_threshold(64);
+    let removals = record_removals(&mut cache);
+
+    // The value is handed over the way it was stored by the client
+    let value = Value::new(get_json_blob(100));
+    cache.set(key!(1), value.clone()).unwrap();
+    cache.remove(&key!(1)).unwrap();
+    {
+        let removals = removals.lock().unwrap();
+        assert_eq!(value, removals[0].1);
+        assert!(!removals[0].1.is_compressed());
+    }
+    assert_eq!(vec![(key!(1), RemovalReason::Deleted)],
+               take_reasons(&removals));
+
+    // Nothing to report if it wasn't there
+    assert!(cache.remove(&key!(1)).is_err());
+    assert!(take_reasons(&removals).is_empty());
+
+    cache.set(key!(1, 1), value!(1)).unwrap();
+    cache.delete_prefix(&[1]);
+    assert_eq!(vec![(key!(1, 1), RemovalReason::Deleted)],
+               take_reasons(&removals));
+}
+
+#[test]
+fn test_listener_expired_and_flushed() {
+    let mut cache = Cache::new(1024);
+    let removals = record_removals(&mut cache);
+
+    // Dead items are reported when they're found to be dead
+    let mut value = value!(1);
+    value.set_exptime(time_now() - 1.0);
+    cache.set(key!(1), value).unwrap();
+    assert!(take_reasons(&removals).is_empty());
+    assert!(cache.get(&key!(1)).is_err());
+    assert_eq!(vec![(key!(1), RemovalReason::Expired)],
+               take_reasons(&removals));
+
+    let mut value = value!(2);
+    value.set_tags(vec!["a".to_string()]);
+    cache.set(key!(2), value).unwrap();
+    cache.invalidate_tag("a");
+    assert!(cache.get(&key!(2)).is_err());
+    assert_eq!(vec![(key!(2), RemovalReason::Flushed)],
+               take_reasons(&removals));
+
+    // ...including by the crawler
+    cache.set(key!(3), value!(3)).unwrap();
+    cache.flush_all(time_now() + 1.0).unwrap();
+    assert_eq!(1, cache.crawl());
+    assert_eq!(vec![(key!(3), RemovalReason::Flushed)],
+               take_reasons(&removals));
+}
+
+#[test]
+fn test_listener_external_values() {
+    let item_size = key!(1).mem_size() as u64 + get_big_value(1).mem_size() as
+                                                  u64;
+    let mut cache = Cache::new(item_size * 2);
+    let path = get_temp_path("extstore-listener");
+    cache.with_extstore(ExtStore::open(&path, 65536).unwrap());
+    let removals = record_removals(&mut cache);
+
+    for i in 1..4 {
+        cache.set(key!(i), get_big_value(i)).unwrap();
+    }
+    assert_eq!(2, cache.get_extstore().unwrap().get_stats().items);
+
+    // Data on disk is read back for the listeners...
+    cache.set(key!(1), value!(1)).unwrap();
+    cache.flush_all(time_now() + 1.0).unwrap();
+    assert!(cache.get(&key!(2)).is_err());
+    {
+        let removals = removals.lock().unwrap();
+        assert_eq!(2, removals.len());
+        assert_eq!(get_big_value(1), removals[0].1);
+        assert_eq!(get_big_value(2), removals[1].1);
+    }
+    assert_eq!(vec![(key!(1), RemovalReason::Replaced),
+                    (key!(2), RemovalReason::Flushed)],
+               take_reasons(&removals));
+
+    // ...before the disk lets go of it
+    assert_eq!(0, cache.get_extstore().unwrap().get_stats().items);
+}
+
 #[test]
 fn test_metrics() {
     // NOTE: The most crucial metric is bytes, so make sure to test every data
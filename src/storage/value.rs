@@ -1,62 +1,281 @@
+use std::borrow::Cow;
 use std::mem;
+use std::str;
 
-use platform::time::time_now;
+use lz4_flex;
+
+
+use super::chunks::Chunks;
+use super::collection::Collection;
+use super::errors::CacheError;
+use super::tag_versions::TagVersions;
+use super::typedefs::CacheResult;
+
+
+// A tag attached to a value, along with the version the tag was at when the
+// value was stored. Invalidating the tag bumps its version, which makes the
+// value dead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub version: u64,
+}
+
+impl Tag {
+    pub fn new(name: &str, version: u64) -> Tag {
+        Tag {
+            name: name.to_string(),
+            version: version,
+        }
+    }
+}
+
+
+// The data of a value. Numbers that are incremented and decremented are kept
+// in native form, and only turned into their decimal form when read.
+// Collections are boxed to keep values that don't hold one small. Data that
+// has been written out to disk is only held by the id it is stored under,
+// along with its length.
+#[derive(Debug, Clone)]
+enum Item {
+    Bytes(Chunks),
+    Counter(u64),
+    Collection(Box<Collection>),
+    External(u64, usize),
+}
 
 
 #[derive(Debug, Clone)]
 pub struct Value {
     // Settable/gettable
-    item: Vec<u8>,
+    item: Item,
     flags: u16, // chosen by the client
     exptime: f64, // expiry time (unixtime), <0 for unset
+    // Chosen by the client, versions managed internally. Most values have no
+    // tags, so they don't pay for more than a pointer.
+    tags: Option<Box<Vec<Tag>>>,
 
     // Managed internally
     atime: f64, // last access time (unixtime)
     cas_id: u64, // Incremented every time the value is changed
+    compressed: bool, // item holds the compressed form of the data
 }
 
 impl PartialEq for Value {
     // Overload eq to make sure we only compare the fields that the client
     // stores explicitly
     fn eq(&self, other: &Value) -> bool {
-        self.item == other.item && self.flags == other.flags
+        let items_eq = match (&self.item, &other.item) {
+            (&Item::Collection(ref a), &Item::Collection(ref b)) => a == b,
+            (&Item::Collection(_), _) => false,
+            (_, &Item::Collection(_)) => false,
+            _ => self.get_item() == other.get_item(),
+        };
+
+        items_eq && self.flags == other.flags
     }
 }
 
 impl Value {
     pub fn new(item: Vec<u8>) -> Value {
+        Value::from_chunks(Chunks::new(item))
+    }
+
+    // For data that was read in chunks to begin with
+    pub fn from_chunks(item: Chunks) -> Value {
         Value {
-            item: item,
+            item: Item::Bytes(item),
             flags: 0,
             atime: -1.0,
             exptime: -1.0,
+            tags: None,
             cas_id: 0,
+            compressed: false,
+        }
+    }
+
+    // Reconstructs a value with all its metadata, eg. when loading it from
+    // persistent storage
+    pub fn from_parts(item: Vec<u8>,
+                      flags: u16,
+                      exptime: f64,
+                      atime: f64,
+                      cas_id: u64,
+                      tags: Vec<Tag>)
+                      -> Value {
+        Value {
+            item: Item::Bytes(Chunks::new(item)),
+            flags: flags,
+            atime: atime,
+            exptime: exptime,
+            tags: box_tags(tags),
+            cas_id: cas_id,
+            compressed: false,
         }
     }
 
     pub fn empty() -> Value {
         Value {
-            item: vec![],
+            item: Item::Bytes(Chunks::empty()),
             flags: 0,
             atime: -1.0,
             exptime: -1.0,
+            tags: None,
             cas_id: 0,
+            compressed: false,
+        }
+    }
+
+
+    // Counters are handed out in their decimal form. Collections have no
+    // plain form and are read through get_collection instead. Data on disk
+    // is read back by the cache.
+    pub fn get_item(&self) -> Cow<Chunks> {
+        match self.item {
+            Item::Bytes(ref chunks) => Cow::Borrowed(chunks),
+            Item::Counter(num) => {
+                Cow::Owned(Chunks::new(num.to_string().into_bytes()))
+            }
+            Item::Collection(_) |
+            Item::External(..) => Cow::Owned(Chunks::empty()),
+        }
+    }
+
+    pub fn set_item(&mut self, item: Chunks) -> &mut Self {
+        self.bump_cas_id();
+        self.item = Item::Bytes(item);
+        self
+    }
+
+    pub fn append_item(&mut self, bytes: Chunks) -> &mut Self {
+        self.bump_cas_id();
+        self.get_bytes_mut().append_chunks(bytes);
+        self
+    }
+
+    pub fn prepend_item(&mut self, bytes: Chunks) -> &mut Self {
+        self.bump_cas_id();
+        self.get_bytes_mut().prepend_chunks(bytes);
+        self
+    }
+
+    // A counter that is appended to is just bytes from then on, and so is a
+    // collection (the driver makes sure that doesn't happen). Data on disk
+    // is read back by the cache before it is modified.
+    fn get_bytes_mut(&mut self) -> &mut Chunks {
+        match self.item {
+            Item::Bytes(_) => (),
+            _ => self.item = Item::Bytes(self.get_item().into_owned()),
+        }
+
+        match self.item {
+            Item::Bytes(ref mut chunks) => chunks,
+            _ => unreachable!(),
         }
     }
 
+    // Returns the number the value holds, if it holds one
+    pub fn get_counter(&self) -> Option<u64> {
+        let chunks = match self.item {
+            Item::Counter(num) => return Some(num),
+            Item::Bytes(ref chunks) => chunks,
+            Item::Collection(_) |
+            Item::External(..) => return None,
+        };
+
+        if self.compressed {
+            return match self.decompressed() {
+                Ok(value) => value.get_counter(),
+                Err(_) => None,
+            };
+        }
+
+        match str::from_utf8(&chunks.to_vec()) {
+            Ok(string) => string.parse::<u64>().ok(),
+            Err(_) => None,
+        }
+    }
 
-    pub fn get_item_mut(&mut self) -> &mut Vec<u8> {
+    pub fn set_counter(&mut self, num: u64) -> &mut Self {
         self.bump_cas_id();
-        &mut self.item
+        self.item = Item::Counter(num);
+        self.compressed = false;
+        self
+    }
+
+    pub fn is_collection(&self) -> bool {
+        self.get_collection().is_some()
     }
 
-    pub fn get_item(&self) -> &Vec<u8> {
-        &self.item
+    pub fn get_collection(&self) -> Option<&Collection> {
+        match self.item {
+            Item::Collection(ref collection) => Some(collection),
+            _ => None,
+        }
     }
 
-    pub fn set_item(&mut self, item: Vec<u8>) -> &mut Self {
+    pub fn set_collection(&mut self, collection: Collection) -> &mut Self {
         self.bump_cas_id();
-        self.item = item;
+        self.with_collection(collection)
+    }
+
+    // Like set_collection, but for a value that is being put together from
+    // its parts, so the cas id stays as it is
+    pub fn with_collection(&mut self, collection: Collection) -> &mut Self {
+        self.item = Item::Collection(Box::new(collection));
+        self.compressed = false;
+        self
+    }
+
+    // Applies op to the collection the value holds. Only counts as a
+    // modification if it succeeds.
+    pub fn update_collection<F, R>(&mut self, op: F) -> CacheResult<R>
+        where F: FnOnce(&mut Collection) -> CacheResult<R>
+    {
+        let rv = match self.item {
+            Item::Collection(ref mut collection) => op(collection),
+            _ => Err(CacheError::WrongType),
+        };
+
+        if rv.is_ok() {
+            self.bump_cas_id();
+        }
+
+        rv
+    }
+
+    pub fn is_external(&self) -> bool {
+        self.get_ext_id().is_some()
+    }
+
+    // The id the data is stored under on disk, if it was written out
+    pub fn get_ext_id(&self) -> Option<u64> {
+        match self.item {
+            Item::External(id, _) => Some(id),
+            _ => None,
+        }
+    }
+
+    // Only plain data is worth writing out to disk
+    pub fn can_write_out(&self) -> bool {
+        match self.item {
+            Item::Bytes(_) => true,
+            _ => false,
+        }
+    }
+
+    // Lets go of the data, which has been written to disk under the id. The
+    // value is no different to the client, so the cas id stays as it is.
+    pub fn write_out(&mut self, id: u64) -> &mut Self {
+        let len = self.len();
+        self.item = Item::External(id, len);
+        self
+    }
+
+    // Takes back the data that was written out to disk, in the form it was
+    // written in
+    pub fn read_in(&mut self, chunks: Chunks) -> &mut Self {
+        self.item = Item::Bytes(chunks);
         self
     }
 
@@ -79,6 +298,40 @@ impl Value {
         self.exptime = exptime;
     }
 
+    pub fn get_tags(&self) -> &[Tag] {
+        match self.tags {
+            Some(ref tags) => tags,
+            None => &[],
+        }
+    }
+
+    pub fn set_tags(&mut self, names: Vec<String>) -> &mut Self {
+        self.bump_cas_id();
+        let tags = names.into_iter()
+                        .map(|name| {
+                            Tag {
+                                name: name,
+                                version: 0,
+                            }
+                        })
+                        .collect();
+        self.tags = box_tags(tags);
+        self
+    }
+
+    // Records the version each tag is at right now, which is what the value
+    // stays alive for
+    pub fn stamp_tags(&mut self, versions: &TagVersions) {
+        match self.tags {
+            Some(ref mut tags) => {
+                for tag in tags.iter_mut() {
+                    tag.version = versions.get(&tag.name);
+                }
+            }
+            None => (),
+        }
+    }
+
     pub fn get_atime(&self) -> &f64 {
         &self.atime
     }
@@ -91,16 +344,126 @@ impl Value {
         self.cas_id += 1;
     }
 
-    pub fn touch(&mut self) {
-        self.atime = time_now();
+    // Puts back the cas id a value had, eg. when an edit of it is replayed
+    pub fn restore_cas_id(&mut self, cas_id: u64) {
+        self.cas_id = cas_id;
+    }
+
+    pub fn touch(&mut self, now: f64) {
+        self.atime = now;
+    }
+
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    // Compresses the item in place, one chunk at a time, unless that would
+    // not make it any smaller. Returns true if the item is now compressed.
+    pub fn compress(&mut self) -> bool {
+        if self.compressed {
+            return true;
+        }
+
+        // Counters are as small as they get
+        let packed = match self.item {
+            Item::Bytes(ref chunks) => {
+                let packed = chunks.iter()
+                                   .map(|chunk| {
+                                       lz4_flex::compress_prepend_size(chunk)
+                                   })
+                                   .collect();
+                Chunks::from_chunks(packed)
+            }
+            _ => return false,
+        };
+        if packed.len() >= self.len() {
+            return false;
+        }
+
+        self.item = Item::Bytes(packed);
+        self.compressed = true;
+        true
+    }
+
+    // Fails if the data doesn't decompress, which leaves the value as it
+    // was. We only ever decompress what we compressed ourselves, but it may
+    // have been damaged since, eg. on disk.
+    pub fn decompress(&mut self) -> CacheResult<()> {
+        // Data on disk needs to be read back first
+        if !self.compressed || self.is_external() {
+            return Ok(());
+        }
+
+        let mut unpacked = vec![];
+        for chunk in self.get_item().iter() {
+            match lz4_flex::decompress_size_prepended(chunk) {
+                Ok(chunk) => unpacked.push(chunk),
+                Err(_) => return Err(CacheError::ValueCorrupt),
+            }
+        }
+        self.item = Item::Bytes(Chunks::from_chunks(unpacked));
+        self.compressed = false;
+
+        Ok(())
+    }
+
+    // Returns the value with its item in plain form, borrowing it when it
+    // isn't compressed
+    pub fn decompressed(&self) -> CacheResult<Cow<Value>> {
+        if !self.compressed || self.is_external() {
+            return Ok(Cow::Borrowed(self));
+        }
+
+        let mut value = self.clone();
+        try!(value.decompress());
+        Ok(Cow::Owned(value))
     }
 
 
+    // The length of the data as the client sees it
     pub fn len(&self) -> usize {
-        self.item.len()
+        match self.item {
+            Item::Bytes(ref chunks) => chunks.len(),
+            Item::Counter(num) => count_digits(num),
+            Item::Collection(ref collection) => collection.data_len(),
+            Item::External(_, len) => len,
+        }
     }
 
     pub fn mem_size(&self) -> usize {
-        mem::size_of::<Self>() + self.item.len()
+        let tags_size: usize = self.get_tags()
+                                   .iter()
+                                   .map(|tag| {
+                                       mem::size_of::<Tag>() + tag.name.len()
+                                   })
+                                   .sum();
+
+        // Counters and pointers to disk are stored inline
+        let item_size = match self.item {
+            Item::Bytes(ref chunks) => chunks.len(),
+            Item::Counter(_) |
+            Item::External(..) => 0,
+            Item::Collection(ref collection) => collection.mem_size(),
+        };
+
+        mem::size_of::<Self>() + item_size + tags_size
+    }
+}
+
+
+fn count_digits(mut num: u64) -> usize {
+    let mut digits = 1;
+    while num >= 10 {
+        num /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+fn box_tags(tags: Vec<Tag>) -> Option<Box<Vec<Tag>>> {
+    match tags.is_empty() {
+        true => None,
+        false => Some(Box::new(tags)),
     }
 }
@@ -19,14 +19,63 @@ pub fn as_number<N: FromStr>(bytes: Vec<u8>) -> TcpTransportResult<N> {
     }
 }
 
+// Parses an argument of the form tags=<tag>[,<tag>...]
+pub fn as_tags(arg: &str) -> TcpTransportResult<Vec<String>> {
+    if !arg.starts_with("tags=") {
+        return Err(TcpTransportError::CommandParseError);
+    }
+
+    let tags: Vec<String> = arg["tags=".len()..]
+                                .split(',')
+                                .map(|tag| tag.to_string())
+                                .collect();
+
+    if tags.iter().any(|tag| tag.is_empty()) {
+        return Err(TcpTransportError::CommandParseError);
+    }
+
+    Ok(tags)
+}
+
+// Parses an argument of the form initial=<number>
+pub fn as_initial(arg: &str) -> TcpTransportResult<u64> {
+    if !arg.starts_with("initial=") {
+        return Err(TcpTransportError::CommandParseError);
+    }
+
+    as_number(arg["initial=".len()..].as_bytes().to_vec())
+}
+
+// Parses an argument of the form exptime=<number>
+pub fn as_exptime(arg: &str) -> TcpTransportResult<u32> {
+    if !arg.starts_with("exptime=") {
+        return Err(TcpTransportError::CommandParseError);
+    }
+
+    as_number(arg["exptime=".len()..].as_bytes().to_vec())
+}
+
+// Parses an argument of the form maxlen=<number>
+pub fn as_max_len(arg: &str) -> TcpTransportResult<usize> {
+    if !arg.starts_with("maxlen=") {
+        return Err(TcpTransportError::CommandParseError);
+    }
+
+    as_number(arg["maxlen=".len()..].as_bytes().to_vec())
+}
+
 
 
 #[cfg(test)]
 mod tests {
     use tcp_transport::TcpTransportError;
 
+    use super::as_exptime;
+    use super::as_initial;
+    use super::as_max_len;
     use super::as_number;
     use super::as_string;
+    use super::as_tags;
 
 
     #[test]
@@ -50,4 +99,52 @@ mod tests {
         let err = as_number::<u64>(vec![b' ', b'1', b'2']).unwrap_err();
         assert_eq!(err, TcpTransportError::NumberParseError);
     }
+    #[test]
+    fn test_as_tags() {
+        let tags = as_tags("tags=a,b").unwrap();
+        assert_eq!(tags, vec!["a".to_string(), "b".to_string()]);
+
+        // not a tags argument
+        let err = as_tags("a,b").unwrap_err();
+        assert_eq!(err, TcpTransportError::CommandParseError);
+
+        // empty tag
+        let err = as_tags("tags=a,").unwrap_err();
+        assert_eq!(err, TcpTransportError::CommandParseError);
+    }
+
+    #[test]
+    fn test_as_initial() {
+        assert_eq!(as_initial("initial=12").unwrap(), 12);
+
+        // not an initial argument
+        let err = as_initial("12").unwrap_err();
+        assert_eq!(err, TcpTransportError::CommandParseError);
+
+        // not a number
+        let err = as_initial("initial=x").unwrap_err();
+        assert_eq!(err, TcpTransportError::NumberParseError);
+    }
+
+    #[test]
+    fn test_as_exptime() {
+        assert_eq!(as_exptime("exptime=60").unwrap(), 60);
+
+        // not an exptime argument
+        let err = as_exptime("60").unwrap_err();
+        assert_eq!(err, TcpTransportError::CommandParseError);
+
+        // not a number
+        let err = as_exptime("exptime=x").unwrap_err();
+        assert_eq!(err, TcpTransportError::NumberParseError);
+    }
+
+    #[test]
+    fn test_as_max_len() {
+        assert_eq!(as_max_len("maxlen=3").unwrap(), 3);
+
+        // not a maxlen argument
+        let err = as_max_len("3").unwrap_err();
+        assert_eq!(err, TcpTransportError::CommandParseError);
+    }
 }
@@ -7,4 +7,5 @@ pub enum TcpTransportError {
     StreamReadError,
     StreamWriteError,
     Utf8Error,
+    ValueTooLong, // the data was skipped, the stream is still usable
 }
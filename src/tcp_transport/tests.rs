@@ -1,16 +1,33 @@
 use protocol::cmd::Cmd;
+use protocol::cmd::DebugTime;
 use protocol::cmd::Delete;
+use protocol::cmd::DeletePrefix;
 use protocol::cmd::FlushAll;
 use protocol::cmd::Get;
 use protocol::cmd::GetInstr;
+use protocol::cmd::HashField;
+use protocol::cmd::HashFieldInstr;
 use protocol::cmd::Inc;
 use protocol::cmd::IncInstr;
+use protocol::cmd::InvalidateTag;
+use protocol::cmd::LeaseGet;
+use protocol::cmd::ListPop;
+use protocol::cmd::ListPush;
+use protocol::cmd::ListRange;
+use protocol::cmd::ListSide;
 use protocol::cmd::Resp;
+use protocol::cmd::Scan;
 use protocol::cmd::Set;
 use protocol::cmd::SetInstr;
+use protocol::cmd::SetMember;
+use protocol::cmd::SetMemberInstr;
 use protocol::cmd::Stat;
+use protocol::cmd::SlowLogInstr;
+use protocol::cmd::StatsInstr;
 use protocol::cmd::Touch;
 use protocol::cmd::Value;
+use storage::Chunks;
+use storage::chunks::CHUNK_SIZE;
 use testlib::test_stream::TestStream;
 
 use super::TcpTransport;
@@ -235,6 +252,34 @@ fn test_read_cmd_cas_noreply() {
 }
 
 
+// Command parsing: DebugTime
+
+#[test]
+fn test_read_cmd_debug_time() {
+    let cmd_str = b"debugtime 60\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::DebugTime(DebugTime::new(60)));
+
+    // Time can be shifted back as well
+    let cmd_str = b"debugtime -30\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::DebugTime(DebugTime::new(-30)));
+
+    let cmd_str = b"debugtime soon\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::NumberParseError);
+}
+
+
 // Command parsing: Decr
 
 #[test]
@@ -281,6 +326,38 @@ fn test_read_cmd_delete_noreply() {
 }
 
 
+// Command parsing: DeletePrefix
+
+#[test]
+fn test_read_cmd_delete_prefix() {
+    let cmd_str = b"delete_prefix user:\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = DeletePrefix::new("user:", false);
+    assert_eq!(cmd, Cmd::DeletePrefix(exp));
+
+    let cmd_str = b"delete_prefix user: noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = DeletePrefix::new("user:", true);
+    assert_eq!(cmd, Cmd::DeletePrefix(exp));
+}
+
+#[test]
+fn test_read_cmd_delete_prefix_empty() {
+    let cmd_str = b"delete_prefix \r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+
 // Command parsing: FlushAll
 
 #[test]
@@ -293,6 +370,27 @@ fn test_read_cmd_flush_all() {
     assert_eq!(cmd, Cmd::FlushAll(FlushAll::new(None, false)));
 }
 
+#[test]
+fn test_read_cmd_flush_namespace() {
+    let cmd_str = b"flush_namespace users\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut flush_all = FlushAll::new(None, false);
+    flush_all.with_namespace("users");
+    assert_eq!(cmd, Cmd::FlushAll(flush_all));
+
+    let cmd_str = b"flush_namespace users noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut flush_all = FlushAll::new(None, true);
+    flush_all.with_namespace("users");
+    assert_eq!(cmd, Cmd::FlushAll(flush_all));
+}
+
 
 // Command parsing: Get
 
@@ -360,6 +458,49 @@ fn test_read_cmd_gets_one_key() {
 
 // Command parsing: Incr
 
+#[test]
+fn test_read_cmd_hash_field() {
+    let cmd_str = b"hset x f 2 noreply\r\nab\r\nhget x f\r\nhdel x f\r\n"
+                      .to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = HashField::new(HashFieldInstr::Set, "x", "f", vec![97, 98],
+                             true);
+    assert_eq!(cmd, Cmd::HashField(exp));
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = HashField::new(HashFieldInstr::Get, "x", "f", vec![], false);
+    assert_eq!(cmd, Cmd::HashField(exp));
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = HashField::new(HashFieldInstr::Delete, "x", "f", vec![], false);
+    assert_eq!(cmd, Cmd::HashField(exp));
+}
+
+#[test]
+fn test_read_cmd_hset_huge_bytelen() {
+    // The claimed length is never allocated, we just run out of data
+    let cmd_str = b"hset x f 99999999999\r\nab\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+#[test]
+fn test_read_cmd_hget_noreply() {
+    // A get always replies
+    let cmd_str = b"hget x f noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
 #[test]
 fn test_read_cmd_incr() {
     let cmd_str = b"incr x 5 \r\n".to_vec();
@@ -380,6 +521,152 @@ fn test_read_cmd_incr_noreply() {
     assert_eq!(cmd, Cmd::Inc(Inc::new(IncInstr::Incr, "x", 5, true)));
 }
 
+#[test]
+fn test_read_cmd_incr_initial() {
+    let cmd_str = b"incr x 5\r\nincr x 5 initial=10 noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Inc(Inc::new(IncInstr::Incr, "x", 5, false)));
+
+    let mut inc = Inc::new(IncInstr::Incr, "x", 5, true);
+    inc.with_initial(10);
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Inc(inc));
+}
+
+#[test]
+fn test_read_cmd_incr_initial_exptime() {
+    let cmd_str = b"incr x 5 exptime=60 initial=10 noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let mut inc = Inc::new(IncInstr::Incr, "x", 5, true);
+    inc.with_initial(10).with_exptime(60);
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Inc(inc));
+}
+
+
+// Command parsing: InvalidateTag
+
+#[test]
+fn test_read_cmd_invalidate_tag() {
+    let cmd_str = b"invalidate_tag users\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = InvalidateTag::new("users", false);
+    assert_eq!(cmd, Cmd::InvalidateTag(exp));
+
+    let cmd_str = b"invalidate_tag users noreply\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = InvalidateTag::new("users", true);
+    assert_eq!(cmd, Cmd::InvalidateTag(exp));
+}
+
+
+// Command parsing: Leases
+
+#[test]
+fn test_read_cmd_lease_get() {
+    let cmd_str = b"lease_get x\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::LeaseGet(LeaseGet::new("x")));
+}
+
+#[test]
+fn test_read_cmd_lease_set() {
+    let cmd_str = b"lease_set x 15 0 3 44 \r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut exp = Set::new(SetInstr::LeaseSet, "x", 15, 0, vec![97, 98, 99], false);
+    exp.with_lease_token(44);
+    assert_eq!(cmd, Cmd::Set(exp));
+}
+
+#[test]
+fn test_read_cmd_lease_set_noreply() {
+    let cmd_str = b"lease_set x 15 0 3 44 noreply\r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut exp = Set::new(SetInstr::LeaseSet, "x", 15, 0, vec![97, 98, 99], true);
+    exp.with_lease_token(44);
+    assert_eq!(cmd, Cmd::Set(exp));
+}
+
+#[test]
+fn test_read_cmd_list_push() {
+    let cmd_str = b"lpush x 3\r\nabc\r\nrpush x 1 noreply maxlen=5\r\nd\r\n"
+                      .to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = ListPush::new(ListSide::Left, "x", vec![97, 98, 99], false);
+    assert_eq!(cmd, Cmd::ListPush(exp));
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut exp = ListPush::new(ListSide::Right, "x", vec![100], true);
+    exp.with_max_len(5);
+    assert_eq!(cmd, Cmd::ListPush(exp));
+}
+
+#[test]
+fn test_read_cmd_list_push_huge_bytelen() {
+    let cmd_str = b"lpush x 99999999999\r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+#[test]
+fn test_read_cmd_list_pop_and_range() {
+    let cmd_str = b"rpop x\r\nlrange x 1 -1\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::ListPop(ListPop::new(ListSide::Right, "x")));
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::ListRange(ListRange::new("x", 1, -1)));
+}
+
+#[test]
+fn test_read_cmd_set_member() {
+    let cmd_str = b"sadd x a noreply\r\nsismember x a\r\nsrem x a\r\n"
+                      .to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = SetMember::new(SetMemberInstr::Add, "x", "a", true);
+    assert_eq!(cmd, Cmd::SetMember(exp));
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = SetMember::new(SetMemberInstr::IsMember, "x", "a", false);
+    assert_eq!(cmd, Cmd::SetMember(exp));
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = SetMember::new(SetMemberInstr::Remove, "x", "a", false);
+    assert_eq!(cmd, Cmd::SetMember(exp));
+}
+
 
 // Command parsing: Quit
 
@@ -422,6 +709,60 @@ fn test_read_cmd_replace() {
 }
 
 
+// Command parsing: Scan
+
+#[test]
+fn test_read_cmd_scan() {
+    let cmd_str = b"scan 0\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Scan(Scan::new(0, "", None)));
+
+    let cmd_str = b"scan 3 user:\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Scan(Scan::new(3, "user:", None)));
+
+    let cmd_str = b"scan 3 user: 100\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Scan(Scan::new(3, "user:", Some(100))));
+}
+
+#[test]
+fn test_read_cmd_scan_invalid() {
+    // cursor is missing
+    let cmd_str = b"scan \r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+
+    // count is not a number
+    let cmd_str = b"scan 0 user: all\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::NumberParseError);
+
+    // too many arguments
+    let cmd_str = b"scan 0 user: 10 20\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+
 // Command parsing: Set
 
 #[test]
@@ -435,6 +776,23 @@ fn test_read_cmd_set_ok() {
     assert_eq!(cmd, Cmd::Set(exp));
 }
 
+#[test]
+fn test_read_cmd_set_too_large() {
+    let cmd_str = b"set x 15 0 3 \r\nabc\r\nset y 15 0 2 \r\nab\r\n"
+                      .to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+    transport.with_max_item_size(2);
+
+    // The data is skipped, so the next command can be read
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(TcpTransportError::ValueTooLong, err);
+
+    let cmd = transport.read_cmd().unwrap();
+    let exp = Set::new(SetInstr::Set, "y", 15, 0, vec![97, 98], false);
+    assert_eq!(cmd, Cmd::Set(exp));
+}
+
 #[test]
 fn test_read_cmd_set_noreply_ok() {
     let cmd_str = b"set x 15 0 3 noreply\r\nabc\r\n".to_vec();
@@ -446,6 +804,55 @@ fn test_read_cmd_set_noreply_ok() {
     assert_eq!(cmd, Cmd::Set(exp));
 }
 
+#[test]
+fn test_read_cmd_set_tags_ok() {
+    let cmd_str = b"set x 15 0 3 tags=a,b\r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut exp = Set::new(SetInstr::Set, "x", 15, 0, vec![97, 98, 99], false);
+    exp.with_tags(vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(cmd, Cmd::Set(exp));
+
+    let cmd_str = b"set x 15 0 3 noreply tags=a\r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let mut exp = Set::new(SetInstr::Set, "x", 15, 0, vec![97, 98, 99], true);
+    exp.with_tags(vec!["a".to_string()]);
+    assert_eq!(cmd, Cmd::Set(exp));
+}
+
+#[test]
+fn test_read_cmd_set_large() {
+    let len = 2 * CHUNK_SIZE + 3;
+    let mut cmd_str = format!("set x 0 0 {} \r\n", len).into_bytes();
+    cmd_str.extend(vec![b'a'; len]);
+    cmd_str.extend(b"\r\n");
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    // Read a chunk at a time, never in one piece
+    let set = match transport.read_cmd().unwrap() {
+        Cmd::Set(set) => set,
+        cmd => panic!("unexpected command {:?}", cmd),
+    };
+    assert_eq!(len, set.data.len());
+    assert_eq!(3, set.data.chunk_count());
+}
+
+#[test]
+fn test_read_cmd_set_tags_invalid() {
+    let cmd_str = b"set x 15 0 3 tags=\r\nabc\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
 #[test]
 fn test_read_cmd_set_under_size() {
     let cmd_str = b"set x 0 0 2 \r\nabc\r\n".to_vec();
@@ -497,6 +904,54 @@ fn test_read_cmd_set_malformed() {
 
 // Command parsing: Stats
 
+#[test]
+fn test_read_cmd_slowlog() {
+    let cmd_str = b"slowlog get\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::SlowLog(SlowLogInstr::Get(None)));
+
+    let cmd_str = b"slowlog get 5\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::SlowLog(SlowLogInstr::Get(Some(5))));
+
+    let cmd_str = b"slowlog reset\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::SlowLog(SlowLogInstr::Reset));
+}
+
+#[test]
+fn test_read_cmd_slowlog_malformed() {
+    let cmd_str = b"slowlog\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+
+    let cmd_str = b"slowlog len\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::InvalidCmd);
+
+    let cmd_str = b"slowlog reset 5\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
 #[test]
 fn test_read_cmd_stats() {
     let cmd_str = b"stats\r\n".to_vec();
@@ -504,7 +959,69 @@ fn test_read_cmd_stats() {
     let mut transport = TcpTransport::new(ts);
 
     let cmd = transport.read_cmd().unwrap();
-    assert_eq!(cmd, Cmd::Stats);
+    assert_eq!(cmd, Cmd::Stats(StatsInstr::General));
+}
+
+#[test]
+fn test_read_cmd_stats_commands() {
+    let cmd_str = b"stats commands\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Stats(StatsInstr::Commands));
+}
+
+#[test]
+fn test_read_cmd_stats_hotkeys() {
+    let cmd_str = b"stats hotkeys\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Stats(StatsInstr::HotKeys(None)));
+
+    let cmd_str = b"stats hotkeys 5 \r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::Stats(StatsInstr::HotKeys(Some(5))));
+}
+
+#[test]
+fn test_read_cmd_stats_namespace() {
+    let cmd_str = b"stats namespace users\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    let name = "users".to_string();
+    assert_eq!(cmd, Cmd::Stats(StatsInstr::Namespace(name)));
+
+    let cmd_str = b"stats namespace\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+#[test]
+fn test_read_cmd_stats_invalid() {
+    let cmd_str = b"stats bogus\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::InvalidCmd);
+
+    let cmd_str = b"stats hotkeys 5 6\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
 }
 
 
@@ -533,6 +1050,26 @@ fn test_read_cmd_touch_noreply() {
 }
 
 
+// Command parsing: UseNamespace
+
+#[test]
+fn test_read_cmd_use_namespace() {
+    let cmd_str = b"use_namespace users\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let cmd = transport.read_cmd().unwrap();
+    assert_eq!(cmd, Cmd::UseNamespace("users".to_string()));
+
+    let cmd_str = b"use_namespace users sessions\r\n".to_vec();
+    let ts = TestStream::new(cmd_str);
+    let mut transport = TcpTransport::new(ts);
+
+    let err = transport.read_cmd().unwrap_err();
+    assert_eq!(err, TcpTransportError::CommandParseError);
+}
+
+
 // Command parsing: Version
 
 #[test]
@@ -574,6 +1111,20 @@ fn test_write_resp_deleted() {
 }
 
 
+// Response writing: DeletedItems
+
+#[test]
+fn test_write_resp_deleted_items() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let resp = Resp::DeletedItems(3);
+    transport.write_resp(&resp).unwrap();
+    let expected = b"DELETED 3\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
+
+
 // Response writing: Empty
 
 #[test]
@@ -630,6 +1181,34 @@ fn test_write_resp_intvalue() {
 }
 
 
+// Response writing: Keys
+
+#[test]
+fn test_write_resp_keys() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let resp = Resp::Keys(7, vec!["a:1".to_string(), "a:2".to_string()]);
+    transport.write_resp(&resp).unwrap();
+    let expected = b"CURSOR 7\r\nKEY a:1\r\nKEY a:2\r\nEND\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
+
+
+// Response writing: Lease
+
+#[test]
+fn test_write_resp_lease() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let resp = Resp::Lease(44);
+    transport.write_resp(&resp).unwrap();
+    let expected = b"LEASE 44\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
+
+
 // Response writing: NotFound
 
 #[test]
@@ -701,6 +1280,20 @@ fn test_write_resp_stats() {
 }
 
 
+// Response writing: Stale
+
+#[test]
+fn test_write_resp_stale() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let resp = Resp::Stale(Value::new("x", 15, b"abc".to_vec()));
+    transport.write_resp(&resp).unwrap();
+    let expected = b"STALE x 15 3\r\nabc\r\nEND\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
+
+
 // Response writing: Stored
 
 #[test]
@@ -770,6 +1363,20 @@ fn test_write_resp_value_one_cas() {
 }
 
 
+#[test]
+fn test_write_resp_value_chunks() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let chunks = Chunks::from_chunks(vec![b"ab".to_vec(), b"cd".to_vec()]);
+    let val1 = Value::from_chunks("x", 15, chunks);
+    let resp = Resp::Values(vec![val1]);
+    transport.write_resp(&resp).unwrap();
+    let expected = b"VALUE x 15 4\r\nabcd\r\nEND\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
+
+
 // Response writing: Version
 
 #[test]
@@ -782,3 +1389,17 @@ fn test_write_resp_version() {
     let expected = b"VERSION 1.0.1\r\n";
     assert_eq!(transport.get_stream().outgoing, expected.to_vec());
 }
+
+
+// Response writing: Wait
+
+#[test]
+fn test_write_resp_wait() {
+    let ts = TestStream::new(vec![]);
+    let mut transport = TcpTransport::new(ts);
+
+    let resp = Resp::Wait;
+    transport.write_resp(&resp).unwrap();
+    let expected = b"WAIT\r\n";
+    assert_eq!(transport.get_stream().outgoing, expected.to_vec());
+}
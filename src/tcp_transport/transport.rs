@@ -1,22 +1,44 @@
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 
 use bufstream::BufStream;
 
 use protocol::cmd::Cmd;
+use protocol::cmd::DebugTime;
 use protocol::cmd::Delete;
+use protocol::cmd::DeletePrefix;
 use protocol::cmd::FlushAll;
 use protocol::cmd::Get;
 use protocol::cmd::GetInstr;
+use protocol::cmd::HashField;
+use protocol::cmd::HashFieldInstr;
 use protocol::cmd::Inc;
 use protocol::cmd::IncInstr;
+use protocol::cmd::InvalidateTag;
+use protocol::cmd::LeaseGet;
+use protocol::cmd::ListPop;
+use protocol::cmd::ListPush;
+use protocol::cmd::ListRange;
+use protocol::cmd::ListSide;
 use protocol::cmd::Resp;
+use protocol::cmd::Scan;
 use protocol::cmd::Set;
 use protocol::cmd::SetInstr;
+use protocol::cmd::SetMember;
+use protocol::cmd::SetMemberInstr;
+use protocol::cmd::SlowLogInstr;
+use protocol::cmd::StatsInstr;
 use protocol::cmd::Touch;
+use storage::Chunks;
+use storage::chunks::CHUNK_SIZE;
 
+use super::conversions::as_exptime;
+use super::conversions::as_initial;
+use super::conversions::as_max_len;
 use super::conversions::as_number;
 use super::conversions::as_string;
+use super::conversions::as_tags;
 use super::errors::TcpTransportError;
 use super::stats::TransportStats;
 use super::typedefs::TcpTransportResult;
@@ -41,6 +63,7 @@ macro_rules! return_err_if {
 pub struct TcpTransport<T: Read + Write> {
     stream: BufStream<T>,
     stats: TransportStats,
+    max_item_size: u64, // longest data block read, in bytes
 }
 
 impl<T: Read + Write> TcpTransport<T> {
@@ -48,9 +71,17 @@ impl<T: Read + Write> TcpTransport<T> {
         TcpTransport {
             stats: TransportStats::new(),
             stream: BufStream::new(stream),
+            max_item_size: u64::MAX,
         }
     }
 
+    pub fn with_max_item_size(&mut self,
+                              max_item_size: u64)
+                              -> &mut TcpTransport<T> {
+        self.max_item_size = max_item_size;
+        self
+    }
+
 
     pub fn get_stats_clone(&self) -> TransportStats {
         self.stats.clone()
@@ -177,6 +208,73 @@ impl<T: Read + Write> TcpTransport<T> {
         Ok((word, end_of_line))
     }
 
+    // Reads a data block of the given length and its line terminator. The
+    // block is read a chunk at a time, so a client can't make us allocate
+    // a huge buffer just by sending a huge length
+    pub fn read_data(&mut self, len: u64) -> TcpTransportResult<Vec<u8>> {
+        let chunks = try!(self.read_data_chunks(len));
+        Ok(chunks.to_vec())
+    }
+
+    // Same as read_data, but reads the block a chunk at a time so that a big
+    // value never needs one contiguous buffer. A block longer than any item
+    // can be is skipped without being kept, so that the client can carry on.
+    pub fn read_data_chunks(&mut self,
+                            len: u64)
+                            -> TcpTransportResult<Chunks> {
+        if len > self.max_item_size {
+            try!(self.skip_data(len));
+            return Err(TcpTransportError::ValueTooLong);
+        }
+
+        let mut chunks = vec![];
+        let mut left = len;
+
+        while left > 0 {
+            let chunk_len = left.min(CHUNK_SIZE as u64);
+            let chunk = try!(self.read_bytes_exact(chunk_len));
+
+            // The data is the wrong size
+            if chunk.len() as u64 != chunk_len {
+                return Err(TcpTransportError::CommandParseError);
+            }
+
+            left -= chunk_len;
+            chunks.push(chunk);
+        }
+
+        try!(self.read_terminator());
+        Ok(Chunks::from_chunks(chunks))
+    }
+
+    fn skip_data(&mut self, len: u64) -> TcpTransportResult<()> {
+        let mut left = len;
+
+        while left > 0 {
+            let chunk_len = left.min(CHUNK_SIZE as u64);
+            let chunk = try!(self.read_bytes_exact(chunk_len));
+
+            // The data is the wrong size
+            if chunk.len() as u64 != chunk_len {
+                return Err(TcpTransportError::CommandParseError);
+            }
+
+            left -= chunk_len;
+        }
+
+        self.read_terminator()
+    }
+
+    // Verify that we found the line terminator
+    fn read_terminator(&mut self) -> TcpTransportResult<()> {
+        let terminator = try!(self.read_bytes_exact(2));
+        if !terminator.ends_with(&[b'\r', b'\n']) {
+            return Err(TcpTransportError::CommandParseError);
+        }
+
+        Ok(())
+    }
+
     pub fn read_line_as_words(&mut self) -> TcpTransportResult<Vec<Vec<u8>>> {
         let mut words = vec![];
 
@@ -198,6 +296,15 @@ impl<T: Read + Write> TcpTransport<T> {
 
     // Writing to the stream
 
+    // Blocks until the client has sent something (or gone away), so that a
+    // command can be timed from when it starts to arrive
+    pub fn wait_for_input(&mut self) -> TcpTransportResult<()> {
+        match self.stream.fill_buf() {
+            Ok(buf) if !buf.is_empty() => Ok(()),
+            _ => Err(TcpTransportError::StreamReadError),
+        }
+    }
+
     pub fn flush_writes(&mut self) -> TcpTransportResult<()> {
         match self.stream.flush() {
             Ok(_) => Ok(()),
@@ -219,6 +326,18 @@ impl<T: Read + Write> TcpTransport<T> {
         }
     }
 
+    // Writes the chunks as they are, without joining them first
+    pub fn write_chunks(&mut self,
+                        chunks: &Chunks)
+                        -> TcpTransportResult<usize> {
+        let mut cnt_written = 0;
+        for chunk in chunks.iter() {
+            cnt_written += try!(self.write_bytes(chunk));
+        }
+
+        Ok(cnt_written)
+    }
+
     pub fn write_string(&mut self, string: &str) -> TcpTransportResult<usize> {
         let bytes = string.to_string().into_bytes();
         Ok(try!(self.write_bytes(&bytes)))
@@ -227,6 +346,17 @@ impl<T: Read + Write> TcpTransport<T> {
 
     // Parse individual commands
 
+    pub fn parse_cmd_debug_time(&mut self) -> TcpTransportResult<Cmd> {
+        // parse the number of seconds, which may be negative
+        let secs_num = {
+            let (secs, end_of_line) = try!(self.read_word_in_line());
+            return_err_if!(!end_of_line, TcpTransportError::CommandParseError);
+            try!(as_number::<i64>(secs))
+        };
+
+        Ok(Cmd::DebugTime(DebugTime { secs: secs_num }))
+    }
+
     pub fn parse_cmd_delete(&mut self) -> TcpTransportResult<Cmd> {
         // parse the key
         let key_str = {
@@ -252,6 +382,19 @@ impl<T: Read + Write> TcpTransport<T> {
         }))
     }
 
+    pub fn parse_cmd_delete_prefix(&mut self) -> TcpTransportResult<Cmd> {
+        let (prefix_str, noreply_flag) = try!(self.parse_name_noreply());
+
+        // An empty prefix would delete everything
+        return_err_if!(prefix_str.is_empty(),
+                       TcpTransportError::CommandParseError);
+
+        Ok(Cmd::DeletePrefix(DeletePrefix {
+            prefix: prefix_str,
+            noreply: noreply_flag,
+        }))
+    }
+
     pub fn parse_cmd_flush_all(&mut self) -> TcpTransportResult<Cmd> {
         // consume the line
         // try!(self.read_line_as_words());
@@ -259,10 +402,44 @@ impl<T: Read + Write> TcpTransport<T> {
         // TODO hardcoded - need better parser primitives
         Ok(Cmd::FlushAll(FlushAll {
             exptime: None,
+            namespace: None,
             noreply: false,
         }))
     }
 
+    // Parses the rest of a line of the form: <name> [noreply]
+    fn parse_name_noreply(&mut self) -> TcpTransportResult<(String, bool)> {
+        // parse the name
+        let (name_str, end_of_line) = {
+            let (name, end_of_line) = try!(self.read_word_in_line());
+            (try!(as_string(name)), end_of_line)
+        };
+
+        // parse noreply
+        let noreply_flag = match end_of_line {
+            true => false,
+            false => {
+                let (noreply, end_of_line) = try!(self.read_word_in_line());
+                return_err_if!(!end_of_line,
+                               TcpTransportError::CommandParseError);
+                let noreply_str = try!(as_string(noreply));
+                noreply_str == "noreply"
+            }
+        };
+
+        Ok((name_str, noreply_flag))
+    }
+
+    pub fn parse_cmd_flush_namespace(&mut self) -> TcpTransportResult<Cmd> {
+        let (namespace_str, noreply_flag) = try!(self.parse_name_noreply());
+
+        Ok(Cmd::FlushAll(FlushAll {
+            exptime: None,
+            namespace: Some(namespace_str),
+            noreply: noreply_flag,
+        }))
+    }
+
     pub fn parse_cmd_get(&mut self,
                          instr: GetInstr)
                          -> TcpTransportResult<Cmd> {
@@ -284,6 +461,57 @@ impl<T: Read + Write> TcpTransport<T> {
         }))
     }
 
+    pub fn parse_cmd_hash_field(&mut self,
+                                instr: HashFieldInstr)
+                                -> TcpTransportResult<Cmd> {
+        // Get takes just the key and the field
+        let max_words = match instr {
+            HashFieldInstr::Get => 2,
+            HashFieldInstr::Set => 4,
+            HashFieldInstr::Delete => 3,
+        };
+
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() < 2 || words.len() > max_words,
+                       TcpTransportError::CommandParseError);
+        let mut words = words.into_iter();
+
+        // parse the key and the field
+        let key_str = try!(as_string(words.next().unwrap()));
+        let field_str = try!(as_string(words.next().unwrap()));
+
+        // parse the bytelen
+        let bytelen_opt = match instr {
+            HashFieldInstr::Set => {
+                match words.next() {
+                    Some(bytelen) => Some(try!(as_number::<u64>(bytelen))),
+                    None => return Err(TcpTransportError::CommandParseError),
+                }
+            }
+            _ => None,
+        };
+
+        // parse noreply
+        let noreply_flag = match words.next() {
+            Some(noreply) => try!(as_string(noreply)) == "noreply",
+            None => false,
+        };
+
+        // We now know the byte length, so read the value
+        let data = match bytelen_opt {
+            Some(bytelen) => try!(self.read_data(bytelen)),
+            None => vec![],
+        };
+
+        Ok(Cmd::HashField(HashField {
+            instr: instr,
+            key: key_str,
+            field: field_str,
+            data: data,
+            noreply: noreply_flag,
+        }))
+    }
+
     pub fn parse_cmd_inc(&mut self,
                          instr: IncInstr)
                          -> TcpTransportResult<Cmd> {
@@ -295,32 +523,161 @@ impl<T: Read + Write> TcpTransport<T> {
         };
 
         // parse the delta
-        let delta_num = {
-            let (exptime, end_of_line) = try!(self.read_word_in_line());
-            return_err_if!(end_of_line, TcpTransportError::CommandParseError);
-            try!(as_number::<u64>(exptime))
+        let (delta_num, end_of_line) = {
+            let (delta, end_of_line) = try!(self.read_word_in_line());
+            (try!(as_number::<u64>(delta)), end_of_line)
         };
 
-        // parse noreply
-        let noreply_flag = {
-            let (noreply, end_of_line) = try!(self.read_word_in_line());
-            return_err_if!(!end_of_line, TcpTransportError::CommandParseError);
-            let noreply_str = try!(as_string(noreply));
-            match noreply_str == "noreply" {
-                true => true,
-                false => false,
+        // parse noreply, the initial value and its exptime, in any order
+        let mut noreply_flag = false;
+        let mut initial_opt = None;
+        let mut exptime_num = 0;
+        if !end_of_line {
+            let words = try!(self.read_line_as_words());
+            return_err_if!(words.len() > 3,
+                           TcpTransportError::CommandParseError);
+
+            for word in words {
+                let word_str = try!(as_string(word));
+                if word_str == "noreply" {
+                    noreply_flag = true;
+                } else if word_str.starts_with("initial=") {
+                    initial_opt = Some(try!(as_initial(&word_str)));
+                } else if word_str.starts_with("exptime=") {
+                    exptime_num = try!(as_exptime(&word_str));
+                }
             }
-        };
+        }
 
         // We got all the values we expected and there is nothing left
         return Ok(Cmd::Inc(Inc {
             instr: instr,
             key: key_str,
             delta: delta_num,
+            initial: initial_opt,
+            exptime: exptime_num,
             noreply: noreply_flag,
         }));
     }
 
+    pub fn parse_cmd_invalidate_tag(&mut self) -> TcpTransportResult<Cmd> {
+        let (tag_str, noreply_flag) = try!(self.parse_name_noreply());
+
+        Ok(Cmd::InvalidateTag(InvalidateTag {
+            tag: tag_str,
+            noreply: noreply_flag,
+        }))
+    }
+
+    pub fn parse_cmd_lease_get(&mut self) -> TcpTransportResult<Cmd> {
+        // parse the key
+        let key_str = {
+            let (key, end_of_line) = try!(self.read_word_in_line());
+            return_err_if!(!end_of_line, TcpTransportError::CommandParseError);
+            try!(as_string(key))
+        };
+
+        Ok(Cmd::LeaseGet(LeaseGet { key: key_str }))
+    }
+
+    pub fn parse_cmd_list_pop(&mut self,
+                              side: ListSide)
+                              -> TcpTransportResult<Cmd> {
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() != 1, TcpTransportError::CommandParseError);
+
+        // parse the key
+        let key_str = try!(as_string(words.into_iter().next().unwrap()));
+
+        Ok(Cmd::ListPop(ListPop {
+            side: side,
+            key: key_str,
+        }))
+    }
+
+    pub fn parse_cmd_list_push(&mut self,
+                               side: ListSide)
+                               -> TcpTransportResult<Cmd> {
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() < 2 || words.len() > 4,
+                       TcpTransportError::CommandParseError);
+        let mut words = words.into_iter();
+
+        // parse the key and the bytelen
+        let key_str = try!(as_string(words.next().unwrap()));
+        let bytelen_num = try!(as_number::<u64>(words.next().unwrap()));
+
+        // parse noreply and the max length, in either order
+        let mut noreply_flag = false;
+        let mut max_len_opt = None;
+        for word in words {
+            let word_str = try!(as_string(word));
+            if word_str == "noreply" {
+                noreply_flag = true;
+            } else if word_str.starts_with("maxlen=") {
+                max_len_opt = Some(try!(as_max_len(&word_str)));
+            }
+        }
+
+        // We now know the byte length, so read the value
+        let data = try!(self.read_data(bytelen_num));
+
+        Ok(Cmd::ListPush(ListPush {
+            side: side,
+            key: key_str,
+            data: data,
+            max_len: max_len_opt,
+            noreply: noreply_flag,
+        }))
+    }
+
+    pub fn parse_cmd_list_range(&mut self) -> TcpTransportResult<Cmd> {
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() != 3, TcpTransportError::CommandParseError);
+        let mut words = words.into_iter();
+
+        // parse the key, start and stop
+        let key_str = try!(as_string(words.next().unwrap()));
+        let start_num = try!(as_number::<i64>(words.next().unwrap()));
+        let stop_num = try!(as_number::<i64>(words.next().unwrap()));
+
+        Ok(Cmd::ListRange(ListRange {
+            key: key_str,
+            start: start_num,
+            stop: stop_num,
+        }))
+    }
+
+    pub fn parse_cmd_scan(&mut self) -> TcpTransportResult<Cmd> {
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() > 3, TcpTransportError::CommandParseError);
+        let mut words = words.into_iter();
+
+        // parse the cursor
+        let cursor_num = match words.next() {
+            Some(cursor) => try!(as_number::<u64>(cursor)),
+            None => return Err(TcpTransportError::CommandParseError),
+        };
+
+        // parse the optional prefix
+        let prefix_str = match words.next() {
+            Some(prefix) => try!(as_string(prefix)),
+            None => "".to_string(),
+        };
+
+        // parse the optional count
+        let count_opt = match words.next() {
+            Some(count) => Some(try!(as_number::<usize>(count))),
+            None => None,
+        };
+
+        Ok(Cmd::Scan(Scan {
+            cursor: cursor_num,
+            prefix: prefix_str,
+            count: count_opt,
+        }))
+    }
+
     pub fn parse_cmd_set(&mut self,
                          instr: SetInstr)
                          -> TcpTransportResult<Cmd> {
@@ -352,41 +709,41 @@ impl<T: Read + Write> TcpTransport<T> {
             try!(as_number::<u64>(bytelen))
         };
 
-        // parse cas_unique
-        let cas_unique_opt = {
-            if instr == SetInstr::Cas {
-                let (cas_unique, _) = try!(self.read_word_in_line());
+        // parse cas_unique or the lease token
+        let (cas_unique_opt, lease_token_opt, end_of_line) = match instr {
+            SetInstr::Cas => {
+                let (cas_unique, end_of_line) = try!(self.read_word_in_line());
                 let cas_unique = try!(as_number(cas_unique));
-                Some(cas_unique)
-            } else {
-                None
+                (Some(cas_unique), None, end_of_line)
             }
-        };
-
-        // parse noreply
-        let noreply_flag = {
-            let (noreply, end_of_line) = try!(self.read_word_in_line());
-            return_err_if!(!end_of_line, TcpTransportError::CommandParseError);
-            let noreply_str = try!(as_string(noreply));
-            match noreply_str == "noreply" {
-                true => true,
-                false => false,
+            SetInstr::LeaseSet => {
+                let (token, end_of_line) = try!(self.read_word_in_line());
+                let token = try!(as_number(token));
+                (None, Some(token), end_of_line)
             }
+            _ => (None, None, false),
         };
 
-        // We now know the byte length, so read the value
-        let value = try!(self.read_bytes_exact(bytelen_num));
-
-        // The value is the wrong size
-        if value.len() as u64 != bytelen_num {
-            return Err(TcpTransportError::CommandParseError);
+        // parse noreply and tags, in either order
+        let mut noreply_flag = false;
+        let mut tags_vec = vec![];
+        if !end_of_line {
+            let words = try!(self.read_line_as_words());
+            return_err_if!(words.len() > 2,
+                           TcpTransportError::CommandParseError);
+
+            for word in words {
+                let word_str = try!(as_string(word));
+                if word_str == "noreply" {
+                    noreply_flag = true;
+                } else if word_str.starts_with("tags=") {
+                    tags_vec = try!(as_tags(&word_str));
+                }
+            }
         }
 
-        // Verify that we found the line terminator
-        let terminator = try!(self.read_bytes_exact(2));
-        if !terminator.ends_with(&[b'\r', b'\n']) {
-            return Err(TcpTransportError::CommandParseError);
-        }
+        // We now know the byte length, so read the value
+        let value = try!(self.read_data_chunks(bytelen_num));
 
         // We got all the values we expected and there is nothing left
         return Ok(Cmd::Set(Set {
@@ -396,10 +753,115 @@ impl<T: Read + Write> TcpTransport<T> {
             exptime: exptime_num,
             data: value,
             cas_unique: cas_unique_opt,
+            lease_token: lease_token_opt,
+            tags: tags_vec,
             noreply: noreply_flag,
         }));
     }
 
+    pub fn parse_cmd_set_member(&mut self,
+                                instr: SetMemberInstr)
+                                -> TcpTransportResult<Cmd> {
+        // IsMember takes just the key and the member
+        let max_words = match instr {
+            SetMemberInstr::IsMember => 2,
+            _ => 3,
+        };
+
+        let words = try!(self.read_line_as_words());
+        return_err_if!(words.len() < 2 || words.len() > max_words,
+                       TcpTransportError::CommandParseError);
+        let mut words = words.into_iter();
+
+        // parse the key and the member
+        let key_str = try!(as_string(words.next().unwrap()));
+        let member_str = try!(as_string(words.next().unwrap()));
+
+        // parse noreply
+        let noreply_flag = match words.next() {
+            Some(noreply) => try!(as_string(noreply)) == "noreply",
+            None => false,
+        };
+
+        Ok(Cmd::SetMember(SetMember {
+            instr: instr,
+            key: key_str,
+            member: member_str,
+            noreply: noreply_flag,
+        }))
+    }
+
+    pub fn parse_cmd_slow_log(&mut self) -> TcpTransportResult<Cmd> {
+        let words = try!(self.read_line_as_words());
+        let mut words = words.into_iter();
+
+        let arg_str = match words.next() {
+            Some(arg) => try!(as_string(arg)),
+            None => return Err(TcpTransportError::CommandParseError),
+        };
+
+        let instr = match arg_str.as_ref() {
+            "get" => {
+                // parse the optional number of commands
+                let cnt = match words.next() {
+                    Some(cnt) => Some(try!(as_number::<usize>(cnt))),
+                    None => None,
+                };
+                SlowLogInstr::Get(cnt)
+            }
+            "reset" => SlowLogInstr::Reset,
+            _ => return Err(TcpTransportError::InvalidCmd),
+        };
+
+        // There should be nothing left
+        return_err_if!(words.next().is_some(),
+                       TcpTransportError::CommandParseError);
+
+        Ok(Cmd::SlowLog(instr))
+    }
+
+    pub fn parse_cmd_stats(&mut self,
+                           end_of_line: bool)
+                           -> TcpTransportResult<Cmd> {
+        // Without arguments we return the general purpose stats
+        let words = match end_of_line {
+            true => vec![],
+            false => try!(self.read_line_as_words()),
+        };
+        let mut words = words.into_iter();
+
+        let arg_str = match words.next() {
+            Some(arg) => try!(as_string(arg)),
+            None => return Ok(Cmd::Stats(StatsInstr::General)),
+        };
+
+        let instr = match arg_str.as_ref() {
+            "commands" => StatsInstr::Commands,
+            "hotkeys" => {
+                // parse the optional number of keys
+                let cnt = match words.next() {
+                    Some(cnt) => Some(try!(as_number::<usize>(cnt))),
+                    None => None,
+                };
+                StatsInstr::HotKeys(cnt)
+            }
+            "namespace" => {
+                // parse the name of the namespace
+                match words.next() {
+                    Some(name) => StatsInstr::Namespace(try!(as_string(name))),
+                    None => return Err(TcpTransportError::CommandParseError),
+                }
+            }
+            _ => return Err(TcpTransportError::InvalidCmd),
+        };
+
+        // There should be nothing left
+        return_err_if!(words.next().is_some(),
+                       TcpTransportError::CommandParseError);
+
+        Ok(Cmd::Stats(instr))
+    }
+
     pub fn parse_cmd_touch(&mut self) -> TcpTransportResult<Cmd> {
         // parse the key
         let key_str = {
@@ -434,12 +896,19 @@ impl<T: Read + Write> TcpTransport<T> {
         }));
     }
 
+    pub fn parse_cmd_use_namespace(&mut self) -> TcpTransportResult<Cmd> {
+        let (name, end_of_line) = try!(self.read_word_in_line());
+        return_err_if!(!end_of_line, TcpTransportError::CommandParseError);
+
+        Ok(Cmd::UseNamespace(try!(as_string(name))))
+    }
+
     // High level functions
 
     pub fn read_cmd(&mut self) -> TcpTransportResult<Cmd> {
-        let keyword_str = {
-            let (word, _) = try!(self.read_word_in_line());
-            try!(as_string(word))
+        let (keyword_str, end_of_line) = {
+            let (word, end_of_line) = try!(self.read_word_in_line());
+            (try!(as_string(word)), end_of_line)
         };
 
         // TODO replace if's with something nicer
@@ -459,6 +928,10 @@ impl<T: Read + Write> TcpTransport<T> {
             return self.parse_cmd_set(SetInstr::Append);
         } else if keyword_str == "prepend" {
             return self.parse_cmd_set(SetInstr::Prepend);
+        } else if keyword_str == "lease_get" {
+            return self.parse_cmd_lease_get();
+        } else if keyword_str == "lease_set" {
+            return self.parse_cmd_set(SetInstr::LeaseSet);
         } else if keyword_str == "touch" {
             return self.parse_cmd_touch();
         } else if keyword_str == "incr" {
@@ -467,10 +940,47 @@ impl<T: Read + Write> TcpTransport<T> {
             return self.parse_cmd_inc(IncInstr::Decr);
         } else if keyword_str == "delete" {
             return self.parse_cmd_delete();
+        } else if keyword_str == "delete_prefix" {
+            return self.parse_cmd_delete_prefix();
         } else if keyword_str == "flush_all" {
             return self.parse_cmd_flush_all();
+        } else if keyword_str == "flush_namespace" {
+            return self.parse_cmd_flush_namespace();
+        } else if keyword_str == "invalidate_tag" {
+            return self.parse_cmd_invalidate_tag();
+        } else if keyword_str == "scan" {
+            return self.parse_cmd_scan();
+        } else if keyword_str == "lpush" {
+            return self.parse_cmd_list_push(ListSide::Left);
+        } else if keyword_str == "rpush" {
+            return self.parse_cmd_list_push(ListSide::Right);
+        } else if keyword_str == "lpop" {
+            return self.parse_cmd_list_pop(ListSide::Left);
+        } else if keyword_str == "rpop" {
+            return self.parse_cmd_list_pop(ListSide::Right);
+        } else if keyword_str == "lrange" {
+            return self.parse_cmd_list_range();
+        } else if keyword_str == "sadd" {
+            return self.parse_cmd_set_member(SetMemberInstr::Add);
+        } else if keyword_str == "srem" {
+            return self.parse_cmd_set_member(SetMemberInstr::Remove);
+        } else if keyword_str == "sismember" {
+            return self.parse_cmd_set_member(SetMemberInstr::IsMember);
+        } else if keyword_str == "hget" {
+            return self.parse_cmd_hash_field(HashFieldInstr::Get);
+        } else if keyword_str == "hset" {
+            return self.parse_cmd_hash_field(HashFieldInstr::Set);
+        } else if keyword_str == "hdel" {
+            return self.parse_cmd_hash_field(HashFieldInstr::Delete);
+        } else if keyword_str == "slowlog" {
+            return_err_if!(end_of_line, TcpTransportError::CommandParseError);
+            return self.parse_cmd_slow_log();
         } else if keyword_str == "stats" {
-            return Ok(Cmd::Stats);
+            return self.parse_cmd_stats(end_of_line);
+        } else if keyword_str == "use_namespace" {
+            return self.parse_cmd_use_namespace();
+        } else if keyword_str == "debugtime" {
+            return self.parse_cmd_debug_time();
         } else if keyword_str == "version" {
             return Ok(Cmd::Version);
         } else if keyword_str == "quit" {
@@ -491,6 +1001,11 @@ impl<T: Read + Write> TcpTransport<T> {
             Resp::Deleted => {
                 try!(self.write_string("DELETED\r\n"));
             }
+            Resp::DeletedItems(ref cnt) => {
+                try!(self.write_string("DELETED "));
+                try!(self.write_string(&cnt.to_string()));
+                try!(self.write_string("\r\n"));
+            }
             Resp::Error => {
                 try!(self.write_string("ERROR\r\n"));
             }
@@ -501,6 +1016,22 @@ impl<T: Read + Write> TcpTransport<T> {
                 try!(self.write_string(&val.to_string()));
                 try!(self.write_string("\r\n"));
             }
+            Resp::Keys(ref cursor, ref keys) => {
+                try!(self.write_string("CURSOR "));
+                try!(self.write_string(&cursor.to_string()));
+                try!(self.write_string("\r\n"));
+                for key in keys {
+                    try!(self.write_string("KEY "));
+                    try!(self.write_string(&key));
+                    try!(self.write_string("\r\n"));
+                }
+                try!(self.write_string("END\r\n"));
+            }
+            Resp::Lease(ref token) => {
+                try!(self.write_string("LEASE "));
+                try!(self.write_string(&token.to_string()));
+                try!(self.write_string("\r\n"));
+            }
             Resp::NotFound => {
                 try!(self.write_string("NOT_FOUND\r\n"));
             }
@@ -525,6 +1056,18 @@ impl<T: Read + Write> TcpTransport<T> {
                 }
                 try!(self.write_string("END\r\n"));
             }
+            Resp::Stale(ref value) => {
+                try!(self.write_string("STALE "));
+                try!(self.write_string(&value.key));
+                try!(self.write_string(" "));
+                try!(self.write_string(&value.flags.to_string()));
+                try!(self.write_string(" "));
+                try!(self.write_string(&value.data.len().to_string()));
+                try!(self.write_string("\r\n"));
+                try!(self.write_chunks(&value.data));
+                try!(self.write_string("\r\n"));
+                try!(self.write_string("END\r\n"));
+            }
             Resp::Stored => {
                 try!(self.write_string("STORED\r\n"));
             }
@@ -546,7 +1089,7 @@ impl<T: Read + Write> TcpTransport<T> {
                                                      .to_string())); // flags
                     }
                     try!(self.write_string(&"\r\n".to_string())); // newline
-                    try!(self.write_bytes(&value.data)); // data block
+                    try!(self.write_chunks(&value.data)); // data block
                     try!(self.write_string(&"\r\n".to_string())); // newline
                 }
                 try!(self.write_string(&"END\r\n".to_string())); // END + newline
@@ -556,6 +1099,9 @@ impl<T: Read + Write> TcpTransport<T> {
                 try!(self.write_string(&version)); // key
                 try!(self.write_string(&"\r\n".to_string())); // newline
             }
+            Resp::Wait => {
+                try!(self.write_string("WAIT\r\n"));
+            }
         }
 
         // Make sure all bytes were actually sent
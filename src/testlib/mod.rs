@@ -1,4 +1,5 @@
 // Declare sub modules
 pub mod cmp;
 pub mod datagen;
+pub mod tempfile;
 pub mod test_stream;
@@ -0,0 +1,12 @@
+use std::env;
+
+use platform::process::get_pid;
+
+
+// A path in the temp dir that is unique to this test process, so that
+// concurrent test runs don't trample on each other's files
+pub fn get_temp_path(name: &str) -> String {
+    let mut path = env::temp_dir();
+    path.push(format!("emcache-test-{}-{}", get_pid(), name));
+    path.to_str().unwrap().to_string()
+}